@@ -0,0 +1,2 @@
+-- Store the VM's vsock CID so relays can reattach after an API restart
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS vsock_cid INTEGER;
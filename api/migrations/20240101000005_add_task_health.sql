@@ -0,0 +1,2 @@
+-- Track whether the task's VM is still sending heartbeats
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS healthy BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,2 @@
+-- Build version the task's sidecar reported in the protocol handshake
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS sidecar_version VARCHAR(64);
@@ -0,0 +1,2 @@
+-- Secret of the task's preview URLs, checked by the preview proxy
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS preview_token VARCHAR(64);
@@ -0,0 +1,13 @@
+-- Public keys users log in to the SSH gateway with. A key belongs to one
+-- user at most, so the gateway can tell who is logging in from the key alone.
+CREATE TABLE IF NOT EXISTS user_ssh_keys (
+    id UUID PRIMARY KEY,
+    user_id VARCHAR(64) NOT NULL,
+    key_type VARCHAR(64) NOT NULL,
+    public_key TEXT NOT NULL,
+    comment TEXT,
+    fingerprint VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_ssh_keys_user_id ON user_ssh_keys(user_id);
@@ -0,0 +1,16 @@
+-- Connections the VMs made through the egress proxy, refused ones included
+CREATE TABLE IF NOT EXISTS task_connections (
+    id BIGSERIAL PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+    host VARCHAR(255) NOT NULL,
+    port INTEGER NOT NULL,
+    method VARCHAR(16) NOT NULL,
+    allowed BOOLEAN NOT NULL,
+    bytes_up BIGINT NOT NULL DEFAULT 0,
+    bytes_down BIGINT NOT NULL DEFAULT 0,
+    started_at TIMESTAMPTZ NOT NULL,
+    duration_ms BIGINT NOT NULL DEFAULT 0,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_task_connections_task_id ON task_connections(task_id, started_at DESC);
@@ -0,0 +1,2 @@
+-- Size of the data volume the sidecar reported mounted at /workspace
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS workspace_bytes BIGINT;
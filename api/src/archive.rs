@@ -0,0 +1,424 @@
+//! Whole-workspace archives. The sidecar produces a tar stream of /workspace
+//! (or a local tar does, over the VM's disk image once QEMU is gone), and it
+//! is gzipped or rewritten as a zip on a blocking thread while the client
+//! downloads it.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use chrono::{Datelike, Timelike};
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{ArchiveFormat, TaskStatus};
+use crate::rootfs::{DiskImage, ImageFormat};
+use crate::AppState;
+
+/// Size of the pieces handed to the client
+const OUTPUT_CHUNK_LEN: usize = 256 * 1024;
+
+/// Distinguishes mount points of concurrent offline archives
+static MOUNT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Network block devices tried for qcow2 images (`modprobe nbd` makes 16)
+const NBD_DEVICES: usize = 16;
+
+impl ArchiveFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Convert a tar stream into `format`. Dropping the returned stream stops the
+/// conversion and drops `tar`.
+pub fn encode<S>(tar: S, format: ArchiveFormat) -> impl Stream<Item = ApiResult<Bytes>>
+where
+    S: Stream<Item = ApiResult<Bytes>> + Send + 'static,
+{
+    let (input_tx, input_rx) = mpsc::channel(4);
+    let (output_tx, mut output_rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        let mut tar = std::pin::pin!(tar);
+        while let Some(chunk) = tar.next().await {
+            let failed = chunk.is_err();
+            if input_tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let errors = output_tx.clone();
+    tokio::task::spawn_blocking(move || {
+        let input = ChannelReader {
+            rx: input_rx,
+            chunk: Bytes::new(),
+        };
+        let output = ChannelWriter {
+            tx: output_tx,
+            buffer: Vec::with_capacity(OUTPUT_CHUNK_LEN),
+        };
+        let result = match format {
+            ArchiveFormat::TarGz => gzip(input, output),
+            ArchiveFormat::Zip => zip(input, output),
+        };
+        // A failed send means the client went away; nobody is left to tell
+        if let Err(e) = result {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                let _ = errors.blocking_send(Err(ApiError::VmError(format!(
+                    "Failed to build the archive: {}",
+                    e
+                ))));
+            }
+        }
+    });
+
+    async_stream::stream! {
+        while let Some(chunk) = output_rx.recv().await {
+            yield chunk;
+        }
+    }
+}
+
+fn gzip(mut input: ChannelReader, output: ChannelWriter) -> io::Result<()> {
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+/// Rewrite the tar entries as a zip. Only files, directories and symlinks
+/// are kept; both tars are run with `--hard-dereference`.
+fn zip(input: ChannelReader, output: ChannelWriter) -> io::Result<()> {
+    use zip::write::SimpleFileOptions;
+
+    let mut archive = tar::Archive::new(input);
+    let mut writer = zip::ZipWriter::new_stream(output);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let name = path
+            .trim_start_matches("./")
+            .trim_end_matches('/')
+            .to_string();
+        if name.is_empty() || name == "." {
+            continue;
+        }
+
+        let options = SimpleFileOptions::default()
+            .unix_permissions(header.mode()? & 0o7777)
+            .last_modified_time(zip_time(header.mtime()?))
+            .large_file(header.size()? >= u32::MAX as u64);
+        let kind = header.entry_type();
+        if kind.is_dir() {
+            // add_directory leaves out the data descriptor a streamed zip
+            // needs, so directories go in as empty entries ending in a slash
+            let options = options.compression_method(zip::CompressionMethod::Stored);
+            writer.start_file(format!("{}/", name), options)?;
+        } else if kind.is_symlink() {
+            let target = entry.link_name()?.unwrap_or_default();
+            writer.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if kind.is_file() {
+            writer.start_file(name, options)?;
+            io::copy(&mut entry, &mut writer)?;
+        }
+    }
+    writer.finish()?.flush()
+}
+
+/// Zip timestamps cover 1980 to 2107; anything else becomes 1980
+fn zip_time(mtime: u64) -> zip::DateTime {
+    chrono::DateTime::from_timestamp(mtime as i64, 0)
+        .and_then(|t| {
+            zip::DateTime::from_date_and_time(
+                u16::try_from(t.year()).ok()?,
+                t.month() as u8,
+                t.day() as u8,
+                t.hour() as u8,
+                t.minute() as u8,
+                t.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Blocking reader over the incoming tar chunks
+struct ChannelReader {
+    rx: mpsc::Receiver<ApiResult<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(io::Error::other(e.to_string())),
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Blocking writer handing the encoded archive to the response body
+struct ChannelWriter {
+    tx: mpsc::Sender<ApiResult<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= OUTPUT_CHUNK_LEN {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a suspended task's VM running while its workspace is archived and
+/// pauses it again afterwards, unless the task was resumed in the meantime
+pub struct WakeGuard {
+    state: Arc<AppState>,
+    task_id: Uuid,
+    vm_id: String,
+}
+
+impl WakeGuard {
+    pub async fn wake(state: Arc<AppState>, task_id: Uuid, vm_id: String) -> ApiResult<Self> {
+        state.vm_manager.wake_vm(&vm_id).await?;
+        Ok(Self {
+            state,
+            task_id,
+            vm_id,
+        })
+    }
+}
+
+impl Drop for WakeGuard {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let task_id = self.task_id;
+        let vm_id = std::mem::take(&mut self.vm_id);
+        tokio::spawn(async move {
+            let suspended = matches!(
+                db::get_task(&state.db, task_id).await,
+                Ok(task) if task.status == TaskStatus::Suspended
+            );
+            state.vm_manager.release_vm(&vm_id, suspended).await;
+        });
+    }
+}
+
+/// A VM disk image mounted read-only on the host, unmounted on drop. The
+/// journal is not replayed, so an image left behind by a crashed VM mounts
+/// as of its last checkpoint. qcow2 overlays are mounted through a network
+/// block device.
+pub struct MountedImage {
+    dir: PathBuf,
+    nbd: Option<PathBuf>,
+}
+
+impl MountedImage {
+    pub async fn mount(image: &DiskImage, task_id: Uuid) -> ApiResult<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "lia-archive-{}-{}",
+            task_id,
+            MOUNT_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (source, nbd, options) = match image.format {
+            ImageFormat::Raw => (image.path.clone(), None, "loop,ro,noload,nosuid,nodev,noexec"),
+            ImageFormat::Qcow2 => {
+                let device = connect_nbd(&image.path).await?;
+                (device.clone(), Some(device), "ro,noload,nosuid,nodev,noexec")
+            }
+        };
+        let failed = |nbd: Option<PathBuf>, error: ApiError| async move {
+            if let Some(device) = nbd {
+                disconnect_nbd(&device).await;
+            }
+            Err(error)
+        };
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+            let error = ApiError::VmError(format!("Failed to create mount point: {}", e));
+            return failed(nbd, error).await;
+        }
+
+        let output = Command::new("mount")
+            .args(["-o", options])
+            .arg(&source)
+            .arg(&dir)
+            .output()
+            .await;
+        let error = match output {
+            Ok(output) if output.status.success() => return Ok(Self { dir, nbd }),
+            Ok(output) => ApiError::VmError(format!(
+                "Failed to mount {}: {}",
+                image.path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => ApiError::VmError(format!("Failed to run mount: {}", e)),
+        };
+        let _ = tokio::fs::remove_dir(&dir).await;
+        failed(nbd, error).await
+    }
+
+    /// Tar stream of `workspace` inside the image, with the same options as
+    /// the sidecar's
+    pub fn tar(
+        self,
+        workspace: &str,
+        exclude: Vec<String>,
+    ) -> impl Stream<Item = ApiResult<Bytes>> {
+        let workspace = self.dir.join(workspace);
+        async_stream::stream! {
+            let mut child = match Command::new("tar")
+                .args(["--create", "--file=-", "--directory"])
+                .arg(&workspace)
+                .args(["--no-wildcards", "--hard-dereference"])
+                .args(exclude.iter().map(|name| format!("--exclude={}", name)))
+                .arg(".")
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+            {
+                Ok(child) => child,
+                Err(e) => {
+                    yield Err(ApiError::VmError(format!("Failed to run tar: {}", e)));
+                    return;
+                }
+            };
+
+            let mut stdout = child.stdout.take().expect("stdout is piped");
+            loop {
+                let mut chunk = vec![0; OUTPUT_CHUNK_LEN];
+                match stdout.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        chunk.truncate(len);
+                        yield Ok(Bytes::from(chunk));
+                    }
+                    Err(e) => {
+                        yield Err(ApiError::VmError(format!("Failed to read tar output: {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            // Exit status 1 only means some files could not be read completely
+            match child.wait().await {
+                Ok(status) if matches!(status.code(), Some(0 | 1)) => {}
+                Ok(status) => yield Err(ApiError::VmError(format!("tar failed: {}", status))),
+                Err(e) => yield Err(ApiError::VmError(format!("Failed to wait for tar: {}", e))),
+            }
+        }
+    }
+}
+
+impl Drop for MountedImage {
+    fn drop(&mut self) {
+        let dir = std::mem::take(&mut self.dir);
+        let nbd = self.nbd.take();
+        tokio::spawn(async move {
+            match Command::new("umount")
+                .arg("--lazy")
+                .arg(&dir)
+                .status()
+                .await
+            {
+                Ok(status) if status.success() => {
+                    let _ = tokio::fs::remove_dir(&dir).await;
+                    if let Some(device) = nbd {
+                        disconnect_nbd(&device).await;
+                    }
+                }
+                Ok(status) => tracing::warn!("umount {} failed: {}", dir.display(), status),
+                Err(e) => tracing::warn!("Failed to run umount {}: {}", dir.display(), e),
+            }
+        });
+    }
+}
+
+/// Attach a qcow2 image, read-only, to the first free /dev/nbdN
+async fn connect_nbd(image: &Path) -> ApiResult<PathBuf> {
+    for n in 0..NBD_DEVICES {
+        let device = PathBuf::from(format!("/dev/nbd{}", n));
+        if !device.exists() {
+            break;
+        }
+        // Devices in use have a server
+        if Path::new(&format!("/sys/block/nbd{}/pid", n)).exists() {
+            continue;
+        }
+        let output = Command::new("qemu-nbd")
+            .args(["--read-only", "--format=qcow2", "--connect"])
+            .arg(&device)
+            .arg(image)
+            .output()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to run qemu-nbd: {}", e)))?;
+        // Another archive may have taken the device meanwhile
+        if output.status.success() {
+            return Ok(device);
+        }
+    }
+    Err(ApiError::VmError(
+        "No free network block device for the qcow2 image (is the nbd module loaded?)".to_string(),
+    ))
+}
+
+async fn disconnect_nbd(device: &Path) {
+    match Command::new("qemu-nbd")
+        .arg("--disconnect")
+        .arg(device)
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => tracing::warn!(
+            "qemu-nbd --disconnect {} failed: {}",
+            device.display(),
+            status
+        ),
+        Err(e) => tracing::warn!("Failed to run qemu-nbd --disconnect: {}", e),
+    }
+}
@@ -1,4 +1,10 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
 use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::models::{EgressPolicy, McpServerConfig};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfig {
@@ -8,6 +14,19 @@ pub struct AppConfig {
     pub vm: VmConfig,
     pub network: NetworkConfig,
     pub claude: ClaudeConfig,
+    /// MCP servers that tasks can reference by name
+    #[serde(default)]
+    pub mcp_servers: HashMap<String, McpServerConfig>,
+    #[serde(default)]
+    pub preview: PreviewConfig,
+    #[serde(default)]
+    pub ssh_gateway: SshGatewayConfig,
+    #[serde(default)]
+    pub egress: EgressConfig,
+    #[serde(default)]
+    pub egress_proxy: EgressProxyConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -45,6 +64,38 @@ pub struct QemuConfig {
     pub pids_dir: String,
     #[serde(default = "default_machine_type")]
     pub machine_type: String,
+    /// How each VM gets its own copy of the root filesystem
+    #[serde(default)]
+    pub rootfs_clone: ImageClone,
+    /// Read-only snapshots of `rootfs_path` that qcow2 overlays are backed by
+    #[serde(default = "default_base_images_dir")]
+    pub base_images_dir: String,
+    /// How each VM gets its data volume from a pre-formatted template
+    #[serde(default)]
+    pub volume_clone: ImageClone,
+    /// Pre-formatted data volumes, one per size
+    #[serde(default = "default_volume_templates_dir")]
+    pub volume_templates_dir: String,
+    /// Restore VMs from a golden snapshot instead of booting them (see `snapshot`)
+    #[serde(default)]
+    pub restore_from_snapshot: bool,
+    /// Golden snapshots: memory and device state plus disks, per rootfs
+    /// version and VM shape
+    #[serde(default = "default_snapshots_dir")]
+    pub snapshots_dir: String,
+}
+
+/// Ways to give a VM its own disk image (see `rootfs` and `volume`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageClone {
+    /// A reflink clone where the filesystem supports it, else a qcow2 overlay
+    #[default]
+    Auto,
+    Reflink,
+    Qcow2,
+    /// A full copy of the root filesystem, or a data volume formatted for the VM
+    Copy,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -59,6 +110,21 @@ pub struct VmConfig {
     pub idle_timeout_minutes: u32,
     #[serde(default = "default_vsock_cid_start")]
     pub vsock_cid_start: u32,
+    /// Seconds between heartbeats sent to the sidecar
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Mark a task unhealthy after this long without hearing from its sidecar
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// Reset (QMP system_reset) VMs that become unhealthy
+    #[serde(default)]
+    pub reset_unhealthy: bool,
+    /// Offer length-prefixed frames to the sidecar instead of JSON lines
+    #[serde(default = "default_true")]
+    pub vsock_framing: bool,
+    /// Offer zstd compression of large frames (needs `vsock_framing`)
+    #[serde(default = "default_true")]
+    pub vsock_compression: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -69,6 +135,135 @@ pub struct NetworkConfig {
     pub bridge_ip: String,
     #[serde(default = "default_subnet")]
     pub subnet: String,
+    /// Bandwidth cap from each VM in kbit/s, unless its task sets one (0: none)
+    #[serde(default)]
+    pub default_upload_kbps: u32,
+    /// Bandwidth cap to each VM in kbit/s, unless its task sets one (0: none)
+    #[serde(default)]
+    pub default_download_kbps: u32,
+    /// New TCP connections per second from each VM, unless its task sets a limit (0: none)
+    #[serde(default)]
+    pub default_connections_per_sec: u32,
+}
+
+/// Outbound network policies of the VMs (see `egress`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgressConfig {
+    /// Policy of tasks that set none and whose guild has no default
+    #[serde(default)]
+    pub default: EgressPolicy,
+    /// Defaults by Discord guild ID
+    #[serde(default)]
+    pub guilds: HashMap<String, EgressPolicy>,
+    /// Hosts every policy lets the VMs reach, so the agent keeps working
+    #[serde(default = "default_egress_always_allow")]
+    pub always_allow: Vec<String>,
+    /// Resolvers the VMs use (the rootfs' /etc/resolv.conf)
+    #[serde(default = "default_egress_dns_servers")]
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            default: EgressPolicy::default(),
+            guilds: HashMap::new(),
+            always_allow: default_egress_always_allow(),
+            dns_servers: default_egress_dns_servers(),
+        }
+    }
+}
+
+/// Logging HTTP(S) proxy on the bridge that the VMs are configured to use
+/// (see `proxy`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgressProxyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Port on the bridge IP
+    #[serde(default = "default_egress_proxy_port")]
+    pub port: u16,
+}
+
+impl Default for EgressProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: default_egress_proxy_port(),
+        }
+    }
+}
+
+/// VMs booted ahead of time so new tasks don't wait for one (see `pool`)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PoolConfig {
+    /// Resource shapes to keep idle VMs of; empty turns the pool off
+    #[serde(default)]
+    pub shapes: Vec<PoolShapeConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolShapeConfig {
+    pub vcpu_count: u32,
+    pub memory_mb: u32,
+    pub storage_gb: u32,
+    /// Idle VMs to keep of this shape
+    pub size: usize,
+}
+
+impl AppConfig {
+    /// Address of the egress proxy, if it is enabled
+    pub fn egress_proxy_addr(&self) -> Option<SocketAddrV4> {
+        if !self.egress_proxy.enabled {
+            return None;
+        }
+        let ip = self.network.bridge_ip.parse().ok()?;
+        Some(SocketAddrV4::new(ip, self.egress_proxy.port))
+    }
+}
+
+/// Preview URLs of servers running in the VMs
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PreviewConfig {
+    /// Also serve previews at `<port>-<task-id>.<domain>`, which needs a
+    /// wildcard DNS record pointing at the API
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+/// SSH gateway (`lia-ssh-gateway` behind the host's sshd) that users reach
+/// their tasks through
+#[derive(Debug, Clone, Deserialize)]
+pub struct SshGatewayConfig {
+    /// Public name of the gateway; without it tasks show the VM's bridge IP
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_ssh_gateway_port")]
+    pub port: u16,
+    /// Account on the gateway host that all users log in as
+    #[serde(default = "default_ssh_gateway_user")]
+    pub user: String,
+}
+
+impl Default for SshGatewayConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: default_ssh_gateway_port(),
+            user: default_ssh_gateway_user(),
+        }
+    }
+}
+
+impl SshGatewayConfig {
+    /// How to log in to a task through the gateway
+    pub fn command(&self, host: &str, task_id: Uuid) -> String {
+        if self.port == 22 {
+            format!("ssh -t {}@{} {}", self.user, host, task_id)
+        } else {
+            format!("ssh -t -p {} {}@{} {}", self.port, self.user, host, task_id)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -109,6 +304,18 @@ fn default_machine_type() -> String {
     "q35".to_string()
 }
 
+fn default_base_images_dir() -> String {
+    "/var/lib/lia/rootfs/base".to_string()
+}
+
+fn default_volume_templates_dir() -> String {
+    "/var/lib/lia/volumes/templates".to_string()
+}
+
+fn default_snapshots_dir() -> String {
+    "/var/lib/lia/snapshots".to_string()
+}
+
 fn default_rootfs_path() -> String {
     "/var/lib/lia/rootfs/rootfs.ext4".to_string()
 }
@@ -145,6 +352,38 @@ fn default_vsock_cid_start() -> u32 {
     100
 }
 
+fn default_heartbeat_interval_secs() -> u64 {
+    10
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    30
+}
+
+fn default_egress_always_allow() -> Vec<String> {
+    vec!["api.anthropic.com".to_string()]
+}
+
+fn default_egress_dns_servers() -> Vec<Ipv4Addr> {
+    vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)]
+}
+
+fn default_egress_proxy_port() -> u16 {
+    3128
+}
+
+fn default_ssh_gateway_port() -> u16 {
+    22
+}
+
+fn default_ssh_gateway_user() -> String {
+    "lia".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
 fn default_bridge_name() -> String {
     "lia-br0".to_string()
 }
@@ -2,7 +2,9 @@ use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::error::{ApiError, ApiResult};
-use crate::models::{GuildTask, Task, TaskConfig, TaskSource, TaskStatus};
+use crate::models::{
+    GuildTask, ParsedSshKey, Task, TaskConfig, TaskConnection, TaskSource, TaskStatus, UserSshKey,
+};
 
 pub async fn create_task(
     pool: &PgPool,
@@ -10,14 +12,15 @@ pub async fn create_task(
     source: TaskSource,
     repositories: &[String],
     config: Option<TaskConfig>,
+    preview_token: &str,
 ) -> ApiResult<Task> {
     let id = Uuid::new_v4();
     let config_json = config.map(sqlx::types::Json);
 
     let task = sqlx::query_as::<_, Task>(
         r#"
-        INSERT INTO tasks (id, user_id, status, source, repositories, config, created_at)
-        VALUES ($1, $2, $3, $4, $5, $6, NOW())
+        INSERT INTO tasks (id, user_id, status, source, repositories, config, preview_token, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())
         RETURNING *
         "#,
     )
@@ -27,6 +30,7 @@ pub async fn create_task(
     .bind(source)
     .bind(repositories)
     .bind(config_json)
+    .bind(preview_token)
     .fetch_one(pool)
     .await?;
 
@@ -162,6 +166,82 @@ pub async fn update_task_ip_address(
     Ok(task)
 }
 
+pub async fn update_task_vsock_cid(pool: &PgPool, id: Uuid, vsock_cid: u32) -> ApiResult<Task> {
+    let task = sqlx::query_as::<_, Task>(
+        r#"
+        UPDATE tasks
+        SET vsock_cid = $2
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(vsock_cid as i32)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::TaskNotFound(id.to_string()))?;
+
+    Ok(task)
+}
+
+/// Record what the sidecar reported in the vsock handshake
+pub async fn update_task_sidecar(
+    pool: &PgPool,
+    id: Uuid,
+    version: &str,
+    workspace_bytes: Option<i64>,
+) -> ApiResult<Task> {
+    let task = sqlx::query_as::<_, Task>(
+        r#"
+        UPDATE tasks
+        SET sidecar_version = $2, workspace_bytes = $3
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(version)
+    .bind(workspace_bytes)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::TaskNotFound(id.to_string()))?;
+
+    Ok(task)
+}
+
+pub async fn update_task_health(pool: &PgPool, id: Uuid, healthy: bool) -> ApiResult<Task> {
+    let task = sqlx::query_as::<_, Task>(
+        r#"
+        UPDATE tasks
+        SET healthy = $2
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(healthy)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::TaskNotFound(id.to_string()))?;
+
+    Ok(task)
+}
+
+/// Tasks whose VM should still have a sidecar to relay to
+pub async fn list_attached_tasks(pool: &PgPool) -> ApiResult<Vec<Task>> {
+    let tasks = sqlx::query_as::<_, Task>(
+        r#"
+        SELECT * FROM tasks
+        WHERE status IN ('running', 'awaiting_input', 'suspended')
+          AND vsock_cid IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tasks)
+}
+
 pub async fn complete_task(
     pool: &PgPool,
     id: Uuid,
@@ -208,3 +288,137 @@ pub async fn delete_task(pool: &PgPool, id: Uuid) -> ApiResult<()> {
 
     Ok(())
 }
+
+pub async fn add_ssh_key(
+    pool: &PgPool,
+    user_id: &str,
+    key: &ParsedSshKey,
+) -> ApiResult<UserSshKey> {
+    let key = sqlx::query_as::<_, UserSshKey>(
+        r#"
+        INSERT INTO user_ssh_keys (id, user_id, key_type, public_key, comment, fingerprint, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW())
+        RETURNING *
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(&key.key_type)
+    .bind(&key.public_key)
+    .bind(&key.comment)
+    .bind(&key.fingerprint)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(key)
+}
+
+pub async fn list_ssh_keys(pool: &PgPool, user_id: &str) -> ApiResult<Vec<UserSshKey>> {
+    let keys = sqlx::query_as::<_, UserSshKey>(
+        "SELECT * FROM user_ssh_keys WHERE user_id = $1 ORDER BY created_at",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(keys)
+}
+
+pub async fn get_ssh_key_by_fingerprint(
+    pool: &PgPool,
+    fingerprint: &str,
+) -> ApiResult<Option<UserSshKey>> {
+    let key = sqlx::query_as::<_, UserSshKey>("SELECT * FROM user_ssh_keys WHERE fingerprint = $1")
+        .bind(fingerprint)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(key)
+}
+
+pub async fn delete_ssh_key(pool: &PgPool, user_id: &str, id: Uuid) -> ApiResult<()> {
+    let result = sqlx::query("DELETE FROM user_ssh_keys WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("SSH key {}", id)));
+    }
+
+    Ok(())
+}
+
+/// The task whose VM currently has `ip_address`. Addresses are reused, so
+/// only tasks that can still have a VM are considered.
+pub async fn get_active_task_by_ip(pool: &PgPool, ip_address: &str) -> ApiResult<Option<Task>> {
+    let task = sqlx::query_as::<_, Task>(
+        r#"
+        SELECT * FROM tasks
+        WHERE ip_address = $1
+          AND status NOT IN ('pending', 'terminated')
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(ip_address)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(task)
+}
+
+pub async fn add_task_connection(pool: &PgPool, connection: &TaskConnection) -> ApiResult<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO task_connections
+            (task_id, host, port, method, allowed, bytes_up, bytes_down, started_at, duration_ms, error)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        "#,
+    )
+    .bind(connection.task_id)
+    .bind(&connection.host)
+    .bind(connection.port)
+    .bind(&connection.method)
+    .bind(connection.allowed)
+    .bind(connection.bytes_up)
+    .bind(connection.bytes_down)
+    .bind(connection.started_at)
+    .bind(connection.duration_ms)
+    .bind(&connection.error)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_task_connections(
+    pool: &PgPool,
+    task_id: Uuid,
+    page: u32,
+    per_page: u32,
+) -> ApiResult<(Vec<TaskConnection>, i64)> {
+    let offset = (page.saturating_sub(1)) * per_page;
+
+    let connections = sqlx::query_as::<_, TaskConnection>(
+        r#"
+        SELECT * FROM task_connections
+        WHERE task_id = $1
+        ORDER BY started_at DESC, id DESC
+        LIMIT $2 OFFSET $3
+        "#,
+    )
+    .bind(task_id)
+    .bind(per_page as i64)
+    .bind(offset as i64)
+    .fetch_all(pool)
+    .await?;
+
+    let total: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM task_connections WHERE task_id = $1")
+        .bind(task_id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok((connections, total.0))
+}
@@ -0,0 +1,450 @@
+//! Outbound network policies of the VMs, enforced with nftables on the host.
+//!
+//! Each VM with a `deny` or `allowlist` policy gets a table of its own in
+//! the bridge family, hooked before the bridge so it sees every frame the VM
+//! sends through its TAP device, whether it is forwarded to another VM,
+//! routed out of the host or addressed to the host itself. Replies to
+//! connections made to the VM (previews, SSH) are let through by conntrack.
+//! Domains are resolved when the rules are installed; `open` installs nothing.
+//! The egress proxy (see `proxy`) checks host names against the same policies.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+
+use crate::config::EgressConfig;
+use crate::models::EgressPolicy;
+
+/// Upper bound for the domains and for the networks of a policy
+pub const MAX_EGRESS_ENTRIES: usize = 64;
+
+/// An IPv4 network, e.g. "140.82.112.0/20"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Net {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Ipv4Net {
+    /// Parse "a.b.c.d/n" or a single address. Host bits are cleared.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            // Digits only; `parse` would take "+8"
+            Some((addr, prefix))
+                if !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                (addr, prefix.parse().ok()?)
+            }
+            Some(_) => return None,
+            None => (s, 32),
+        };
+        if prefix > 32 {
+            return None;
+        }
+        let addr: Ipv4Addr = addr.parse().ok()?;
+        let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+        Some(Self {
+            addr: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        })
+    }
+}
+
+impl Ipv4Net {
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+        u32::from(addr) & mask == u32::from(self.addr)
+    }
+}
+
+impl std::fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.prefix == 32 {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl EgressConfig {
+    /// Policy of a task: its own, else its guild's, else the global default
+    pub fn policy_for(&self, task: Option<&EgressPolicy>, guild_id: Option<&str>) -> EgressPolicy {
+        task.or_else(|| guild_id.and_then(|id| self.guilds.get(id)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Check a policy's domains and networks before anything is installed
+pub fn validate(policy: &EgressPolicy) -> Result<(), String> {
+    let EgressPolicy::Allowlist { domains, cidrs } = policy else {
+        return Ok(());
+    };
+    if domains.len() > MAX_EGRESS_ENTRIES || cidrs.len() > MAX_EGRESS_ENTRIES {
+        return Err(format!(
+            "An egress allowlist holds at most {} domains and {} networks",
+            MAX_EGRESS_ENTRIES, MAX_EGRESS_ENTRIES
+        ));
+    }
+    if let Some(domain) = domains.iter().find(|d| !is_valid_domain(d)) {
+        return Err(format!("Invalid egress domain: {:?}", domain));
+    }
+    if let Some(cidr) = cidrs.iter().find(|c| Ipv4Net::parse(c).is_none()) {
+        return Err(format!("Invalid egress network: {:?}", cidr));
+    }
+    Ok(())
+}
+
+/// Plain host names only; wildcards cannot be resolved up front
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The networks a policy lets the VM reach, with `always_allow` and the
+/// policy's domains resolved to their current addresses. None for `open`.
+pub async fn allowed_networks(
+    policy: &EgressPolicy,
+    config: &EgressConfig,
+) -> Option<Vec<Ipv4Net>> {
+    let (domains, cidrs): (&[String], &[String]) = match policy {
+        EgressPolicy::Open => return None,
+        EgressPolicy::Deny => (&[], &[]),
+        EgressPolicy::Allowlist { domains, cidrs } => (domains, cidrs),
+    };
+
+    let mut networks: Vec<Ipv4Net> = cidrs.iter().filter_map(|c| Ipv4Net::parse(c)).collect();
+    for domain in config.always_allow.iter().chain(domains) {
+        match tokio::net::lookup_host((domain.as_str(), 443)).await {
+            Ok(addrs) => networks.extend(addrs.filter_map(|addr| match addr.ip() {
+                IpAddr::V4(addr) => Some(Ipv4Net { addr, prefix: 32 }),
+                IpAddr::V6(_) => None,
+            })),
+            Err(e) => tracing::warn!("Failed to resolve egress domain {}: {}", domain, e),
+        }
+    }
+    networks.sort_by_key(|n| (n.addr, n.prefix));
+    networks.dedup();
+    Some(networks)
+}
+
+/// Whether a policy lets the VM reach `host` at `addr`, the way the egress
+/// proxy checks a connection: the host is one of the listed domains (or of
+/// `always_allow`), or the address is in one of the listed networks
+pub fn permits(policy: &EgressPolicy, config: &EgressConfig, host: &str, addr: IpAddr) -> bool {
+    let (domains, cidrs): (&[String], &[String]) = match policy {
+        EgressPolicy::Open => return true,
+        EgressPolicy::Deny => (&[], &[]),
+        EgressPolicy::Allowlist { domains, cidrs } => (domains, cidrs),
+    };
+
+    let host = host.trim_end_matches('.');
+    if config
+        .always_allow
+        .iter()
+        .chain(domains)
+        .any(|domain| domain.eq_ignore_ascii_case(host))
+    {
+        return true;
+    }
+    let IpAddr::V4(addr) = addr else {
+        return false;
+    };
+    cidrs
+        .iter()
+        .filter_map(|c| Ipv4Net::parse(c))
+        .any(|net| net.contains(addr))
+}
+
+/// Name of the nftables table of the VM on `tap_name`
+pub fn table_name(tap_name: &str) -> String {
+    format!("lia_egress_{}", tap_name.replace('-', "_"))
+}
+
+/// nft script that (re)creates the VM's table: frames from `tap_name` may
+/// reach `allowed`, DNS on `dns_servers`, the egress proxy if there is one,
+/// and nothing else
+pub fn ruleset(
+    tap_name: &str,
+    allowed: &[Ipv4Net],
+    dns_servers: &[Ipv4Addr],
+    proxy: Option<SocketAddrV4>,
+) -> String {
+    let table = table_name(tap_name);
+    let mut script = String::new();
+
+    // Declaring the table first makes the delete work when it is missing
+    script.push_str(&format!("table bridge {} {{}}\n", table));
+    script.push_str(&format!("delete table bridge {}\n", table));
+    script.push_str(&format!("table bridge {} {{\n", table));
+
+    script.push_str("\tset allowed {\n\t\ttype ipv4_addr\n\t\tflags interval\n");
+    if !allowed.is_empty() {
+        let elements: Vec<String> = allowed.iter().map(|n| n.to_string()).collect();
+        script.push_str(&format!("\t\tauto-merge\n\t\telements = {{ {} }}\n", elements.join(", ")));
+    }
+    script.push_str("\t}\n");
+
+    script.push_str("\tset dns {\n\t\ttype ipv4_addr\n");
+    if !dns_servers.is_empty() {
+        let elements: Vec<String> = dns_servers.iter().map(|a| a.to_string()).collect();
+        script.push_str(&format!("\t\telements = {{ {} }}\n", elements.join(", ")));
+    }
+    script.push_str("\t}\n");
+
+    script.push_str("\tchain prerouting {\n");
+    script.push_str("\t\ttype filter hook prerouting priority -200; policy accept;\n");
+    script.push_str(&format!("\t\tiifname != \"{}\" accept\n", tap_name));
+    script.push_str("\t\tether type arp accept\n");
+    script.push_str("\t\tct state established,related accept\n");
+    script.push_str("\t\tip daddr @dns udp dport 53 accept\n");
+    script.push_str("\t\tip daddr @dns tcp dport 53 accept\n");
+    if let Some(proxy) = proxy {
+        script.push_str(&format!(
+            "\t\tip daddr {} tcp dport {} accept\n",
+            proxy.ip(),
+            proxy.port()
+        ));
+    }
+    script.push_str("\t\tip daddr @allowed accept\n");
+    script.push_str("\t\tcounter drop\n");
+    script.push_str("\t}\n}\n");
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(domains: &[&str], cidrs: &[&str]) -> EgressPolicy {
+        EgressPolicy::Allowlist {
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            cidrs: cidrs.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn net(s: &str) -> Ipv4Net {
+        Ipv4Net::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_network_parsing() {
+        assert_eq!(net("140.82.112.0/20").to_string(), "140.82.112.0/20");
+        assert_eq!(net("10.1.2.3").prefix, 32);
+        assert_eq!(net("10.1.2.3").to_string(), "10.1.2.3");
+        assert_eq!(net("10.1.2.3/32").to_string(), "10.1.2.3");
+        assert_eq!(net("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        // Host bits are cleared
+        assert_eq!(net("140.82.113.7/20").to_string(), "140.82.112.0/20");
+        assert_eq!(net("255.255.255.255/0").to_string(), "0.0.0.0/0");
+        assert_eq!(net("10.1.2.3/31").to_string(), "10.1.2.2/31");
+
+        for bad in [
+            "",
+            "10.1.2",
+            "10.1.2.3.4",
+            "10.1.2.256",
+            "10.1.2.-1",
+            "010.1.2.3",
+            " 10.1.2.3",
+            "10.1.2.3/",
+            "10.1.2.3/33",
+            "10.1.2.3/+8",
+            "10.1.2.3/-1",
+            "10.1.2.3/8/8",
+            "::1",
+            "example.com",
+        ] {
+            assert_eq!(Ipv4Net::parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let github = net("140.82.112.0/20");
+        assert!(github.contains(Ipv4Addr::new(140, 82, 112, 0)));
+        assert!(github.contains(Ipv4Addr::new(140, 82, 127, 255)));
+        assert!(!github.contains(Ipv4Addr::new(140, 82, 128, 0)));
+        assert!(!github.contains(Ipv4Addr::new(140, 82, 111, 255)));
+
+        let host = net("10.0.0.1");
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 2)));
+
+        let everything = net("0.0.0.0/0");
+        assert!(everything.contains(Ipv4Addr::new(0, 0, 0, 0)));
+        assert!(everything.contains(Ipv4Addr::new(255, 255, 255, 255)));
+    }
+
+    #[test]
+    fn test_domain_validation() {
+        for good in [
+            "github.com",
+            "api.github.com",
+            "a-b.example",
+            "localhost",
+            "x1.io",
+        ] {
+            assert!(is_valid_domain(good), "{:?}", good);
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for bad in [
+            "",
+            "*.github.com",
+            "github..com",
+            ".github.com",
+            "github.com.",
+            "-github.com",
+            "github-.com",
+            "git hub.com",
+            "github.com:443",
+            "https://github.com",
+            "bücher.de",
+            long_label.as_str(),
+            long_name.as_str(),
+        ] {
+            assert!(!is_valid_domain(bad), "{:?}", bad);
+        }
+        assert!(is_valid_domain(&format!("{}.com", "a".repeat(63))));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&EgressPolicy::Open).is_ok());
+        assert!(validate(&EgressPolicy::Deny).is_ok());
+        assert!(validate(&allowlist(&["github.com"], &["10.0.0.0/8", "1.1.1.1"])).is_ok());
+        assert!(validate(&allowlist(&["*.github.com"], &[])).is_err());
+        assert!(validate(&allowlist(&[], &["10.0.0.0/33"])).is_err());
+
+        let domains: Vec<String> = (0..MAX_EGRESS_ENTRIES)
+            .map(|i| format!("d{}.example", i))
+            .collect();
+        let cidrs: Vec<String> = (0..MAX_EGRESS_ENTRIES)
+            .map(|i| format!("10.0.{}.0/24", i))
+            .collect();
+        let at_limit = EgressPolicy::Allowlist {
+            domains: domains.clone(),
+            cidrs: cidrs.clone(),
+        };
+        assert!(validate(&at_limit).is_ok());
+
+        let mut too_many = domains.clone();
+        too_many.push("one-more.example".to_string());
+        let policy = EgressPolicy::Allowlist {
+            domains: too_many,
+            cidrs: cidrs.clone(),
+        };
+        assert!(validate(&policy).is_err());
+
+        let mut too_many = cidrs;
+        too_many.push("10.1.0.0/24".to_string());
+        let policy = EgressPolicy::Allowlist {
+            domains,
+            cidrs: too_many,
+        };
+        assert!(validate(&policy).is_err());
+    }
+
+    #[test]
+    fn test_permits() {
+        let config = EgressConfig::default();
+        let github: IpAddr = "140.82.112.3".parse().unwrap();
+        let elsewhere: IpAddr = "93.184.216.34".parse().unwrap();
+
+        assert!(permits(
+            &EgressPolicy::Open,
+            &config,
+            "example.com",
+            elsewhere
+        ));
+        assert!(permits(
+            &EgressPolicy::Deny,
+            &config,
+            "api.anthropic.com",
+            elsewhere
+        ));
+        assert!(!permits(
+            &EgressPolicy::Deny,
+            &config,
+            "example.com",
+            github
+        ));
+
+        let policy = allowlist(&["github.com"], &["140.82.112.0/20"]);
+        assert!(permits(&policy, &config, "GitHub.com.", elsewhere));
+        assert!(permits(&policy, &config, "api.anthropic.com", elsewhere));
+        assert!(permits(&policy, &config, "example.com", github));
+        assert!(!permits(&policy, &config, "api.github.com", elsewhere));
+        assert!(!permits(&policy, &config, "example.com", elsewhere));
+        assert!(!permits(
+            &policy,
+            &config,
+            "example.com",
+            "::1".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_table_name() {
+        assert_eq!(table_name("lia-tap-3"), "lia_egress_lia_tap_3");
+    }
+
+    #[test]
+    fn test_ruleset() {
+        let script = ruleset(
+            "lia-tap-3",
+            &[net("140.82.112.0/20"), net("160.79.104.10")],
+            &[Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            Some("172.16.0.1:3128".parse().unwrap()),
+        );
+        assert_eq!(
+            script,
+            "table bridge lia_egress_lia_tap_3 {}\n\
+             delete table bridge lia_egress_lia_tap_3\n\
+             table bridge lia_egress_lia_tap_3 {\n\
+             \tset allowed {\n\
+             \t\ttype ipv4_addr\n\
+             \t\tflags interval\n\
+             \t\tauto-merge\n\
+             \t\telements = { 140.82.112.0/20, 160.79.104.10 }\n\
+             \t}\n\
+             \tset dns {\n\
+             \t\ttype ipv4_addr\n\
+             \t\telements = { 8.8.8.8, 8.8.4.4 }\n\
+             \t}\n\
+             \tchain prerouting {\n\
+             \t\ttype filter hook prerouting priority -200; policy accept;\n\
+             \t\tiifname != \"lia-tap-3\" accept\n\
+             \t\tether type arp accept\n\
+             \t\tct state established,related accept\n\
+             \t\tip daddr @dns udp dport 53 accept\n\
+             \t\tip daddr @dns tcp dport 53 accept\n\
+             \t\tip daddr 172.16.0.1 tcp dport 3128 accept\n\
+             \t\tip daddr @allowed accept\n\
+             \t\tcounter drop\n\
+             \t}\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_ruleset_without_entries() {
+        // nft refuses an empty `elements`, so empty sets have none
+        let script = ruleset("lia-tap-3", &[], &[], None);
+        assert!(script.contains("\tset allowed {\n\t\ttype ipv4_addr\n\t\tflags interval\n\t}\n"));
+        assert!(script.contains("\tset dns {\n\t\ttype ipv4_addr\n\t}\n"));
+        assert!(!script.contains("elements"));
+        assert!(!script.contains("tcp dport 3128"));
+        assert!(script.ends_with("\t\tip daddr @allowed accept\n\t\tcounter drop\n\t}\n}\n"));
+    }
+}
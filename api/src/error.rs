@@ -20,6 +20,9 @@ pub enum ApiError {
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
 
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     #[error("VM error: {0}")]
     VmError(String),
 
@@ -31,6 +34,12 @@ pub enum ApiError {
 
     #[error("Task in invalid state: {0}")]
     InvalidState(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Bad gateway: {0}")]
+    BadGateway(String),
 }
 
 #[derive(Debug, Serialize)]
@@ -46,6 +55,7 @@ impl IntoResponse for ApiError {
             ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, "NOT_FOUND", msg.clone()),
             ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, "BAD_REQUEST", msg.clone()),
             ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED", msg.clone()),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, "FORBIDDEN", msg.clone()),
             ApiError::VmError(msg) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "VM_ERROR", msg.clone())
             }
@@ -66,6 +76,8 @@ impl IntoResponse for ApiError {
                 )
             }
             ApiError::InvalidState(msg) => (StatusCode::CONFLICT, "INVALID_STATE", msg.clone()),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, "CONFLICT", msg.clone()),
+            ApiError::BadGateway(msg) => (StatusCode::BAD_GATEWAY, "BAD_GATEWAY", msg.clone()),
         };
 
         let body = Json(ErrorResponse {
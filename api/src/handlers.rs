@@ -1,15 +1,19 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
+    body::{Body, Bytes},
     extract::{
         ws::{Message, WebSocket},
         Path, Query, State, WebSocketUpgrade,
     },
+    http::{header, HeaderValue},
     response::{
         sse::{Event, KeepAlive, Sse},
-        IntoResponse,
+        IntoResponse, Redirect, Response,
     },
     Json,
 };
@@ -17,19 +21,48 @@ use futures::{SinkExt, Stream, StreamExt};
 use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
 use uuid::Uuid;
 
+use crate::archive::{self, MountedImage, WakeGuard};
 use crate::db;
+use crate::egress;
 use crate::error::{ApiError, ApiResult};
 use crate::models::{
-    is_valid_repo_format, BootStage, CreateTaskRequest, ListTasksQuery, LogsQuery, LogsResponse,
-    StreamLogsQuery, TaskListResponse, TaskResponse, TaskStatus, WsMessage,
+    is_valid_mcp_server_name, is_valid_repo_format, is_valid_workspace_path, parse_ssh_public_key,
+    AddSshKeyRequest, ArchiveQuery, BootStage, CreateTaskRequest, DiffMode, DiffQuery, DiffResponse,
+    ExecRequest, ExecStream, FileError, FileErrorKind, FileUploadResponse, ListTasksQuery,
+    LogsQuery, LogsResponse, McpServerConfig, McpServerRequest, NetworkLogQuery,
+    NetworkLogResponse, PermissionResponseRequest, PreviewPath, SshKeyQuery, SshKeyResponse,
+    StreamLogsQuery, TaskListResponse, TaskResponse, TaskStatsResponse, TaskStatus,
+    TerminalMessage, TerminalQuery, TreeQuery, TreeResponse, UserSshKey, VsockMessage, WsMessage,
+    FILE_CHUNK_LEN,
 };
+use crate::preview;
+use crate::traffic;
 use crate::vsock::VsockRelay;
+use crate::ws::{PendingRequest, TaskChannel};
 use crate::AppState;
 
+/// How long the sidecar gets to answer one request (file chunk, listing)
+const SIDECAR_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Deepest workspace listing a client can ask for
+const MAX_TREE_DEPTH: u32 = 16;
+
+/// Time limits of commands run with `exec`
+const DEFAULT_EXEC_TIMEOUT_SECS: u64 = 300;
+const MAX_EXEC_TIMEOUT_SECS: u64 = 3600;
+
+/// Most ports a task can open to preview URLs
+const MAX_PREVIEW_PORTS: usize = 16;
+
 pub async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Prometheus metrics of the warm pool
+pub async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    state.pool.metrics()
+}
+
 pub async fn create_task(
     State(state): State<Arc<AppState>>,
     Json(req): Json<CreateTaskRequest>,
@@ -55,6 +88,34 @@ pub async fn create_task(
         }
     }
 
+    if let Some(config) = &req.config {
+        if config.preview_ports.len() > MAX_PREVIEW_PORTS {
+            return Err(ApiError::BadRequest(format!(
+                "At most {} preview ports can be declared",
+                MAX_PREVIEW_PORTS
+            )));
+        }
+        if config.preview_ports.contains(&0) {
+            return Err(ApiError::BadRequest("Preview port 0 is invalid".to_string()));
+        }
+        if let Some(policy) = &config.egress {
+            egress::validate(policy).map_err(ApiError::BadRequest)?;
+        }
+    }
+    let egress_policy = state.config.egress.policy_for(
+        req.config.as_ref().and_then(|c| c.egress.as_ref()),
+        req.guild_id.as_deref(),
+    );
+
+    // Resolve MCP servers before creating anything so bad definitions fail fast
+    let mcp_servers = match &req.mcp_servers {
+        Some(servers) if !servers.is_empty() => Some(resolve_mcp_servers(
+            servers,
+            &state.config.mcp_servers,
+        )?),
+        _ => None,
+    };
+
     // Use a default user_id if not provided
     let user_id = req
         .user_id
@@ -68,6 +129,7 @@ pub async fn create_task(
         req.source,
         &req.repositories,
         req.config.clone(),
+        &preview::new_token(),
     )
     .await?;
 
@@ -99,6 +161,7 @@ pub async fn create_task(
     let prompt = req.prompt.clone();
     let files = req.files.clone();
     let task_config = req.config.clone();
+    let permissions = req.config.as_ref().and_then(|c| c.permission_policy());
     let ssh_public_key = req.ssh_public_key.clone();
     let channel_clone = channel.clone();
 
@@ -122,16 +185,34 @@ pub async fn create_task(
             }
         });
 
-        match state_clone
-            .vm_manager
-            .create_vm_with_progress(
+        // A VM from the warm pool is already booted
+        let pooled = state_clone
+            .pool
+            .claim(
+                &state_clone.vm_manager,
                 task_id,
                 task_config.as_ref(),
+                &egress_policy,
                 ssh_public_key.as_deref(),
-                Some(progress_callback),
             )
-            .await
-        {
+            .await;
+        let vm = match pooled {
+            Some(vm_info) => Ok(vm_info),
+            None => {
+                state_clone
+                    .vm_manager
+                    .create_vm_with_progress(
+                        task_id,
+                        task_config.as_ref(),
+                        &egress_policy,
+                        ssh_public_key.as_deref(),
+                        Some(progress_callback),
+                    )
+                    .await
+            }
+        };
+
+        match vm {
             Ok(vm_info) => {
                 tracing::info!("VM created: {:?}", vm_info);
 
@@ -155,14 +236,25 @@ pub async fn create_task(
                     tracing::error!("Failed to update task IP address: {}", e);
                 }
 
+                // Store the CID so the relay can reattach after an API restart
+                if let Err(e) = db::update_task_vsock_cid(&state_clone.db, task_id, vm_info.cid).await {
+                    tracing::error!("Failed to update task vsock CID: {}", e);
+                }
+
                 // Progress: connecting to agent
                 send_progress(&channel_clone, BootStage::ConnectingAgent).await;
 
                 // Start vsock relay using the VM's CID for direct AF_VSOCK connection
-                let relay = VsockRelay::new(task_id, vm_info.cid, state_clone.ws_registry.clone());
+                let relay = VsockRelay::new(task_id, vm_info.cid, state_clone.clone());
 
                 match relay
-                    .start(state_clone.config.claude.api_key.clone(), prompt, files)
+                    .start(
+                        state_clone.config.claude.api_key.clone(),
+                        prompt,
+                        files,
+                        mcp_servers,
+                        permissions,
+                    )
                     .await
                 {
                     Ok(input_tx) => {
@@ -215,11 +307,45 @@ pub async fn create_task(
     // Return task response
     let task = db::get_task(&state.db, task_id).await?;
     let guild_id = db::get_guild_id_for_task(&state.db, task_id).await?;
-    Ok(Json(TaskResponse::from_task(
-        task,
-        guild_id,
-        &state.config.server.web_url,
-    )))
+    Ok(Json(task.into_response(guild_id, &state.config)))
+}
+
+/// Turn the requested MCP servers into the name -> definition map sent to the
+/// sidecar, looking up references in the server-side registry
+fn resolve_mcp_servers(
+    requested: &[McpServerRequest],
+    registry: &HashMap<String, McpServerConfig>,
+) -> ApiResult<BTreeMap<String, McpServerConfig>> {
+    let mut resolved = BTreeMap::new();
+
+    for server in requested {
+        if !is_valid_mcp_server_name(&server.name) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid MCP server name: '{}'. Use letters, digits, '_' or '-'",
+                server.name
+            )));
+        }
+
+        let config = match &server.config {
+            Some(config) => config.clone(),
+            None => registry.get(&server.name).cloned().ok_or_else(|| {
+                ApiError::BadRequest(format!("Unknown MCP server: '{}'", server.name))
+            })?,
+        };
+
+        config.validate().map_err(|e| {
+            ApiError::BadRequest(format!("Invalid MCP server '{}': {}", server.name, e))
+        })?;
+
+        if resolved.insert(server.name.clone(), config).is_some() {
+            return Err(ApiError::BadRequest(format!(
+                "Duplicate MCP server: '{}'",
+                server.name
+            )));
+        }
+    }
+
+    Ok(resolved)
 }
 
 pub async fn get_task(
@@ -228,11 +354,7 @@ pub async fn get_task(
 ) -> ApiResult<Json<TaskResponse>> {
     let task = db::get_task(&state.db, id).await?;
     let guild_id = db::get_guild_id_for_task(&state.db, id).await?;
-    Ok(Json(TaskResponse::from_task(
-        task,
-        guild_id,
-        &state.config.server.web_url,
-    )))
+    Ok(Json(task.into_response(guild_id, &state.config)))
 }
 
 pub async fn list_tasks(
@@ -251,11 +373,7 @@ pub async fn list_tasks(
     let mut task_responses = Vec::with_capacity(tasks.len());
     for task in tasks {
         let guild_id = db::get_guild_id_for_task(&state.db, task.id).await?;
-        task_responses.push(TaskResponse::from_task(
-            task,
-            guild_id,
-            &state.config.server.web_url,
-        ));
+        task_responses.push(task.into_response(guild_id, &state.config));
     }
 
     Ok(Json(TaskListResponse {
@@ -315,11 +433,7 @@ pub async fn resume_task(
     let task = db::update_task_status(&state.db, id, TaskStatus::Running, None).await?;
     let guild_id = db::get_guild_id_for_task(&state.db, id).await?;
 
-    Ok(Json(TaskResponse::from_task(
-        task,
-        guild_id,
-        &state.config.server.web_url,
-    )))
+    Ok(Json(task.into_response(guild_id, &state.config)))
 }
 
 pub async fn get_task_output(
@@ -357,8 +471,10 @@ async fn handle_ws(state: Arc<AppState>, task_id: Uuid, socket: WebSocket) {
     // Get or create channel
     let channel = state.ws_registry.get_or_create(task_id).await;
 
-    // Send buffered output first
-    for msg in channel.get_buffered_output().await {
+    // Send buffered output first, then any approvals still waiting on the user
+    let mut backlog = channel.get_buffered_output().await;
+    backlog.extend(channel.get_pending_permissions().await);
+    for msg in backlog {
         if let Ok(json) = serde_json::to_string(&msg) {
             if ws_sender.send(Message::Text(json)).await.is_err() {
                 return;
@@ -406,6 +522,20 @@ async fn handle_ws(state: Arc<AppState>, task_id: Uuid, socket: WebSocket) {
                                 tracing::warn!("Failed to forward input to VM for task {}", task_id);
                             }
                         }
+                        WsMessage::PermissionResponse { request_id, decision, message } => {
+                            let forwarded = channel
+                                .respond_permission(request_id.clone(), decision, message)
+                                .await;
+                            if !forwarded {
+                                tracing::warn!("No pending approval {} for task {}", request_id, task_id);
+                            }
+                        }
+                        WsMessage::Interrupt => {
+                            tracing::info!("Interrupt requested for task {}", task_id);
+                            if !channel.send_to_vm(VsockMessage::Interrupt).await {
+                                tracing::warn!("Failed to forward interrupt to VM for task {}", task_id);
+                            }
+                        }
                         WsMessage::Ping => {
                             channel.send(WsMessage::Pong).await;
                         }
@@ -422,6 +552,752 @@ async fn handle_ws(state: Arc<AppState>, task_id: Uuid, socket: WebSocket) {
     sender_task.abort();
 }
 
+/// List tool approvals waiting on the user (supervised mode)
+pub async fn list_permissions(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Vec<WsMessage>>> {
+    // Verify task exists
+    let _ = db::get_task(&state.db, id).await?;
+
+    if let Some(channel) = state.ws_registry.get(id).await {
+        Ok(Json(channel.get_pending_permissions().await))
+    } else {
+        Ok(Json(vec![]))
+    }
+}
+
+/// Approve or deny a pending tool call, for clients without a WebSocket (e.g. Discord)
+pub async fn respond_permission(
+    State(state): State<Arc<AppState>>,
+    Path((id, request_id)): Path<(Uuid, String)>,
+    Json(body): Json<PermissionResponseRequest>,
+) -> ApiResult<impl IntoResponse> {
+    // Verify task exists
+    let _ = db::get_task(&state.db, id).await?;
+
+    let channel = state
+        .ws_registry
+        .get(id)
+        .await
+        .ok_or_else(|| ApiError::NotFound(format!("Approval request not found: {}", request_id)))?;
+
+    if !channel
+        .respond_permission(request_id.clone(), body.decision, body.message)
+        .await
+    {
+        return Err(ApiError::NotFound(format!(
+            "Approval request not found: {}",
+            request_id
+        )));
+    }
+
+    Ok(axum::http::StatusCode::ACCEPTED)
+}
+
+/// Upload a file into the task's workspace, replacing any file at that path.
+/// The body is streamed to the VM in chunks.
+pub async fn upload_file(
+    State(state): State<Arc<AppState>>,
+    Path((id, path)): Path<(Uuid, String)>,
+    body: Body,
+) -> ApiResult<Json<FileUploadResponse>> {
+    check_workspace_path(&path)?;
+    let channel = sidecar_channel(&state, id, "files").await?;
+    let mut request = channel.start_request();
+
+    let mut body = body.into_data_stream();
+    let mut buffer = Vec::with_capacity(FILE_CHUNK_LEN);
+    let mut offset = 0;
+    loop {
+        let next = body
+            .next()
+            .await
+            .transpose()
+            .map_err(|e| ApiError::BadRequest(format!("Failed to read upload: {}", e)))?;
+        let done = next.is_none();
+        if let Some(bytes) = next {
+            buffer.extend_from_slice(&bytes);
+        }
+
+        // Full chunks go out as they fill up, the remainder with the last one
+        while buffer.len() >= FILE_CHUNK_LEN || done {
+            let data: Vec<u8> = buffer.drain(..buffer.len().min(FILE_CHUNK_LEN)).collect();
+            let last = done && buffer.is_empty();
+            let len = data.len() as u64;
+            let write = VsockMessage::FileWrite {
+                request_id: request.id.clone(),
+                path: path.clone(),
+                offset,
+                data,
+                done: last,
+            };
+            if !request.send(write).await {
+                return Err(connection_lost());
+            }
+            offset += len;
+
+            // The sidecar answers a failed upload right away
+            if let Some(answer) = request.try_recv() {
+                return upload_result(answer, path);
+            }
+            if last {
+                break;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+
+    let answer = sidecar_answer(&mut request).await?;
+    upload_result(answer, path)
+}
+
+/// Download a file from the task's workspace, fetching it from the VM chunk
+/// by chunk as the client reads
+pub async fn download_file(
+    State(state): State<Arc<AppState>>,
+    Path((id, path)): Path<(Uuid, String)>,
+) -> ApiResult<Response> {
+    check_workspace_path(&path)?;
+    let channel = sidecar_channel(&state, id, "files").await?;
+    let mut request = channel.start_request();
+
+    // An empty read checks the file and returns its size
+    let (_, size) = read_file_chunk(&mut request, &path, 0, 0).await?;
+
+    let name = path.rsplit('/').next().unwrap_or(&path).replace(['"', '\\'], "_");
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", name))
+        .unwrap_or_else(|_| HeaderValue::from_static("attachment"));
+
+    let chunks = async_stream::stream! {
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(FILE_CHUNK_LEN as u64);
+            match read_file_chunk(&mut request, &path, offset, len).await {
+                Ok((data, _)) if !data.is_empty() => {
+                    offset += data.len() as u64;
+                    yield Ok::<_, ApiError>(Bytes::from(data));
+                }
+                Ok(_) => {
+                    yield Err(ApiError::VmError(format!("{} shrank during the download", path)));
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Download of {} from task {} failed: {}", path, id, e);
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream")),
+            (header::CONTENT_LENGTH, HeaderValue::from(size)),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+fn check_workspace_path(path: &str) -> ApiResult<()> {
+    if !is_valid_workspace_path(path) {
+        return Err(ApiError::BadRequest(format!(
+            "Path must be relative to /workspace and may not contain '..': {}",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// List a workspace directory with sizes, modes, mtimes and git status.
+/// Entries ignored by `.gitignore` are left out.
+pub async fn get_tree(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<TreeQuery>,
+) -> ApiResult<Json<TreeResponse>> {
+    if !params.path.is_empty() {
+        check_workspace_path(&params.path)?;
+    }
+    if params.depth == 0 || params.depth > MAX_TREE_DEPTH {
+        return Err(ApiError::BadRequest(format!(
+            "depth must be between 1 and {}",
+            MAX_TREE_DEPTH
+        )));
+    }
+    let channel = sidecar_channel(&state, id, "tree").await?;
+    let mut request = channel.start_request();
+
+    let list = VsockMessage::ListTree {
+        request_id: request.id.clone(),
+        path: params.path.clone(),
+        depth: params.depth,
+    };
+    if !request.send(list).await {
+        return Err(connection_lost());
+    }
+
+    match sidecar_answer(&mut request).await? {
+        VsockMessage::Tree {
+            entries, truncated, ..
+        } => Ok(Json(TreeResponse {
+            path: params.path,
+            entries,
+            truncated,
+        })),
+        VsockMessage::FileResult {
+            error: Some(error), ..
+        } => Err(file_error(error)),
+        _ => Err(ApiError::VmError("Unexpected answer to a tree listing".to_string())),
+    }
+}
+
+/// Changes in each repository of the workspace against its base commit:
+/// changed files with line counts, plus the full patch in `patch` mode
+pub async fn get_diff(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<DiffQuery>,
+) -> ApiResult<Json<DiffResponse>> {
+    let channel = sidecar_channel(&state, id, "diff").await?;
+    let mut request = channel.start_request();
+
+    let get = VsockMessage::GetDiff {
+        request_id: request.id.clone(),
+        stat_only: params.mode == DiffMode::Stat,
+    };
+    if !request.send(get).await {
+        return Err(connection_lost());
+    }
+
+    match sidecar_answer(&mut request).await? {
+        VsockMessage::Diff { repos, .. } => Ok(Json(DiffResponse { repos })),
+        VsockMessage::FileResult {
+            error: Some(error), ..
+        } => Err(file_error(error)),
+        _ => Err(ApiError::VmError("Unexpected answer to a diff request".to_string())),
+    }
+}
+
+/// Run a command in the task's VM as the claude user, outside of Claude, and
+/// stream its stdout, stderr and exit status as server-sent events. The
+/// command is killed when the client disconnects.
+pub async fn exec_command(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<ExecRequest>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    if req.argv.first().is_none_or(|program| program.is_empty()) {
+        return Err(ApiError::BadRequest("argv must name a command".to_string()));
+    }
+    let timeout_secs = req.timeout_secs.unwrap_or(DEFAULT_EXEC_TIMEOUT_SECS);
+    if timeout_secs == 0 || timeout_secs > MAX_EXEC_TIMEOUT_SECS {
+        return Err(ApiError::BadRequest(format!(
+            "timeout_secs must be between 1 and {}",
+            MAX_EXEC_TIMEOUT_SECS
+        )));
+    }
+    let channel = sidecar_channel(&state, id, "exec").await?;
+    let request = channel.start_request();
+    let request_id = request.id.clone();
+
+    let start = VsockMessage::ExecStart {
+        request_id: request_id.clone(),
+        argv: req.argv,
+        env: req.env,
+        cwd: req.cwd,
+        timeout_secs,
+    };
+    if !request.send(start).await {
+        return Err(connection_lost());
+    }
+
+    let mut output = request.unbounded();
+
+    let stream = async_stream::stream! {
+        let mut kill = ExecKillGuard {
+            channel,
+            request_id: Some(request_id),
+        };
+        while let Some(msg) = output.recv().await {
+            match msg {
+                VsockMessage::ExecOutput { stream, data, .. } => {
+                    let event = match stream {
+                        ExecStream::Stdout => "stdout",
+                        ExecStream::Stderr => "stderr",
+                    };
+                    let data = serde_json::json!({ "data": data });
+                    yield Ok(Event::default().event(event).data(data.to_string()));
+                }
+                VsockMessage::ExecExit {
+                    error: Some(error), ..
+                } => {
+                    kill.request_id = None;
+                    let data = serde_json::json!({ "error": error });
+                    yield Ok(Event::default().event("error").data(data.to_string()));
+                    return;
+                }
+                VsockMessage::ExecExit {
+                    code,
+                    signal,
+                    timed_out,
+                    ..
+                } => {
+                    kill.request_id = None;
+                    let data = serde_json::json!({
+                        "code": code,
+                        "signal": signal,
+                        "timed_out": timed_out
+                    });
+                    yield Ok(Event::default().event("exit").data(data.to_string()));
+                    return;
+                }
+                _ => {}
+            }
+        }
+        kill.request_id = None;
+        let data = serde_json::json!({ "error": connection_lost().to_string() });
+        yield Ok(Event::default().event("error").data(data.to_string()));
+    };
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Kills a command whose client went away before it ended
+struct ExecKillGuard {
+    channel: Arc<TaskChannel>,
+    /// Cleared once the command ended
+    request_id: Option<String>,
+}
+
+impl Drop for ExecKillGuard {
+    fn drop(&mut self) {
+        if let Some(request_id) = self.request_id.take() {
+            let channel = self.channel.clone();
+            tokio::spawn(async move {
+                channel.send_to_vm(VsockMessage::ExecKill { request_id }).await;
+            });
+        }
+    }
+}
+
+/// Network usage of the task's VM against its limits
+pub async fn get_task_stats(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<TaskStatsResponse>> {
+    let task = db::get_task(&state.db, id).await?;
+    let limits = traffic::limits_for(task.config.as_deref(), &state.config.network);
+    let network = match task.status {
+        TaskStatus::Pending | TaskStatus::Terminated => None,
+        _ => state.vm_manager.network_stats(id, limits).await?,
+    }
+    .ok_or_else(|| {
+        ApiError::InvalidState(format!(
+            "Task has no running VM, current status: {}",
+            task.status
+        ))
+    })?;
+
+    Ok(Json(TaskStatsResponse {
+        task_id: id,
+        network,
+        disk: state.vm_manager.disk_stats(id).await,
+    }))
+}
+
+/// Connections the task's VM made through the egress proxy, newest first
+pub async fn get_network_log(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<NetworkLogQuery>,
+) -> ApiResult<Json<NetworkLogResponse>> {
+    db::get_task(&state.db, id).await?;
+    let (connections, total) =
+        db::list_task_connections(&state.db, id, query.page, query.per_page).await?;
+
+    Ok(Json(NetworkLogResponse {
+        task_id: id,
+        connections: connections.into_iter().map(|c| c.into_response()).collect(),
+        total,
+        page: query.page,
+        per_page: query.per_page,
+    }))
+}
+
+/// Interactive login shell in the task's VM, backed by a PTY in the sidecar.
+/// Binary frames carry the terminal's input and output; text frames carry
+/// resizes from the client and the shell's exit or an error from the server.
+/// Every connection gets a shell of its own, hung up when it closes.
+pub async fn terminal_ws(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Query(size): Query<TerminalQuery>,
+    ws: WebSocketUpgrade,
+) -> ApiResult<Response> {
+    if size.cols == 0 || size.rows == 0 {
+        return Err(ApiError::BadRequest("cols and rows must not be 0".to_string()));
+    }
+    let channel = sidecar_channel(&state, id, "pty").await?;
+    Ok(ws.on_upgrade(move |socket| handle_terminal(channel, size, socket)))
+}
+
+async fn handle_terminal(channel: Arc<TaskChannel>, size: TerminalQuery, socket: WebSocket) {
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+    let request = channel.start_request();
+    let request_id = request.id.clone();
+
+    let open = VsockMessage::PtyOpen {
+        request_id: request_id.clone(),
+        cols: size.cols,
+        rows: size.rows,
+    };
+    if !request.send(open).await {
+        let error = TerminalMessage::Error {
+            message: connection_lost().to_string(),
+        };
+        let _ = ws_sender.send(terminal_text(&error)).await;
+        return;
+    }
+    let mut output = request.unbounded();
+
+    let mut ended = false;
+    loop {
+        tokio::select! {
+            msg = output.recv() => {
+                let frame = match msg {
+                    Some(VsockMessage::PtyOutput { data, .. }) => Message::Binary(data),
+                    Some(VsockMessage::PtyExit { code, signal, .. }) => {
+                        ended = true;
+                        terminal_text(&TerminalMessage::Exit { code, signal })
+                    }
+                    Some(VsockMessage::FileResult { error, .. }) => {
+                        ended = true;
+                        let message = error.map_or_else(|| "Shell failed".to_string(), |e| e.message);
+                        terminal_text(&TerminalMessage::Error { message })
+                    }
+                    Some(_) => continue,
+                    None => {
+                        ended = true;
+                        let message = connection_lost().to_string();
+                        terminal_text(&TerminalMessage::Error { message })
+                    }
+                };
+                if ws_sender.send(frame).await.is_err() || ended {
+                    break;
+                }
+            }
+            frame = ws_receiver.next() => match frame {
+                Some(Ok(Message::Binary(data))) => {
+                    let input = VsockMessage::PtyInput {
+                        request_id: request_id.clone(),
+                        data,
+                    };
+                    channel.send_to_vm(input).await;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(TerminalMessage::Resize { cols, rows }) = serde_json::from_str(&text) {
+                        let resize = VsockMessage::PtyResize {
+                            request_id: request_id.clone(),
+                            cols,
+                            rows,
+                        };
+                        channel.send_to_vm(resize).await;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    if ended {
+        let _ = ws_sender.send(Message::Close(None)).await;
+    } else {
+        channel.send_to_vm(VsockMessage::PtyClose { request_id }).await;
+    }
+}
+
+fn terminal_text(msg: &TerminalMessage) -> Message {
+    Message::Text(serde_json::to_string(msg).unwrap_or_default())
+}
+
+/// Proxy a request to a server in the task's VM, under
+/// `/<task-id>/preview/<port>/`. Without the trailing slash, relative links in
+/// the page would miss the prefix, so the client is redirected to it.
+pub async fn preview(
+    State(state): State<Arc<AppState>>,
+    Path(target): Path<PreviewPath>,
+    req: axum::extract::Request,
+) -> ApiResult<Response> {
+    let prefix = format!("/{}/preview/{}", target.id, target.port);
+    if req.uri().path() == prefix {
+        let location = match req.uri().query() {
+            Some(query) => format!("{}/?{}", prefix, query),
+            None => format!("{}/", prefix),
+        };
+        return Ok(Redirect::permanent(&location).into_response());
+    }
+    preview::proxy(&state, target.id, target.port, &prefix, req).await
+}
+
+/// Download the whole workspace as a tar.gz or zip. A suspended task's VM is
+/// resumed for the download; once QEMU is gone the archive is read from the
+/// VM's disk image instead.
+pub async fn get_archive(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<ArchiveQuery>,
+) -> ApiResult<Response> {
+    let exclude: Vec<String> = params
+        .exclude
+        .iter()
+        .flat_map(|names| names.split(','))
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(name) = exclude
+        .iter()
+        .find(|name| name.contains('/') || *name == "." || *name == "..")
+    {
+        return Err(ApiError::BadRequest(format!(
+            "exclude takes file names, not paths: {}",
+            name
+        )));
+    }
+
+    let task = db::get_task(&state.db, id).await?;
+    let vm_id = task.vm_id.clone().unwrap_or_else(|| format!("vm-{}", id));
+    let tar = if state.vm_manager.is_vm_running(&vm_id).await {
+        let wake = if task.status == TaskStatus::Suspended {
+            Some(WakeGuard::wake(state.clone(), id, vm_id).await?)
+        } else {
+            None
+        };
+        let channel = connected_channel(&state, id, "archive").await?;
+        sidecar_tar(channel, exclude, wake).boxed()
+    } else {
+        let on_data_volume = task.workspace_bytes.is_some();
+        let Some((image, workspace)) = state.vm_manager.workspace_image(id, on_data_volume).await
+        else {
+            return Err(ApiError::NotFound(format!(
+                "The workspace of task {} no longer exists",
+                id
+            )));
+        };
+        tracing::info!("VM of task {} is not running, archiving its disk image", id);
+        MountedImage::mount(&image, id)
+            .await?
+            .tar(workspace, exclude)
+            .boxed()
+    };
+
+    let disposition = HeaderValue::from_str(&format!(
+        "attachment; filename=\"workspace-{}.{}\"",
+        id,
+        params.format.extension()
+    ))
+    .unwrap_or_else(|_| HeaderValue::from_static("attachment"));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(params.format.content_type())),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(archive::encode(tar, params.format)),
+    )
+        .into_response())
+}
+
+/// Tar stream of the workspace from the sidecar, pulled one chunk at a time.
+/// `wake` keeps a suspended VM running until the stream is dropped.
+fn sidecar_tar(
+    channel: Arc<TaskChannel>,
+    exclude: Vec<String>,
+    wake: Option<WakeGuard>,
+) -> impl Stream<Item = ApiResult<Bytes>> {
+    async_stream::stream! {
+        let _wake = wake;
+        let mut request = channel.start_request();
+        let mut next = VsockMessage::ArchiveStart {
+            request_id: request.id.clone(),
+            exclude,
+        };
+        loop {
+            if !request.send(next).await {
+                yield Err(connection_lost());
+                break;
+            }
+            match sidecar_answer(&mut request).await {
+                Ok(VsockMessage::ArchiveData { data, done, .. }) => {
+                    yield Ok(Bytes::from(data));
+                    if done {
+                        break;
+                    }
+                }
+                Ok(VsockMessage::FileResult {
+                    error: Some(error), ..
+                }) => {
+                    yield Err(file_error(error));
+                    break;
+                }
+                Ok(_) => {
+                    yield Err(ApiError::VmError("Unexpected answer to an archive request".to_string()));
+                    break;
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+            next = VsockMessage::ArchiveNext {
+                request_id: request.id.clone(),
+            };
+        }
+    }
+}
+
+/// Channel of a running task whose sidecar announced `feature`
+async fn sidecar_channel(state: &AppState, id: Uuid, feature: &str) -> ApiResult<Arc<TaskChannel>> {
+    let task = db::get_task(&state.db, id).await?;
+    if !matches!(task.status, TaskStatus::Running | TaskStatus::AwaitingInput) {
+        return Err(ApiError::InvalidState(format!(
+            "Task is not running, current status: {}",
+            task.status
+        )));
+    }
+    connected_channel(state, id, feature).await
+}
+
+/// Channel of a task whose sidecar is connected and announced `feature`,
+/// whatever the task's status
+async fn connected_channel(state: &AppState, id: Uuid, feature: &str) -> ApiResult<Arc<TaskChannel>> {
+    let channel = state
+        .ws_registry
+        .get(id)
+        .await
+        .ok_or_else(|| ApiError::InvalidState("Task is not connected to its VM".to_string()))?;
+    if !channel.sidecar_supports(feature).await {
+        return Err(ApiError::InvalidState(format!(
+            "The task's sidecar is too old for this request (no '{}' feature)",
+            feature
+        )));
+    }
+    Ok(channel)
+}
+
+/// Ask the sidecar for `len` bytes of `path` from `offset`. Returns the data
+/// and the size of the whole file.
+async fn read_file_chunk(
+    request: &mut PendingRequest,
+    path: &str,
+    offset: u64,
+    len: u64,
+) -> ApiResult<(Vec<u8>, u64)> {
+    let read = VsockMessage::FileRead {
+        request_id: request.id.clone(),
+        path: path.to_string(),
+        offset,
+        len,
+    };
+    if !request.send(read).await {
+        return Err(connection_lost());
+    }
+
+    match sidecar_answer(request).await? {
+        VsockMessage::FileData { data, size, .. } => Ok((data, size)),
+        VsockMessage::FileResult {
+            error: Some(error), ..
+        } => Err(file_error(error)),
+        _ => Err(ApiError::VmError("Unexpected answer to a file read".to_string())),
+    }
+}
+
+fn upload_result(answer: VsockMessage, path: String) -> ApiResult<Json<FileUploadResponse>> {
+    match answer {
+        VsockMessage::FileResult {
+            error: Some(error), ..
+        } => Err(file_error(error)),
+        VsockMessage::FileResult { size, .. } => Ok(Json(FileUploadResponse { path, size })),
+        _ => Err(ApiError::VmError("Unexpected answer to a file upload".to_string())),
+    }
+}
+
+/// Wait for the sidecar's next answer to a request
+async fn sidecar_answer(request: &mut PendingRequest) -> ApiResult<VsockMessage> {
+    match tokio::time::timeout(SIDECAR_REQUEST_TIMEOUT, request.recv()).await {
+        Ok(Some(answer)) => Ok(answer),
+        Ok(None) => Err(connection_lost()),
+        Err(_) => Err(ApiError::VmError(
+            "The VM did not answer in time".to_string(),
+        )),
+    }
+}
+
+fn file_error(error: FileError) -> ApiError {
+    match error.kind {
+        FileErrorKind::InvalidPath => ApiError::BadRequest(error.message),
+        FileErrorKind::NotFound => ApiError::NotFound(error.message),
+        FileErrorKind::Io => ApiError::VmError(error.message),
+    }
+}
+
+fn connection_lost() -> ApiError {
+    ApiError::VmError("Connection to the VM was lost".to_string())
+}
+
+/// Register a public key that `user_id` logs in to the SSH gateway with
+pub async fn add_ssh_key(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+    Json(req): Json<AddSshKeyRequest>,
+) -> ApiResult<Json<SshKeyResponse>> {
+    let key = parse_ssh_public_key(&req.public_key).map_err(ApiError::BadRequest)?;
+    if db::get_ssh_key_by_fingerprint(&state.db, &key.fingerprint)
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::Conflict(format!(
+            "Key {} is already registered",
+            key.fingerprint
+        )));
+    }
+    let key = db::add_ssh_key(&state.db, &user_id, &key).await?;
+    Ok(Json(key.into_response()))
+}
+
+pub async fn list_ssh_keys(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> ApiResult<Json<Vec<SshKeyResponse>>> {
+    let keys = db::list_ssh_keys(&state.db, &user_id).await?;
+    Ok(Json(keys.into_iter().map(UserSshKey::into_response).collect()))
+}
+
+pub async fn delete_ssh_key(
+    State(state): State<Arc<AppState>>,
+    Path((user_id, key_id)): Path<(String, Uuid)>,
+) -> ApiResult<impl IntoResponse> {
+    db::delete_ssh_key(&state.db, &user_id, key_id).await?;
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Look up a key by fingerprint, for the SSH gateway to tell who is logging in
+pub async fn find_ssh_key(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SshKeyQuery>,
+) -> ApiResult<Json<SshKeyResponse>> {
+    db::get_ssh_key_by_fingerprint(&state.db, &query.fingerprint)
+        .await?
+        .map(|key| Json(key.into_response()))
+        .ok_or_else(|| ApiError::NotFound(format!("SSH key {}", query.fingerprint)))
+}
+
 /// Get VM logs (snapshot) - last N lines
 pub async fn get_vm_logs(
     State(state): State<Arc<AppState>>,
@@ -1,7 +1,8 @@
 use std::sync::Arc;
 
 use axum::{
-    routing::{delete, get, post},
+    middleware,
+    routing::{any, delete, get, post},
     Router,
 };
 use sqlx::postgres::PgPoolOptions;
@@ -12,12 +13,21 @@ use tower_http::{
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod archive;
 mod config;
 mod db;
+mod egress;
 mod error;
 mod handlers;
 mod models;
+mod pool;
+mod preview;
+mod proxy;
 mod qemu;
+mod rootfs;
+mod snapshot;
+mod traffic;
+mod volume;
 mod vsock;
 mod ws;
 
@@ -28,6 +38,7 @@ pub struct AppState {
     pub config: AppConfig,
     pub vm_manager: qemu::VmManager,
     pub ws_registry: Arc<ws::WsRegistry>,
+    pub pool: pool::WarmPool,
 }
 
 #[tokio::main]
@@ -48,6 +59,10 @@ async fn main() -> anyhow::Result<()> {
 
     // Load configuration
     let config = AppConfig::load()?;
+    let egress = &config.egress;
+    for policy in std::iter::once(&egress.default).chain(egress.guilds.values()) {
+        egress::validate(policy).map_err(|e| anyhow::anyhow!("[egress] {}", e))?;
+    }
 
     info!(api_key = %config.claude.api_key, "Using Claude API KEY");
 
@@ -74,8 +89,23 @@ async fn main() -> anyhow::Result<()> {
         config: config.clone(),
         vm_manager,
         ws_registry,
+        pool: pool::WarmPool::new(&config),
     });
 
+    // Reconnect to VMs that kept running across a restart
+    vsock::reattach_tasks(&state).await;
+
+    // Boot idle VMs for new tasks to claim
+    if !config.pool.shapes.is_empty() {
+        tokio::spawn(pool::run(state.clone()));
+    }
+
+    if let Some(addr) = config.egress_proxy_addr() {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(proxy::serve(state.clone(), listener));
+        info!("Egress proxy listening on {}", addr);
+    }
+
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -84,6 +114,7 @@ async fn main() -> anyhow::Result<()> {
     // Build router
     let app = Router::new()
         .route("/health", get(handlers::health_check))
+        .route("/metrics", get(handlers::metrics))
         .route("/api/v1/tasks", post(handlers::create_task))
         .route("/api/v1/tasks", get(handlers::list_tasks))
         .route("/api/v1/tasks/:id", get(handlers::get_task))
@@ -91,8 +122,36 @@ async fn main() -> anyhow::Result<()> {
         .route("/api/v1/tasks/:id/resume", post(handlers::resume_task))
         .route("/api/v1/tasks/:id/output", get(handlers::get_task_output))
         .route("/api/v1/tasks/:id/stream", get(handlers::ws_stream))
+        .route("/api/v1/tasks/:id/permissions", get(handlers::list_permissions))
+        .route(
+            "/api/v1/tasks/:id/permissions/:request_id",
+            post(handlers::respond_permission),
+        )
+        .route(
+            "/api/v1/tasks/:id/files/*path",
+            get(handlers::download_file).put(handlers::upload_file),
+        )
+        .route("/api/v1/tasks/:id/tree", get(handlers::get_tree))
+        .route("/api/v1/tasks/:id/archive", get(handlers::get_archive))
+        .route("/api/v1/tasks/:id/diff", get(handlers::get_diff))
+        .route("/api/v1/tasks/:id/exec", post(handlers::exec_command))
+        .route("/api/v1/tasks/:id/terminal", get(handlers::terminal_ws))
+        .route("/api/v1/tasks/:id/stats", get(handlers::get_task_stats))
+        .route("/api/v1/tasks/:id/network", get(handlers::get_network_log))
         .route("/api/v1/tasks/:id/logs", get(handlers::get_vm_logs))
         .route("/api/v1/tasks/:id/logs/stream", get(handlers::stream_vm_logs))
+        .route(
+            "/api/v1/users/:user_id/ssh-keys",
+            get(handlers::list_ssh_keys).post(handlers::add_ssh_key),
+        )
+        .route(
+            "/api/v1/users/:user_id/ssh-keys/:key_id",
+            delete(handlers::delete_ssh_key),
+        )
+        .route("/api/v1/ssh-keys", get(handlers::find_ssh_key))
+        .route("/:id/preview/:port", any(handlers::preview))
+        .route("/:id/preview/:port/*path", any(handlers::preview))
+        .layer(middleware::from_fn_with_state(state.clone(), preview::subdomains))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
         .with_state(state);
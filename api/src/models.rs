@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -5,39 +7,17 @@ use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "VARCHAR", rename_all = "lowercase")]
-#[serde(rename_all = "lowercase")]
-pub enum TaskStatus {
-    Pending,
-    Starting,
-    Running,
-    Suspended,
-    Terminated,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "VARCHAR", rename_all = "lowercase")]
-#[serde(rename_all = "lowercase")]
-pub enum TaskSource {
-    Discord,
-    Web,
-}
+use crate::config::AppConfig;
 
-impl Default for TaskSource {
-    fn default() -> Self {
-        TaskSource::Web
-    }
-}
-
-impl std::fmt::Display for TaskSource {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TaskSource::Discord => write!(f, "discord"),
-            TaskSource::Web => write!(f, "web"),
-        }
-    }
-}
+// Types shared with the sidecar and the CLI
+pub use lia_protocol::{
+    is_valid_workspace_path, AddSshKeyRequest, DiskStats, EgressPolicy, ExecStream, FileError,
+    FileErrorKind, GuestStats, McpServerConfig, NetworkConnection, NetworkLimits,
+    NetworkLogResponse, NetworkStats, PermissionDecision, PermissionPolicy, RepoDiff, Sequenced,
+    SshKeyResponse, TaskConfig, TaskFile, TaskListResponse, TaskResponse, TaskSource,
+    TaskStatsResponse, TaskStatus, TerminalMessage, TrafficStats, TreeEntry, VsockMessage,
+    WorkspaceDisk, FILE_CHUNK_LEN,
+};
 
 lazy_static! {
     static ref REPO_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9._-]+/[a-zA-Z0-9._-]+$").unwrap();
@@ -47,54 +27,6 @@ pub fn is_valid_repo_format(repo: &str) -> bool {
     REPO_REGEX.is_match(repo)
 }
 
-impl std::fmt::Display for TaskStatus {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TaskStatus::Pending => write!(f, "pending"),
-            TaskStatus::Starting => write!(f, "starting"),
-            TaskStatus::Running => write!(f, "running"),
-            TaskStatus::Suspended => write!(f, "suspended"),
-            TaskStatus::Terminated => write!(f, "terminated"),
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TaskConfig {
-    #[serde(default = "default_timeout")]
-    pub timeout_minutes: u32,
-    #[serde(default = "default_memory")]
-    pub max_memory_mb: u32,
-    #[serde(default = "default_vcpu")]
-    pub vcpu_count: u32,
-    #[serde(default = "default_storage")]
-    pub storage_gb: u32,
-}
-
-fn default_timeout() -> u32 {
-    30
-}
-fn default_memory() -> u32 {
-    2048
-}
-fn default_vcpu() -> u32 {
-    2
-}
-fn default_storage() -> u32 {
-    50
-}
-
-impl Default for TaskConfig {
-    fn default() -> Self {
-        Self {
-            timeout_minutes: default_timeout(),
-            max_memory_mb: default_memory(),
-            vcpu_count: default_vcpu(),
-            storage_gb: default_storage(),
-        }
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct Task {
     pub id: Uuid,
@@ -110,6 +42,135 @@ pub struct Task {
     pub exit_code: Option<i32>,
     pub error_message: Option<String>,
     pub ip_address: Option<String>,
+    /// vsock CID of the task's VM, used to reattach after an API restart
+    pub vsock_cid: Option<i32>,
+    /// False while the VM's heartbeats are missing
+    pub healthy: bool,
+    /// Sidecar build version reported in the vsock handshake
+    pub sidecar_version: Option<String>,
+    /// Size of the data volume the sidecar reported mounted at /workspace
+    pub workspace_bytes: Option<i64>,
+    /// Secret of the task's preview URLs; None for tasks created before them
+    pub preview_token: Option<String>,
+}
+
+/// Public key registered for logging in to the SSH gateway
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct UserSshKey {
+    pub id: Uuid,
+    pub user_id: String,
+    pub key_type: String,
+    pub public_key: String,
+    pub comment: Option<String>,
+    pub fingerprint: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UserSshKey {
+    pub fn into_response(self) -> SshKeyResponse {
+        SshKeyResponse {
+            id: self.id,
+            user_id: self.user_id,
+            key_type: self.key_type,
+            public_key: self.public_key,
+            comment: self.comment,
+            fingerprint: self.fingerprint,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// A connection through the egress proxy, as logged
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct TaskConnection {
+    pub id: i64,
+    pub task_id: Uuid,
+    pub host: String,
+    pub port: i32,
+    pub method: String,
+    pub allowed: bool,
+    pub bytes_up: i64,
+    pub bytes_down: i64,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: i64,
+    pub error: Option<String>,
+}
+
+impl TaskConnection {
+    pub fn into_response(self) -> NetworkConnection {
+        NetworkConnection {
+            id: self.id,
+            host: self.host,
+            port: self.port as u16,
+            method: self.method,
+            allowed: self.allowed,
+            bytes_up: self.bytes_up as u64,
+            bytes_down: self.bytes_down as u64,
+            started_at: self.started_at,
+            duration_ms: self.duration_ms as u64,
+            error: self.error,
+        }
+    }
+}
+
+/// Key algorithms the SSH gateway accepts
+const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// An authorized_keys line taken apart
+#[derive(Debug, Clone)]
+pub struct ParsedSshKey {
+    pub key_type: String,
+    pub public_key: String,
+    pub comment: Option<String>,
+    pub fingerprint: String,
+}
+
+/// Parse `<type> <base64 blob> [comment]` and compute the key's OpenSSH
+/// SHA256 fingerprint
+pub fn parse_ssh_public_key(line: &str) -> Result<ParsedSshKey, String> {
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+
+    let mut fields = line.trim().splitn(3, char::is_whitespace);
+    let key_type = fields.next().unwrap_or_default();
+    let public_key = fields.next().unwrap_or_default();
+    let comment = fields
+        .next()
+        .map(str::trim)
+        .filter(|comment| !comment.is_empty());
+    if !SSH_KEY_TYPES.contains(&key_type) {
+        return Err(format!("Unsupported key type '{}'", key_type));
+    }
+    let blob = base64::engine::general_purpose::STANDARD
+        .decode(public_key)
+        .map_err(|_| "Key is not valid base64".to_string())?;
+    // The blob starts with its own type as a length-prefixed string
+    let named = blob
+        .get(..4)
+        .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+        .and_then(|len| blob.get(4..4 + len));
+    if named != Some(key_type.as_bytes()) {
+        return Err(format!("Key data is not a {} key", key_type));
+    }
+
+    let digest = Sha256::digest(&blob);
+    Ok(ParsedSshKey {
+        key_type: key_type.to_string(),
+        public_key: public_key.to_string(),
+        comment: comment.map(String::from),
+        fingerprint: format!(
+            "SHA256:{}",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+        ),
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -119,10 +180,23 @@ pub struct GuildTask {
     pub created_at: DateTime<Utc>,
 }
 
+/// MCP server requested for a task
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TaskFile {
+pub struct McpServerRequest {
+    /// Server name, used by Claude as the `mcp__<name>__` tool prefix
     pub name: String,
-    pub content: String,
+    /// Inline definition. When omitted, `name` refers to a server registered
+    /// under `[mcp_servers]` in the API config.
+    #[serde(default)]
+    pub config: Option<McpServerConfig>,
+}
+
+lazy_static! {
+    static ref MCP_NAME_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_-]{1,64}$").unwrap();
+}
+
+pub fn is_valid_mcp_server_name(name: &str) -> bool {
+    MCP_NAME_REGEX.is_match(name)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -138,66 +212,48 @@ pub struct CreateTaskRequest {
     pub files: Option<Vec<TaskFile>>,
     /// SSH public key for accessing the VM (e.g., "ssh-rsa AAAA... user@host")
     pub ssh_public_key: Option<String>,
+    /// MCP servers to make available to the agent
+    pub mcp_servers: Option<Vec<McpServerRequest>>,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct TaskResponse {
-    pub id: Uuid,
-    pub user_id: String,
-    pub guild_id: Option<String>,
-    pub status: TaskStatus,
-    pub source: TaskSource,
-    pub repositories: Vec<String>,
-    pub vm_id: Option<String>,
-    pub config: Option<TaskConfig>,
-    pub created_at: DateTime<Utc>,
-    pub started_at: Option<DateTime<Utc>>,
-    pub completed_at: Option<DateTime<Utc>>,
-    pub exit_code: Option<i32>,
-    pub error_message: Option<String>,
-    pub web_url: String,
-    /// SSH connection info (e.g., "ssh root@172.16.0.100")
-    pub ssh_command: Option<String>,
-    /// IP address of the VM
-    pub ip_address: Option<String>,
-}
-
-impl TaskResponse {
-    pub fn from_task(task: Task, guild_id: Option<String>, web_base_url: &str) -> Self {
-        let ssh_command = task
-            .ip_address
-            .as_ref()
-            .map(|ip| format!("ssh root@{}", ip));
+impl Task {
+    /// REST representation of the task
+    pub fn into_response(self, guild_id: Option<String>, config: &AppConfig) -> TaskResponse {
+        // Through the gateway when there is one; the bridge IP only works on
+        // the host itself
+        let ssh_command = match &config.ssh_gateway.host {
+            Some(host) => Some(config.ssh_gateway.command(host, self.id)),
+            None => self
+                .ip_address
+                .as_ref()
+                .map(|ip| format!("ssh root@{}", ip)),
+        };
 
-        Self {
-            id: task.id,
-            user_id: task.user_id,
+        TaskResponse {
+            id: self.id,
+            user_id: self.user_id,
             guild_id,
-            status: task.status,
-            source: task.source,
-            repositories: task.repositories,
-            vm_id: task.vm_id,
-            config: task.config.map(|c| c.0),
-            created_at: task.created_at,
-            started_at: task.started_at,
-            completed_at: task.completed_at,
-            exit_code: task.exit_code,
-            error_message: task.error_message,
-            web_url: format!("{}/tasks/{}", web_base_url, task.id),
+            status: self.status,
+            source: self.source,
+            repositories: self.repositories,
+            vm_id: self.vm_id,
+            config: self.config.map(|c| c.0),
+            created_at: self.created_at,
+            started_at: self.started_at,
+            completed_at: self.completed_at,
+            exit_code: self.exit_code,
+            error_message: self.error_message,
+            web_url: format!("{}/tasks/{}", config.server.web_url, self.id),
             ssh_command,
-            ip_address: task.ip_address,
+            ip_address: self.ip_address,
+            healthy: self.healthy,
+            sidecar_version: self.sidecar_version,
+            workspace_bytes: self.workspace_bytes.map(|bytes| bytes as u64),
+            preview_token: self.preview_token,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct TaskListResponse {
-    pub tasks: Vec<TaskResponse>,
-    pub total: i64,
-    pub page: u32,
-    pub per_page: u32,
-}
-
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListTasksQuery {
     pub user_id: Option<String>,
@@ -208,6 +264,18 @@ pub struct ListTasksQuery {
     pub per_page: u32,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetworkLogQuery {
+    #[serde(default = "default_page")]
+    pub page: u32,
+    #[serde(default = "default_network_per_page")]
+    pub per_page: u32,
+}
+
+fn default_network_per_page() -> u32 {
+    50
+}
+
 fn default_page() -> u32 {
     1
 }
@@ -245,40 +313,54 @@ impl BootStage {
 
 // WebSocket message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum WsMessage {
     Output { data: String, timestamp: i64 },
     Input { data: String },
     Status { status: TaskStatus, exit_code: Option<i32> },
     Progress { stage: BootStage, message: String },
     Error { message: String },
-    Ping,
-    Pong,
-}
-
-// vsock message types for sidecar communication
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum VsockMessage {
-    Init {
-        api_key: String,
-        prompt: String,
-        files: Option<Vec<TaskFile>>,
+    /// Tool approval waiting on the user (supervised mode)
+    PermissionRequest {
+        request_id: String,
+        tool_name: String,
+        input: serde_json::Value,
+        /// Unix millis after which the task's default decision applies
+        expires_at: i64,
     },
-    Output {
-        data: String,
+    /// User's answer to a PermissionRequest
+    PermissionResponse {
+        request_id: String,
+        decision: PermissionDecision,
+        #[serde(default)]
+        message: Option<String>,
     },
-    Input {
-        data: String,
+    /// A PermissionRequest was answered or timed out
+    PermissionResolved {
+        request_id: String,
+        decision: PermissionDecision,
+        timed_out: bool,
     },
-    Exit {
-        code: i32,
+    /// Heartbeat state of the VM changed, or fresh guest stats arrived
+    Health {
+        healthy: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<GuestStats>,
     },
-    /// Error message from the sidecar (e.g., Claude Code failed to start)
-    Error {
-        message: String,
-    },
-    Heartbeat,
+    /// Cancel the agent's current turn, keeping the task running
+    Interrupt,
+    /// The interrupted turn ended and the agent is waiting for input
+    TurnCancelled,
+    Ping,
+    Pong,
+}
+
+// Body for POST /tasks/:id/permissions/:request_id
+#[derive(Debug, Clone, Deserialize)]
+pub struct PermissionResponseRequest {
+    pub decision: PermissionDecision,
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 // Query params for log endpoints
@@ -302,6 +384,122 @@ fn default_stream_tail() -> usize {
     20
 }
 
+// Response for PUT /files/*path
+#[derive(Debug, Clone, Serialize)]
+pub struct FileUploadResponse {
+    pub path: String,
+    pub size: u64,
+}
+
+// Query params for GET /tree
+#[derive(Debug, Clone, Deserialize)]
+pub struct TreeQuery {
+    /// Directory relative to /workspace; empty for the root
+    #[serde(default)]
+    pub path: String,
+    #[serde(default = "default_tree_depth")]
+    pub depth: u32,
+}
+
+fn default_tree_depth() -> u32 {
+    1
+}
+
+// Response for GET /tree
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeResponse {
+    pub path: String,
+    pub entries: Vec<TreeEntry>,
+    /// The listing hit the sidecar's entry limit
+    pub truncated: bool,
+}
+
+// Query params for GET /archive
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Comma-separated names to leave out at any depth, e.g. `.git,node_modules`
+    #[serde(default)]
+    pub exclude: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+// Query params for GET /diff
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffQuery {
+    #[serde(default)]
+    pub mode: DiffMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffMode {
+    /// Changed files with line counts
+    #[default]
+    Stat,
+    /// Changed files plus the full patch
+    Patch,
+}
+
+// Response for GET /diff
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffResponse {
+    pub repos: Vec<RepoDiff>,
+}
+
+// Body for POST /exec
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecRequest {
+    pub argv: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Relative to /workspace unless absolute
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+// Path params of preview URLs
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreviewPath {
+    pub id: Uuid,
+    pub port: u16,
+}
+
+// Query params for GET /ssh-keys
+#[derive(Debug, Clone, Deserialize)]
+pub struct SshKeyQuery {
+    pub fingerprint: String,
+}
+
+// Query params for the terminal WebSocket: the initial size of the shell
+#[derive(Debug, Clone, Deserialize)]
+pub struct TerminalQuery {
+    #[serde(default = "default_terminal_cols")]
+    pub cols: u16,
+    #[serde(default = "default_terminal_rows")]
+    pub rows: u16,
+}
+
+fn default_terminal_cols() -> u16 {
+    80
+}
+
+fn default_terminal_rows() -> u16 {
+    24
+}
+
+
 // Response for GET /logs
 #[derive(Debug, Clone, Serialize)]
 pub struct LogsResponse {
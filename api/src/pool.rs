@@ -0,0 +1,261 @@
+//! Warm pool of VMs booted ahead of time. For each resource shape in
+//! `[pool]`, a background task keeps `size` VMs idle, booted with the sidecar
+//! waiting for `Init` and confined by the `deny` egress policy. `create_task`
+//! claims one of the task's shape and `VmManager::adopt_vm` renames it after
+//! the task; the pool then boots a replacement. Tasks of other shapes, and
+//! tasks with an `ssh_public_key` (set on the kernel command line at boot),
+//! boot a VM of their own.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::config::{AppConfig, VmConfig};
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{EgressPolicy, TaskConfig};
+use crate::qemu::{VmInfo, VmManager, VmShape};
+use crate::vsock;
+use crate::AppState;
+
+/// How long the pool waits before checking on its VMs again, or retrying
+/// after a boot failed
+const REFILL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct ShapePool {
+    /// Idle VMs to keep
+    size: usize,
+    /// Oldest first
+    idle: VecDeque<VmInfo>,
+    booting: usize,
+    hits: u64,
+    misses: u64,
+}
+
+pub struct WarmPool {
+    vm: VmConfig,
+    shapes: Mutex<BTreeMap<VmShape, ShapePool>>,
+    /// Woken when a VM is claimed
+    refill: Notify,
+}
+
+impl WarmPool {
+    pub fn new(config: &AppConfig) -> Self {
+        let mut shapes = BTreeMap::new();
+        for shape in &config.pool.shapes {
+            let key = VmShape {
+                vcpu_count: shape.vcpu_count,
+                memory_mb: shape.memory_mb,
+                storage_gb: shape.storage_gb,
+            };
+            shapes.entry(key).or_insert_with(ShapePool::default).size += shape.size;
+        }
+        Self {
+            vm: config.vm.clone(),
+            shapes: Mutex::new(shapes),
+            refill: Notify::new(),
+        }
+    }
+
+    /// An idle VM of the task's shape, handed over to the task with
+    /// `ssh_public_key` installed. None if there is none, and the task has to
+    /// boot a VM of its own.
+    pub async fn claim(
+        &self,
+        vm_manager: &VmManager,
+        task_id: Uuid,
+        task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
+        ssh_public_key: Option<&str>,
+    ) -> Option<VmInfo> {
+        let shape = VmShape::of(task_config, &self.vm);
+        loop {
+            let vm = {
+                let mut shapes = self.shapes.lock().unwrap();
+                let pool = shapes.entry(shape).or_default();
+                match pool.idle.pop_front() {
+                    Some(vm) => vm,
+                    None => {
+                        pool.misses += 1;
+                        return None;
+                    }
+                }
+            };
+            self.refill.notify_one();
+
+            match vm_manager.adopt_vm(&vm.vm_id, task_id, task_config, egress).await {
+                Ok(vm) => {
+                    // Booted without the key, the VM gets it the way a
+                    // restored one does
+                    if let Some(key) = ssh_public_key {
+                        if let Err(e) = vm_manager.personalize_vm(&vm, Some(key)).await {
+                            tracing::warn!(
+                                "Failed to install SSH key in pooled VM {}: {}",
+                                vm.vm_id,
+                                e
+                            );
+                            let _ = vm_manager.stop_vm(&vm.vm_id).await;
+                            self.shapes.lock().unwrap().entry(shape).or_default().misses += 1;
+                            return None;
+                        }
+                    }
+                    self.shapes.lock().unwrap().entry(shape).or_default().hits += 1;
+                    return Some(vm);
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to hand pooled VM {} to task {}: {}",
+                    vm.vm_id,
+                    task_id,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// A shape short of idle VMs, counted as booting one
+    fn start_boot(&self) -> Option<VmShape> {
+        let mut shapes = self.shapes.lock().unwrap();
+        let (shape, pool) = shapes
+            .iter_mut()
+            .find(|(_, pool)| pool.idle.len() + pool.booting < pool.size)?;
+        pool.booting += 1;
+        Some(*shape)
+    }
+
+    fn finish_boot(&self, shape: VmShape, vm: Option<VmInfo>) {
+        let mut shapes = self.shapes.lock().unwrap();
+        let pool = shapes.entry(shape).or_default();
+        pool.booting -= 1;
+        pool.idle.extend(vm);
+    }
+
+    /// Stop tracking idle VMs whose QEMU is gone, and clean up after them
+    async fn drop_dead(&self, vm_manager: &VmManager) {
+        let idle: Vec<String> = {
+            let shapes = self.shapes.lock().unwrap();
+            shapes
+                .values()
+                .flat_map(|pool| pool.idle.iter().map(|vm| vm.vm_id.clone()))
+                .collect()
+        };
+        for vm_id in idle {
+            if vm_manager.is_vm_running(&vm_id).await {
+                continue;
+            }
+            tracing::warn!("Pooled VM {} stopped, replacing it", vm_id);
+            for pool in self.shapes.lock().unwrap().values_mut() {
+                pool.idle.retain(|vm| vm.vm_id != vm_id);
+            }
+            let _ = vm_manager.stop_vm(&vm_id).await;
+        }
+    }
+
+    /// Prometheus text exposition of the pool's state
+    pub fn metrics(&self) -> String {
+        let shapes = self.shapes.lock().unwrap();
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&ShapePool) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (shape, pool) in shapes.iter() {
+                let _ = writeln!(out, "{}{{shape=\"{}\"}} {}", name, shape, value(pool));
+            }
+        };
+        family(
+            "lia_pool_size",
+            "gauge",
+            "Idle VMs the pool keeps of each shape",
+            &|pool| pool.size as u64,
+        );
+        family(
+            "lia_pool_idle_vms",
+            "gauge",
+            "Booted VMs waiting for a task",
+            &|pool| pool.idle.len() as u64,
+        );
+        family(
+            "lia_pool_booting_vms",
+            "gauge",
+            "VMs being booted for the pool",
+            &|pool| pool.booting as u64,
+        );
+        family(
+            "lia_pool_hits_total",
+            "counter",
+            "Tasks that got a VM from the pool",
+            &|pool| pool.hits,
+        );
+        family(
+            "lia_pool_misses_total",
+            "counter",
+            "Tasks that found no idle VM of their shape and booted one",
+            &|pool| pool.misses,
+        );
+        out
+    }
+}
+
+/// Keep the pool full for as long as the API runs. Idle VMs left by an
+/// earlier run are stopped first: nothing tracks them any more.
+pub async fn run(state: Arc<AppState>) {
+    discard_leftovers(&state).await;
+
+    let pool = &state.pool;
+    loop {
+        pool.drop_dead(&state.vm_manager).await;
+        while let Some(shape) = pool.start_boot() {
+            match boot(&state.vm_manager, shape).await {
+                Ok(vm) => {
+                    tracing::info!("Pooled VM {} ({}) ready", vm.vm_id, shape);
+                    pool.finish_boot(shape, Some(vm));
+                }
+                Err(e) => {
+                    tracing::error!("Failed to boot a VM for the pool ({}): {}", shape, e);
+                    pool.finish_boot(shape, None);
+                    break;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = pool.refill.notified() => {}
+            _ = tokio::time::sleep(REFILL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Boot a VM of `shape` and wait for its sidecar
+async fn boot(vm_manager: &VmManager, shape: VmShape) -> ApiResult<VmInfo> {
+    let vm = vm_manager.create_pool_vm(&shape.task_config()).await?;
+    if let Err(e) = vsock::wait_for_sidecar(vm.cid).await {
+        let _ = vm_manager.stop_vm(&vm.vm_id).await;
+        return Err(e);
+    }
+    Ok(vm)
+}
+
+/// Stop the VMs whose IDs belong to no task: idle VMs of the pool
+async fn discard_leftovers(state: &AppState) {
+    let Ok(mut entries) = tokio::fs::read_dir(&state.config.qemu.pids_dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("vm-")?.strip_suffix(".pid"))
+            .and_then(|id| id.parse::<Uuid>().ok())
+        else {
+            continue;
+        };
+        if let Err(ApiError::TaskNotFound(_)) = db::get_task(&state.db, id).await {
+            tracing::info!("Stopping VM {} left idle in the pool", id);
+            state.vm_manager.discard_vm(id).await;
+        }
+    }
+}
@@ -0,0 +1,503 @@
+//! Preview URLs for servers the agent starts in its VM, like a dev server on
+//! port 3000. Requests to `/<task-id>/preview/<port>/...`, or to
+//! `<port>-<task-id>.<domain>` when a preview domain is configured, are
+//! proxied to the VM's address on the TAP network, WebSocket upgrades
+//! included. Only the ports the task declared in `preview_ports` can be
+//! reached, and only with the task's preview token.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, Version},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::models::TaskStatus;
+use crate::AppState;
+
+/// Query parameter carrying the preview token; stripped before forwarding
+const TOKEN_PARAM: &str = "preview_token";
+
+/// Cookie remembering the preview token, so that pages can load their
+/// assets; stripped before forwarding
+const TOKEN_COOKIE: &str = "lia_preview_token";
+
+/// How long the server in the VM gets to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A fresh preview token for a new task
+pub fn new_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Proxy `req` to `port` in the task's VM. `prefix` is the part of the path
+/// that addressed the preview, empty on a preview subdomain.
+pub async fn proxy(
+    state: &AppState,
+    task_id: Uuid,
+    port: u16,
+    prefix: &str,
+    req: Request,
+) -> ApiResult<Response> {
+    let task = db::get_task(&state.db, task_id).await?;
+    let Some(expected) = task.preview_token.as_deref() else {
+        return Err(ApiError::Forbidden(
+            "Previews are not available for this task".to_string(),
+        ));
+    };
+    let from_query = query_token(req.uri()).is_some();
+    match presented_token(&req) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {}
+        _ => {
+            return Err(ApiError::Unauthorized(
+                "Missing or wrong preview token".to_string(),
+            ))
+        }
+    }
+
+    let declared = task
+        .config
+        .as_ref()
+        .is_some_and(|config| config.preview_ports.contains(&port));
+    if !declared {
+        return Err(ApiError::Forbidden(format!(
+            "Port {} is not a preview port of this task",
+            port
+        )));
+    }
+    if !matches!(task.status, TaskStatus::Running | TaskStatus::AwaitingInput) {
+        return Err(ApiError::InvalidState(format!(
+            "Task is not running, current status: {}",
+            task.status
+        )));
+    }
+    let addr = task
+        .ip_address
+        .as_deref()
+        .and_then(|ip| ip.parse().ok())
+        .map(|ip| SocketAddr::new(ip, port))
+        .ok_or_else(|| ApiError::InvalidState("Task has no IP address yet".to_string()))?;
+
+    let mut response = forward(req, addr, prefix).await?;
+    // Remember a token given in the URL for the requests the page makes
+    if from_query {
+        let cookie = format!(
+            "{}={}; Path={}/; HttpOnly; SameSite=Lax",
+            TOKEN_COOKIE, expected, prefix
+        );
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+    Ok(response)
+}
+
+/// Serve preview subdomains ahead of the API's routes
+pub async fn subdomains(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let target = state.config.preview.domain.as_deref().and_then(|domain| {
+        let host = req.headers().get(header::HOST)?.to_str().ok()?;
+        parse_host(host, domain)
+    });
+    match target {
+        Some((task_id, port)) => match proxy(&state, task_id, port, "", req).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        },
+        None => next.run(req).await,
+    }
+}
+
+/// Task and port of a `<port>-<task-id>.<domain>` host
+pub fn parse_host(host: &str, domain: &str) -> Option<(Uuid, u16)> {
+    let host = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    let label = host
+        .strip_suffix(domain)?
+        .strip_suffix('.')
+        .filter(|label| !label.contains('.'))?;
+    let (port, task_id) = label.split_once('-')?;
+    Some((Uuid::parse_str(task_id).ok()?, port.parse().ok()?))
+}
+
+/// Send `req` to the server at `addr` on a connection of its own and relay
+/// its answer, handing over the connection if it accepts an upgrade.
+/// `prefix` is removed from the path, along with the preview token from the
+/// query and cookies.
+pub async fn forward(mut req: Request, addr: SocketAddr, prefix: &str) -> ApiResult<Response> {
+    let path = req.uri().path();
+    let path = match path.strip_prefix(prefix) {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ if prefix.is_empty() => path,
+        _ => "/",
+    };
+    let path_and_query = match query_without_token(req.uri()) {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let uri: Uri = path_and_query
+        .parse()
+        .map_err(|_| ApiError::BadRequest("Invalid preview path".to_string()))?;
+
+    let upgrade = upgrade_protocol(req.headers());
+    let original_host = req.headers().get(header::HOST).cloned();
+    let headers = req.headers_mut();
+    remove_hop_headers(headers);
+    remove_token_cookie(headers);
+    // Dev servers tend to only answer to localhost
+    headers.insert(
+        header::HOST,
+        HeaderValue::from_str(&format!("localhost:{}", addr.port())).expect("valid host"),
+    );
+    if let Some(host) = original_host {
+        headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+    }
+    if !prefix.is_empty() {
+        if let Ok(prefix) = HeaderValue::from_str(prefix) {
+            headers.insert(HeaderName::from_static("x-forwarded-prefix"), prefix);
+        }
+    }
+    if let Some(protocol) = &upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, protocol.clone());
+    }
+    let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut req));
+    *req.uri_mut() = uri;
+    *req.version_mut() = Version::HTTP_11;
+
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            return Err(ApiError::BadGateway(format!(
+                "Nothing is listening on port {}: {}",
+                addr.port(),
+                e
+            )))
+        }
+        Err(_) => {
+            return Err(ApiError::BadGateway(format!(
+                "Port {} did not accept the connection in time",
+                addr.port()
+            )))
+        }
+    };
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| ApiError::BadGateway(format!("Failed to reach port {}: {}", addr.port(), e)))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.with_upgrades().await {
+            tracing::debug!("Preview connection to {} ended: {}", addr, e);
+        }
+    });
+
+    let mut response = sender
+        .send_request(req)
+        .await
+        .map_err(|e| ApiError::BadGateway(format!("Port {} failed to answer: {}", addr.port(), e)))?;
+
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let Some(client_upgrade) = client_upgrade else {
+            return Err(ApiError::BadGateway(
+                "Upgrade answered without being asked for".to_string(),
+            ));
+        };
+        let upstream_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                Ok((client, upstream)) => {
+                    let mut client = TokioIo::new(client);
+                    let mut upstream = TokioIo::new(upstream);
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                }
+                Err(e) => tracing::debug!("Preview upgrade to {} failed: {}", addr, e),
+            }
+        });
+        let (parts, _) = response.into_parts();
+        return Ok(Response::from_parts(parts, Body::empty()));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    remove_hop_headers(&mut parts.headers);
+    Ok(Response::from_parts(parts, Body::new(body)))
+}
+
+/// The protocol a request asks to upgrade to, if any
+fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let asked = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    if asked {
+        headers.get(header::UPGRADE).cloned()
+    } else {
+        None
+    }
+}
+
+/// Remove the headers that only apply to one connection, including those
+/// the Connection header names
+fn remove_hop_headers(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// The preview token from the query or, failing that, the cookie
+fn presented_token(req: &Request) -> Option<String> {
+    query_token(req.uri()).or_else(|| {
+        req.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|cookie| cookie_value(cookie, TOKEN_COOKIE))
+            .map(String::from)
+    })
+}
+
+fn query_token(uri: &Uri) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(TOKEN_PARAM)?.strip_prefix('='))
+        .map(String::from)
+}
+
+fn query_without_token(uri: &Uri) -> Option<String> {
+    let query = uri
+        .query()?
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some(TOKEN_PARAM))
+        .collect::<Vec<_>>()
+        .join("&");
+    (!query.is_empty()).then_some(query)
+}
+
+fn cookie_value<'a>(cookie: &'a str, name: &str) -> Option<&'a str> {
+    let (key, value) = cookie.trim().split_once('=')?;
+    (key == name).then_some(value)
+}
+
+fn remove_token_cookie(headers: &mut HeaderMap) {
+    let cookies: Vec<String> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter(|cookie| cookie_value(cookie, TOKEN_COOKIE).is_none())
+        .map(|cookie| cookie.trim().to_string())
+        .filter(|cookie| !cookie.is_empty())
+        .collect();
+    headers.remove(header::COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+        if !cookies.is_empty() {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+}
+
+/// Compare without revealing how much of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ws::WebSocketUpgrade;
+    use axum::routing::get;
+    use axum::Router;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite;
+
+    const PREFIX: &str = "/task/preview/3000";
+
+    async fn serve(app: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Stands in for a dev server in the VM: echoes WebSocket messages on
+    /// /ws and describes any other request it gets
+    async fn guest() -> SocketAddr {
+        let echo = |ws: WebSocketUpgrade| async {
+            ws.on_upgrade(|mut socket| async move {
+                while let Some(Ok(msg)) = socket.recv().await {
+                    if socket.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+        let describe = |req: Request| async move {
+            let (parts, body) = req.into_parts();
+            let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            let header = |name: &str| {
+                parts
+                    .headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            };
+            axum::Json(serde_json::json!({
+                "method": parts.method.as_str(),
+                "uri": parts.uri.to_string(),
+                "host": header("host"),
+                "cookie": header("cookie"),
+                "forwarded_host": header("x-forwarded-host"),
+                "forwarded_prefix": header("x-forwarded-prefix"),
+                "body": String::from_utf8_lossy(&body),
+            }))
+        };
+        serve(Router::new().route("/ws", get(echo)).fallback(describe)).await
+    }
+
+    /// Forwards everything under PREFIX to `guest`
+    async fn front(guest: SocketAddr) -> SocketAddr {
+        serve(Router::new().fallback(move |req: Request| forward(req, guest, PREFIX))).await
+    }
+
+    #[tokio::test]
+    async fn test_forwards_path_query_and_headers() {
+        let guest = guest().await;
+        let front = front(guest).await;
+
+        let seen: serde_json::Value = reqwest::Client::new()
+            .get(format!(
+                "http://{}{}/app/page?x=1&preview_token=secret&y=2",
+                front, PREFIX
+            ))
+            .header("cookie", "a=1; lia_preview_token=secret; b=2")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(seen["method"], "GET");
+        assert_eq!(seen["uri"], "/app/page?x=1&y=2");
+        assert_eq!(seen["host"], format!("localhost:{}", guest.port()));
+        assert_eq!(seen["cookie"], "a=1; b=2");
+        assert_eq!(seen["forwarded_host"], front.to_string());
+        assert_eq!(seen["forwarded_prefix"], PREFIX);
+    }
+
+    #[tokio::test]
+    async fn test_forwards_request_bodies_and_drops_token_only_cookies() {
+        let front = front(guest().await).await;
+
+        let seen: serde_json::Value = reqwest::Client::new()
+            .post(format!("http://{}{}/api/items?preview_token=secret", front, PREFIX))
+            .header("cookie", "lia_preview_token=secret")
+            .body("{\"name\":\"item\"}")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(seen["method"], "POST");
+        assert_eq!(seen["uri"], "/api/items");
+        assert_eq!(seen["cookie"], serde_json::Value::Null);
+        assert_eq!(seen["body"], "{\"name\":\"item\"}");
+    }
+
+    #[tokio::test]
+    async fn test_passes_websockets_through() {
+        let front = front(guest().await).await;
+
+        let (mut socket, response) =
+            tokio_tungstenite::connect_async(format!("ws://{}{}/ws", front, PREFIX))
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        for text in ["first", "second"] {
+            socket
+                .send(tungstenite::Message::Text(text.to_string()))
+                .await
+                .unwrap();
+            let echoed = socket.next().await.unwrap().unwrap();
+            assert_eq!(echoed, tungstenite::Message::Text(text.to_string()));
+        }
+        socket.close(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed_port_is_a_bad_gateway() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let front = front(closed).await;
+
+        let response = reqwest::get(format!("http://{}{}/", front, PREFIX))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 502);
+    }
+
+    #[test]
+    fn test_parse_host() {
+        let task_id = Uuid::from_u128(0x1234);
+        let domain = "preview.example.com";
+        assert_eq!(
+            parse_host(&format!("3000-{}.{}", task_id, domain), domain),
+            Some((task_id, 3000))
+        );
+        assert_eq!(
+            parse_host(&format!("8080-{}.{}:8811", task_id, domain), domain),
+            Some((task_id, 8080))
+        );
+        assert_eq!(parse_host(domain, domain), None);
+        assert_eq!(parse_host(&format!("a.3000-{}.{}", task_id, domain), domain), None);
+        assert_eq!(parse_host(&format!("3000-{}.notpreview.example.com", task_id), domain), None);
+        assert_eq!(parse_host(&format!("99999-{}.{}", task_id, domain), domain), None);
+    }
+
+    #[test]
+    fn test_remove_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, x-session"));
+        headers.insert("x-session", HeaderValue::from_static("1"));
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/html"));
+        remove_hop_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[header::ACCEPT], "text/html");
+    }
+}
@@ -0,0 +1,393 @@
+//! Egress proxy: an HTTP(S) forward proxy on the bridge IP that the VMs are
+//! configured to use through `HTTP_PROXY` and `HTTPS_PROXY`. The task is
+//! known from the client's address on the TAP network. Every request, refused
+//! or not, is logged to `task_connections` when its connection ends.
+//!
+//! HTTPS goes through CONNECT tunnels. Plain HTTP requests in absolute form
+//! are sent on in origin form with `Connection: close`, so each connection to
+//! the proxy carries one request. The task's egress policy is applied to the
+//! host named in the request (see `egress::permits`), and the proxy never
+//! connects to the host itself or to the VM network.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::db;
+use crate::egress::{self, Ipv4Net};
+use crate::models::{Task, TaskConnection};
+use crate::AppState;
+
+/// Upper bound for a request line and its headers
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// How long a client gets to send its request
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the remote host gets to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers meant for the proxy, not passed on
+const PROXY_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+/// Accept connections from the VMs until the listener fails
+pub async fn serve(state: Arc<AppState>, listener: TcpListener) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Egress proxy failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&state, stream, peer).await {
+                tracing::debug!("Egress proxy connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// A request as far as the proxy needs it
+struct ProxyRequest {
+    method: String,
+    host: String,
+    port: u16,
+    /// The head to send to the remote host; None for CONNECT
+    forward_head: Option<Vec<u8>>,
+}
+
+async fn handle(state: &AppState, mut client: TcpStream, peer: SocketAddr) -> std::io::Result<()> {
+    let Some(task) = task_for_peer(state, peer.ip()).await else {
+        return respond(&mut client, "403 Forbidden", "Unknown client").await;
+    };
+
+    let (head, body_start) = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut client)).await
+    {
+        Ok(Ok(Some(read))) => read,
+        Ok(Ok(None)) => return respond(&mut client, "400 Bad Request", "Bad request").await,
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return respond(&mut client, "408 Request Timeout", "Request timeout").await,
+    };
+    let request = match parse_request(&head) {
+        Ok(request) => request,
+        Err(message) => return respond(&mut client, "400 Bad Request", message).await,
+    };
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let mut log = TaskConnection {
+        id: 0,
+        task_id: task.id,
+        host: request.host.clone(),
+        port: i32::from(request.port),
+        method: request.method.clone(),
+        allowed: false,
+        bytes_up: 0,
+        bytes_down: 0,
+        started_at,
+        duration_ms: 0,
+        error: None,
+    };
+
+    let result = relay(state, &task, &request, &mut client, body_start, &mut log).await;
+    log.duration_ms = started.elapsed().as_millis() as i64;
+    if let Err(e) = db::add_task_connection(&state.db, &log).await {
+        tracing::warn!("Failed to log egress connection of task {}: {}", task.id, e);
+    }
+    result
+}
+
+/// Check the request against the task's policy, connect and pass the data
+/// through, recording the outcome in `log`
+async fn relay(
+    state: &AppState,
+    task: &Task,
+    request: &ProxyRequest,
+    client: &mut TcpStream,
+    body_start: Vec<u8>,
+    log: &mut TaskConnection,
+) -> std::io::Result<()> {
+    let guild_id = db::get_guild_id_for_task(&state.db, task.id)
+        .await
+        .ok()
+        .flatten();
+    let policy = state.config.egress.policy_for(
+        task.config.as_deref().and_then(|c| c.egress.as_ref()),
+        guild_id.as_deref(),
+    );
+
+    let addrs: Vec<SocketAddr> =
+        match tokio::net::lookup_host((request.host.as_str(), request.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                log.error = Some(format!("Failed to resolve {}: {}", request.host, e));
+                return respond(client, "502 Bad Gateway", "Cannot resolve host").await;
+            }
+        };
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| is_public(state, addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        log.error = Some("Host is not reachable through the proxy".to_string());
+        return respond(
+            client,
+            "403 Forbidden",
+            "Host is not reachable through the proxy",
+        )
+        .await;
+    }
+    let Some(addr) = addrs
+        .into_iter()
+        .find(|addr| egress::permits(&policy, &state.config.egress, &request.host, addr.ip()))
+    else {
+        log.error = Some("Not allowed by the egress policy".to_string());
+        return respond(client, "403 Forbidden", "Not allowed by the egress policy").await;
+    };
+    log.allowed = true;
+
+    let mut remote = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(remote)) => remote,
+        Ok(Err(e)) => {
+            log.error = Some(format!("Failed to connect to {}: {}", addr, e));
+            return respond(client, "502 Bad Gateway", "Cannot connect to host").await;
+        }
+        Err(_) => {
+            log.error = Some(format!("Timed out connecting to {}", addr));
+            return respond(client, "504 Gateway Timeout", "Host did not answer").await;
+        }
+    };
+
+    match &request.forward_head {
+        Some(head) => {
+            remote.write_all(head).await?;
+            log.bytes_up += head.len() as i64;
+        }
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+        }
+    }
+    remote.write_all(&body_start).await?;
+    log.bytes_up += body_start.len() as i64;
+
+    match tokio::io::copy_bidirectional(client, &mut remote).await {
+        Ok((up, down)) => {
+            log.bytes_up += up as i64;
+            log.bytes_down += down as i64;
+        }
+        Err(e) => log.error = Some(format!("Connection broken: {}", e)),
+    }
+    Ok(())
+}
+
+/// The task whose VM has `ip`
+async fn task_for_peer(state: &AppState, ip: IpAddr) -> Option<Task> {
+    match db::get_active_task_by_ip(&state.db, &ip.to_string()).await {
+        Ok(task) => task,
+        Err(e) => {
+            tracing::warn!("Egress proxy failed to look up the task of {}: {}", ip, e);
+            None
+        }
+    }
+}
+
+/// Addresses the proxy may connect to: not the host, not the VM network
+fn is_public(state: &AppState, ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let vm_network = Ipv4Net::parse(&state.config.network.subnet);
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || vm_network.is_some_and(|net| net.contains(ip)))
+        }
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()),
+    }
+}
+
+/// Read up to the end of the request's headers. Returns the head and what
+/// the client sent after it, or None if the head is malformed or too long.
+async fn read_head(client: &mut TcpStream) -> std::io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let end = searched + pos + 4;
+            let rest = buf.split_off(end);
+            return Ok(Some((buf, rest)));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Ok(None);
+        }
+    }
+}
+
+fn parse_request(head: &[u8]) -> Result<ProxyRequest, &'static str> {
+    let head = std::str::from_utf8(head).map_err(|_| "Bad request")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err("Bad request line");
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = split_host_port(target, None).ok_or("Bad CONNECT target")?;
+        return Ok(ProxyRequest {
+            method: "CONNECT".to_string(),
+            host,
+            port,
+            forward_head: None,
+        });
+    }
+
+    let rest = target
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &target[7..])
+        .ok_or("Only http:// URLs can be requested; use CONNECT for https")?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_string()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_string()),
+    };
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (host, port) = split_host_port(authority, Some(80)).ok_or("Bad host")?;
+
+    let mut forward = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if !PROXY_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            forward.push_str(line);
+            forward.push_str("\r\n");
+        }
+    }
+    forward.push_str("Connection: close\r\n\r\n");
+
+    Ok(ProxyRequest {
+        method: method.to_string(),
+        host,
+        port,
+        forward_head: Some(forward.into_bytes()),
+    })
+}
+
+/// Host (lowercase, brackets removed) and port of "host[:port]"
+fn split_host_port(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        (host, rest.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port?,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    (!host.is_empty() && port != 0).then_some((host, port))
+}
+
+/// Answer the client with a short plain-text response
+async fn respond(client: &mut TcpStream, status: &str, message: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_request() {
+        let request =
+            parse_request(b"CONNECT GitHub.com:443 HTTP/1.1\r\nHost: github.com:443\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.method, "CONNECT");
+        assert_eq!((request.host.as_str(), request.port), ("github.com", 443));
+        assert!(request.forward_head.is_none());
+
+        assert!(parse_request(b"CONNECT github.com HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_absolute_form_is_rewritten() {
+        let request = parse_request(
+            b"GET http://user@example.com:8080/a/b?c=d HTTP/1.1\r\n\
+              Host: example.com:8080\r\n\
+              Proxy-Connection: keep-alive\r\n\
+              Proxy-Authorization: Basic eDp5\r\n\
+              Accept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("example.com", 8080));
+        assert_eq!(
+            String::from_utf8(request.forward_head.unwrap()).unwrap(),
+            "GET /a/b?c=d HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+
+        let request = parse_request(b"GET http://example.com?q HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.port, 80);
+        assert!(request
+            .forward_head
+            .unwrap()
+            .starts_with(b"GET /?q HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_other_targets_are_refused() {
+        assert!(parse_request(b"GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request(b"GET /index.html HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request(b"GET\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("[::1]:8443", None),
+            Some(("::1".to_string(), 8443))
+        );
+        assert_eq!(
+            split_host_port("example.com.", Some(80)),
+            Some(("example.com".to_string(), 80))
+        );
+        assert_eq!(split_host_port("example.com:0", None), None);
+        assert_eq!(split_host_port(":443", None), None);
+    }
+}
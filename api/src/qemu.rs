@@ -1,5 +1,6 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
@@ -11,9 +12,74 @@ use tokio::process::Command;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, VmConfig};
+use crate::egress;
 use crate::error::{ApiError, ApiResult};
-use crate::models::{BootStage, TaskConfig};
+use crate::models::{
+    BootStage, DiskStats, EgressPolicy, NetworkLimits, NetworkStats, TaskConfig, VsockMessage,
+};
+use crate::rootfs::{self, DiskImage, RootfsStore};
+use crate::snapshot::{Golden, Manifest, SnapshotStore};
+use crate::traffic;
+use crate::volume::VolumeStore;
+use crate::vsock;
+
+/// How long traffic counters are watched to work out current throughput
+const STATS_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Longest a VM's state may take to be saved or loaded
+const MIGRATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// TAP device of a task's VM
+pub fn tap_name(task_id: Uuid) -> String {
+    format!("tap-{}", &task_id.to_string()[..8])
+}
+
+/// Resources of a VM. Pooled VMs and golden snapshots only serve VMs of the
+/// same shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VmShape {
+    pub vcpu_count: u32,
+    pub memory_mb: u32,
+    pub storage_gb: u32,
+}
+
+impl VmShape {
+    /// Shape of the VM `create_vm_with_progress` makes for a task
+    pub fn of(task_config: Option<&TaskConfig>, vm: &VmConfig) -> Self {
+        match task_config {
+            Some(c) => Self {
+                vcpu_count: c.vcpu_count,
+                memory_mb: c.max_memory_mb,
+                storage_gb: c.storage_gb,
+            },
+            None => Self {
+                vcpu_count: vm.default_vcpu_count,
+                memory_mb: vm.default_memory_mb,
+                storage_gb: vm.default_storage_gb,
+            },
+        }
+    }
+
+    pub fn task_config(self) -> TaskConfig {
+        TaskConfig {
+            vcpu_count: self.vcpu_count,
+            max_memory_mb: self.memory_mb,
+            storage_gb: self.storage_gb,
+            ..TaskConfig::default()
+        }
+    }
+}
+
+impl fmt::Display for VmShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}cpu-{}mb-{}gb",
+            self.vcpu_count, self.memory_mb, self.storage_gb
+        )
+    }
+}
 
 /// Callback type for reporting VM creation progress
 pub type ProgressCallback = Box<dyn Fn(BootStage) + Send + Sync>;
@@ -153,15 +219,26 @@ impl QmpClient {
             .await
             .map_err(|e| ApiError::VmError(format!("Failed to flush QMP command: {}", e)))?;
 
-        // Read response
-        let mut response_line = String::new();
-        reader
-            .read_line(&mut response_line)
-            .await
-            .map_err(|e| ApiError::VmError(format!("Failed to read QMP response: {}", e)))?;
-
-        let response: QmpResponse = serde_json::from_str(&response_line)
-            .map_err(|e| ApiError::VmError(format!("Failed to parse QMP response: {}", e)))?;
+        // Read response, skipping events QEMU emits in the meantime
+        let response: QmpResponse = loop {
+            let mut response_line = String::new();
+            let read = reader
+                .read_line(&mut response_line)
+                .await
+                .map_err(|e| ApiError::VmError(format!("Failed to read QMP response: {}", e)))?;
+            if read == 0 {
+                return Err(ApiError::VmError(
+                    "QMP socket closed before a response".to_string(),
+                ));
+            }
+            let value: serde_json::Value = serde_json::from_str(&response_line)
+                .map_err(|e| ApiError::VmError(format!("Failed to parse QMP response: {}", e)))?;
+            if value.get("event").is_none() {
+                break serde_json::from_value(value).map_err(|e| {
+                    ApiError::VmError(format!("Failed to parse QMP response: {}", e))
+                })?;
+            }
+        };
 
         if let Some(error) = response.error {
             return Err(ApiError::VmError(format!(
@@ -192,12 +269,61 @@ impl QmpClient {
         Ok(())
     }
 
+    /// Hard reset, like pressing the reset button (QMP "system_reset" command)
+    pub async fn reset(&self) -> ApiResult<()> {
+        self.send_command("system_reset", None).await?;
+        Ok(())
+    }
+
     /// Force quit (QMP "quit" command)
     pub async fn quit(&self) -> ApiResult<()> {
         self.send_command("quit", None).await?;
         Ok(())
     }
 
+    /// Write the state of the paused VM to `path` (QMP "migrate" to a file)
+    pub async fn save_state(&self, path: &Path) -> ApiResult<()> {
+        let uri = format!("file:{}", path.display());
+        self.send_command("migrate", Some(serde_json::json!({ "uri": uri })))
+            .await?;
+        self.wait_for_migration().await
+    }
+
+    /// Load a state written by `save_state` into a VM started with
+    /// `-incoming defer` (QMP "migrate-incoming"). The VM stays paused.
+    pub async fn load_state(&self, path: &Path) -> ApiResult<()> {
+        let uri = format!("file:{}", path.display());
+        self.send_command("migrate-incoming", Some(serde_json::json!({ "uri": uri })))
+            .await?;
+        self.wait_for_migration().await
+    }
+
+    async fn wait_for_migration(&self) -> ApiResult<()> {
+        let deadline = tokio::time::Instant::now() + MIGRATION_TIMEOUT;
+        loop {
+            let info = self.send_command("query-migrate", None).await?;
+            match info.get("status").and_then(|s| s.as_str()) {
+                Some("completed") => return Ok(()),
+                Some(status @ ("failed" | "cancelled")) => {
+                    return Err(ApiError::VmError(format!(
+                        "Migration {}: {}",
+                        status,
+                        info.get("error-desc")
+                            .and_then(|d| d.as_str())
+                            .unwrap_or("no details")
+                    )))
+                }
+                _ if tokio::time::Instant::now() > deadline => {
+                    return Err(ApiError::VmError(format!(
+                        "Migration not done after {:?}",
+                        MIGRATION_TIMEOUT
+                    )))
+                }
+                _ => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
+            }
+        }
+    }
+
     /// Query VM status
     #[allow(dead_code)]
     pub async fn query_status(&self) -> ApiResult<String> {
@@ -215,6 +341,11 @@ pub struct VmManager {
     vms: Arc<RwLock<HashMap<String, VmInfo>>>,
     next_cid: AtomicU32,
     next_ip: AtomicU32,
+    /// Paused VMs resumed for a while (see `wake_vm`), with their wake count
+    woken: std::sync::Mutex<HashMap<String, usize>>,
+    rootfs: RootfsStore,
+    volumes: VolumeStore,
+    snapshots: SnapshotStore,
 }
 
 impl VmManager {
@@ -222,8 +353,12 @@ impl VmManager {
         Self {
             next_cid: AtomicU32::new(config.vm.vsock_cid_start),
             next_ip: AtomicU32::new(100), // Start from 172.16.0.100
+            rootfs: RootfsStore::new(config.qemu.clone()),
+            volumes: VolumeStore::new(config.qemu.clone()),
+            snapshots: SnapshotStore::new(config.qemu.clone()),
             config,
             vms: Arc::new(RwLock::new(HashMap::new())),
+            woken: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
@@ -265,6 +400,25 @@ impl VmManager {
         Ok(())
     }
 
+    /// Rename the TAP device of a running VM
+    async fn rename_tap(&self, from: &str, to: &str) -> ApiResult<()> {
+        let output = Command::new("lia-rename-tap")
+            .arg(from)
+            .arg(to)
+            .output()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to rename TAP device: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(ApiError::VmError(format!(
+                "Failed to rename TAP device: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Delete a TAP device
     async fn delete_tap(&self, tap_name: &str) -> ApiResult<()> {
         let output = Command::new("lia-delete-tap")
@@ -284,25 +438,423 @@ impl VmManager {
         Ok(())
     }
 
+    /// Cap the bandwidth and connection rate of the VM on `tap_name`
+    async fn apply_limits(&self, tap_name: &str, limits: &NetworkLimits) -> ApiResult<()> {
+        for args in traffic::tc_commands(tap_name, limits) {
+            let output = Command::new("tc")
+                .args(&args)
+                .output()
+                .await
+                .map_err(|e| ApiError::VmError(format!("Failed to run tc: {}", e)))?;
+
+            if !output.status.success() {
+                return Err(ApiError::VmError(format!(
+                    "Failed to apply network limits ({}): {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the limits of the VM on `tap_name`, so others can be applied
+    async fn clear_limits(&self, tap_name: &str) {
+        for parent in ["root", "ingress"] {
+            let _ = Command::new("tc")
+                .args(["qdisc", "del", "dev", tap_name, parent])
+                .output()
+                .await;
+        }
+    }
+
+    /// Confine what the VM on `tap_name` can reach. Nothing to do for `open`.
+    async fn apply_egress(&self, tap_name: &str, policy: &EgressPolicy) -> ApiResult<()> {
+        let Some(allowed) = egress::allowed_networks(policy, &self.config.egress).await else {
+            return Ok(());
+        };
+        let ruleset = egress::ruleset(
+            tap_name,
+            &allowed,
+            &self.config.egress.dns_servers,
+            self.config.egress_proxy_addr(),
+        );
+
+        let mut child = Command::new("nft")
+            .arg("-f")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| ApiError::VmError(format!("Failed to run nft: {}", e)))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(ruleset.as_bytes())
+                .await
+                .map_err(|e| ApiError::VmError(format!("Failed to run nft: {}", e)))?;
+        }
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to run nft: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(ApiError::VmError(format!(
+                "Failed to install egress rules: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        tracing::info!(
+            "Egress of {} limited to {} networks",
+            tap_name,
+            allowed.len()
+        );
+        Ok(())
+    }
+
+    /// Remove the egress rules of the VM on `tap_name`, if it has any
+    async fn remove_egress(&self, tap_name: &str) -> ApiResult<()> {
+        let table = egress::table_name(tap_name);
+        let exists = Command::new("nft")
+            .args(["list", "table", "bridge", &table])
+            .output()
+            .await
+            .is_ok_and(|output| output.status.success());
+        if !exists {
+            return Ok(());
+        }
+
+        let output = Command::new("nft")
+            .args(["delete", "table", "bridge", &table])
+            .output()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to run nft: {}", e)))?;
+
+        if !output.status.success() {
+            tracing::warn!(
+                "Failed to remove egress rules of {}: {}",
+                tap_name,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        Ok(())
+    }
+
     pub async fn create_vm(
         &self,
         task_id: Uuid,
         task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
         ssh_public_key: Option<&str>,
     ) -> ApiResult<VmInfo> {
-        self.create_vm_with_progress(task_id, task_config, ssh_public_key, None)
+        self.create_vm_with_progress(task_id, task_config, egress, ssh_public_key, None)
+            .await
+    }
+
+    /// Boot a VM for the warm pool, of the shape in `shape`. It gets an ID of
+    /// its own and is confined by the `deny` policy until `adopt_vm` hands it
+    /// to a task.
+    pub async fn create_pool_vm(&self, shape: &TaskConfig) -> ApiResult<VmInfo> {
+        self.create_vm_with_progress(Uuid::new_v4(), Some(shape), &EgressPolicy::Deny, None, None)
             .await
     }
 
+    /// Hand a VM of the warm pool to a task: its TAP device, files and disks
+    /// are renamed after the task, as if it had been created for it, and it
+    /// gets the task's egress policy and limits. A VM that cannot be handed
+    /// over is stopped.
+    pub async fn adopt_vm(
+        &self,
+        vm_id: &str,
+        task_id: Uuid,
+        task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
+    ) -> ApiResult<VmInfo> {
+        if !self.is_vm_running(vm_id).await {
+            let _ = self.stop_vm(vm_id).await;
+            return Err(ApiError::VmError(format!("VM {} is not running", vm_id)));
+        }
+        let Some(mut info) = self.vms.write().await.remove(vm_id) else {
+            return Err(ApiError::VmError(format!("VM not found: {}", vm_id)));
+        };
+
+        match self.rename_vm(&mut info, task_id, task_config, egress).await {
+            Ok(()) => {
+                tracing::info!("VM {} handed to task {}", vm_id, task_id);
+                self.vms
+                    .write()
+                    .await
+                    .insert(info.vm_id.clone(), info.clone());
+                Ok(info)
+            }
+            Err(e) => {
+                let vm_id = info.vm_id.clone();
+                self.vms.write().await.insert(vm_id.clone(), info);
+                let _ = self.stop_vm(&vm_id).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Move `info` to the names of `task_id`. The task's egress rules are in
+    /// place before the TAP device takes its name, so the VM is never
+    /// unconfined. `info` follows each step, for cleanup if one fails.
+    async fn rename_vm(
+        &self,
+        info: &mut VmInfo,
+        task_id: Uuid,
+        task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
+    ) -> ApiResult<()> {
+        let tap = tap_name(task_id);
+        if let Err(e) = self.apply_egress(&tap, egress).await {
+            let _ = self.remove_egress(&tap).await;
+            return Err(e);
+        }
+        if let Err(e) = self.rename_tap(&info.tap_name, &tap).await {
+            let _ = self.remove_egress(&tap).await;
+            return Err(e);
+        }
+        let _ = self.remove_egress(&info.tap_name).await;
+        info.tap_name = tap;
+        self.clear_limits(&info.tap_name).await;
+        let limits = traffic::limits_for(task_config, &self.config.network);
+        self.apply_limits(&info.tap_name, &limits).await?;
+
+        let vm_id = format!("vm-{}", task_id);
+        let rename = |path: &PathBuf, name: String| {
+            let (from, to) = (path.clone(), path.with_file_name(name));
+            async move {
+                tokio::fs::rename(&from, &to).await.map_err(|e| {
+                    ApiError::VmError(format!("Failed to rename {}: {}", from.display(), e))
+                })?;
+                Ok::<_, ApiError>(to)
+            }
+        };
+        info.qmp_socket_path = rename(&info.qmp_socket_path, format!("{}.qmp", vm_id)).await?;
+        info.pid_file = rename(&info.pid_file, format!("{}.pid", vm_id)).await?;
+        info.log_path = rename(&info.log_path, format!("{}.log", vm_id)).await?;
+        info.vm_id = vm_id;
+
+        if let Some(volume) = self.volumes.rename(info.task_id, task_id).await? {
+            info.volume_path = volume.path;
+        }
+        self.rootfs.rename(info.task_id, task_id).await?;
+        info.task_id = task_id;
+        Ok(())
+    }
+
+    /// Make the task's VM. With `restore_from_snapshot` it is restored from
+    /// the golden snapshot of its shape, taken first if there is none yet;
+    /// without, or if that fails, it boots.
     pub async fn create_vm_with_progress(
         &self,
         task_id: Uuid,
         task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
         ssh_public_key: Option<&str>,
         on_progress: Option<ProgressCallback>,
+    ) -> ApiResult<VmInfo> {
+        if self.config.qemu.restore_from_snapshot {
+            let shape = VmShape::of(task_config, &self.config.vm);
+            match self.golden(shape).await {
+                Ok(golden) => {
+                    let restored = self
+                        .launch_vm(
+                            task_id,
+                            task_config,
+                            egress,
+                            ssh_public_key,
+                            Some((shape, &golden)),
+                            on_progress.as_ref(),
+                        )
+                        .await;
+                    match restored {
+                        Ok(vm_info) => return Ok(vm_info),
+                        Err(e) => tracing::warn!(
+                            "Failed to restore VM of task {} from snapshot, booting it: {}",
+                            task_id,
+                            e
+                        ),
+                    }
+                }
+                Err(e) => tracing::warn!(
+                    "No golden snapshot of {}, booting VM of task {}: {}",
+                    shape,
+                    task_id,
+                    e
+                ),
+            }
+        }
+
+        self.launch_vm(
+            task_id,
+            task_config,
+            egress,
+            ssh_public_key,
+            None,
+            on_progress.as_ref(),
+        )
+        .await
+    }
+
+    /// The golden snapshot of `shape` for the current rootfs. It is taken
+    /// from a VM booted for it if there is none, unless that already failed.
+    async fn golden(&self, shape: VmShape) -> ApiResult<Golden> {
+        let _guard = self.snapshots.lock().await;
+        if let Some(golden) = self.snapshots.find(shape).await {
+            return Ok(golden);
+        }
+        if self.snapshots.has_failed(shape).await {
+            return Err(ApiError::VmError(
+                "taking it failed before, see the log".to_string(),
+            ));
+        }
+
+        tracing::info!("Taking golden snapshot of {}", shape);
+        let started = std::time::Instant::now();
+        let task_config = shape.task_config();
+        let golden = match self
+            .launch_vm(
+                Uuid::new_v4(),
+                Some(&task_config),
+                &EgressPolicy::Deny,
+                None,
+                None,
+                None,
+            )
+            .await
+        {
+            Ok(vm) => {
+                let golden = self.save_golden(&vm, shape).await;
+                let _ = self.stop_vm(&vm.vm_id).await;
+                golden
+            }
+            Err(e) => Err(e),
+        };
+        match &golden {
+            Ok(_) => tracing::info!(
+                "Golden snapshot of {} taken in {:?}",
+                shape,
+                started.elapsed()
+            ),
+            Err(e) => {
+                tracing::error!("Failed to take golden snapshot of {}: {}", shape, e);
+                self.snapshots.mark_failed(shape).await;
+            }
+        }
+        golden
+    }
+
+    /// Wait for the sidecar of the freshly booted `vm`, then save it as the
+    /// golden snapshot of `shape`. The VM is left paused, without its disks.
+    async fn save_golden(&self, vm: &VmInfo, shape: VmShape) -> ApiResult<Golden> {
+        vsock::wait_for_sidecar(vm.cid).await?;
+        let partial = self.snapshots.partial_dir(shape).await?;
+        let qmp = QmpClient::new(vm.qmp_socket_path.clone());
+        let saved = match qmp.pause().await {
+            Ok(()) => qmp.save_state(&partial.join("state")).await,
+            Err(e) => Err(e),
+        };
+        let disks = (
+            self.rootfs.find(vm.task_id).await,
+            self.volumes.find(vm.task_id).await,
+        );
+        let (rootfs, volume) = match (saved, disks) {
+            (Ok(()), (Some(rootfs), Some(volume))) => (rootfs, volume),
+            (saved, _) => {
+                let _ = tokio::fs::remove_dir_all(&partial).await;
+                saved?;
+                return Err(ApiError::VmError("Disks of the VM are gone".to_string()));
+            }
+        };
+
+        let manifest = Manifest {
+            kernel_cmdline: self.kernel_cmdline(&vm.ip_address, &vm.gateway, None),
+            mac_address: self.generate_mac(&vm.ip_address),
+            rootfs_format: rootfs.format.as_str().to_string(),
+            volume_format: volume.format.as_str().to_string(),
+        };
+        self.snapshots
+            .save(shape, &partial, &rootfs, &volume, &manifest)
+            .await
+    }
+
+    /// Load `golden` into `vm`, started with `-incoming defer`, run it and
+    /// make it the VM at its own address. A snapshot that cannot be loaded
+    /// is discarded, to be taken again.
+    async fn restore(
+        &self,
+        vm: &VmInfo,
+        shape: VmShape,
+        golden: &Golden,
+        ssh_public_key: Option<&str>,
+    ) -> ApiResult<()> {
+        let qmp = QmpClient::new(vm.qmp_socket_path.clone());
+        if let Err(e) = qmp.load_state(&golden.state).await {
+            self.snapshots.discard(shape).await;
+            return Err(e);
+        }
+        qmp.resume().await?;
+        self.personalize_vm(vm, ssh_public_key).await
+    }
+
+    /// Send the running `vm` its own address, MAC, the host's clock, fresh
+    /// entropy and `ssh_public_key`. Also installs the key of a task that
+    /// claimed a VM from the warm pool, whose address does not change.
+    pub async fn personalize_vm(&self, vm: &VmInfo, ssh_public_key: Option<&str>) -> ApiResult<()> {
+        let entropy: [u8; 32] = rand::random();
+        let personalize = VsockMessage::Personalize {
+            request_id: Uuid::new_v4().to_string(),
+            ip_address: vm.ip_address.clone(),
+            gateway: vm.gateway.clone(),
+            mac_address: self.generate_mac(&vm.ip_address),
+            ssh_public_key: ssh_public_key.map(str::to_string),
+            time_unix_ms: chrono::Utc::now().timestamp_millis(),
+            entropy: entropy.to_vec(),
+        };
+        vsock::personalize(vm.cid, &personalize).await
+    }
+
+    /// Kernel command line of a booted VM; see lia-network-init in the rootfs
+    fn kernel_cmdline(
+        &self,
+        ip_address: &str,
+        gateway: &str,
+        ssh_public_key: Option<&str>,
+    ) -> String {
+        let ssh_key_arg = ssh_public_key
+            .map(|k| format!(" lia.ssh_key={}", k.replace(' ', "^")))
+            .unwrap_or_default();
+        let proxy_arg = self
+            .config
+            .egress_proxy_addr()
+            .map(|addr| format!(" lia.proxy=http://{}", addr))
+            .unwrap_or_default();
+
+        format!(
+            "console=ttyS0 root=/dev/vda rw rootflags=discard init=/sbin/init \
+             lia.ip={} lia.gateway={} lia.workspace=/dev/vdb{}{}",
+            ip_address, gateway, ssh_key_arg, proxy_arg
+        )
+    }
+
+    /// Start QEMU for a task: booted, or restored from `golden`
+    async fn launch_vm(
+        &self,
+        task_id: Uuid,
+        task_config: Option<&TaskConfig>,
+        egress: &EgressPolicy,
+        ssh_public_key: Option<&str>,
+        golden: Option<(VmShape, &Golden)>,
+        on_progress: Option<&ProgressCallback>,
     ) -> ApiResult<VmInfo> {
         let report_progress = |stage: BootStage| {
-            if let Some(ref callback) = on_progress {
+            if let Some(callback) = on_progress {
                 callback(stage);
             }
         };
@@ -313,14 +865,12 @@ impl VmManager {
         // Allocate network resources
         let ip_address = self.allocate_ip();
         let gateway = self.config.network.bridge_ip.clone();
-        let tap_name = format!("tap-{}", &task_id.to_string()[..8]);
+        let tap_name = tap_name(task_id);
         let mac_address = self.generate_mac(&ip_address);
 
         // Create paths
         let qmp_socket_path =
             PathBuf::from(&self.config.qemu.sockets_dir).join(format!("{}.qmp", vm_id));
-        let volume_path =
-            PathBuf::from(&self.config.qemu.volumes_dir).join(format!("{}.ext4", task_id));
         let log_path = PathBuf::from(&self.config.qemu.logs_dir).join(format!("{}.log", vm_id));
         let pid_file = PathBuf::from(&self.config.qemu.pids_dir).join(format!("{}.pid", vm_id));
 
@@ -338,21 +888,41 @@ impl VmManager {
             .await
             .map_err(|e| ApiError::VmError(format!("Failed to create pids dir: {}", e)))?;
 
-        // Create TAP device
+        // Create TAP device, confined and limited before the VM can send anything
         self.create_tap(&tap_name).await?;
+        let limits = traffic::limits_for(task_config, &self.config.network);
+        let confined = match self.apply_egress(&tap_name, egress).await {
+            Ok(()) => self.apply_limits(&tap_name, &limits).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = confined {
+            let _ = self.remove_egress(&tap_name).await;
+            let _ = self.delete_tap(&tap_name).await;
+            return Err(e);
+        }
 
-        // Create sparse volume file
+        // Clone the data volume and rootfs for this VM
         let storage_gb = task_config
             .map(|c| c.storage_gb)
             .unwrap_or(self.config.vm.default_storage_gb);
-        self.create_sparse_volume(&volume_path, storage_gb).await?;
-
-        // Copy rootfs for this VM
-        let vm_rootfs_path =
-            PathBuf::from(&self.config.qemu.volumes_dir).join(format!("{}-rootfs.ext4", task_id));
-        tokio::fs::copy(&self.config.qemu.rootfs_path, &vm_rootfs_path)
-            .await
-            .map_err(|e| ApiError::VmError(format!("Failed to copy rootfs: {}", e)))?;
+        let started = std::time::Instant::now();
+        let (volume, vm_rootfs) = match golden {
+            Some((_, golden)) => (
+                self.volumes.create_from(task_id, &golden.volume).await?,
+                self.rootfs.create_from(task_id, &golden.rootfs).await?,
+            ),
+            None => (
+                self.volumes.create(task_id, storage_gb).await?,
+                self.rootfs.create(task_id).await?,
+            ),
+        };
+        tracing::info!(
+            "Disks of task {} ready in {:?} (rootfs {}, volume {})",
+            task_id,
+            started.elapsed(),
+            vm_rootfs.format.as_str(),
+            volume.format.as_str()
+        );
 
         // Report: configuring VM
         report_progress(BootStage::ConfiguringVm);
@@ -365,15 +935,18 @@ impl VmManager {
             .map(|c| c.max_memory_mb)
             .unwrap_or(self.config.vm.default_memory_mb);
 
-        // Build kernel command line
-        let ssh_key_arg = ssh_public_key
-            .map(|k| format!(" lia.ssh_key={}", k.replace(' ', "^")))
-            .unwrap_or_default();
-
-        let kernel_cmdline = format!(
-            "console=ttyS0 root=/dev/vda rw init=/sbin/init lia.ip={} lia.gateway={}{}",
-            ip_address, gateway, ssh_key_arg
-        );
+        // A restore starts QEMU the way the snapshot's VM was started; the
+        // guest is given its own address and MAC once it runs
+        let (kernel_cmdline, device_mac) = match golden {
+            Some((_, golden)) => (
+                golden.manifest.kernel_cmdline.clone(),
+                golden.manifest.mac_address.clone(),
+            ),
+            None => (
+                self.kernel_cmdline(&ip_address, &gateway, ssh_public_key),
+                mac_address,
+            ),
+        };
 
         // Build QEMU command
         let mut qemu_cmd = Command::new(&self.config.qemu.bin_path);
@@ -400,17 +973,20 @@ impl VmManager {
             .arg("-append")
             .arg(&kernel_cmdline);
 
-        // Drives: rootfs and data volume
+        // Drives: rootfs and data volume. Blocks the guest discards, or
+        // overwrites with zeroes, are given back to the host.
         qemu_cmd
             .arg("-drive")
             .arg(format!(
-                "file={},format=raw,if=virtio,id=rootfs",
-                vm_rootfs_path.display()
+                "file={},format={},if=virtio,id=rootfs,discard=unmap,detect-zeroes=unmap",
+                vm_rootfs.path.display(),
+                vm_rootfs.format.as_str()
             ))
             .arg("-drive")
             .arg(format!(
-                "file={},format=raw,if=virtio,id=data",
-                volume_path.display()
+                "file={},format={},if=virtio,id=data,discard=unmap,detect-zeroes=unmap",
+                volume.path.display(),
+                volume.format.as_str()
             ));
 
         // Network configuration
@@ -421,13 +997,22 @@ impl VmManager {
                 tap_name
             ))
             .arg("-device")
-            .arg(format!("virtio-net-pci,netdev=net0,mac={}", mac_address));
+            .arg(format!("virtio-net-pci,netdev=net0,mac={}", device_mac));
 
         // vsock device for host-guest communication
         qemu_cmd
             .arg("-device")
             .arg(format!("vhost-vsock-pci,guest-cid={}", cid));
 
+        // Snapshotted and restored VMs need the same devices. The generation
+        // ID changes on every start, which tells a restored guest to reseed.
+        if self.config.qemu.restore_from_snapshot {
+            qemu_cmd.arg("-device").arg("vmgenid,guid=auto");
+        }
+        if golden.is_some() {
+            qemu_cmd.arg("-incoming").arg("defer");
+        }
+
         // QMP socket for runtime control
         qemu_cmd
             .arg("-qmp")
@@ -458,6 +1043,7 @@ impl VmManager {
 
         if !output.status.success() {
             // Clean up TAP device on failure
+            let _ = self.remove_egress(&tap_name).await;
             let _ = self.delete_tap(&tap_name).await;
             let stderr = String::from_utf8_lossy(&output.stderr);
             let stdout = String::from_utf8_lossy(&output.stdout);
@@ -484,7 +1070,7 @@ impl VmManager {
             task_id,
             cid,
             qmp_socket_path,
-            volume_path,
+            volume_path: volume.path,
             log_path,
             pid_file,
             pid,
@@ -499,35 +1085,16 @@ impl VmManager {
             .await
             .insert(vm_id.clone(), vm_info.clone());
 
-        Ok(vm_info)
-    }
-
-    async fn create_sparse_volume(&self, path: &PathBuf, size_gb: u32) -> ApiResult<()> {
-        let file = tokio::fs::File::create(path)
-            .await
-            .map_err(|e| ApiError::VmError(format!("Failed to create volume file: {}", e)))?;
-
-        let size_bytes = (size_gb as u64) * 1024 * 1024 * 1024;
-        file.set_len(size_bytes)
-            .await
-            .map_err(|e| ApiError::VmError(format!("Failed to set volume size: {}", e)))?;
-
-        // Format as ext4
-        let output = Command::new("mkfs.ext4")
-            .arg("-F")
-            .arg(path)
-            .output()
-            .await
-            .map_err(|e| ApiError::VmError(format!("Failed to format volume: {}", e)))?;
-
-        if !output.status.success() {
-            return Err(ApiError::VmError(format!(
-                "mkfs.ext4 failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )));
+        if let Some((shape, golden)) = golden {
+            let started = std::time::Instant::now();
+            if let Err(e) = self.restore(&vm_info, shape, golden, ssh_public_key).await {
+                let _ = self.stop_vm(&vm_id).await;
+                return Err(e);
+            }
+            tracing::info!("VM {} restored in {:?}", vm_id, started.elapsed());
         }
 
-        Ok(())
+        Ok(vm_info)
     }
 
     async fn wait_for_socket(&self, socket_path: &PathBuf) -> ApiResult<()> {
@@ -592,56 +1159,165 @@ impl VmManager {
         qmp.resume().await
     }
 
-    pub async fn stop_vm(&self, vm_id: &str) -> ApiResult<()> {
-        // Remove from tracking
-        let vm_info = self.vms.write().await.remove(vm_id);
+    /// Hard-reset a VM. Works for VMs started before an API restart too,
+    /// since the QMP socket path only depends on the VM ID.
+    pub async fn reset_vm(&self, vm_id: &str) -> ApiResult<()> {
+        self.qmp_client(vm_id).reset().await
+    }
 
-        if let Some(info) = vm_info {
-            // Try graceful shutdown via QMP first
-            let qmp = QmpClient::new(info.qmp_socket_path.clone());
-            if let Err(e) = qmp.quit().await {
-                tracing::warn!("QMP quit failed: {}, falling back to SIGTERM", e);
-
-                // Fallback: kill by PID
-                if let Some(pid) = info.pid {
-                    let _ = Command::new("kill")
-                        .arg("-TERM")
-                        .arg(pid.to_string())
-                        .output()
-                        .await;
+    /// Resume a paused VM for a while, e.g. to read its workspace. Every
+    /// successful call is matched by one `release_vm`.
+    pub async fn wake_vm(&self, vm_id: &str) -> ApiResult<()> {
+        let first = {
+            let mut woken = self.woken.lock().unwrap();
+            let count = woken.entry(vm_id.to_string()).or_insert(0);
+            *count += 1;
+            *count == 1
+        };
+        if first {
+            if let Err(e) = self.qmp_client(vm_id).resume().await {
+                self.woken.lock().unwrap().remove(vm_id);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 
-                    // Wait a bit and force kill if needed
-                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-                    let _ = Command::new("kill")
-                        .arg("-KILL")
-                        .arg(pid.to_string())
-                        .output()
-                        .await;
+    /// End a `wake_vm`. After the last one the VM is paused again if `pause`
+    /// is set (it is not when the task was resumed in the meantime).
+    pub async fn release_vm(&self, vm_id: &str, pause: bool) {
+        let last = {
+            let mut woken = self.woken.lock().unwrap();
+            match woken.get_mut(vm_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                Some(_) => {
+                    woken.remove(vm_id);
+                    true
                 }
+                None => false,
             }
+        };
+        if last && pause {
+            if let Err(e) = self.qmp_client(vm_id).pause().await {
+                tracing::warn!("Failed to pause VM {} again: {}", vm_id, e);
+            }
+        }
+    }
 
-            // Delete TAP device
-            let _ = self.delete_tap(&info.tap_name).await;
+    /// QMP client by VM ID, for VMs started before an API restart too
+    fn qmp_client(&self, vm_id: &str) -> QmpClient {
+        QmpClient::new(PathBuf::from(&self.config.qemu.sockets_dir).join(format!("{}.qmp", vm_id)))
+    }
 
-            // Cleanup files
-            let _ = tokio::fs::remove_file(&info.qmp_socket_path).await;
-            let _ = tokio::fs::remove_file(&info.volume_path).await;
-            let _ = tokio::fs::remove_file(&info.log_path).await;
-            let _ = tokio::fs::remove_file(&info.pid_file).await;
+    /// Whether the VM's QEMU process is alive, paused or not
+    pub async fn is_vm_running(&self, vm_id: &str) -> bool {
+        let pid_file = PathBuf::from(&self.config.qemu.pids_dir).join(format!("{}.pid", vm_id));
+        match tokio::fs::read_to_string(&pid_file).await {
+            Ok(pid) => Path::new("/proc").join(pid.trim()).exists(),
+            Err(_) => false,
+        }
+    }
 
-            // Also remove the copied rootfs
-            let rootfs_copy = PathBuf::from(&self.config.qemu.volumes_dir)
-                .join(format!("{}-rootfs.ext4", info.task_id));
-            let _ = tokio::fs::remove_file(&rootfs_copy).await;
+    /// Disk image holding the task's /workspace and the directory it is in:
+    /// the root of the data volume where the guest mounted it there, else
+    /// /workspace in the VM's clone of the rootfs
+    pub async fn workspace_image(
+        &self,
+        task_id: Uuid,
+        on_data_volume: bool,
+    ) -> Option<(DiskImage, &'static str)> {
+        if on_data_volume {
+            self.volumes.find(task_id).await.map(|image| (image, "."))
+        } else {
+            self.rootfs.find(task_id).await.map(|image| (image, "workspace"))
+        }
+    }
+
+    pub async fn stop_vm(&self, vm_id: &str) -> ApiResult<()> {
+        // Remove from tracking
+        let vm_info = self.vms.write().await.remove(vm_id);
+
+        if let Some(info) = vm_info {
+            self.destroy_vm(info).await;
         }
 
         Ok(())
     }
 
+    /// Stop a VM of the warm pool left behind by an earlier run of the API,
+    /// and remove its TAP device, files and disks
+    pub async fn discard_vm(&self, id: Uuid) {
+        let vm_id = format!("vm-{}", id);
+        let pid_file = PathBuf::from(&self.config.qemu.pids_dir).join(format!("{}.pid", vm_id));
+        let info = VmInfo {
+            task_id: id,
+            cid: 0,
+            qmp_socket_path: PathBuf::from(&self.config.qemu.sockets_dir)
+                .join(format!("{}.qmp", vm_id)),
+            volume_path: PathBuf::from(&self.config.qemu.volumes_dir)
+                .join(format!("{}.ext4", id)),
+            log_path: PathBuf::from(&self.config.qemu.logs_dir).join(format!("{}.log", vm_id)),
+            pid: self.read_pid_file(&pid_file).await.ok(),
+            pid_file,
+            tap_name: tap_name(id),
+            ip_address: String::new(),
+            gateway: String::new(),
+            vm_id,
+        };
+        self.destroy_vm(info).await;
+    }
+
+    /// Shut the VM down and remove its TAP device, files and disks
+    async fn destroy_vm(&self, info: VmInfo) {
+        // Try graceful shutdown via QMP first
+        let qmp = QmpClient::new(info.qmp_socket_path.clone());
+        if let Err(e) = qmp.quit().await {
+            tracing::warn!("QMP quit failed: {}, falling back to SIGTERM", e);
+
+            // Fallback: kill by PID
+            if let Some(pid) = info.pid {
+                let _ = Command::new("kill")
+                    .arg("-TERM")
+                    .arg(pid.to_string())
+                    .output()
+                    .await;
+
+                // Wait a bit and force kill if needed
+                tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                let _ = Command::new("kill")
+                    .arg("-KILL")
+                    .arg(pid.to_string())
+                    .output()
+                    .await;
+            }
+        }
+
+        // Delete TAP device
+        let _ = self.remove_egress(&info.tap_name).await;
+        let _ = self.delete_tap(&info.tap_name).await;
+
+        // Cleanup files
+        let _ = tokio::fs::remove_file(&info.qmp_socket_path).await;
+        let _ = tokio::fs::remove_file(&info.log_path).await;
+        let _ = tokio::fs::remove_file(&info.pid_file).await;
+
+        // Also remove the disks
+        self.volumes.remove(info.task_id).await;
+        self.rootfs.remove(info.task_id).await;
+    }
+
     pub async fn get_vm_info(&self, vm_id: &str) -> Option<VmInfo> {
         self.vms.read().await.get(vm_id).cloned()
     }
 
+    /// Make sure new VMs don't reuse the CID of a VM that outlived an API restart
+    pub fn reserve_cid(&self, cid: u32) {
+        self.next_cid.fetch_max(cid + 1, Ordering::SeqCst);
+    }
+
     /// Get the CID for connecting to the VM via vsock
     #[allow(dead_code)]
     pub fn get_vm_cid(&self, _vm_id: &str) -> Option<u32> {
@@ -650,6 +1326,59 @@ impl VmManager {
         None
     }
 
+    /// Traffic of the task's VM under `limits`, measured over a short interval.
+    /// None when the VM has no network interface.
+    pub async fn network_stats(
+        &self,
+        task_id: Uuid,
+        limits: NetworkLimits,
+    ) -> ApiResult<Option<NetworkStats>> {
+        let tap_name = tap_name(task_id);
+        let Some((before_up, before_down)) = traffic::read_counters(&tap_name).await else {
+            return Ok(None);
+        };
+        let started = std::time::Instant::now();
+        tokio::time::sleep(STATS_SAMPLE_INTERVAL).await;
+        let Some((mut upload, mut download)) = traffic::read_counters(&tap_name).await else {
+            return Ok(None);
+        };
+
+        let secs = started.elapsed().as_secs_f64();
+        let kbps = |before: u64, after: u64| (after.saturating_sub(before) * 8) as f64 / 1000.0 / secs;
+        upload.rate_kbps = kbps(before_up.bytes, upload.bytes);
+        download.rate_kbps = kbps(before_down.bytes, download.bytes);
+
+        let output = Command::new("tc")
+            .args(["-s", "-j", "qdisc", "show", "dev", &tap_name])
+            .output()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to run tc: {}", e)))?;
+        let (upload_dropped, download_dropped) =
+            traffic::parse_qdisc_drops(&String::from_utf8_lossy(&output.stdout));
+        upload.dropped = upload_dropped;
+        download.dropped = download_dropped;
+
+        Ok(Some(NetworkStats {
+            limits,
+            upload,
+            download,
+        }))
+    }
+
+    /// Disk space the task's VM takes beyond the shared base images
+    pub async fn disk_stats(&self, task_id: Uuid) -> DiskStats {
+        let own_bytes = |image: Option<DiskImage>| async move {
+            match image {
+                Some(image) => rootfs::own_bytes(&image.path).await,
+                None => 0,
+            }
+        };
+        DiskStats {
+            rootfs_bytes: own_bytes(self.rootfs.find(task_id).await).await,
+            data_bytes: own_bytes(self.volumes.find(task_id).await).await,
+        }
+    }
+
     /// Get VM CID from task_id (async version)
     pub async fn get_cid_for_task(&self, task_id: Uuid) -> Option<u32> {
         let vm_id = format!("vm-{}", task_id);
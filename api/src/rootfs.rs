@@ -0,0 +1,431 @@
+//! Root filesystems of the VMs. Every VM boots from an image of its own,
+//! made from `rootfs_path` without copying it where possible: a reflink clone
+//! shares the base's blocks on filesystems that support it (btrfs, XFS), and
+//! elsewhere a qcow2 overlay holds only what the VM writes on top of a
+//! read-only snapshot of the base. The snapshot is taken once per version of
+//! `rootfs_path`, so rebuilding the rootfs never changes what existing
+//! overlays see, and is deleted once no overlay uses it.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::{ImageClone, QemuConfig};
+use crate::error::{ApiError, ApiResult};
+use crate::snapshot;
+
+/// Disk image formats, as QEMU's `-drive format=` names them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+impl ImageFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Raw => "ext4",
+            Self::Qcow2 => "qcow2",
+        }
+    }
+}
+
+/// A VM's disk image
+#[derive(Debug, Clone)]
+pub struct DiskImage {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+}
+
+/// Makes and removes the VMs' images
+pub struct RootfsStore {
+    config: QemuConfig,
+    /// Held while a snapshot of the base is taken or pruned
+    base_lock: Mutex<()>,
+}
+
+impl RootfsStore {
+    pub fn new(config: QemuConfig) -> Self {
+        Self {
+            config,
+            base_lock: Mutex::new(()),
+        }
+    }
+
+    fn image_path(&self, task_id: Uuid, format: ImageFormat) -> PathBuf {
+        PathBuf::from(&self.config.volumes_dir).join(format!(
+            "{}-rootfs.{}",
+            task_id,
+            format.extension()
+        ))
+    }
+
+    /// The task's image, however it was made
+    pub async fn find(&self, task_id: Uuid) -> Option<DiskImage> {
+        for format in [ImageFormat::Qcow2, ImageFormat::Raw] {
+            let path = self.image_path(task_id, format);
+            if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                return Some(DiskImage { path, format });
+            }
+        }
+        None
+    }
+
+    /// Make the task's image according to `rootfs_clone`
+    pub async fn create(&self, task_id: Uuid) -> ApiResult<DiskImage> {
+        let base = Path::new(&self.config.rootfs_path);
+        let raw = self.image_path(task_id, ImageFormat::Raw);
+        // Like the copy it replaces, a new image starts from the base
+        for format in [ImageFormat::Qcow2, ImageFormat::Raw] {
+            let _ = tokio::fs::remove_file(self.image_path(task_id, format)).await;
+        }
+
+        match self.config.rootfs_clone {
+            ImageClone::Auto => match reflink(base, &raw).await {
+                Ok(()) => Ok(DiskImage {
+                    path: raw,
+                    format: ImageFormat::Raw,
+                }),
+                Err(e) if is_unsupported(&e) => self.create_overlay(task_id).await,
+                Err(e) => Err(ApiError::VmError(format!("Failed to clone rootfs: {}", e))),
+            },
+            ImageClone::Reflink => {
+                reflink(base, &raw)
+                    .await
+                    .map_err(|e| ApiError::VmError(format!("Failed to clone rootfs: {}", e)))?;
+                Ok(DiskImage {
+                    path: raw,
+                    format: ImageFormat::Raw,
+                })
+            }
+            ImageClone::Qcow2 => self.create_overlay(task_id).await,
+            ImageClone::Copy => {
+                tokio::fs::copy(base, &raw)
+                    .await
+                    .map_err(|e| ApiError::VmError(format!("Failed to copy rootfs: {}", e)))?;
+                Ok(DiskImage {
+                    path: raw,
+                    format: ImageFormat::Raw,
+                })
+            }
+        }
+    }
+
+    /// Make the task's image from the rootfs of a golden snapshot
+    pub async fn create_from(&self, task_id: Uuid, golden: &DiskImage) -> ApiResult<DiskImage> {
+        for format in [ImageFormat::Qcow2, ImageFormat::Raw] {
+            let _ = tokio::fs::remove_file(self.image_path(task_id, format)).await;
+        }
+        snapshot::clone_disk(
+            golden,
+            &self.image_path(task_id, ImageFormat::Raw),
+            &self.image_path(task_id, ImageFormat::Qcow2),
+        )
+        .await
+    }
+
+    /// Give the image of `from` to `to`, e.g. when a VM of the warm pool is
+    /// handed to a task. The VM keeps using it.
+    pub async fn rename(&self, from: Uuid, to: Uuid) -> ApiResult<Option<DiskImage>> {
+        let Some(image) = self.find(from).await else {
+            return Ok(None);
+        };
+        let path = self.image_path(to, image.format);
+        tokio::fs::rename(&image.path, &path)
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to rename rootfs: {}", e)))?;
+        Ok(Some(DiskImage {
+            path,
+            format: image.format,
+        }))
+    }
+
+    /// Delete the task's image, whatever its format, and the snapshot of an
+    /// older base that only it was using
+    pub async fn remove(&self, task_id: Uuid) {
+        let _ = tokio::fs::remove_file(self.image_path(task_id, ImageFormat::Raw)).await;
+        let overlay = self.image_path(task_id, ImageFormat::Qcow2);
+        if tokio::fs::remove_file(&overlay).await.is_ok() {
+            let _guard = self.base_lock.lock().await;
+            if let Ok(current) = self.snapshot_path().await {
+                self.prune_snapshots(&current).await;
+            }
+        }
+    }
+
+    /// Where the snapshot of the current `rootfs_path` is kept
+    async fn snapshot_path(&self) -> io::Result<PathBuf> {
+        let meta = tokio::fs::metadata(&self.config.rootfs_path).await?;
+        Ok(PathBuf::from(&self.config.base_images_dir).join(format!(
+            "rootfs-{}-{}.ext4",
+            meta.mtime(),
+            meta.size()
+        )))
+    }
+
+    /// qcow2 image backed by the current snapshot of the base
+    async fn create_overlay(&self, task_id: Uuid) -> ApiResult<DiskImage> {
+        let path = self.image_path(task_id, ImageFormat::Qcow2);
+        // Taking the lock keeps a concurrent prune from deleting the base
+        // before the overlay that uses it exists
+        let _guard = self.base_lock.lock().await;
+        let base = self.base_snapshot().await?;
+        create_overlay_image(&base, ImageFormat::Raw, &path).await?;
+
+        Ok(DiskImage {
+            path,
+            format: ImageFormat::Qcow2,
+        })
+    }
+
+    /// Read-only snapshot of the current `rootfs_path`, taken if it is new.
+    /// Called with `base_lock` held.
+    async fn base_snapshot(&self) -> ApiResult<PathBuf> {
+        let base = Path::new(&self.config.rootfs_path);
+        let snapshot = self
+            .snapshot_path()
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to read {}: {}", base.display(), e)))?;
+        if tokio::fs::try_exists(&snapshot).await.unwrap_or(false) {
+            return Ok(snapshot);
+        }
+
+        tracing::info!("Taking a snapshot of {} for overlays", base.display());
+        tokio::fs::create_dir_all(&self.config.base_images_dir)
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to create base images dir: {}", e)))?;
+        let partial = snapshot.with_extension("partial");
+        let _ = tokio::fs::remove_file(&partial).await;
+        let copied = match reflink(base, &partial).await {
+            Err(e) if is_unsupported(&e) => tokio::fs::copy(base, &partial).await.map(|_| ()),
+            result => result,
+        };
+        let finished = match copied {
+            Ok(()) => {
+                let _ =
+                    tokio::fs::set_permissions(&partial, PermissionsExt::from_mode(0o444)).await;
+                tokio::fs::rename(&partial, &snapshot).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = finished {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(ApiError::VmError(format!(
+                "Failed to snapshot rootfs: {}",
+                e
+            )));
+        }
+
+        self.prune_snapshots(&snapshot).await;
+        Ok(snapshot)
+    }
+
+    /// Delete snapshots other than `current` that no overlay is backed by,
+    /// counting the disks of golden VM snapshots (see `snapshot`).
+    /// Called with `base_lock` held.
+    async fn prune_snapshots(&self, current: &Path) {
+        let mut in_use = vec![current.to_path_buf()];
+        let mut dirs = vec![PathBuf::from(&self.config.volumes_dir)];
+        if let Ok(mut entries) = tokio::fs::read_dir(&self.config.snapshots_dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                dirs.push(entry.path());
+            }
+        }
+        for dir in dirs {
+            match backing_files(&dir).await {
+                Some(backing) => in_use.extend(backing),
+                // Keep everything rather than guess
+                None => return,
+            }
+        }
+
+        let Ok(mut entries) = tokio::fs::read_dir(&self.config.base_images_dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "ext4") && !in_use.contains(&path) {
+                tracing::info!("Removing unused rootfs snapshot {}", path.display());
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+        }
+    }
+}
+
+/// qcow2 image at `path` on top of the image `backing`
+pub async fn create_overlay_image(
+    backing: &Path,
+    backing_format: ImageFormat,
+    path: &Path,
+) -> ApiResult<()> {
+    let output = Command::new("qemu-img")
+        .args(["create", "-q", "-f", "qcow2", "-F"])
+        .arg(backing_format.as_str())
+        .arg("-b")
+        .arg(backing)
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| ApiError::VmError(format!("Failed to run qemu-img: {}", e)))?;
+    if !output.status.success() {
+        return Err(ApiError::VmError(format!(
+            "Failed to create overlay {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Backing files of the qcow2 overlays in `dir`, or None if one can't be read
+pub async fn backing_files(dir: &Path) -> Option<Vec<PathBuf>> {
+    let mut backing = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return Some(backing);
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "qcow2") {
+            backing.push(backing_file(&path).await?);
+        }
+    }
+    Some(backing)
+}
+
+/// Backing file of a qcow2 overlay, read even while a VM has it open
+async fn backing_file(overlay: &Path) -> Option<PathBuf> {
+    let output = Command::new("qemu-img")
+        .args(["info", "--output=json", "--force-share"])
+        .arg(overlay)
+        .output()
+        .await
+        .ok()
+        .filter(|output| output.status.success())?;
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    info.get("full-backing-filename")
+        .or_else(|| info.get("backing-filename"))
+        .and_then(|name| name.as_str())
+        .map(PathBuf::from)
+}
+
+/// Clone `src` to a new file `dst` that shares its blocks (FICLONE)
+pub async fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let source = std::fs::File::open(&src)?;
+        let target = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&dst)?;
+        // SAFETY: FICLONE only reads the source descriptor we pass
+        let rc = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            drop(target);
+            let _ = std::fs::remove_file(&dst);
+            return Err(e);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Errors of a filesystem that cannot reflink, as opposed to real failures
+pub fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+    )
+}
+
+const FICLONE: libc::c_ulong = 0x4004_9409;
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+const FIEMAP_BATCH: usize = 128;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// Bytes of the file stored on disk for it alone: allocated extents not
+/// shared with another file, so a reflink clone or a qcow2 overlay counts
+/// only what the VM changed. Falls back to all allocated blocks where the
+/// filesystem cannot map extents.
+pub async fn own_bytes(path: &Path) -> u64 {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path).ok()?;
+        let allocated = file.metadata().ok()?.blocks() * 512;
+        Some(unshared_bytes(&file).unwrap_or(allocated))
+    })
+    .await
+    .ok()
+    .flatten()
+    .unwrap_or(0)
+}
+
+fn unshared_bytes(file: &std::fs::File) -> io::Result<u64> {
+    let mut total = 0;
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: 0,
+            mapped_extents: 0,
+            extent_count: FIEMAP_BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); FIEMAP_BATCH],
+        };
+        // SAFETY: the kernel writes at most extent_count extents into `map`
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map as *mut Fiemap) };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let extents = &map.extents[..map.mapped_extents as usize];
+        for extent in extents {
+            if extent.flags & FIEMAP_EXTENT_SHARED == 0 {
+                total += extent.length;
+            }
+        }
+        match extents.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => return Ok(total),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Golden VM snapshots. Booting a VM costs seconds of kernel and systemd
+//! start-up, so for each version of `rootfs_path` and each VM shape a VM is
+//! booted once, left until its sidecar listens, and saved: its memory and
+//! device state (QEMU migration to a file) and its two disks. VMs are then
+//! restored from the snapshot with clones of those disks (a reflink clone,
+//! else a qcow2 overlay) and personalized over vsock. Snapshots of an older
+//! rootfs are deleted once no overlay is backed by their disks.
+
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::QemuConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::qemu::VmShape;
+use crate::rootfs::{self, DiskImage, ImageFormat};
+
+/// How the snapshotted VM was started; a restore must match it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub kernel_cmdline: String,
+    pub mac_address: String,
+    pub rootfs_format: String,
+    pub volume_format: String,
+}
+
+/// A snapshot ready to restore VMs from
+#[derive(Debug, Clone)]
+pub struct Golden {
+    /// Migration stream of the VM, paused
+    pub state: PathBuf,
+    pub rootfs: DiskImage,
+    pub volume: DiskImage,
+    pub manifest: Manifest,
+}
+
+/// Keeps the golden snapshots in `snapshots_dir`, one directory each
+pub struct SnapshotStore {
+    config: QemuConfig,
+    /// Held while a snapshot is taken, so each is taken once
+    lock: Mutex<()>,
+    /// Snapshots that could not be taken, not to be tried again until the
+    /// rootfs changes or the API restarts
+    failed: std::sync::Mutex<HashSet<PathBuf>>,
+}
+
+impl SnapshotStore {
+    pub fn new(config: QemuConfig) -> Self {
+        Self {
+            config,
+            lock: Mutex::new(()),
+            failed: std::sync::Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Guard to hold from looking for a snapshot until it is saved
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+
+    /// Name of the current rootfs version, like the base snapshots of `rootfs`
+    async fn rootfs_version(&self) -> ApiResult<String> {
+        let meta = tokio::fs::metadata(&self.config.rootfs_path)
+            .await
+            .map_err(|e| {
+                ApiError::VmError(format!("Failed to read {}: {}", self.config.rootfs_path, e))
+            })?;
+        Ok(format!("rootfs-{}-{}", meta.mtime(), meta.size()))
+    }
+
+    async fn dir(&self, shape: VmShape) -> ApiResult<PathBuf> {
+        Ok(PathBuf::from(&self.config.snapshots_dir).join(format!(
+            "{}-{}",
+            self.rootfs_version().await?,
+            shape
+        )))
+    }
+
+    /// The snapshot of `shape` for the current rootfs, if one was taken
+    pub async fn find(&self, shape: VmShape) -> Option<Golden> {
+        let dir = self.dir(shape).await.ok()?;
+        let manifest = tokio::fs::read(dir.join("manifest.json")).await.ok()?;
+        let manifest: Manifest = serde_json::from_slice(&manifest).ok()?;
+        let disk = |name: &str, format: &str| {
+            let format = match format {
+                "qcow2" => ImageFormat::Qcow2,
+                _ => ImageFormat::Raw,
+            };
+            DiskImage {
+                path: dir.join(format!("{}.{}", name, format.extension())),
+                format,
+            }
+        };
+        Some(Golden {
+            state: dir.join("state"),
+            rootfs: disk("rootfs", &manifest.rootfs_format),
+            volume: disk("volume", &manifest.volume_format),
+            manifest,
+        })
+    }
+
+    pub async fn has_failed(&self, shape: VmShape) -> bool {
+        match self.dir(shape).await {
+            Ok(dir) => self.failed.lock().unwrap().contains(&dir),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn mark_failed(&self, shape: VmShape) {
+        if let Ok(dir) = self.dir(shape).await {
+            self.failed.lock().unwrap().insert(dir);
+        }
+    }
+
+    /// Where the migration stream of a new snapshot of `shape` is written,
+    /// in a directory of its own until `save` moves it into place
+    pub async fn partial_dir(&self, shape: VmShape) -> ApiResult<PathBuf> {
+        let dir = self.dir(shape).await?.with_extension("partial");
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to create snapshot dir: {}", e)))?;
+        Ok(dir)
+    }
+
+    /// Turn `partial` (holding `state`) into the snapshot of `shape`, taking
+    /// over the disks of the VM it was taken from. That VM must not write to
+    /// them any more.
+    pub async fn save(
+        &self,
+        shape: VmShape,
+        partial: &Path,
+        rootfs: &DiskImage,
+        volume: &DiskImage,
+        manifest: &Manifest,
+    ) -> ApiResult<Golden> {
+        let failed =
+            |e: std::io::Error| ApiError::VmError(format!("Failed to save snapshot: {}", e));
+        let result = async {
+            for (disk, name) in [(rootfs, "rootfs"), (volume, "volume")] {
+                let path = partial.join(format!("{}.{}", name, disk.format.extension()));
+                tokio::fs::rename(&disk.path, &path).await?;
+            }
+            let manifest = serde_json::to_vec_pretty(manifest).map_err(std::io::Error::other)?;
+            tokio::fs::write(partial.join("manifest.json"), manifest).await?;
+            tokio::fs::rename(
+                partial,
+                self.dir(shape).await.map_err(std::io::Error::other)?,
+            )
+            .await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_dir_all(partial).await;
+            return Err(failed(e));
+        }
+
+        self.prune().await;
+        self.find(shape)
+            .await
+            .ok_or_else(|| ApiError::VmError("Saved snapshot cannot be read".to_string()))
+    }
+
+    /// Delete the snapshot of `shape`, e.g. after a restore from it failed
+    pub async fn discard(&self, shape: VmShape) {
+        if let Ok(dir) = self.dir(shape).await {
+            tracing::warn!("Discarding golden snapshot {}", dir.display());
+            let _ = tokio::fs::remove_dir_all(dir).await;
+        }
+    }
+
+    /// Delete snapshots of older rootfs versions that no overlay in
+    /// `volumes_dir` is backed by
+    async fn prune(&self) {
+        let Ok(current) = self.rootfs_version().await else {
+            return;
+        };
+        let Some(in_use) = rootfs::backing_files(Path::new(&self.config.volumes_dir)).await else {
+            return;
+        };
+        let Ok(mut entries) = tokio::fs::read_dir(&self.config.snapshots_dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with(&format!("{}-", current))
+                || in_use.iter().any(|backing| backing.starts_with(&path))
+            {
+                continue;
+            }
+            tracing::info!("Removing golden snapshot {}", path.display());
+            let _ = tokio::fs::remove_dir_all(&path).await;
+        }
+    }
+}
+
+/// Clone a disk of a snapshot for a restored VM: a reflink clone in the
+/// disk's format, at `raw` or `overlay`, else a qcow2 overlay at `overlay`
+pub async fn clone_disk(golden: &DiskImage, raw: &Path, overlay: &Path) -> ApiResult<DiskImage> {
+    let path = match golden.format {
+        ImageFormat::Raw => raw,
+        ImageFormat::Qcow2 => overlay,
+    };
+    match rootfs::reflink(&golden.path, path).await {
+        Ok(()) => Ok(DiskImage {
+            path: path.to_path_buf(),
+            format: golden.format,
+        }),
+        Err(e) if rootfs::is_unsupported(&e) => {
+            rootfs::create_overlay_image(&golden.path, golden.format, overlay).await?;
+            Ok(DiskImage {
+                path: overlay.to_path_buf(),
+                format: ImageFormat::Qcow2,
+            })
+        }
+        Err(e) => Err(ApiError::VmError(format!(
+            "Failed to clone {}: {}",
+            golden.path.display(),
+            e
+        ))),
+    }
+}
@@ -0,0 +1,223 @@
+//! Bandwidth and connection rate limits of the VMs, applied with tc on their
+//! TAP devices, and the traffic counters of those devices.
+//!
+//! What the VM sends arrives on the TAP's ingress, where it can only be
+//! policed: a flower filter drops TCP SYNs over the connection rate, then a
+//! matchall filter drops packets over the upload rate. What the VM receives
+//! leaves through the TAP's root qdisc, an HTB class at the download rate
+//! with fq_codel under it, so it is shaped rather than dropped.
+
+use std::path::Path;
+
+use crate::config::NetworkConfig;
+use crate::models::{NetworkLimits, TaskConfig, TrafficStats};
+
+/// Limits of a task: its own, else the API's defaults
+pub fn limits_for(task_config: Option<&TaskConfig>, network: &NetworkConfig) -> NetworkLimits {
+    NetworkLimits {
+        upload_kbps: task_config
+            .and_then(|c| c.upload_kbps)
+            .unwrap_or(network.default_upload_kbps),
+        download_kbps: task_config
+            .and_then(|c| c.download_kbps)
+            .unwrap_or(network.default_download_kbps),
+        connections_per_sec: task_config
+            .and_then(|c| c.connections_per_sec)
+            .unwrap_or(network.default_connections_per_sec),
+    }
+}
+
+/// tc invocations that apply `limits` to `tap_name`, in order
+pub fn tc_commands(tap_name: &str, limits: &NetworkLimits) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    let tc = |args: &str| -> Vec<String> {
+        args.replace("{tap}", tap_name)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect()
+    };
+
+    if limits.download_kbps > 0 {
+        let rate = limits.download_kbps;
+        commands.push(tc("qdisc replace dev {tap} root handle 1: htb default 10"));
+        commands.push(tc(&format!(
+            "class replace dev {{tap}} parent 1: classid 1:10 htb rate {}kbit ceil {}kbit",
+            rate, rate
+        )));
+        commands.push(tc("qdisc replace dev {tap} parent 1:10 handle 10: fq_codel"));
+    }
+
+    if limits.upload_kbps > 0 || limits.connections_per_sec > 0 {
+        commands.push(tc("qdisc replace dev {tap} handle ffff: ingress"));
+    }
+    if limits.connections_per_sec > 0 {
+        let rate = limits.connections_per_sec;
+        // SYN without ACK: the first packet of every outgoing TCP connection
+        commands.push(tc(&format!(
+            "filter add dev {{tap}} parent ffff: protocol ip prio 1 flower ip_proto tcp \
+             tcp_flags 0x2/0x12 action police pkts_rate {} pkts_burst {} \
+             conform-exceed drop/continue",
+            rate,
+            rate.saturating_mul(2)
+        )));
+    }
+    if limits.upload_kbps > 0 {
+        let rate = limits.upload_kbps;
+        commands.push(tc(&format!(
+            "filter add dev {{tap}} parent ffff: protocol all prio 2 matchall \
+             action police rate {}kbit burst {} conform-exceed drop/ok",
+            rate,
+            police_burst(rate)
+        )));
+    }
+
+    commands
+}
+
+/// Bucket size of an upload policer in bytes: 100ms at the rate, but no
+/// less than a few full-size frames so single packets always fit
+fn police_burst(rate_kbps: u32) -> u64 {
+    (u64::from(rate_kbps) * 1000 / 8 / 10).max(16 * 1024)
+}
+
+/// Byte and packet totals of the TAP device, seen from the VM:
+/// (upload, download)
+pub async fn read_counters(tap_name: &str) -> Option<(TrafficStats, TrafficStats)> {
+    let dir = Path::new("/sys/class/net").join(tap_name).join("statistics");
+    let read = |name: &'static str| {
+        let path = dir.join(name);
+        async move {
+            tokio::fs::read_to_string(path)
+                .await
+                .ok()?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        }
+    };
+
+    // The host receives what the VM uploads
+    let upload = TrafficStats {
+        bytes: read("rx_bytes").await?,
+        packets: read("rx_packets").await?,
+        ..Default::default()
+    };
+    let download = TrafficStats {
+        bytes: read("tx_bytes").await?,
+        packets: read("tx_packets").await?,
+        ..Default::default()
+    };
+    Some((upload, download))
+}
+
+/// Packets dropped by the limits, from `tc -s -j qdisc show`: (upload, download)
+pub fn parse_qdisc_drops(json: &str) -> (u64, u64) {
+    let Ok(serde_json::Value::Array(qdiscs)) = serde_json::from_str(json) else {
+        return (0, 0);
+    };
+
+    let mut upload = 0;
+    let mut download = 0;
+    for qdisc in qdiscs {
+        let drops = qdisc.get("drops").and_then(|d| d.as_u64()).unwrap_or(0);
+        let kind = qdisc.get("kind").and_then(|k| k.as_str());
+        let handle = qdisc.get("handle").and_then(|h| h.as_str());
+        match (kind, handle) {
+            // Drops of the policers are counted on the ingress qdisc
+            (Some("ingress"), _) => upload += drops,
+            // The download shaper drops in its fq_codel, not in HTB
+            (Some("fq_codel"), Some("10:")) => download += drops,
+            _ => {}
+        }
+    }
+    (upload, download)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(upload_kbps: u32, download_kbps: u32, connections_per_sec: u32) -> NetworkLimits {
+        NetworkLimits {
+            upload_kbps,
+            download_kbps,
+            connections_per_sec,
+        }
+    }
+
+    fn joined(commands: Vec<Vec<String>>) -> Vec<String> {
+        commands.into_iter().map(|args| args.join(" ")).collect()
+    }
+
+    #[test]
+    fn test_limits_for() {
+        let network: NetworkConfig = serde_json::from_value(serde_json::json!({
+            "default_upload_kbps": 20000,
+            "default_download_kbps": 100000,
+            "default_connections_per_sec": 50
+        }))
+        .unwrap();
+        assert_eq!(limits_for(None, &network), limits(20000, 100000, 50));
+
+        let task: TaskConfig = serde_json::from_value(serde_json::json!({
+            "upload_kbps": 0,
+            "connections_per_sec": 5
+        }))
+        .unwrap();
+        assert_eq!(limits_for(Some(&task), &network), limits(0, 100000, 5));
+    }
+
+    #[test]
+    fn test_tc_commands() {
+        assert_eq!(
+            joined(tc_commands("lia-tap-3", &limits(20000, 100000, 50))),
+            [
+                "qdisc replace dev lia-tap-3 root handle 1: htb default 10",
+                "class replace dev lia-tap-3 parent 1: classid 1:10 htb rate 100000kbit ceil 100000kbit",
+                "qdisc replace dev lia-tap-3 parent 1:10 handle 10: fq_codel",
+                "qdisc replace dev lia-tap-3 handle ffff: ingress",
+                "filter add dev lia-tap-3 parent ffff: protocol ip prio 1 flower ip_proto tcp \
+                 tcp_flags 0x2/0x12 action police pkts_rate 50 pkts_burst 100 conform-exceed drop/continue",
+                "filter add dev lia-tap-3 parent ffff: protocol all prio 2 matchall action police \
+                 rate 20000kbit burst 250000 conform-exceed drop/ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tc_commands_skip_unset_limits() {
+        assert!(tc_commands("lia-tap-3", &limits(0, 0, 0)).is_empty());
+
+        let download_only = joined(tc_commands("lia-tap-3", &limits(0, 8000, 0)));
+        assert_eq!(download_only.len(), 3);
+        assert!(download_only.iter().all(|c| !c.contains("ingress")));
+
+        // A slow upload still lets full-size frames through
+        assert_eq!(
+            joined(tc_commands("lia-tap-3", &limits(64, 0, 0))),
+            [
+                "qdisc replace dev lia-tap-3 handle ffff: ingress",
+                "filter add dev lia-tap-3 parent ffff: protocol all prio 2 matchall action police \
+                 rate 64kbit burst 16384 conform-exceed drop/ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_qdisc_drops() {
+        // `tc -s -j qdisc show dev lia-tap-3` from iproute2 6.1
+        let output = r#"[{"kind":"htb","handle":"1:","root":true,"refcnt":2,"options":{"r2q":10,"default":"0x10","direct_packets_stat":0,"direct_qlen":1000},"bytes":734002113,"packets":498112,"drops":0,"overlimits":1841,"requeues":0,"backlog":0,"qlen":0},{"kind":"fq_codel","handle":"10:","parent":"1:10","options":{"limit":10240,"flows":1024,"quantum":1514,"target":4999,"interval":99999,"memory_limit":33554432,"ecn":true,"drop_batch":64},"bytes":734002113,"packets":498112,"drops":17,"overlimits":0,"requeues":0,"backlog":0,"qlen":0,"maxpacket":1514,"drop_overlimit":0,"new_flow_count":312,"ecn_mark":0,"new_flows_len":0,"old_flows_len":1},{"kind":"ingress","handle":"ffff:","parent":"ffff:fff1","options":{},"bytes":18233412,"packets":20311,"drops":42,"overlimits":0,"requeues":0,"backlog":0,"qlen":0}]"#;
+        assert_eq!(parse_qdisc_drops(output), (42, 17));
+
+        // Without limits the TAP has its default qdisc only
+        let output = r#"[{"kind":"fq_codel","handle":"0:","root":true,"refcnt":2,"options":{"limit":10240,"flows":1024,"quantum":1514,"target":4999,"interval":99999,"memory_limit":33554432,"ecn":true,"drop_batch":64},"bytes":5120,"packets":40,"drops":3,"overlimits":0,"requeues":0,"backlog":0,"qlen":0}]"#;
+        assert_eq!(parse_qdisc_drops(output), (0, 0));
+
+        assert_eq!(parse_qdisc_drops("[]"), (0, 0));
+        assert_eq!(parse_qdisc_drops(""), (0, 0));
+        assert_eq!(
+            parse_qdisc_drops("Cannot find device \"lia-tap-3\""),
+            (0, 0)
+        );
+    }
+}
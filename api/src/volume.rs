@@ -0,0 +1,199 @@
+//! Data volumes of the VMs. Formatting a fresh sparse volume for every VM
+//! adds to its boot time, so each size is formatted once into a read-only
+//! template in `volume_templates_dir`, which VMs get a reflink clone or a
+//! qcow2 overlay of like their root filesystems (see `rootfs`). Templates are
+//! never rewritten, so overlays stay valid for as long as they exist.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::{ImageClone, QemuConfig};
+use crate::error::{ApiError, ApiResult};
+use crate::rootfs::{self, DiskImage, ImageFormat};
+use crate::snapshot;
+
+/// Makes and removes the VMs' data volumes
+pub struct VolumeStore {
+    config: QemuConfig,
+    /// Held while a template is formatted
+    template_lock: Mutex<()>,
+}
+
+impl VolumeStore {
+    pub fn new(config: QemuConfig) -> Self {
+        Self {
+            config,
+            template_lock: Mutex::new(()),
+        }
+    }
+
+    fn image_path(&self, task_id: Uuid, format: ImageFormat) -> PathBuf {
+        PathBuf::from(&self.config.volumes_dir).join(format!("{}.{}", task_id, format.extension()))
+    }
+
+    /// The task's volume, however it was made
+    pub async fn find(&self, task_id: Uuid) -> Option<DiskImage> {
+        for format in [ImageFormat::Qcow2, ImageFormat::Raw] {
+            let path = self.image_path(task_id, format);
+            if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                return Some(DiskImage { path, format });
+            }
+        }
+        None
+    }
+
+    /// Make the task's ext4 volume of `size_gb` according to `volume_clone`
+    pub async fn create(&self, task_id: Uuid, size_gb: u32) -> ApiResult<DiskImage> {
+        let raw = self.image_path(task_id, ImageFormat::Raw);
+        self.remove(task_id).await;
+
+        let clone_error = |e| ApiError::VmError(format!("Failed to clone volume: {}", e));
+        match self.config.volume_clone {
+            ImageClone::Auto => {
+                let template = self.template(size_gb).await?;
+                match rootfs::reflink(&template, &raw).await {
+                    Ok(()) => Ok(DiskImage {
+                        path: raw,
+                        format: ImageFormat::Raw,
+                    }),
+                    Err(e) if rootfs::is_unsupported(&e) => {
+                        self.create_overlay(task_id, &template).await
+                    }
+                    Err(e) => Err(clone_error(e)),
+                }
+            }
+            ImageClone::Reflink => {
+                let template = self.template(size_gb).await?;
+                rootfs::reflink(&template, &raw)
+                    .await
+                    .map_err(clone_error)?;
+                Ok(DiskImage {
+                    path: raw,
+                    format: ImageFormat::Raw,
+                })
+            }
+            ImageClone::Qcow2 => {
+                let template = self.template(size_gb).await?;
+                self.create_overlay(task_id, &template).await
+            }
+            ImageClone::Copy => {
+                format_volume(&raw, size_gb).await?;
+                Ok(DiskImage {
+                    path: raw,
+                    format: ImageFormat::Raw,
+                })
+            }
+        }
+    }
+
+    /// Make the task's volume from the volume of a golden snapshot
+    pub async fn create_from(&self, task_id: Uuid, golden: &DiskImage) -> ApiResult<DiskImage> {
+        self.remove(task_id).await;
+        snapshot::clone_disk(
+            golden,
+            &self.image_path(task_id, ImageFormat::Raw),
+            &self.image_path(task_id, ImageFormat::Qcow2),
+        )
+        .await
+    }
+
+    /// Give the volume of `from` to `to` (see `RootfsStore::rename`)
+    pub async fn rename(&self, from: Uuid, to: Uuid) -> ApiResult<Option<DiskImage>> {
+        let Some(image) = self.find(from).await else {
+            return Ok(None);
+        };
+        let path = self.image_path(to, image.format);
+        tokio::fs::rename(&image.path, &path)
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to rename volume: {}", e)))?;
+        Ok(Some(DiskImage {
+            path,
+            format: image.format,
+        }))
+    }
+
+    /// Delete the task's volume, whatever its format
+    pub async fn remove(&self, task_id: Uuid) {
+        for format in [ImageFormat::Qcow2, ImageFormat::Raw] {
+            let _ = tokio::fs::remove_file(self.image_path(task_id, format)).await;
+        }
+    }
+
+    async fn create_overlay(&self, task_id: Uuid, template: &Path) -> ApiResult<DiskImage> {
+        let path = self.image_path(task_id, ImageFormat::Qcow2);
+        rootfs::create_overlay_image(template, ImageFormat::Raw, &path).await?;
+        Ok(DiskImage {
+            path,
+            format: ImageFormat::Qcow2,
+        })
+    }
+
+    /// Read-only template of a volume of `size_gb`, formatted if it is new
+    async fn template(&self, size_gb: u32) -> ApiResult<PathBuf> {
+        let template = PathBuf::from(&self.config.volume_templates_dir)
+            .join(format!("data-{}g.ext4", size_gb));
+        let _guard = self.template_lock.lock().await;
+        if tokio::fs::try_exists(&template).await.unwrap_or(false) {
+            return Ok(template);
+        }
+
+        tracing::info!("Formatting a {} GB volume template", size_gb);
+        tokio::fs::create_dir_all(&self.config.volume_templates_dir)
+            .await
+            .map_err(|e| {
+                ApiError::VmError(format!("Failed to create volume templates dir: {}", e))
+            })?;
+        let partial = template.with_extension("partial");
+        let formatted = format_volume(&partial, size_gb).await;
+        let finished = match formatted {
+            Ok(()) => {
+                let _ =
+                    tokio::fs::set_permissions(&partial, PermissionsExt::from_mode(0o444)).await;
+                tokio::fs::rename(&partial, &template).await.map_err(|e| {
+                    ApiError::VmError(format!("Failed to save volume template: {}", e))
+                })
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = finished {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+
+        Ok(template)
+    }
+}
+
+/// Sparse file of `size_gb` formatted as ext4
+async fn format_volume(path: &Path, size_gb: u32) -> ApiResult<()> {
+    let file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| ApiError::VmError(format!("Failed to create volume file: {}", e)))?;
+
+    let size_bytes = (size_gb as u64) * 1024 * 1024 * 1024;
+    file.set_len(size_bytes)
+        .await
+        .map_err(|e| ApiError::VmError(format!("Failed to set volume size: {}", e)))?;
+
+    // Format as ext4, with no blocks reserved for root: the volume is all the
+    // workspace's
+    let output = Command::new("mkfs.ext4")
+        .args(["-F", "-m", "0"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| ApiError::VmError(format!("Failed to format volume: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(ApiError::VmError(format!(
+            "mkfs.ext4 failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    Ok(())
+}
@@ -1,32 +1,106 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use futures::StreamExt;
+use lia_protocol::frame::{self, Transport};
+use lia_protocol::{FEATURES, PROTOCOL_VERSION};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
+use tokio_util::bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, FramedRead};
 use tokio_vsock::{VsockAddr, VsockStream};
 use uuid::Uuid;
 
-use crate::error::ApiResult;
-use crate::models::{TaskFile, VsockMessage, WsMessage};
-use crate::ws::WsRegistry;
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{
+    McpServerConfig, PermissionPolicy, Sequenced, Task, TaskFile, TaskStatus, VsockMessage,
+    WorkspaceDisk, WsMessage,
+};
+use crate::AppState;
 
 /// vsock port used by the agent sidecar in the VM
 const VSOCK_PORT: u32 = 5000;
 
+/// Sidecar features every task relies on
+const REQUIRED_FEATURES: &[&str] = &["replay", "heartbeat"];
+
+/// How long the sidecar gets to answer the hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest hello line accepted from the sidecar
+const MAX_HELLO_LEN: usize = 64 * 1024;
+
 /// Maximum connection attempts (600 * 100ms = 60 seconds)
 const MAX_ATTEMPTS: u32 = 600;
 
+/// Reconnect backoff after the connection drops (doubles up to the max)
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How often received messages are acknowledged to the sidecar
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Heartbeat bookkeeping for one task, kept across reconnects
+struct Liveness {
+    last_seen: Instant,
+    healthy: bool,
+    /// A reset was already issued for the current unhealthy episode
+    reset_issued: bool,
+    /// The VM was reset, so its sidecar came back without the session
+    vm_reset: bool,
+}
+
+impl Liveness {
+    fn new() -> Self {
+        Self {
+            last_seen: Instant::now(),
+            healthy: true,
+            reset_issued: false,
+            vm_reset: false,
+        }
+    }
+}
+
+/// Why the protocol handshake failed
+enum HandshakeError {
+    /// The connection failed before the sidecar answered; worth retrying
+    Io(std::io::Error),
+    /// The sidecar answered but cannot serve this API
+    Incompatible(String),
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "vsock handshake failed: {}", e),
+            HandshakeError::Incompatible(message) => write!(f, "Incompatible sidecar: {}", message),
+        }
+    }
+}
+
+/// Why a connection to the sidecar ended
+enum ConnectionEnd {
+    /// Connection dropped while the task is still running
+    Lost,
+    /// Sidecar exited or the task's channel is gone
+    Finished,
+}
+
+#[derive(Clone)]
 pub struct VsockRelay {
     task_id: Uuid,
     guest_cid: u32,
-    ws_registry: Arc<WsRegistry>,
+    state: Arc<AppState>,
 }
 
 impl VsockRelay {
-    pub fn new(task_id: Uuid, guest_cid: u32, ws_registry: Arc<WsRegistry>) -> Self {
+    pub fn new(task_id: Uuid, guest_cid: u32, state: Arc<AppState>) -> Self {
         Self {
             task_id,
             guest_cid,
-            ws_registry,
+            state,
         }
     }
 
@@ -35,20 +109,21 @@ impl VsockRelay {
         api_key: String,
         prompt: String,
         files: Option<Vec<TaskFile>>,
-    ) -> ApiResult<mpsc::Sender<String>> {
+        mcp_servers: Option<BTreeMap<String, McpServerConfig>>,
+        permissions: Option<PermissionPolicy>,
+    ) -> ApiResult<mpsc::Sender<VsockMessage>> {
         // Create channel for sending input to the VM
-        let (input_tx, mut input_rx) = mpsc::channel::<String>(100);
+        let (input_tx, input_rx) = mpsc::channel::<VsockMessage>(100);
+        let approval_timeout_ms = approval_timeout_ms(permissions.as_ref());
 
-        let task_id = self.task_id;
         let guest_cid = self.guest_cid;
-        let ws_registry = self.ws_registry.clone();
 
         // Connect to the VM via vsock
         // QEMU's vhost-vsock-pci device allows direct AF_VSOCK connections
         // The guest CID is assigned when creating the VM
         let vsock_addr = VsockAddr::new(guest_cid, VSOCK_PORT);
         let mut attempts = 0;
-        let stream = loop {
+        let mut stream = loop {
             match VsockStream::connect(vsock_addr).await {
                 Ok(stream) => {
                     tracing::info!(
@@ -61,7 +136,7 @@ impl VsockRelay {
                 Err(e) => {
                     attempts += 1;
                     if attempts > MAX_ATTEMPTS {
-                        return Err(crate::error::ApiError::VmError(format!(
+                        return Err(ApiError::VmError(format!(
                             "Failed to connect to vsock (CID {}, port {}) after {}s: {}",
                             guest_cid,
                             VSOCK_PORT,
@@ -84,98 +159,830 @@ impl VsockRelay {
             }
         };
 
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
+        // Exchange hellos before anything else
+        let mut required = REQUIRED_FEATURES.to_vec();
+        if permissions.is_some() {
+            required.push("permissions");
+        }
+        if mcp_servers.as_ref().is_some_and(|servers| !servers.is_empty()) {
+            required.push("mcp");
+        }
+        let transport = self
+            .handshake(&mut stream, &required)
+            .await
+            .map_err(|e| ApiError::VmError(e.to_string()))?;
 
         // Send init message
         let init_msg = VsockMessage::Init {
             api_key,
             prompt,
             files,
+            mcp_servers,
+            permissions,
         };
-        let init_json = serde_json::to_string(&init_msg).unwrap() + "\n";
-        writer.write_all(init_json.as_bytes()).await.map_err(|e| {
-            crate::error::ApiError::VmError(format!("Failed to send init message: {}", e))
-        })?;
-        writer.flush().await.map_err(|e| {
-            crate::error::ApiError::VmError(format!("Failed to flush init message: {}", e))
-        })?;
+        write_message(&mut stream, transport, &init_msg)
+            .await
+            .map_err(|e| ApiError::VmError(format!("Failed to send init message: {}", e)))?;
 
-        // Spawn reader task
-        let ws_registry_clone = ws_registry.clone();
+        let relay = self.clone();
         tokio::spawn(async move {
-            tracing::info!("vsock reader task started for task {}", task_id);
-            let mut line = String::new();
-            loop {
-                line.clear();
-                match reader.read_line(&mut line).await {
-                    Ok(0) => {
-                        // EOF - connection closed
-                        tracing::info!("vsock connection closed for task {}", task_id);
-                        break;
-                    }
-                    Ok(n) => {
-                        tracing::debug!("vsock received {} bytes for task {}: {}", n, task_id, line.trim());
-                        match serde_json::from_str::<VsockMessage>(&line) {
-                            Ok(msg) => {
-                                match msg {
-                                    VsockMessage::Output { data } => {
-                                        tracing::debug!("Broadcasting output for task {}", task_id);
-                                        let ws_msg = WsMessage::Output {
-                                            data,
-                                            timestamp: chrono::Utc::now().timestamp_millis(),
-                                        };
-                                        ws_registry_clone.broadcast(task_id, ws_msg).await;
-                                    }
-                                    VsockMessage::Exit { code } => {
-                                        tracing::info!("Task {} exited with code {}", task_id, code);
-                                        let ws_msg = WsMessage::Status {
-                                            status: crate::models::TaskStatus::Terminated,
-                                            exit_code: Some(code),
-                                        };
-                                        ws_registry_clone.broadcast(task_id, ws_msg).await;
-                                        break;
-                                    }
-                                    VsockMessage::Error { message } => {
-                                        tracing::error!("Sidecar error for task {}: {}", task_id, message);
-                                        let ws_msg = WsMessage::Error { message };
-                                        ws_registry_clone.broadcast(task_id, ws_msg).await;
-                                    }
-                                    VsockMessage::Heartbeat => {
-                                        tracing::debug!("Heartbeat received for task {}", task_id);
-                                    }
-                                    _ => {
-                                        tracing::debug!("Unknown message type for task {}", task_id);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                tracing::warn!("Failed to parse vsock message for task {}: {} (raw: {})", task_id, e, line.trim());
-                            }
+            relay.run(Some((stream, transport)), input_rx, approval_timeout_ms).await;
+        });
+
+        Ok(input_tx)
+    }
+
+    /// Take over the session of a sidecar that is already running (e.g. after
+    /// an API restart). Connects in the background; the sidecar replays any
+    /// output that was never acknowledged.
+    pub fn reattach(&self, permissions: Option<&PermissionPolicy>) -> mpsc::Sender<VsockMessage> {
+        let (input_tx, input_rx) = mpsc::channel::<VsockMessage>(100);
+        let approval_timeout_ms = approval_timeout_ms(permissions);
+
+        let relay = self.clone();
+        tokio::spawn(async move {
+            relay.run(None, input_rx, approval_timeout_ms).await;
+        });
+
+        input_tx
+    }
+
+    /// Relay messages for the lifetime of the task, reconnecting whenever the
+    /// connection drops
+    async fn run(
+        self,
+        mut stream: Option<(VsockStream, Transport)>,
+        mut input_rx: mpsc::Receiver<VsockMessage>,
+        approval_timeout_ms: i64,
+    ) {
+        let task_id = self.task_id;
+        tracing::info!("vsock relay started for task {}", task_id);
+
+        // Highest sequence number received from the sidecar
+        let mut last_seq = 0;
+        let mut liveness = Liveness::new();
+        loop {
+            let (current, transport) = match stream.take() {
+                Some(connection) => connection,
+                None => match self.reconnect(last_seq, &mut liveness).await {
+                    Some(connection) => connection,
+                    None => break,
+                },
+            };
+
+            let end = self
+                .pump(current, transport, &mut input_rx, &mut last_seq, &mut liveness, approval_timeout_ms)
+                .await;
+            // Requests in flight were tied to that connection
+            if let Some(channel) = self.state.ws_registry.get(task_id).await {
+                channel.fail_requests();
+            }
+            match end {
+                ConnectionEnd::Finished => break,
+                ConnectionEnd::Lost => {
+                    tracing::warn!("vsock connection lost for task {}, reconnecting", task_id);
+                }
+            }
+        }
+
+        tracing::info!("vsock relay stopped for task {}", task_id);
+    }
+
+    /// Reconnect with exponential backoff and resume after `last_seq`.
+    /// Returns None once the task is no longer running.
+    async fn reconnect(
+        &self,
+        last_seq: u64,
+        liveness: &mut Liveness,
+    ) -> Option<(VsockStream, Transport)> {
+        let vsock_addr = VsockAddr::new(self.guest_cid, VSOCK_PORT);
+        let mut delay = RECONNECT_BASE_DELAY;
+
+        loop {
+            match db::get_task(&self.state.db, self.task_id).await {
+                Ok(task) if task.status == TaskStatus::Terminated => return None,
+                Err(ApiError::TaskNotFound(_)) => return None,
+                Err(e) => tracing::warn!("Failed to check task {}: {}", self.task_id, e),
+                Ok(_) => {}
+            }
+
+            match VsockStream::connect(vsock_addr).await {
+                Ok(mut stream) => {
+                    let transport = match self.handshake(&mut stream, REQUIRED_FEATURES).await {
+                        Ok(transport) => transport,
+                        Err(HandshakeError::Incompatible(message)) => {
+                            // The VM will not change its mind; stop relaying
+                            let message = format!("Incompatible sidecar: {}", message);
+                            tracing::error!("Task {}: {}", self.task_id, message);
+                            self.state
+                                .ws_registry
+                                .broadcast(self.task_id, WsMessage::Error { message })
+                                .await;
+                            return None;
                         }
+                        Err(e) => {
+                            tracing::debug!("{}", e);
+                            tokio::time::sleep(delay).await;
+                            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                            self.check_liveness(liveness).await;
+                            continue;
+                        }
+                    };
+
+                    // A fresh sidecar rejects Attach, and the session cannot
+                    // be started again without the original Init
+                    if liveness.vm_reset {
+                        self.end_session("The VM was reset and lost the Claude session").await;
+                        return None;
                     }
-                    Err(e) => {
-                        tracing::error!("Error reading from vsock: {}", e);
-                        break;
+
+                    let attach = VsockMessage::Attach { last_seq };
+                    match write_message(&mut stream, transport, &attach).await {
+                        Ok(()) => {
+                            tracing::info!(
+                                "vsock reattached to CID {} for task {} (last seq {})",
+                                self.guest_cid,
+                                self.task_id,
+                                last_seq
+                            );
+                            return Some((stream, transport));
+                        }
+                        Err(e) => tracing::debug!("Failed to send attach message: {}", e),
                     }
                 }
+                Err(e) => {
+                    tracing::debug!(
+                        "Reconnect to CID {} failed, retrying in {:?}: {}",
+                        self.guest_cid,
+                        delay,
+                        e
+                    );
+                }
             }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            self.check_liveness(liveness).await;
+        }
+    }
+
+    /// Send our hello and check the sidecar's answer: same protocol version
+    /// and every feature in `required`. Records the sidecar version and
+    /// workspace size on the task and returns the transport both sides agreed
+    /// on.
+    async fn handshake(
+        &self,
+        stream: &mut VsockStream,
+        required: &[&str],
+    ) -> Result<Transport, HandshakeError> {
+        let ours = self.hello_features();
+        let hello = VsockMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: ours.clone(),
+            workspace: None,
+        };
+        write_message(stream, Transport::Lines, &hello)
+            .await
+            .map_err(HandshakeError::Io)?;
+
+        let line = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_line_unbuffered(stream)).await {
+            Ok(line) => line.map_err(HandshakeError::Io)?,
+            Err(_) => {
+                return Err(HandshakeError::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "no answer from the sidecar",
+                )))
+            }
+        };
+
+        // Sidecars that predate the handshake answer with an unsequenced Error
+        let reply = serde_json::from_str::<Sequenced<VsockMessage>>(&line).map_err(|e| {
+            HandshakeError::Incompatible(format!("unreadable hello ({}): {}", e, line))
+        })?;
+        let (version, features, workspace) = match reply.msg {
+            VsockMessage::Hello {
+                protocol_version,
+                version,
+                features,
+                workspace,
+            } => {
+                if protocol_version != PROTOCOL_VERSION {
+                    return Err(HandshakeError::Incompatible(format!(
+                        "sidecar {} speaks vsock protocol {}, this API speaks {}",
+                        version, protocol_version, PROTOCOL_VERSION
+                    )));
+                }
+                (version, features, workspace)
+            }
+            VsockMessage::Error { message } => {
+                return Err(HandshakeError::Incompatible(format!(
+                    "sidecar rejected the handshake: {}",
+                    message
+                )))
+            }
+            other => {
+                return Err(HandshakeError::Incompatible(format!(
+                    "expected a hello from the sidecar, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let missing: Vec<&str> = required
+            .iter()
+            .copied()
+            .filter(|feature| !features.iter().any(|f| f == feature))
+            .collect();
+        if !missing.is_empty() {
+            return Err(HandshakeError::Incompatible(format!(
+                "sidecar {} lacks required features: {}",
+                version,
+                missing.join(", ")
+            )));
+        }
+
+        let transport = Transport::negotiate(&ours, &features);
+        tracing::info!(
+            "Task {} sidecar {} (protocol {}, features: {}, transport: {:?})",
+            self.task_id,
+            version,
+            PROTOCOL_VERSION,
+            features.join(", "),
+            transport
+        );
+        let workspace_bytes = workspace
+            .filter(|disk| disk.mounted)
+            .map(|disk| disk.total_bytes as i64);
+        let stored =
+            db::update_task_sidecar(&self.state.db, self.task_id, &version, workspace_bytes).await;
+        match stored {
+            Ok(task) => self.check_workspace(&task, workspace),
+            Err(e) => tracing::error!("Failed to store sidecar version: {}", e),
+        }
+        self.state
+            .ws_registry
+            .get_or_create(self.task_id)
+            .await
+            .set_sidecar_features(features)
+            .await;
+
+        Ok(transport)
+    }
+
+    /// Warn when /workspace is not on the task's data volume, or is much
+    /// smaller than the storage the task asked for
+    fn check_workspace(&self, task: &Task, workspace: Option<WorkspaceDisk>) {
+        let storage_gb = task
+            .config
+            .as_ref()
+            .map(|c| c.storage_gb)
+            .unwrap_or(self.state.config.vm.default_storage_gb);
+        let requested = storage_gb as u64 * 1024 * 1024 * 1024;
+        match workspace {
+            // Sidecars that predate the report
+            None => {}
+            Some(disk) if !disk.mounted => tracing::warn!(
+                "Task {} has /workspace on its root filesystem, not on its {} GB data volume",
+                self.task_id,
+                storage_gb
+            ),
+            // ext4 keeps a little of the volume for itself
+            Some(disk) if disk.total_bytes < requested / 10 * 9 => tracing::warn!(
+                "Task {} has {} bytes in /workspace, {} GB were requested",
+                self.task_id,
+                disk.total_bytes,
+                storage_gb
+            ),
+            Some(_) => {}
+        }
+    }
+
+    /// Features announced in our hello; transports can be turned off in config
+    fn hello_features(&self) -> Vec<String> {
+        let vm = &self.state.config.vm;
+        let transports = frame::transport_features().into_iter().filter(|f| match *f {
+            frame::FEATURE_FRAMED => vm.vsock_framing,
+            frame::FEATURE_ZSTD => vm.vsock_framing && vm.vsock_compression,
+            _ => true,
         });
+        FEATURES
+            .iter()
+            .copied()
+            .chain(transports)
+            .map(String::from)
+            .collect()
+    }
 
-        // Spawn writer task for input
-        tokio::spawn(async move {
-            while let Some(input) = input_rx.recv().await {
-                let input_msg = VsockMessage::Input { data: input };
-                let json = serde_json::to_string(&input_msg).unwrap() + "\n";
-                if writer.write_all(json.as_bytes()).await.is_err() {
-                    break;
+    /// Relay over one connection until it drops or the session ends
+    async fn pump(
+        &self,
+        stream: VsockStream,
+        transport: Transport,
+        input_rx: &mut mpsc::Receiver<VsockMessage>,
+        last_seq: &mut u64,
+        liveness: &mut Liveness,
+        approval_timeout_ms: i64,
+    ) -> ConnectionEnd {
+        let task_id = self.task_id;
+        let (reader, mut writer) = stream.into_split();
+        let mut messages = FramedRead::new(reader, VsockCodec::new(transport));
+
+        let mut acked = *last_seq;
+        let mut ack_timer = tokio::time::interval(ACK_INTERVAL);
+        let heartbeat_interval = Duration::from_secs(self.state.config.vm.heartbeat_interval_secs);
+        let mut heartbeat_timer = tokio::time::interval(heartbeat_interval);
+
+        loop {
+            tokio::select! {
+                received = messages.next() => {
+                    let parsed = match received {
+                        Some(Ok(parsed)) => parsed,
+                        None => {
+                            // EOF - connection closed
+                            tracing::info!("vsock connection closed for task {}", task_id);
+                            return ConnectionEnd::Lost;
+                        }
+                        Some(Err(e)) => {
+                            tracing::error!("Error reading from vsock: {}", e);
+                            return ConnectionEnd::Lost;
+                        }
+                    };
+
+                    let Sequenced { seq, msg } = match parsed {
+                        Ok(sequenced) => sequenced,
+                        Err(e) => {
+                            tracing::warn!("Failed to parse vsock message for task {}: {}", task_id, e);
+                            continue;
+                        }
+                    };
+                    // File, archive, command and shell output chunks are too
+                    // large to log
+                    if !matches!(
+                        msg,
+                        VsockMessage::FileData { .. }
+                            | VsockMessage::ArchiveData { .. }
+                            | VsockMessage::ExecOutput { .. }
+                            | VsockMessage::PtyOutput { .. }
+                    ) {
+                        tracing::debug!("vsock received for task {} (seq {:?}): {:?}", task_id, seq, msg);
+                    }
+                    // Anything from the sidecar proves the VM is alive
+                    self.mark_alive(liveness).await;
+                    if let Some(seq) = seq {
+                        // Already seen before the reconnect
+                        if seq <= *last_seq {
+                            continue;
+                        }
+                        *last_seq = seq;
+                    }
+
+                    if !self.handle_message(msg, approval_timeout_ms).await {
+                        return ConnectionEnd::Finished;
+                    }
+                }
+                msg = input_rx.recv() => {
+                    let Some(msg) = msg else {
+                        return ConnectionEnd::Finished;
+                    };
+                    if let Err(e) = write_message(&mut writer, transport, &msg).await {
+                        tracing::warn!("Failed to send message to task {}: {}", task_id, e);
+                        return ConnectionEnd::Lost;
+                    }
                 }
-                if writer.flush().await.is_err() {
-                    break;
+                _ = ack_timer.tick() => {
+                    if *last_seq > acked {
+                        if write_message(&mut writer, transport, &VsockMessage::Ack { seq: *last_seq }).await.is_err() {
+                            return ConnectionEnd::Lost;
+                        }
+                        acked = *last_seq;
+                    }
+                }
+                _ = heartbeat_timer.tick() => {
+                    if write_message(&mut writer, transport, &VsockMessage::Heartbeat { stats: None }).await.is_err() {
+                        return ConnectionEnd::Lost;
+                    }
+                    self.check_liveness(liveness).await;
                 }
             }
-        });
+        }
+    }
 
-        Ok(input_tx)
+    async fn mark_alive(&self, liveness: &mut Liveness) {
+        liveness.last_seen = Instant::now();
+        if liveness.healthy {
+            return;
+        }
+
+        tracing::info!("Task {} is responding again", self.task_id);
+        liveness.healthy = true;
+        liveness.reset_issued = false;
+        self.set_health(true).await;
+    }
+
+    /// Mark the task unhealthy once heartbeats have been missing for too long,
+    /// and reset the VM if configured to
+    async fn check_liveness(&self, liveness: &mut Liveness) {
+        let timeout = Duration::from_secs(self.state.config.vm.heartbeat_timeout_secs);
+        if liveness.last_seen.elapsed() < timeout {
+            return;
+        }
+
+        if liveness.healthy {
+            // A paused VM is expected to be silent
+            if let Ok(task) = db::get_task(&self.state.db, self.task_id).await {
+                if task.status == TaskStatus::Suspended {
+                    liveness.last_seen = Instant::now();
+                    return;
+                }
+            }
+
+            tracing::warn!(
+                "Task {} sent no heartbeat for {:?}, marking unhealthy",
+                self.task_id,
+                liveness.last_seen.elapsed()
+            );
+            liveness.healthy = false;
+            self.set_health(false).await;
+        }
+
+        if self.state.config.vm.reset_unhealthy && !liveness.reset_issued {
+            liveness.reset_issued = true;
+            let vm_id = format!("vm-{}", self.task_id);
+            tracing::warn!("Resetting unresponsive VM {}", vm_id);
+            let message = match self.state.vm_manager.reset_vm(&vm_id).await {
+                Ok(()) => {
+                    liveness.vm_reset = true;
+                    "VM stopped responding and was reset".to_string()
+                }
+                Err(e) => format!("VM stopped responding and could not be reset: {}", e),
+            };
+            self.state
+                .ws_registry
+                .broadcast(self.task_id, WsMessage::Error { message })
+                .await;
+        }
+    }
+
+    /// Give up on a task whose session is gone: mark it terminated and tell
+    /// the clients why
+    async fn end_session(&self, message: &str) {
+        tracing::error!("Task {}: {}", self.task_id, message);
+        if let Err(e) = db::update_task_status(&self.state.db, self.task_id, TaskStatus::Terminated, None).await {
+            tracing::error!("Failed to update task status: {}", e);
+        }
+        let ws_registry = &self.state.ws_registry;
+        ws_registry
+            .broadcast(self.task_id, WsMessage::Error { message: message.to_string() })
+            .await;
+        ws_registry
+            .broadcast(
+                self.task_id,
+                WsMessage::Status {
+                    status: TaskStatus::Terminated,
+                    exit_code: None,
+                },
+            )
+            .await;
+    }
+
+    async fn set_health(&self, healthy: bool) {
+        if let Err(e) = db::update_task_health(&self.state.db, self.task_id, healthy).await {
+            tracing::error!("Failed to update task health: {}", e);
+        }
+        self.state
+            .ws_registry
+            .broadcast(self.task_id, WsMessage::Health { healthy, stats: None })
+            .await;
+    }
+
+    /// Forward a sidecar message to WebSocket clients. Returns false when the
+    /// sidecar has exited.
+    async fn handle_message(&self, msg: VsockMessage, approval_timeout_ms: i64) -> bool {
+        let task_id = self.task_id;
+        let ws_registry = &self.state.ws_registry;
+        let db = &self.state.db;
+
+        match msg {
+            VsockMessage::Output { data } => {
+                tracing::debug!("Broadcasting output for task {}", task_id);
+                let ws_msg = WsMessage::Output {
+                    data,
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::Exit { code } => {
+                tracing::info!("Task {} exited with code {}", task_id, code);
+                let ws_msg = WsMessage::Status {
+                    status: TaskStatus::Terminated,
+                    exit_code: Some(code),
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+                return false;
+            }
+            VsockMessage::AgentStarted { resumed } => {
+                tracing::info!("Task {} agent started (resumed: {})", task_id, resumed);
+                if let Err(e) = db::update_task_status(db, task_id, TaskStatus::Running, None).await {
+                    tracing::error!("Failed to update task status: {}", e);
+                }
+                let ws_msg = WsMessage::Status {
+                    status: TaskStatus::Running,
+                    exit_code: None,
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::AgentExited { code, session_id } => {
+                // The VM stays up; the next input resumes the session
+                tracing::info!("Task {} agent exited with code {} (session {:?})", task_id, code, session_id);
+                if let Err(e) = db::update_task_status(db, task_id, TaskStatus::AwaitingInput, None).await {
+                    tracing::error!("Failed to update task status: {}", e);
+                }
+                let ws_msg = WsMessage::Status {
+                    status: TaskStatus::AwaitingInput,
+                    exit_code: Some(code),
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::Error { message } => {
+                tracing::error!("Sidecar error for task {}: {}", task_id, message);
+                let ws_msg = WsMessage::Error { message };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::PermissionRequest { request_id, tool_name, input } => {
+                tracing::info!("Task {} requests approval for {} ({})", task_id, tool_name, request_id);
+                let ws_msg = WsMessage::PermissionRequest {
+                    request_id,
+                    tool_name,
+                    input,
+                    expires_at: chrono::Utc::now().timestamp_millis() + approval_timeout_ms,
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::PermissionResolved { request_id, decision, timed_out } => {
+                tracing::info!("Task {} approval {} resolved: {:?} (timed out: {})", task_id, request_id, decision, timed_out);
+                let ws_msg = WsMessage::PermissionResolved {
+                    request_id,
+                    decision,
+                    timed_out,
+                };
+                ws_registry.broadcast(task_id, ws_msg).await;
+            }
+            VsockMessage::TurnCancelled => {
+                tracing::info!("Task {} turn cancelled", task_id);
+                ws_registry.broadcast(task_id, WsMessage::TurnCancelled).await;
+            }
+            VsockMessage::Heartbeat { stats } => {
+                tracing::debug!("Heartbeat received for task {}: {:?}", task_id, stats);
+                if stats.is_some() {
+                    let ws_msg = WsMessage::Health {
+                        healthy: true,
+                        stats,
+                    };
+                    ws_registry.broadcast(task_id, ws_msg).await;
+                }
+            }
+            VsockMessage::FileData { ref request_id, .. }
+            | VsockMessage::FileResult { ref request_id, .. }
+            | VsockMessage::Tree { ref request_id, .. }
+            | VsockMessage::ArchiveData { ref request_id, .. }
+            | VsockMessage::Diff { ref request_id, .. }
+            | VsockMessage::ExecOutput { ref request_id, .. }
+            | VsockMessage::ExecExit { ref request_id, .. }
+            | VsockMessage::PtyOutput { ref request_id, .. }
+            | VsockMessage::PtyExit { ref request_id, .. } => {
+                if let Some(channel) = ws_registry.get(task_id).await {
+                    let request_id = request_id.clone();
+                    channel.deliver(&request_id, msg).await;
+                }
+            }
+            // Host -> sidecar messages; a sidecar never sends these
+            VsockMessage::Hello { .. }
+            | VsockMessage::Init { .. }
+            | VsockMessage::Input { .. }
+            | VsockMessage::PermissionResponse { .. }
+            | VsockMessage::Attach { .. }
+            | VsockMessage::Ack { .. }
+            | VsockMessage::Interrupt
+            | VsockMessage::FileWrite { .. }
+            | VsockMessage::FileRead { .. }
+            | VsockMessage::ListTree { .. }
+            | VsockMessage::ArchiveStart { .. }
+            | VsockMessage::ArchiveNext { .. }
+            | VsockMessage::GetDiff { .. }
+            | VsockMessage::ExecStart { .. }
+            | VsockMessage::ExecKill { .. }
+            | VsockMessage::PtyOpen { .. }
+            | VsockMessage::PtyInput { .. }
+            | VsockMessage::PtyResize { .. }
+            | VsockMessage::PtyClose { .. }
+            | VsockMessage::Personalize { .. }
+            // Only sent on a connection of its own, to `personalize`
+            | VsockMessage::Personalized { .. } => {
+                tracing::warn!("Unexpected message from the sidecar of task {}", task_id);
+            }
+        }
+
+        true
+    }
+}
+
+/// Wait until the sidecar of the VM with `guest_cid` accepts connections.
+/// The connection is closed without a hello, which the sidecar ignores.
+pub async fn wait_for_sidecar(guest_cid: u32) -> ApiResult<()> {
+    connect_when_ready(guest_cid).await.map(drop)
+}
+
+/// Send a `Personalize` to the sidecar of a VM restored from a snapshot, on a
+/// connection of its own, and wait for the result
+pub async fn personalize(guest_cid: u32, msg: &VsockMessage) -> ApiResult<()> {
+    let failed = |e: String| ApiError::VmError(format!("Failed to personalize VM: {}", e));
+    let mut stream = connect_when_ready(guest_cid).await?;
+
+    // Without transport features both sides stay on JSON lines
+    let hello = VsockMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        workspace: None,
+    };
+    let exchange = async {
+        write_message(&mut stream, Transport::Lines, &hello).await?;
+        let theirs = read_line_unbuffered(&mut stream).await?;
+        write_message(&mut stream, Transport::Lines, msg).await?;
+        let result = read_line_unbuffered(&mut stream).await?;
+        Ok::<_, std::io::Error>((theirs, result))
+    };
+    let (theirs, result) = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| failed("no answer from the sidecar".to_string()))?
+        .map_err(|e| failed(e.to_string()))?;
+
+    match serde_json::from_str::<Sequenced<VsockMessage>>(&theirs).map(|m| m.msg) {
+        Ok(VsockMessage::Hello { features, .. }) if features.iter().any(|f| f == "personalize") => {}
+        Ok(VsockMessage::Hello { version, .. }) => {
+            return Err(failed(format!("sidecar {} cannot personalize", version)))
+        }
+        _ => return Err(failed(format!("expected a hello, got: {}", theirs))),
+    }
+    match serde_json::from_str::<Sequenced<VsockMessage>>(&result).map(|m| m.msg) {
+        Ok(VsockMessage::Personalized { error: None, .. }) => Ok(()),
+        Ok(VsockMessage::Personalized {
+            error: Some(error), ..
+        }) => Err(failed(error)),
+        _ => Err(failed(format!("unexpected answer: {}", result))),
+    }
+}
+
+/// Connect to the sidecar of the VM with `guest_cid`, retrying for as long
+/// as a boot may take
+async fn connect_when_ready(guest_cid: u32) -> ApiResult<VsockStream> {
+    let vsock_addr = VsockAddr::new(guest_cid, VSOCK_PORT);
+    let mut attempts = 0;
+    loop {
+        match VsockStream::connect(vsock_addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                attempts += 1;
+                if attempts > MAX_ATTEMPTS {
+                    return Err(ApiError::VmError(format!(
+                        "Sidecar of CID {} not reachable after {}s: {}",
+                        guest_cid,
+                        MAX_ATTEMPTS / 10,
+                        e
+                    )));
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Reattach relays to VMs that kept running while the API was down
+pub async fn reattach_tasks(state: &Arc<AppState>) {
+    let tasks = match db::list_attached_tasks(&state.db).await {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            tracing::error!("Failed to list tasks to reattach: {}", e);
+            return;
+        }
+    };
+
+    for task in tasks {
+        let Some(cid) = task.vsock_cid else {
+            continue;
+        };
+        let cid = cid as u32;
+        tracing::info!("Reattaching to task {} (CID {})", task.id, cid);
+
+        // Keep new VMs off this CID
+        state.vm_manager.reserve_cid(cid);
+
+        let permissions = task.config.as_ref().and_then(|c| c.permission_policy());
+        let relay = VsockRelay::new(task.id, cid, state.clone());
+        let input_tx = relay.reattach(permissions.as_ref());
+        state
+            .ws_registry
+            .get_or_create(task.id)
+            .await
+            .set_input_sender(input_tx)
+            .await;
+    }
+}
+
+/// Unix millis added to the arrival time of a PermissionRequest
+fn approval_timeout_ms(permissions: Option<&PermissionPolicy>) -> i64 {
+    permissions
+        .map(|p| p.timeout_secs as i64 * 1000)
+        .unwrap_or_default()
+}
+
+/// Read one line without buffering past it, so nothing after the hello is lost
+async fn read_line_unbuffered(stream: &mut VsockStream) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() == MAX_HELLO_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "hello line too long",
+            ));
+        }
+        line.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Write one message to the sidecar in the connection's transport
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    transport: Transport,
+    msg: &VsockMessage,
+) -> std::io::Result<()> {
+    let bytes = transport.encode(None, msg)?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await
+}
+
+/// Splits what the sidecar sends into messages, as JSON lines or frames
+struct VsockCodec {
+    transport: Transport,
+    /// Bytes of the buffer already searched for a newline
+    scanned: usize,
+}
+
+impl VsockCodec {
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            scanned: 0,
+        }
+    }
+}
+
+impl Decoder for VsockCodec {
+    /// The inner error is a JSON line that did not parse, which is skipped
+    /// rather than dropping the connection
+    type Item = Result<Sequenced<VsockMessage>, String>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        match self.transport {
+            Transport::Lines => {
+                let Some(end) = src[self.scanned..].iter().position(|b| *b == b'\n') else {
+                    self.scanned = src.len();
+                    if src.len() > frame::MAX_FRAME_LEN {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "vsock line too long",
+                        ));
+                    }
+                    return Ok(None);
+                };
+                let line = src.split_to(self.scanned + end + 1);
+                self.scanned = 0;
+                let line = String::from_utf8_lossy(&line[..line.len() - 1]);
+                Ok(Some(
+                    serde_json::from_str(&line).map_err(|e| format!("{} (raw: {})", e, line)),
+                ))
+            }
+            Transport::Framed { .. } => {
+                let Some(header) = src.get(..frame::HEADER_LEN) else {
+                    return Ok(None);
+                };
+                let header: [u8; frame::HEADER_LEN] = header.try_into().expect("header-sized slice");
+                let (flags, len) = frame::parse_header(&header)?;
+                if src.len() < frame::HEADER_LEN + len {
+                    src.reserve(frame::HEADER_LEN + len - src.len());
+                    return Ok(None);
+                }
+                src.advance(frame::HEADER_LEN);
+                let body = src.split_to(len);
+                frame::decode_frame(flags, &body).map(|msg| Some(Ok(msg)))
+            }
+        }
     }
 }
@@ -1,10 +1,10 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use tokio::sync::{broadcast, mpsc, RwLock};
 use uuid::Uuid;
 
-use crate::models::WsMessage;
+use crate::models::{PermissionDecision, VsockMessage, WsMessage};
 
 const CHANNEL_CAPACITY: usize = 1024;
 
@@ -12,8 +12,69 @@ const CHANNEL_CAPACITY: usize = 1024;
 pub struct TaskChannel {
     pub sender: broadcast::Sender<WsMessage>,
     pub output_buffer: Arc<RwLock<Vec<WsMessage>>>,
-    /// Sender for forwarding input to the VM via vsock
-    input_sender: RwLock<Option<mpsc::Sender<String>>>,
+    /// Sender for forwarding messages to the VM via vsock
+    input_sender: RwLock<Option<mpsc::Sender<VsockMessage>>>,
+    /// Tool approvals still waiting on the user, replayed to new subscribers
+    pending_permissions: RwLock<HashMap<String, WsMessage>>,
+    /// Features the sidecar announced on the current connection
+    sidecar_features: RwLock<Vec<String>>,
+    /// Requests waiting on sidecar answers (e.g. file transfers), by request_id
+    requests: Mutex<HashMap<String, mpsc::Sender<VsockMessage>>>,
+}
+
+/// Capacity of the answer queue of one request
+const REQUEST_CAPACITY: usize = 16;
+
+/// A request sent to the sidecar, receiving the answers that carry its id.
+/// Unregistered when dropped.
+pub struct PendingRequest {
+    channel: Arc<TaskChannel>,
+    pub id: String,
+    answers: mpsc::Receiver<VsockMessage>,
+}
+
+impl PendingRequest {
+    /// Send a message for this request to the VM
+    pub async fn send(&self, msg: VsockMessage) -> bool {
+        self.channel.send_to_vm(msg).await
+    }
+
+    /// Next answer, or None once the connection to the sidecar was lost
+    pub async fn recv(&mut self) -> Option<VsockMessage> {
+        self.answers.recv().await
+    }
+
+    /// An answer that already arrived, without waiting
+    pub fn try_recv(&mut self) -> Option<VsockMessage> {
+        self.answers.try_recv().ok()
+    }
+
+    /// Queue the answers without limit, so that a slow consumer of a long
+    /// stream of them never holds up the relay. The request stays registered
+    /// until the returned receiver is dropped.
+    pub fn unbounded(mut self) -> mpsc::UnboundedReceiver<VsockMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = self.recv() => {
+                        let Some(msg) = msg else { break };
+                        if tx.send(msg).is_err() {
+                            break;
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        rx
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.channel.requests.lock().unwrap().remove(&self.id);
+    }
 }
 
 impl TaskChannel {
@@ -23,32 +84,124 @@ impl TaskChannel {
             sender,
             output_buffer: Arc::new(RwLock::new(Vec::new())),
             input_sender: RwLock::new(None),
+            pending_permissions: RwLock::new(HashMap::new()),
+            sidecar_features: RwLock::new(Vec::new()),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember the features from the sidecar's hello
+    pub async fn set_sidecar_features(&self, features: Vec<String>) {
+        *self.sidecar_features.write().await = features;
+    }
+
+    /// Whether the connected sidecar announced `feature`
+    pub async fn sidecar_supports(&self, feature: &str) -> bool {
+        self.sidecar_features.read().await.iter().any(|f| f == feature)
+    }
+
+    /// Register a new request; answers carrying its id are routed to it
+    pub fn start_request(self: &Arc<Self>) -> PendingRequest {
+        let id = Uuid::new_v4().to_string();
+        let (tx, answers) = mpsc::channel(REQUEST_CAPACITY);
+        self.requests.lock().unwrap().insert(id.clone(), tx);
+        PendingRequest {
+            channel: self.clone(),
+            id,
+            answers,
+        }
+    }
+
+    /// Route a sidecar answer to the request waiting on it
+    pub async fn deliver(&self, request_id: &str, msg: VsockMessage) {
+        let sender = self.requests.lock().unwrap().get(request_id).cloned();
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(msg).await;
+            }
+            None => tracing::debug!("Dropping answer for unknown request {}", request_id),
         }
     }
 
-    /// Set the input sender for forwarding input to the VM
-    pub async fn set_input_sender(&self, sender: mpsc::Sender<String>) {
+    /// Fail every pending request; their answers would have come over a
+    /// connection that is gone
+    pub fn fail_requests(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+
+    /// Set the input sender for forwarding messages to the VM
+    pub async fn set_input_sender(&self, sender: mpsc::Sender<VsockMessage>) {
         *self.input_sender.write().await = Some(sender);
     }
 
-    /// Send input to the VM via vsock
-    pub async fn send_input(&self, data: String) -> bool {
+    /// Send a message to the VM via vsock
+    pub async fn send_to_vm(&self, msg: VsockMessage) -> bool {
         if let Some(sender) = self.input_sender.read().await.as_ref() {
-            sender.send(data).await.is_ok()
+            sender.send(msg).await.is_ok()
         } else {
             tracing::warn!("No input sender available for task");
             false
         }
     }
 
+    /// Send input to the VM via vsock
+    pub async fn send_input(&self, data: String) -> bool {
+        self.send_to_vm(VsockMessage::Input { data }).await
+    }
+
+    /// Forward the user's decision on a pending tool approval to the VM.
+    /// Returns false if no such approval is pending.
+    pub async fn respond_permission(
+        &self,
+        request_id: String,
+        decision: PermissionDecision,
+        message: Option<String>,
+    ) -> bool {
+        if !self
+            .pending_permissions
+            .read()
+            .await
+            .contains_key(&request_id)
+        {
+            return false;
+        }
+        self.send_to_vm(VsockMessage::PermissionResponse {
+            request_id,
+            decision,
+            message,
+        })
+        .await
+    }
+
+    pub async fn get_pending_permissions(&self) -> Vec<WsMessage> {
+        self.pending_permissions
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect()
+    }
+
     pub fn subscribe(&self) -> broadcast::Receiver<WsMessage> {
         self.sender.subscribe()
     }
 
     pub async fn send(&self, msg: WsMessage) {
-        // Buffer output messages
-        if matches!(msg, WsMessage::Output { .. }) {
-            self.output_buffer.write().await.push(msg.clone());
+        // Buffer output messages and track pending approvals
+        match &msg {
+            WsMessage::Output { .. } => self.output_buffer.write().await.push(msg.clone()),
+            WsMessage::PermissionRequest { request_id, .. } => {
+                self.pending_permissions
+                    .write()
+                    .await
+                    .insert(request_id.clone(), msg.clone());
+            }
+            WsMessage::PermissionResolved { request_id, .. } => {
+                self.pending_permissions.write().await.remove(request_id);
+            }
+            // Approvals of an interrupted turn are abandoned by the agent
+            WsMessage::TurnCancelled => self.pending_permissions.write().await.clear(),
+            _ => {}
         }
         // Ignore send errors (no subscribers)
         let _ = self.sender.send(msg);
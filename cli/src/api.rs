@@ -1,6 +1,6 @@
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt};
+use lia_protocol::TaskListResponse;
 use serde::Deserialize;
 
 pub struct ApiClient {
@@ -9,24 +9,7 @@ pub struct ApiClient {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct TaskListResponse {
-    pub tasks: Vec<TaskResponse>,
-    pub total: i64,
-    pub page: u32,
-    pub per_page: u32,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct TaskResponse {
-    pub id: String,
-    pub user_id: String,
-    pub status: String,
-    pub source: String,
-    pub created_at: DateTime<Utc>,
-    pub ip_address: Option<String>,
-}
-
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct LogsResponse {
     pub task_id: String,
     pub lines: Vec<String>,
@@ -34,6 +17,7 @@ pub struct LogsResponse {
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct SseEvent {
     pub event_type: String,
     pub task_id: Option<String>,
@@ -1,8 +1,8 @@
 use anyhow::Result;
-use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use colored::Colorize;
 use futures::StreamExt;
+use lia_protocol::TaskStatus;
 use std::io::{self, Write};
 
 mod api;
@@ -76,12 +76,13 @@ async fn list_tasks(client: &ApiClient, status: Option<&str>) -> Result<()> {
     );
 
     for task in response.tasks {
-        let status_colored = match task.status.as_str() {
-            "running" => task.status.green(),
-            "starting" | "pending" => task.status.yellow(),
-            "suspended" => task.status.blue(),
-            "terminated" => task.status.red(),
-            _ => task.status.normal(),
+        let status = task.status.to_string();
+        let status_colored = match task.status {
+            TaskStatus::Running => status.green(),
+            TaskStatus::AwaitingInput => status.cyan(),
+            TaskStatus::Starting | TaskStatus::Pending => status.yellow(),
+            TaskStatus::Suspended => status.blue(),
+            TaskStatus::Terminated => status.red(),
         };
 
         let created = task
@@ -91,7 +92,13 @@ async fn list_tasks(client: &ApiClient, status: Option<&str>) -> Result<()> {
 
         let ip = task.ip_address.unwrap_or_else(|| "-".to_string());
 
-        println!("{:<38} {:<12} {:<20} {:<16}", task.id, status_colored, created, ip);
+        println!(
+            "{:<38} {:<12} {:<20} {:<16}",
+            task.id.to_string(),
+            status_colored,
+            created,
+            ip
+        );
     }
 
     println!("\n{} total tasks", response.total);
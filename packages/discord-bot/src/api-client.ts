@@ -2,6 +2,8 @@ import type {
   CreateTaskRequest,
   TaskResponse,
   TaskListResponse,
+  PermissionDecision,
+  WsPermissionRequestMessage,
 } from "@lia/shared";
 import { config } from "./config";
 
@@ -83,6 +85,42 @@ export class VmApiClient {
     return response.json();
   }
 
+  async listPermissions(taskId: string): Promise<WsPermissionRequestMessage[]> {
+    const response = await fetch(
+      `${this.baseUrl}/api/v1/tasks/${taskId}/permissions`
+    );
+
+    if (!response.ok) {
+      const error = await response.json().catch(() => ({}));
+      throw new Error(error.error || `API error: ${response.status}`);
+    }
+
+    return response.json();
+  }
+
+  async respondPermission(
+    taskId: string,
+    requestId: string,
+    decision: PermissionDecision,
+    message?: string
+  ): Promise<void> {
+    const response = await fetch(
+      `${this.baseUrl}/api/v1/tasks/${taskId}/permissions/${requestId}`,
+      {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ decision, message }),
+      }
+    );
+
+    if (!response.ok) {
+      const error = await response.json().catch(() => ({}));
+      throw new Error(error.error || `API error: ${response.status}`);
+    }
+  }
+
   async deleteTask(taskId: string): Promise<void> {
     const response = await fetch(`${this.baseUrl}/api/v1/tasks/${taskId}`, {
       method: "DELETE",
@@ -0,0 +1,88 @@
+import {
+  SlashCommandBuilder,
+  EmbedBuilder,
+  type ChatInputCommandInteraction,
+} from "discord.js";
+import type { PermissionDecision } from "@lia/shared";
+import { apiClient } from "../api-client";
+
+export const approve = {
+  data: new SlashCommandBuilder()
+    .setName("approve")
+    .setDescription("Approve or deny a pending tool call of a supervised agent")
+    .addStringOption((option) =>
+      option
+        .setName("task_id")
+        .setDescription("The task ID")
+        .setRequired(true)
+    )
+    .addStringOption((option) =>
+      option
+        .setName("decision")
+        .setDescription("Whether to allow the tool call")
+        .setRequired(true)
+        .addChoices(
+          { name: "Allow", value: "allow" },
+          { name: "Deny", value: "deny" }
+        )
+    )
+    .addStringOption((option) =>
+      option
+        .setName("request_id")
+        .setDescription("The approval request ID (defaults to the oldest pending)")
+        .setRequired(false)
+    ),
+
+  async execute(interaction: ChatInputCommandInteraction) {
+    const taskId = interaction.options.getString("task_id", true);
+    const decision = interaction.options.getString(
+      "decision",
+      true
+    ) as PermissionDecision;
+    let requestId = interaction.options.getString("request_id");
+
+    await interaction.deferReply();
+
+    try {
+      const pending = await apiClient.listPermissions(taskId);
+      const request = requestId
+        ? pending.find((p) => p.request_id === requestId)
+        : pending[0];
+
+      if (!request) {
+        await interaction.editReply({
+          content: "No pending approval request for this task.",
+        });
+        return;
+      }
+      requestId = request.request_id;
+
+      await apiClient.respondPermission(taskId, requestId, decision);
+
+      const input = JSON.stringify(request.input, null, 2);
+      const embed = new EmbedBuilder()
+        .setColor(decision === "allow" ? 0x00ff00 : 0xff0000)
+        .setTitle(decision === "allow" ? "Tool Call Allowed" : "Tool Call Denied")
+        .addFields(
+          { name: "Task ID", value: `\`${taskId}\`` },
+          { name: "Tool", value: `\`${request.tool_name}\``, inline: true },
+          { name: "Request ID", value: `\`${requestId}\``, inline: true },
+          {
+            name: "Input",
+            value: `\`\`\`json\n${input.slice(0, 1000)}\n\`\`\``,
+          }
+        )
+        .setTimestamp();
+
+      await interaction.editReply({
+        embeds: [embed],
+      });
+    } catch (error) {
+      const message =
+        error instanceof Error ? error.message : "Unknown error occurred";
+      await interaction.editReply({
+        content: `Failed to respond to approval request: ${message}`,
+      });
+    }
+  },
+};
@@ -4,5 +4,6 @@ import { status } from "./status";
 import { resume } from "./resume";
 import { stop } from "./stop";
 import { list } from "./list";
+import { approve } from "./approve";
 
-export const commands = [spawn, spawnFile, status, resume, stop, list];
+export const commands = [spawn, spawnFile, status, resume, stop, list, approve];
@@ -53,6 +53,7 @@ function formatStatus(status: string): string {
     pending: "⏳ Pending",
     starting: "🚀 Starting",
     running: "▶️ Running",
+    awaiting_input: "💬 Awaiting Input",
     suspended: "⏸️ Suspended",
     terminated: "⏹️ Terminated",
   };
@@ -110,6 +110,7 @@ function formatStatus(status: string): string {
     pending: "⏳ Pending",
     starting: "🚀 Starting",
     running: "▶️ Running",
+    awaiting_input: "💬 Awaiting Input",
     suspended: "⏸️ Suspended",
     terminated: "⏹️ Terminated",
   };
@@ -121,6 +122,7 @@ function getStatusColor(status: string): number {
     pending: 0xffa500, // Orange
     starting: 0x00bfff, // Blue
     running: 0x00ff00, // Green
+    awaiting_input: 0x00ced1, // Teal
     suspended: 0xffff00, // Yellow
     terminated: 0x808080, // Gray
   };
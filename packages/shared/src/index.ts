@@ -5,6 +5,7 @@ export const TaskStatus = {
   Pending: "pending",
   Starting: "starting",
   Running: "running",
+  AwaitingInput: "awaiting_input",
   Suspended: "suspended",
   Terminated: "terminated",
 } as const;
@@ -27,16 +28,65 @@ export const GitHubRepoSchema = z
     "Repository must be in 'owner/repo' format"
   );
 
+// Tool approval decision (supervised mode)
+export const PermissionDecisionSchema = z.enum(["allow", "deny"]);
+
+export type PermissionDecision = z.infer<typeof PermissionDecisionSchema>;
+
 // Task configuration schema
+// Where a task's VM may connect to
+export const EgressPolicySchema = z.discriminatedUnion("mode", [
+  z.object({ mode: z.literal("deny") }),
+  z.object({
+    mode: z.literal("allowlist"),
+    domains: z.array(z.string()).max(64).optional(),
+    cidrs: z.array(z.string()).max(64).optional(), // IPv4 networks, e.g. "140.82.112.0/20"
+  }),
+  z.object({ mode: z.literal("open") }),
+]);
+
+export type EgressPolicy = z.infer<typeof EgressPolicySchema>;
+
 export const TaskConfigSchema = z.object({
   timeout_minutes: z.number().optional().default(30),
   max_memory_mb: z.number().optional().default(2048),
   vcpu_count: z.number().optional().default(2),
   storage_gb: z.number().optional().default(50),
+  // "supervised" relays every tool call to the user for approval
+  permission_mode: z.enum(["bypass", "supervised"]).optional().default("bypass"),
+  approval_timeout_secs: z.number().optional().default(300),
+  approval_default: PermissionDecisionSchema.optional().default("deny"),
+  // Ports of servers in the VM reachable through preview URLs
+  preview_ports: z.array(z.number().int().min(1).max(65535)).max(16).optional(),
+  // Unset: the guild's or the API's default policy
+  egress: EgressPolicySchema.optional(),
+  // Bandwidth caps in kbit/s and new TCP connections per second; unset: the API's defaults, 0: unlimited
+  upload_kbps: z.number().int().min(0).optional(),
+  download_kbps: z.number().int().min(0).optional(),
+  connections_per_sec: z.number().int().min(0).optional(),
 });
 
 export type TaskConfig = z.infer<typeof TaskConfigSchema>;
 
+// MCP server definition (inline) or reference to a server registered on the API
+export const McpServerConfigSchema = z.object({
+  type: z.enum(["stdio", "sse", "http"]).optional(),
+  command: z.string().optional(),
+  args: z.array(z.string()).optional(),
+  env: z.record(z.string()).optional(),
+  url: z.string().optional(),
+  headers: z.record(z.string()).optional(),
+});
+
+export type McpServerConfig = z.infer<typeof McpServerConfigSchema>;
+
+export const McpServerRequestSchema = z.object({
+  name: z.string().regex(/^[a-zA-Z0-9_-]{1,64}$/),
+  config: McpServerConfigSchema.optional(),
+});
+
+export type McpServerRequest = z.infer<typeof McpServerRequestSchema>;
+
 // Task creation request
 export const CreateTaskRequestSchema = z.object({
   prompt: z.string().min(1).max(100000),
@@ -54,6 +104,7 @@ export const CreateTaskRequestSchema = z.object({
     )
     .optional(),
   ssh_public_key: z.string().optional(),
+  mcp_servers: z.array(McpServerRequestSchema).optional(),
 });
 
 export type CreateTaskRequest = z.infer<typeof CreateTaskRequestSchema>;
@@ -63,7 +114,7 @@ export const TaskResponseSchema = z.object({
   id: z.string().uuid(),
   user_id: z.string(),
   guild_id: z.string().nullable(),
-  status: z.enum(["pending", "starting", "running", "suspended", "terminated"]),
+  status: z.enum(["pending", "starting", "running", "awaiting_input", "suspended", "terminated"]),
   source: z.enum(["discord", "web"]),
   repositories: z.array(z.string()),
   vm_id: z.string().nullable(),
@@ -76,10 +127,85 @@ export const TaskResponseSchema = z.object({
   web_url: z.string().url().optional(),
   ssh_command: z.string().nullable().optional(),
   ip_address: z.string().nullable().optional(),
+  healthy: z.boolean().optional(), // false while the VM misses heartbeats
+  sidecar_version: z.string().nullable().optional(),
+  workspace_bytes: z.number().nullable().optional(), // size of the data volume at /workspace
+  preview_token: z.string().optional(), // secret of the task's preview URLs
 });
 
 export type TaskResponse = z.infer<typeof TaskResponseSchema>;
 
+// Network usage of a task's VM (GET /api/v1/tasks/:id/stats); limits of 0 are unlimited
+export const TrafficStatsSchema = z.object({
+  bytes: z.number(),
+  packets: z.number(),
+  dropped: z.number(), // packets dropped by the limits
+  rate_kbps: z.number(),
+});
+
+export const TaskStatsResponseSchema = z.object({
+  task_id: z.string().uuid(),
+  network: z.object({
+    limits: z.object({
+      upload_kbps: z.number(),
+      download_kbps: z.number(),
+      connections_per_sec: z.number(),
+    }),
+    upload: TrafficStatsSchema, // from the VM
+    download: TrafficStatsSchema, // to the VM
+  }),
+  disk: z.object({
+    rootfs_bytes: z.number(), // changed blocks only, not the shared base image
+    data_bytes: z.number(),
+  }),
+});
+
+export type TaskStatsResponse = z.infer<typeof TaskStatsResponseSchema>;
+
+// Connections of a task's VM through the egress proxy (GET /api/v1/tasks/:id/network), newest first
+export const NetworkConnectionSchema = z.object({
+  id: z.number(),
+  host: z.string(),
+  port: z.number(),
+  method: z.string(), // "CONNECT" for HTTPS tunnels
+  allowed: z.boolean(), // false when the egress policy refused the host
+  bytes_up: z.number(), // sent by the VM
+  bytes_down: z.number(), // received by the VM
+  started_at: z.string().datetime(),
+  duration_ms: z.number(),
+  error: z.string().nullable(),
+});
+
+export const NetworkLogResponseSchema = z.object({
+  task_id: z.string().uuid(),
+  connections: z.array(NetworkConnectionSchema),
+  total: z.number(),
+  page: z.number(),
+  per_page: z.number(),
+});
+
+export type NetworkConnection = z.infer<typeof NetworkConnectionSchema>;
+export type NetworkLogResponse = z.infer<typeof NetworkLogResponseSchema>;
+
+// SSH keys users log in to the SSH gateway with
+export const AddSshKeyRequestSchema = z.object({
+  public_key: z.string().min(1), // authorized_keys line, e.g. the contents of ~/.ssh/id_ed25519.pub
+});
+
+export type AddSshKeyRequest = z.infer<typeof AddSshKeyRequestSchema>;
+
+export const SshKeyResponseSchema = z.object({
+  id: z.string().uuid(),
+  user_id: z.string(),
+  key_type: z.string(),
+  public_key: z.string(),
+  comment: z.string().nullable(),
+  fingerprint: z.string(), // SHA256:..., as printed by ssh-keygen -lf
+  created_at: z.string().datetime(),
+});
+
+export type SshKeyResponse = z.infer<typeof SshKeyResponseSchema>;
+
 // VM boot progress stages (verbose for vm-api, simplified for display)
 export const BootStage = {
   CreatingVm: "creating_vm",
@@ -111,6 +237,12 @@ export const WsMessageType = {
   Status: "status",
   Progress: "progress",
   Error: "error",
+  PermissionRequest: "permission_request",
+  PermissionResponse: "permission_response",
+  PermissionResolved: "permission_resolved",
+  Interrupt: "interrupt",
+  TurnCancelled: "turn_cancelled",
+  Health: "health",
   Ping: "ping",
   Pong: "pong",
 } as const;
@@ -131,7 +263,7 @@ export const WsInputMessageSchema = z.object({
 
 export const WsStatusMessageSchema = z.object({
   type: z.literal("status"),
-  status: z.enum(["pending", "starting", "running", "suspended", "terminated"]),
+  status: z.enum(["pending", "starting", "running", "awaiting_input", "suspended", "terminated"]),
   exit_code: z.number().nullable().optional(),
 });
 
@@ -154,6 +286,58 @@ export const WsErrorMessageSchema = z.object({
   message: z.string(),
 });
 
+// Tool approval waiting on the user (server -> client)
+export const WsPermissionRequestMessageSchema = z.object({
+  type: z.literal("permission_request"),
+  request_id: z.string(),
+  tool_name: z.string(),
+  input: z.unknown(),
+  expires_at: z.number(), // Unix millis, after which the default decision applies
+});
+
+// User's answer to a permission request (client -> server)
+export const WsPermissionResponseMessageSchema = z.object({
+  type: z.literal("permission_response"),
+  request_id: z.string(),
+  decision: PermissionDecisionSchema,
+  message: z.string().optional(),
+});
+
+// Permission request answered or timed out (server -> client)
+export const WsPermissionResolvedMessageSchema = z.object({
+  type: z.literal("permission_resolved"),
+  request_id: z.string(),
+  decision: PermissionDecisionSchema,
+  timed_out: z.boolean(),
+});
+
+// Cancel the agent's current turn (client -> server)
+export const WsInterruptMessageSchema = z.object({
+  type: z.literal("interrupt"),
+});
+
+// Interrupted turn ended, agent waits for input (server -> client)
+export const WsTurnCancelledMessageSchema = z.object({
+  type: z.literal("turn_cancelled"),
+});
+
+// Guest resource usage reported with sidecar heartbeats
+export const GuestStatsSchema = z.object({
+  uptime_secs: z.number(),
+  load_avg: z.number(), // 1-minute load average
+  mem_total_mb: z.number(),
+  mem_available_mb: z.number(),
+});
+
+export type GuestStats = z.infer<typeof GuestStatsSchema>;
+
+// VM liveness changed or new guest stats arrived (server -> client)
+export const WsHealthMessageSchema = z.object({
+  type: z.literal("health"),
+  healthy: z.boolean(),
+  stats: GuestStatsSchema.nullable().optional(),
+});
+
 export const WsPingMessageSchema = z.object({
   type: z.literal("ping"),
 });
@@ -168,6 +352,12 @@ export const WsMessageSchema = z.discriminatedUnion("type", [
   WsStatusMessageSchema,
   WsProgressMessageSchema,
   WsErrorMessageSchema,
+  WsPermissionRequestMessageSchema,
+  WsPermissionResponseMessageSchema,
+  WsPermissionResolvedMessageSchema,
+  WsInterruptMessageSchema,
+  WsTurnCancelledMessageSchema,
+  WsHealthMessageSchema,
   WsPingMessageSchema,
   WsPongMessageSchema,
 ]);
@@ -178,6 +368,12 @@ export type WsInputMessage = z.infer<typeof WsInputMessageSchema>;
 export type WsStatusMessage = z.infer<typeof WsStatusMessageSchema>;
 export type WsProgressMessage = z.infer<typeof WsProgressMessageSchema>;
 export type WsErrorMessage = z.infer<typeof WsErrorMessageSchema>;
+export type WsPermissionRequestMessage = z.infer<typeof WsPermissionRequestMessageSchema>;
+export type WsPermissionResponseMessage = z.infer<typeof WsPermissionResponseMessageSchema>;
+export type WsPermissionResolvedMessage = z.infer<typeof WsPermissionResolvedMessageSchema>;
+export type WsInterruptMessage = z.infer<typeof WsInterruptMessageSchema>;
+export type WsTurnCancelledMessage = z.infer<typeof WsTurnCancelledMessageSchema>;
+export type WsHealthMessage = z.infer<typeof WsHealthMessageSchema>;
 
 // API error response
 export const ApiErrorSchema = z.object({
@@ -215,6 +411,7 @@ export interface VsockInitMessage {
   api_key: string;
   prompt: string;
   files?: Array<{ name: string; content: string }>;
+  mcp_servers?: Record<string, McpServerConfig>;
 }
 
 export interface VsockOutputMessage {
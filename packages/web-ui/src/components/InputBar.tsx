@@ -2,13 +2,15 @@ import { useState, useRef, useEffect } from "react";
 import { useTaskStore } from "../store";
 import { Button } from "@/components/ui/button";
 import { Textarea } from "@/components/ui/textarea";
-import { Send } from "lucide-react";
+import { Send, Square } from "lucide-react";
 import { cn } from "@/lib/utils";
 
 export function InputBar() {
   const [input, setInput] = useState("");
   const inputRef = useRef<HTMLTextAreaElement>(null);
   const sendInput = useTaskStore((state) => state.sendInput);
+  const interrupt = useTaskStore((state) => state.interrupt);
+  const turnActive = useTaskStore((state) => state.turnActive);
   const task = useTaskStore((state) => state.task);
   const isScrolledToBottom = useTaskStore((state) => state.isScrolledToBottom);
 
@@ -52,11 +54,19 @@ export function InputBar() {
           placeholder={
             isDisabled
               ? "Resume the session to send input..."
-              : "Send a follow-up prompt... (Enter to send, Shift+Enter for newline)"
+              : task?.status === "awaiting_input"
+                ? "Claude has exited. Send a prompt to resume the session..."
+                : "Send a follow-up prompt... (Enter to send, Shift+Enter for newline)"
           }
           className="flex-1 resize-none min-h-[40px] max-h-[120px]"
           rows={1}
         />
+        {turnActive && (
+          <Button type="button" variant="outline" onClick={interrupt} disabled={isDisabled}>
+            <Square className="h-4 w-4 mr-2" />
+            Stop
+          </Button>
+        )}
         <Button
           type="submit"
           disabled={isDisabled || !input.trim()}
@@ -39,6 +39,7 @@ export function Layout() {
   function getStatusVariant(status: string) {
     switch (status) {
       case "running":
+      case "awaiting_input":
         return "running";
       case "pending":
       case "starting":
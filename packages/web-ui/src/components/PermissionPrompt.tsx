@@ -0,0 +1,60 @@
+import { useEffect, useState } from "react";
+import { useTaskStore } from "../store";
+import { Button } from "@/components/ui/button";
+import { ShieldQuestion } from "lucide-react";
+
+function formatRemaining(expiresAt: number, now: number): string {
+  const seconds = Math.max(0, Math.round((expiresAt - now) / 1000));
+  const minutes = Math.floor(seconds / 60);
+  return minutes > 0 ? `${minutes}m ${seconds % 60}s` : `${seconds}s`;
+}
+
+export function PermissionPrompt() {
+  const pendingPermissions = useTaskStore((state) => state.pendingPermissions);
+  const respondPermission = useTaskStore((state) => state.respondPermission);
+  const [now, setNow] = useState(Date.now());
+
+  // Tick once a second so the countdown stays current
+  useEffect(() => {
+    if (pendingPermissions.length === 0) return;
+    const timer = setInterval(() => setNow(Date.now()), 1000);
+    return () => clearInterval(timer);
+  }, [pendingPermissions.length]);
+
+  if (pendingPermissions.length === 0) {
+    return null;
+  }
+
+  return (
+    <div className="border-t bg-muted/40 p-4 space-y-3">
+      {pendingPermissions.map((request) => (
+        <div key={request.request_id} className="rounded-md border bg-background p-3">
+          <div className="flex items-center justify-between gap-2">
+            <div className="flex items-center gap-2 text-sm font-medium">
+              <ShieldQuestion className="h-4 w-4" />
+              Claude wants to use <code>{request.tool_name}</code>
+            </div>
+            <span className="text-xs text-muted-foreground">
+              {formatRemaining(request.expires_at, now)} left
+            </span>
+          </div>
+          <pre className="mt-2 max-h-40 overflow-auto rounded bg-muted p-2 text-xs">
+            {JSON.stringify(request.input, null, 2)}
+          </pre>
+          <div className="mt-2 flex justify-end gap-2">
+            <Button
+              size="sm"
+              variant="outline"
+              onClick={() => respondPermission(request.request_id, "deny")}
+            >
+              Deny
+            </Button>
+            <Button size="sm" onClick={() => respondPermission(request.request_id, "allow")}>
+              Approve
+            </Button>
+          </div>
+        </div>
+      ))}
+    </div>
+  );
+}
@@ -85,6 +85,9 @@ export function TaskHeader({ task }: TaskHeaderProps) {
           <h1 className="text-lg font-semibold text-foreground">Lia</h1>
           <div className="flex items-center gap-2">
             <StatusBadge status={task.status} />
+            {task.healthy === false && (
+              <Badge variant="terminated">Unresponsive</Badge>
+            )}
             <span className="text-sm text-muted-foreground font-mono">
               {task.id}
             </span>
@@ -103,7 +106,9 @@ export function TaskHeader({ task }: TaskHeaderProps) {
             </Button>
           )}
 
-          {(task.status === "running" || task.status === "suspended") && (
+          {(task.status === "running" ||
+            task.status === "awaiting_input" ||
+            task.status === "suspended") && (
             <DropdownMenu>
               <DropdownMenuTrigger asChild>
                 <Button variant="ghost" size="sm" className="h-8 w-8 p-0 data-[state=open]:bg-accent data-[state=open]:opacity-70">
@@ -156,6 +161,7 @@ function StatusBadge({ status }: { status: string }) {
     pending: { variant: "pending", label: "Pending" },
     starting: { variant: "pending", label: "Starting" },
     running: { variant: "running", label: "Running" },
+    awaiting_input: { variant: "running", label: "Awaiting Input" },
     suspended: { variant: "suspended", label: "Suspended" },
     terminated: { variant: "terminated", label: "Terminated" },
   };
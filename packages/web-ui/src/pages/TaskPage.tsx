@@ -3,6 +3,7 @@ import { useParams, useNavigate } from "react-router-dom";
 import { MessageList } from "../components/messages";
 import { TaskHeader } from "../components/TaskHeader";
 import { InputBar } from "../components/InputBar";
+import { PermissionPrompt } from "../components/PermissionPrompt";
 import { useTaskStore } from "../store";
 import { getTask, createWebSocket } from "../api";
 import type { WsMessage } from "@lia/shared";
@@ -64,6 +65,10 @@ export function TaskPage() {
                 if (store.task) {
                   store.setTask({ ...store.task, status: msg.status });
                 }
+                // Claude exited, so no turn is running anymore
+                if (msg.status === "awaiting_input") {
+                  store.cancelTurn();
+                }
                 break;
               case "progress":
                 // Update boot progress
@@ -79,6 +84,20 @@ export function TaskPage() {
               case "error":
                 store.setError(msg.message);
                 break;
+              case "permission_request":
+                store.addPermissionRequest(msg);
+                break;
+              case "permission_resolved":
+                store.removePermissionRequest(msg.request_id);
+                break;
+              case "turn_cancelled":
+                store.cancelTurn();
+                break;
+              case "health":
+                if (store.task && store.task.healthy !== msg.healthy) {
+                  store.setTask({ ...store.task, healthy: msg.healthy });
+                }
+                break;
             }
           } catch {
             console.error("Failed to parse WebSocket message");
@@ -163,7 +182,11 @@ export function TaskPage() {
         <MessageList />
       </div>
 
-      {(task.status === "running" || task.status === "suspended") && (
+      <PermissionPrompt />
+
+      {(task.status === "running" ||
+        task.status === "awaiting_input" ||
+        task.status === "suspended") && (
         <InputBar />
       )}
     </div>
@@ -1,5 +1,13 @@
 import { create } from "zustand";
-import type { TaskResponse, WsInputMessage, BootStage } from "@lia/shared";
+import type {
+  TaskResponse,
+  WsInputMessage,
+  BootStage,
+  PermissionDecision,
+  WsPermissionRequestMessage,
+  WsPermissionResponseMessage,
+  WsInterruptMessage,
+} from "@lia/shared";
 import type { ParsedMessage } from "./types/claude-stream";
 import { MessageParser } from "./lib/message-parser";
 
@@ -13,6 +21,8 @@ interface TaskState {
   isScrolledToBottom: boolean;
   bootStage: BootStage | null;
   bootMessage: string | null;
+  pendingPermissions: WsPermissionRequestMessage[];
+  turnActive: boolean;
 
   setTask: (task: TaskResponse) => void;
   setConnectionStatus: (status: TaskState["connectionStatus"]) => void;
@@ -22,6 +32,11 @@ interface TaskState {
   sendInput: (data: string) => void;
   setIsScrolledToBottom: (value: boolean) => void;
   setBootProgress: (stage: BootStage, message: string) => void;
+  addPermissionRequest: (request: WsPermissionRequestMessage) => void;
+  removePermissionRequest: (requestId: string) => void;
+  respondPermission: (requestId: string, decision: PermissionDecision) => void;
+  interrupt: () => void;
+  cancelTurn: () => void;
   reset: () => void;
 }
 
@@ -35,6 +50,8 @@ export const useTaskStore = create<TaskState>((set, get) => ({
   isScrolledToBottom: true,
   bootStage: null,
   bootMessage: null,
+  pendingPermissions: [],
+  turnActive: false,
 
   setTask: (task) => set({ task }),
   setConnectionStatus: (connectionStatus) => set({ connectionStatus }),
@@ -46,12 +63,55 @@ export const useTaskStore = create<TaskState>((set, get) => ({
     const { parser } = get();
     // Parse the JSON line and update messages
     parser.parseMessage(data);
+    // A turn runs from the first event after a prompt until its result
+    let turnActive = get().turnActive;
+    try {
+      turnActive = JSON.parse(data).type !== "result";
+    } catch {
+      // Non-JSON output (stderr) doesn't change the turn state
+    }
     // Update messages array (creates new reference for React)
-    set({ messages: parser.getMessages() });
+    set({ messages: parser.getMessages(), turnActive });
   },
 
   setWebSocket: (ws) => set({ ws }),
 
+  addPermissionRequest: (request) =>
+    set((state) => ({
+      pendingPermissions: [
+        ...state.pendingPermissions.filter((p) => p.request_id !== request.request_id),
+        request,
+      ],
+    })),
+
+  removePermissionRequest: (requestId) =>
+    set((state) => ({
+      pendingPermissions: state.pendingPermissions.filter((p) => p.request_id !== requestId),
+    })),
+
+  respondPermission: (requestId, decision) => {
+    const { ws } = get();
+    if (ws && ws.readyState === WebSocket.OPEN) {
+      const msg: WsPermissionResponseMessage = {
+        type: "permission_response",
+        request_id: requestId,
+        decision,
+      };
+      ws.send(JSON.stringify(msg));
+    }
+  },
+
+  interrupt: () => {
+    const { ws } = get();
+    if (ws && ws.readyState === WebSocket.OPEN) {
+      const msg: WsInterruptMessage = { type: "interrupt" };
+      ws.send(JSON.stringify(msg));
+    }
+  },
+
+  // Pending approvals belong to the cancelled turn and are dropped with it
+  cancelTurn: () => set({ turnActive: false, pendingPermissions: [] }),
+
   sendInput: (data) => {
     const { ws, parser } = get();
     if (ws && ws.readyState === WebSocket.OPEN) {
@@ -59,7 +119,7 @@ export const useTaskStore = create<TaskState>((set, get) => ({
       ws.send(JSON.stringify(msg));
       // Add user input to messages
       parser.addUserInput(data.trim());
-      set({ messages: parser.getMessages() });
+      set({ messages: parser.getMessages(), turnActive: true });
     }
   },
 
@@ -75,6 +135,8 @@ export const useTaskStore = create<TaskState>((set, get) => ({
       isScrolledToBottom: true,
       bootStage: null,
       bootMessage: null,
+      pendingPermissions: [],
+      turnActive: false,
     });
   },
 }));
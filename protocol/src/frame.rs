@@ -0,0 +1,319 @@
+//! Length-prefixed binary framing for the vsock connection.
+//!
+//! Hellos are always JSON lines. If both hellos list the `framed` feature,
+//! everything after them is sent as frames:
+//!
+//! ```text
+//! [len: u32 big-endian][flags: u8][body: len bytes]
+//! ```
+//!
+//! The low bits of `flags` give the frame kind. `Output` messages travel as
+//! raw bytes (an 8-byte sequence number followed by the data) instead of
+//! being escaped into a JSON string. Messages with a binary `data` field
+//! (file and archive chunks, terminal I/O) are a JSON header without the
+//! data, then the bytes themselves, instead of base64. Every other message
+//! is its JSON encoding. With `zstd` negotiated as well, bodies above
+//! `COMPRESS_THRESHOLD` bytes are compressed and flagged with `FLAG_ZSTD`.
+
+use std::io::{self, Read};
+
+use crate::vsock::{Sequenced, VsockMessage};
+
+/// Feature announced by peers that can send and receive frames
+pub const FEATURE_FRAMED: &str = "framed";
+
+/// Feature announced by peers built with zstd support
+pub const FEATURE_ZSTD: &str = "zstd";
+
+/// Length prefix plus flags byte
+pub const HEADER_LEN: usize = 5;
+
+/// Largest frame body accepted from a peer
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Smaller bodies are not worth compressing
+const COMPRESS_THRESHOLD: usize = 512;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Body is a JSON-encoded `Sequenced<VsockMessage>`
+const KIND_MESSAGE: u8 = 0;
+/// Body is a sequence number and raw output bytes
+const KIND_OUTPUT: u8 = 1;
+/// Body is the length of a JSON header, the header (the message with an
+/// empty `data`) and the raw `data`
+const KIND_PAYLOAD: u8 = 2;
+const KIND_MASK: u8 = 0x0f;
+
+/// Body is zstd-compressed
+pub const FLAG_ZSTD: u8 = 0x80;
+
+/// Wire encoding of one connection, fixed by the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Newline-delimited JSON
+    #[default]
+    Lines,
+    /// Length-prefixed frames, optionally compressed
+    Framed { zstd: bool },
+}
+
+impl Transport {
+    /// Pick the encoding both peers support from the features in their hellos
+    pub fn negotiate(ours: &[String], theirs: &[String]) -> Self {
+        let both = |feature: &str| {
+            ours.iter().any(|f| f == feature) && theirs.iter().any(|f| f == feature)
+        };
+        if !both(FEATURE_FRAMED) {
+            return Transport::Lines;
+        }
+        Transport::Framed {
+            zstd: cfg!(feature = "zstd") && both(FEATURE_ZSTD),
+        }
+    }
+
+    /// Encode a message (with its sequence number, if any) for the wire
+    pub fn encode(&self, seq: Option<u64>, msg: &VsockMessage) -> io::Result<Vec<u8>> {
+        match self {
+            Transport::Lines => {
+                let mut line = serde_json::to_vec(&Sequenced { seq, msg })?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            Transport::Framed { zstd } => encode_frame(seq, msg, *zstd),
+        }
+    }
+}
+
+/// Transport features this build supports, for the hello
+pub fn transport_features() -> Vec<&'static str> {
+    let mut features = vec![FEATURE_FRAMED];
+    if cfg!(feature = "zstd") {
+        features.push(FEATURE_ZSTD);
+    }
+    features
+}
+
+/// Build one frame. `compress` only applies to bodies above the threshold.
+pub fn encode_frame(seq: Option<u64>, msg: &VsockMessage, compress: bool) -> io::Result<Vec<u8>> {
+    let (kind, body) = match msg {
+        VsockMessage::Output { data } => {
+            let mut body = Vec::with_capacity(8 + data.len());
+            body.extend_from_slice(&seq.unwrap_or_default().to_be_bytes());
+            body.extend_from_slice(data.as_bytes());
+            (KIND_OUTPUT, body)
+        }
+        msg => match split_payload(msg) {
+            Some((header, data)) => {
+                let header = serde_json::to_vec(&Sequenced { seq, msg: &header })?;
+                let mut body = Vec::with_capacity(4 + header.len() + data.len());
+                body.extend_from_slice(&(header.len() as u32).to_be_bytes());
+                body.extend_from_slice(&header);
+                body.extend_from_slice(data);
+                (KIND_PAYLOAD, body)
+            }
+            None => (KIND_MESSAGE, serde_json::to_vec(&Sequenced { seq, msg })?),
+        },
+    };
+
+    let compress = compress && cfg!(feature = "zstd") && body.len() > COMPRESS_THRESHOLD;
+    let (flags, body) = if compress {
+        (kind | FLAG_ZSTD, compress_body(&body)?)
+    } else {
+        (kind, body)
+    };
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the limit", body.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.push(flags);
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Split a frame header into flags and body length
+pub fn parse_header(header: &[u8; HEADER_LEN]) -> io::Result<(u8, usize)> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the limit", len),
+        ));
+    }
+    Ok((header[4], len))
+}
+
+/// Decode a frame body. A sequence number of 0 in an output frame means none.
+pub fn decode_frame(flags: u8, body: &[u8]) -> io::Result<Sequenced<VsockMessage>> {
+    let decompressed;
+    let body = if flags & FLAG_ZSTD != 0 {
+        decompressed = decompress_body(body)?;
+        &decompressed[..]
+    } else {
+        body
+    };
+
+    match flags & KIND_MASK {
+        KIND_MESSAGE => Ok(serde_json::from_slice(body)?),
+        KIND_OUTPUT => {
+            if body.len() < 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "output frame shorter than its sequence number",
+                ));
+            }
+            let (seq, data) = body.split_at(8);
+            let seq = u64::from_be_bytes(seq.try_into().expect("8-byte slice"));
+            Ok(Sequenced {
+                seq: (seq != 0).then_some(seq),
+                msg: VsockMessage::Output {
+                    data: String::from_utf8_lossy(data).into_owned(),
+                },
+            })
+        }
+        KIND_PAYLOAD => {
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+            if body.len() < 4 {
+                return Err(invalid("payload frame shorter than its header length"));
+            }
+            let (len, rest) = body.split_at(4);
+            let len = u32::from_be_bytes(len.try_into().expect("4-byte slice")) as usize;
+            if rest.len() < len {
+                return Err(invalid("payload frame shorter than its header"));
+            }
+            let (header, data) = rest.split_at(len);
+            let mut sequenced: Sequenced<VsockMessage> = serde_json::from_slice(header)?;
+            match payload_mut(&mut sequenced.msg) {
+                Some(payload) => *payload = data.to_vec(),
+                None => return Err(invalid("payload frame for a message without data")),
+            }
+            Ok(sequenced)
+        }
+        kind => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown frame kind {}", kind),
+        )),
+    }
+}
+
+/// A message with a binary payload, as the same message with an empty
+/// `data`, and the payload
+fn split_payload(msg: &VsockMessage) -> Option<(VsockMessage, &[u8])> {
+    let (header, data) = match msg {
+        VsockMessage::FileWrite {
+            request_id,
+            path,
+            offset,
+            data,
+            done,
+        } => (
+            VsockMessage::FileWrite {
+                request_id: request_id.clone(),
+                path: path.clone(),
+                offset: *offset,
+                data: Vec::new(),
+                done: *done,
+            },
+            data,
+        ),
+        VsockMessage::FileData {
+            request_id,
+            offset,
+            data,
+            size,
+        } => (
+            VsockMessage::FileData {
+                request_id: request_id.clone(),
+                offset: *offset,
+                data: Vec::new(),
+                size: *size,
+            },
+            data,
+        ),
+        VsockMessage::ArchiveData {
+            request_id,
+            data,
+            done,
+        } => (
+            VsockMessage::ArchiveData {
+                request_id: request_id.clone(),
+                data: Vec::new(),
+                done: *done,
+            },
+            data,
+        ),
+        VsockMessage::PtyInput { request_id, data } => (
+            VsockMessage::PtyInput {
+                request_id: request_id.clone(),
+                data: Vec::new(),
+            },
+            data,
+        ),
+        VsockMessage::PtyOutput { request_id, data } => (
+            VsockMessage::PtyOutput {
+                request_id: request_id.clone(),
+                data: Vec::new(),
+            },
+            data,
+        ),
+        _ => return None,
+    };
+    Some((header, data.as_slice()))
+}
+
+/// Where a payload frame's bytes go in the message decoded from its header
+fn payload_mut(msg: &mut VsockMessage) -> Option<&mut Vec<u8>> {
+    match msg {
+        VsockMessage::FileWrite { data, .. }
+        | VsockMessage::FileData { data, .. }
+        | VsockMessage::ArchiveData { data, .. }
+        | VsockMessage::PtyInput { data, .. }
+        | VsockMessage::PtyOutput { data, .. } => Some(data),
+        _ => None,
+    }
+}
+
+/// Read one frame. Returns None on a clean EOF before the header.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Sequenced<VsockMessage>>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let (flags, len) = parse_header(&header)?;
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    decode_frame(flags, &body).map(Some)
+}
+
+#[cfg(feature = "zstd")]
+fn compress_body(body: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::bulk::compress(body, ZSTD_LEVEL)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_body(_body: &[u8]) -> io::Result<Vec<u8>> {
+    Err(zstd_missing())
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_body(body: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::bulk::decompress(body, MAX_FRAME_LEN)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_body(_body: &[u8]) -> io::Result<Vec<u8>> {
+    Err(zstd_missing())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_missing() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "zstd support is not compiled in")
+}
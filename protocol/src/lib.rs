@@ -0,0 +1,18 @@
+//! Types shared by the API, the agent sidecar and the CLI.
+//!
+//! `vsock` is the host <-> sidecar wire protocol and has no dependencies
+//! beyond serde (and base64 for file data). `task` holds the REST task types
+//! and is behind the `task` feature (on by default); the sidecar builds
+//! without it. `frame` is the optional length-prefixed encoding of the vsock
+//! connection, with zstd compression behind the `zstd` feature.
+
+pub mod frame;
+pub mod vsock;
+
+#[cfg(feature = "task")]
+pub mod task;
+
+pub use vsock::*;
+
+#[cfg(feature = "task")]
+pub use task::*;
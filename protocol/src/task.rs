@@ -0,0 +1,320 @@
+//! Task types of the REST API
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::vsock::{PermissionDecision, PermissionPolicy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type))]
+#[cfg_attr(feature = "sqlx", sqlx(type_name = "VARCHAR", rename_all = "lowercase"))]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Pending,
+    Starting,
+    Running,
+    /// VM is up but Claude exited; the next input resumes the session
+    #[serde(rename = "awaiting_input")]
+    #[cfg_attr(feature = "sqlx", sqlx(rename = "awaiting_input"))]
+    AwaitingInput,
+    Suspended,
+    Terminated,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type))]
+#[cfg_attr(feature = "sqlx", sqlx(type_name = "VARCHAR", rename_all = "lowercase"))]
+#[serde(rename_all = "lowercase")]
+pub enum TaskSource {
+    Discord,
+    #[default]
+    Web,
+}
+
+impl std::fmt::Display for TaskSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskSource::Discord => write!(f, "discord"),
+            TaskSource::Web => write!(f, "web"),
+        }
+    }
+}
+
+impl std::fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskStatus::Pending => write!(f, "pending"),
+            TaskStatus::Starting => write!(f, "starting"),
+            TaskStatus::Running => write!(f, "running"),
+            TaskStatus::AwaitingInput => write!(f, "awaiting_input"),
+            TaskStatus::Suspended => write!(f, "suspended"),
+            TaskStatus::Terminated => write!(f, "terminated"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionMode {
+    /// Run Claude with --dangerously-skip-permissions
+    #[default]
+    Bypass,
+    /// Relay every tool approval to the user and wait for a decision
+    Supervised,
+}
+
+/// Where a task's VM may open connections to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum EgressPolicy {
+    /// Nothing but the API's always-allowed hosts (the Anthropic API)
+    Deny,
+    /// Those hosts, DNS, and these domains and IPv4 networks
+    Allowlist {
+        #[serde(default)]
+        domains: Vec<String>,
+        /// e.g. "140.82.112.0/20" or a single address
+        #[serde(default)]
+        cidrs: Vec<String>,
+    },
+    /// Anywhere, including the host's network
+    #[default]
+    Open,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskConfig {
+    #[serde(default = "default_timeout")]
+    pub timeout_minutes: u32,
+    #[serde(default = "default_memory")]
+    pub max_memory_mb: u32,
+    #[serde(default = "default_vcpu")]
+    pub vcpu_count: u32,
+    #[serde(default = "default_storage")]
+    pub storage_gb: u32,
+    /// Whether tool calls are auto-approved or relayed to the user
+    #[serde(default)]
+    pub permission_mode: PermissionMode,
+    /// How long a tool approval waits for the user in supervised mode
+    #[serde(default = "default_approval_timeout")]
+    pub approval_timeout_secs: u32,
+    /// Decision applied when an approval times out
+    #[serde(default)]
+    pub approval_default: PermissionDecision,
+    /// Ports of servers in the VM that preview URLs may reach
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preview_ports: Vec<u16>,
+    /// Outbound network policy; the guild's or the global default when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<EgressPolicy>,
+    /// Bandwidth cap from the VM, in kbit/s; the API's default when unset, 0 for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_kbps: Option<u32>,
+    /// Bandwidth cap to the VM, in kbit/s; the API's default when unset, 0 for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_kbps: Option<u32>,
+    /// New TCP connections the VM may open per second; the API's default when unset, 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connections_per_sec: Option<u32>,
+}
+
+impl TaskConfig {
+    /// Approval policy for the sidecar, or None when tool calls are auto-approved
+    pub fn permission_policy(&self) -> Option<PermissionPolicy> {
+        match self.permission_mode {
+            PermissionMode::Bypass => None,
+            PermissionMode::Supervised => Some(PermissionPolicy {
+                timeout_secs: self.approval_timeout_secs,
+                default_decision: self.approval_default,
+            }),
+        }
+    }
+}
+
+fn default_timeout() -> u32 {
+    30
+}
+fn default_memory() -> u32 {
+    2048
+}
+fn default_vcpu() -> u32 {
+    2
+}
+fn default_storage() -> u32 {
+    50
+}
+fn default_approval_timeout() -> u32 {
+    300
+}
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        Self {
+            timeout_minutes: default_timeout(),
+            max_memory_mb: default_memory(),
+            vcpu_count: default_vcpu(),
+            storage_gb: default_storage(),
+            permission_mode: PermissionMode::default(),
+            approval_timeout_secs: default_approval_timeout(),
+            approval_default: PermissionDecision::default(),
+            preview_ports: Vec::new(),
+            egress: None,
+            upload_kbps: None,
+            download_kbps: None,
+            connections_per_sec: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskResponse {
+    pub id: Uuid,
+    pub user_id: String,
+    pub guild_id: Option<String>,
+    pub status: TaskStatus,
+    pub source: TaskSource,
+    pub repositories: Vec<String>,
+    pub vm_id: Option<String>,
+    pub config: Option<TaskConfig>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    pub error_message: Option<String>,
+    pub web_url: String,
+    /// SSH connection info (e.g., "ssh root@172.16.0.100")
+    pub ssh_command: Option<String>,
+    /// IP address of the VM
+    pub ip_address: Option<String>,
+    /// False when the VM stopped sending heartbeats
+    pub healthy: bool,
+    /// Build version of the agent sidecar in the VM
+    pub sidecar_version: Option<String>,
+    /// Size of the data volume mounted at /workspace, as the sidecar reported
+    /// it; None until then, or when /workspace is not on the data volume
+    pub workspace_bytes: Option<u64>,
+    /// Token for the task's preview URLs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskListResponse {
+    pub tasks: Vec<TaskResponse>,
+    pub total: i64,
+    pub page: u32,
+    pub per_page: u32,
+}
+
+/// Text frames of the terminal WebSocket; the shell's input and output go in
+/// binary frames
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TerminalMessage {
+    /// Client -> server: the terminal was resized
+    Resize { cols: u16, rows: u16 },
+    /// Server -> client: the shell ended
+    Exit { code: Option<i32>, signal: Option<i32> },
+    /// Server -> client: the shell could not be started or was lost
+    Error { message: String },
+}
+
+/// Public key a user logs in to the SSH gateway with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshKeyResponse {
+    pub id: Uuid,
+    pub user_id: String,
+    /// Algorithm, e.g. "ssh-ed25519"
+    pub key_type: String,
+    /// Base64 key blob, as in an authorized_keys line
+    pub public_key: String,
+    pub comment: Option<String>,
+    /// OpenSSH SHA256 fingerprint, e.g. "SHA256:OMaRChZIDHUrVGGKxKMN1ZnEjuw742dnp5MuddTB4Pw"
+    pub fingerprint: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body for adding an SSH key to a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddSshKeyRequest {
+    /// authorized_keys line: type, base64 blob and an optional comment
+    pub public_key: String,
+}
+
+/// Network limits in effect for a VM; 0 means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkLimits {
+    pub upload_kbps: u32,
+    pub download_kbps: u32,
+    pub connections_per_sec: u32,
+}
+
+/// Traffic in one direction of a VM's network interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    /// Totals since the VM started
+    pub bytes: u64,
+    pub packets: u64,
+    /// Packets dropped by the limits
+    pub dropped: u64,
+    /// Current throughput
+    pub rate_kbps: f64,
+}
+
+/// Network usage of a task's VM
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NetworkStats {
+    pub limits: NetworkLimits,
+    /// From the VM
+    pub upload: TrafficStats,
+    /// To the VM
+    pub download: TrafficStats,
+}
+
+/// Disk space a task's VM takes on the host beyond the shared base image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskStats {
+    /// What the VM changed in its root filesystem
+    pub rootfs_bytes: u64,
+    /// What is allocated in its data volume
+    pub data_bytes: u64,
+}
+
+/// Resource usage of a task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStatsResponse {
+    pub task_id: Uuid,
+    pub network: NetworkStats,
+    pub disk: DiskStats,
+}
+
+/// A connection a task's VM made through the egress proxy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkConnection {
+    pub id: i64,
+    pub host: String,
+    pub port: u16,
+    /// "CONNECT" for tunnels (HTTPS), else the method of a plain HTTP request
+    pub method: String,
+    /// False when the task's egress policy refused the host
+    pub allowed: bool,
+    /// Sent by the VM
+    pub bytes_up: u64,
+    /// Received by the VM
+    pub bytes_down: u64,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// Why the connection failed or was refused
+    pub error: Option<String>,
+}
+
+/// Connections of a task through the egress proxy, newest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkLogResponse {
+    pub task_id: Uuid,
+    pub connections: Vec<NetworkConnection>,
+    pub total: i64,
+    pub page: u32,
+    pub per_page: u32,
+}
@@ -0,0 +1,506 @@
+//! Host <-> sidecar protocol over vsock
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped on every incompatible change to `VsockMessage`; both sides refuse a
+/// peer with a different version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional capabilities announced in `Hello`
+pub const FEATURES: &[&str] = &[
+    "replay",
+    "heartbeat",
+    "permissions",
+    "interrupt",
+    "resume",
+    "mcp",
+    "files",
+    "tree",
+    "archive",
+    "diff",
+    "exec",
+    "pty",
+    "personalize",
+];
+
+/// Largest piece of a file carried by one `FileWrite` or `FileData`
+pub const FILE_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Whether `path` is a plain relative path (no `..`, `.` or leading `/`) that
+/// stays inside the workspace when joined to it. Symlinks are the sidecar's
+/// to check.
+pub fn is_valid_workspace_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionDecision {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// Supervised-mode settings sent to the sidecar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionPolicy {
+    pub timeout_secs: u32,
+    pub default_decision: PermissionDecision,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskFile {
+    pub name: String,
+    pub content: String,
+}
+
+/// Why a file transfer failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileErrorKind {
+    /// Not a workspace path, or it leads out of the workspace
+    InvalidPath,
+    /// No such file, or not a regular file
+    NotFound,
+    /// Reading or writing failed
+    Io,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileError {
+    pub kind: FileErrorKind,
+    pub message: String,
+}
+
+/// What a workspace tree entry is; symlinks are not followed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// One file or directory in a workspace listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeEntry {
+    /// Relative to /workspace
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Permission bits, e.g. 0o644
+    pub mode: u32,
+    /// Last modification, in Unix seconds
+    pub mtime: i64,
+    /// Two-letter `git status --porcelain` code (e.g. " M", "??"), if the
+    /// entry is in a repository and not clean
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_status: Option<String>,
+}
+
+/// Changes of one git repository in the workspace against its base commit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepoDiff {
+    /// Relative to /workspace; empty when the workspace itself is the repository
+    pub path: String,
+    /// Ref the base was taken from (e.g. "origin/HEAD"), or None when the
+    /// repository has no upstream and only uncommitted changes are shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_ref: Option<String>,
+    /// Base commit; None in a repository without commits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    /// Changed tracked files, committed or not
+    pub files: Vec<DiffFile>,
+    /// New files git does not know about yet (not in `files` or `patch`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub untracked: Vec<String>,
+    /// Full `git diff` output, when asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+    /// `patch` was cut off at the sidecar's size limit
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// One line of `git diff --numstat`, with its `--name-status` letter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffFile {
+    /// Relative to the repository
+    pub path: String,
+    /// Previous path of a renamed or copied file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    /// A(dded), M(odified), D(eleted), R(enamed), C(opied) or T(ype change)
+    pub status: String,
+    /// Line counts; None for binary files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additions: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletions: Option<u64>,
+}
+
+/// Output stream of a command run with ExecStart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecStream {
+    Stdout,
+    Stderr,
+}
+
+/// MCP server definition, written verbatim into the `mcpServers` map of the
+/// `--mcp-config` file passed to Claude Code inside the VM
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpServerConfig {
+    /// Transport type: "stdio" (default when omitted), "sse" or "http"
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<String>,
+    /// Command to launch for stdio servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Endpoint for sse/http servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+impl McpServerConfig {
+    /// Check that the definition has what its transport needs
+    pub fn validate(&self) -> Result<(), String> {
+        match self.transport.as_deref() {
+            None | Some("stdio") => {
+                if self.command.as_deref().is_none_or(str::is_empty) {
+                    return Err("stdio servers require a command".to_string());
+                }
+            }
+            Some("sse") | Some("http") => {
+                if self.url.as_deref().is_none_or(str::is_empty) {
+                    return Err("sse/http servers require a url".to_string());
+                }
+            }
+            Some(other) => return Err(format!("unknown transport '{}'", other)),
+        }
+        Ok(())
+    }
+}
+
+/// Basic guest numbers the sidecar reports with each heartbeat
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuestStats {
+    pub uptime_secs: u64,
+    /// 1-minute load average
+    pub load_avg: f64,
+    pub mem_total_mb: u64,
+    pub mem_available_mb: u64,
+}
+
+/// Filesystem holding /workspace, as the sidecar finds it when it says hello
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkspaceDisk {
+    /// Whether /workspace is a filesystem of its own (the task's data volume)
+    /// rather than a directory of the root filesystem
+    pub mounted: bool,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Messages exchanged between the host and the agent sidecar, one JSON object
+/// per line. Every variant must be handled explicitly on both sides.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VsockMessage {
+    /// First message on every connection, in both directions: the host sends
+    /// its hello and the sidecar answers with its own (or an Error)
+    Hello {
+        protocol_version: u32,
+        /// Build version of the sender
+        version: String,
+        #[serde(default)]
+        features: Vec<String>,
+        /// Sent by the sidecar only
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        workspace: Option<WorkspaceDisk>,
+    },
+    Init {
+        api_key: String,
+        prompt: String,
+        files: Option<Vec<TaskFile>>,
+        /// Resolved MCP servers keyed by name
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mcp_servers: Option<BTreeMap<String, McpServerConfig>>,
+        /// Supervised-mode policy; None runs with --dangerously-skip-permissions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        permissions: Option<PermissionPolicy>,
+    },
+    Output {
+        data: String,
+    },
+    Input {
+        data: String,
+    },
+    /// Sidecar is shutting down (older sidecars send this when Claude exits)
+    Exit {
+        code: i32,
+    },
+    /// Claude Code was launched, or relaunched to resume the session
+    AgentStarted {
+        resumed: bool,
+    },
+    /// Claude Code exited but the VM and sidecar keep running
+    AgentExited {
+        code: i32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+    /// Error message from the sidecar (e.g., Claude Code failed to start)
+    Error {
+        message: String,
+    },
+    /// Claude asked to use a tool (supervised mode)
+    PermissionRequest {
+        request_id: String,
+        tool_name: String,
+        input: serde_json::Value,
+    },
+    /// Host's answer to a PermissionRequest
+    PermissionResponse {
+        request_id: String,
+        decision: PermissionDecision,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Sidecar applied a decision, either from the host or after the timeout
+    PermissionResolved {
+        request_id: String,
+        decision: PermissionDecision,
+        timed_out: bool,
+    },
+    /// First message on a reconnect: the sidecar replays messages after `last_seq`
+    Attach {
+        last_seq: u64,
+    },
+    /// Host processed all sidecar messages up to `seq`
+    Ack {
+        seq: u64,
+    },
+    /// Ask the sidecar to cancel the in-flight Claude turn
+    Interrupt,
+    /// The interrupted turn ended; Claude is ready for the next input
+    TurnCancelled,
+    /// Keep-alive in both directions; the sidecar's carries guest stats
+    Heartbeat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<GuestStats>,
+    },
+    /// Piece of an upload to `path` (relative to /workspace). Chunks arrive in
+    /// order; the file replaces `path` once the chunk marked `done` is written.
+    FileWrite {
+        request_id: String,
+        path: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        done: bool,
+    },
+    /// Ask for up to `len` bytes of `path` (relative to /workspace) from `offset`
+    FileRead {
+        request_id: String,
+        path: String,
+        offset: u64,
+        len: u64,
+    },
+    /// Answer to a FileRead. `size` is the length of the whole file; a short or
+    /// empty `data` means the end was reached.
+    FileData {
+        request_id: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        size: u64,
+    },
+    /// List `path` (relative to /workspace, empty for the root) down to
+    /// `depth` levels, leaving out what `.gitignore` excludes
+    ListTree {
+        request_id: String,
+        path: String,
+        depth: u32,
+    },
+    /// Answer to ListTree, depth first in name order. `truncated` is set when
+    /// the listing hit the sidecar's entry limit. Failures are a FileResult.
+    Tree {
+        request_id: String,
+        entries: Vec<TreeEntry>,
+        truncated: bool,
+    },
+    /// Start a tar archive of the whole workspace, leaving out entries named
+    /// like one of `exclude` at any depth. Answered with the first ArchiveData.
+    ArchiveStart {
+        request_id: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        exclude: Vec<String>,
+    },
+    /// Ask for the next piece of a started archive
+    ArchiveNext {
+        request_id: String,
+    },
+    /// Up to one chunk of the tar stream; `done` marks the last one. Failures
+    /// are a FileResult.
+    ArchiveData {
+        request_id: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        done: bool,
+    },
+    /// Diff every repository in the workspace against its base commit, with
+    /// the full patch unless `stat_only`
+    GetDiff {
+        request_id: String,
+        stat_only: bool,
+    },
+    /// Answer to GetDiff, repositories in path order. Failures are a FileResult.
+    Diff {
+        request_id: String,
+        repos: Vec<RepoDiff>,
+    },
+    /// Run `argv` as the claude user, outside of Claude. `cwd` is relative to
+    /// /workspace unless absolute. The command is killed after `timeout_secs`.
+    ExecStart {
+        request_id: String,
+        argv: Vec<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+        timeout_secs: u64,
+    },
+    /// Kill a command started with ExecStart; it still ends with an ExecExit
+    ExecKill {
+        request_id: String,
+    },
+    /// Output of a running command, as it arrives (invalid UTF-8 is replaced)
+    ExecOutput {
+        request_id: String,
+        stream: ExecStream,
+        data: String,
+    },
+    /// The command ended: `code` if it exited, `signal` if it was killed,
+    /// `error` if it could not be started
+    ExecExit {
+        request_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+        timed_out: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Open an interactive login shell as the claude user on a new PTY of
+    /// `cols` x `rows`. Failures are a FileResult.
+    PtyOpen {
+        request_id: String,
+        cols: u16,
+        rows: u16,
+    },
+    /// Keystrokes for a shell opened with PtyOpen
+    PtyInput {
+        request_id: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// The terminal was resized
+    PtyResize {
+        request_id: String,
+        cols: u16,
+        rows: u16,
+    },
+    /// The terminal went away; the shell gets a hangup
+    PtyClose {
+        request_id: String,
+    },
+    /// Terminal output of a shell
+    PtyOutput {
+        request_id: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// The shell ended: `code` if it exited, `signal` if it was killed
+    PtyExit {
+        request_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+    },
+    /// Make a VM restored from a snapshot its own: network identity, clock,
+    /// SSH key and a fresh entropy seed. Sent as the first message on a
+    /// connection of its own, before the session starts; answered with
+    /// Personalized.
+    Personalize {
+        request_id: String,
+        ip_address: String,
+        gateway: String,
+        mac_address: String,
+        /// Installed for root; None keeps the VM without one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ssh_public_key: Option<String>,
+        /// Host time in Unix milliseconds; the guest clock stopped at the snapshot
+        time_unix_ms: i64,
+        /// Credited to the guest's random pool
+        #[serde(with = "base64_bytes")]
+        entropy: Vec<u8>,
+    },
+    /// Answer to Personalize: `error` names the step that failed, if one did
+    Personalized {
+        request_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Upload finished (`size` bytes written) or a transfer failed
+    FileResult {
+        request_id: String,
+        size: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<FileError>,
+    },
+}
+
+/// Sidecar -> host wire format: the message plus its sequence number.
+/// Hellos and errors sent before a connection is attached carry no `seq`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequenced<M> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub msg: M,
+}
+
+/// Binary payloads travel as base64 strings inside the JSON messages
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
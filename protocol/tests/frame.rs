@@ -0,0 +1,189 @@
+use std::io::Cursor;
+
+use lia_protocol::frame::{self, Transport, HEADER_LEN};
+use lia_protocol::VsockMessage;
+
+fn features(list: &[&str]) -> Vec<String> {
+    list.iter().map(|f| f.to_string()).collect()
+}
+
+#[test]
+fn test_negotiate_requires_both_peers() {
+    let framed = features(&["replay", "framed", "zstd"]);
+    let lines = features(&["replay"]);
+
+    assert_eq!(Transport::negotiate(&framed, &lines), Transport::Lines);
+    assert_eq!(Transport::negotiate(&lines, &framed), Transport::Lines);
+    assert_eq!(
+        Transport::negotiate(&framed, &features(&["framed"])),
+        Transport::Framed { zstd: false }
+    );
+    assert_eq!(
+        Transport::negotiate(&framed, &framed),
+        Transport::Framed {
+            zstd: cfg!(feature = "zstd")
+        }
+    );
+}
+
+#[test]
+fn test_lines_transport_matches_json_lines() {
+    let msg = VsockMessage::Ack { seq: 3 };
+    let line = Transport::Lines.encode(None, &msg).unwrap();
+    assert_eq!(line, b"{\"type\":\"ack\",\"seq\":3}\n");
+}
+
+#[test]
+fn test_output_frame_carries_raw_bytes() {
+    let data = r#"{"type":"assistant","message":{"content":"say \"hi\""}}"#;
+    let msg = VsockMessage::Output {
+        data: data.to_string(),
+    };
+    let encoded = frame::encode_frame(Some(9), &msg, false).unwrap();
+
+    // Header, 8-byte sequence number, then the line exactly as Claude wrote it
+    assert_eq!(encoded.len(), HEADER_LEN + 8 + data.len());
+    assert_eq!(&encoded[HEADER_LEN + 8..], data.as_bytes());
+
+    let decoded = frame::read_frame(&mut Cursor::new(encoded)).unwrap().unwrap();
+    assert_eq!(decoded.seq, Some(9));
+    assert!(matches!(decoded.msg, VsockMessage::Output { data: ref d } if d == data));
+}
+
+#[test]
+fn test_message_frames_roundtrip() {
+    let messages = [
+        (Some(1), VsockMessage::AgentStarted { resumed: false }),
+        (None, VsockMessage::Heartbeat { stats: None }),
+        (
+            None,
+            VsockMessage::Input {
+                data: "next".to_string(),
+            },
+        ),
+    ];
+
+    let mut stream = Vec::new();
+    for (seq, msg) in &messages {
+        stream.extend(frame::encode_frame(*seq, msg, false).unwrap());
+    }
+
+    let mut reader = Cursor::new(stream);
+    for (seq, msg) in &messages {
+        let decoded = frame::read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(decoded.seq, *seq);
+        assert_eq!(
+            serde_json::to_value(&decoded.msg).unwrap(),
+            serde_json::to_value(msg).unwrap()
+        );
+    }
+    assert!(frame::read_frame(&mut reader).unwrap().is_none());
+}
+
+#[test]
+fn test_truncated_and_oversized_frames_fail() {
+    let msg = VsockMessage::Output {
+        data: "partial".to_string(),
+    };
+    let mut encoded = frame::encode_frame(Some(1), &msg, false).unwrap();
+    encoded.truncate(encoded.len() - 2);
+    assert!(frame::read_frame(&mut Cursor::new(encoded)).is_err());
+
+    let header = [0xff, 0xff, 0xff, 0xff, 0];
+    assert!(frame::parse_header(&header).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_large_frames_are_compressed() {
+    let data = "tool output line\n".repeat(1000);
+    let msg = VsockMessage::Output { data: data.clone() };
+
+    let plain = frame::encode_frame(Some(2), &msg, false).unwrap();
+    let compressed = frame::encode_frame(Some(2), &msg, true).unwrap();
+    assert_eq!(compressed[4] & frame::FLAG_ZSTD, frame::FLAG_ZSTD);
+    assert!(compressed.len() < plain.len() / 10);
+
+    let decoded = frame::read_frame(&mut Cursor::new(compressed)).unwrap().unwrap();
+    assert!(matches!(decoded.msg, VsockMessage::Output { data: ref d } if *d == data));
+
+    // Small bodies are sent as is
+    let small = VsockMessage::Ack { seq: 2 };
+    let encoded = frame::encode_frame(None, &small, true).unwrap();
+    assert_eq!(encoded[4] & frame::FLAG_ZSTD, 0);
+}
+
+#[test]
+fn test_payload_frames_carry_raw_bytes() {
+    let data: Vec<u8> = (0..=255).collect();
+    let messages = [
+        VsockMessage::FileWrite {
+            request_id: "w".to_string(),
+            path: "src/main.rs".to_string(),
+            offset: 1024,
+            data: data.clone(),
+            done: true,
+        },
+        VsockMessage::FileData {
+            request_id: "r".to_string(),
+            offset: 0,
+            data: data.clone(),
+            size: 4096,
+        },
+        VsockMessage::ArchiveData {
+            request_id: "a".to_string(),
+            data: data.clone(),
+            done: false,
+        },
+        VsockMessage::PtyInput {
+            request_id: "p".to_string(),
+            data: data.clone(),
+        },
+        VsockMessage::PtyOutput {
+            request_id: "p".to_string(),
+            data: Vec::new(),
+        },
+    ];
+
+    for msg in &messages {
+        let encoded = frame::encode_frame(Some(4), msg, false).unwrap();
+        // The payload is the tail of the frame, not base64 in the JSON
+        let payload = match msg {
+            VsockMessage::PtyOutput { data, .. } => data,
+            _ => &data,
+        };
+        assert!(encoded.ends_with(payload));
+
+        let decoded = frame::read_frame(&mut Cursor::new(encoded)).unwrap().unwrap();
+        assert_eq!(decoded.seq, Some(4));
+        assert_eq!(
+            serde_json::to_value(&decoded.msg).unwrap(),
+            serde_json::to_value(msg).unwrap()
+        );
+    }
+
+    let base64_len = serde_json::to_vec(&messages[2]).unwrap().len();
+    let framed_len = frame::encode_frame(None, &messages[2], false).unwrap().len();
+    assert!(framed_len < base64_len);
+}
+
+#[test]
+fn test_malformed_payload_frames_fail() {
+    let frame_of = |body: &[u8]| {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.push(2);
+        frame.extend_from_slice(body);
+        frame
+    };
+
+    // Too short for the header length, header longer than the body
+    assert!(frame::read_frame(&mut Cursor::new(frame_of(&[0, 0]))).is_err());
+    assert!(frame::read_frame(&mut Cursor::new(frame_of(&[0, 0, 0, 9, b'{']))).is_err());
+
+    // A message without a payload field
+    let header = br#"{"type":"ack","seq":1}"#;
+    let mut body = (header.len() as u32).to_be_bytes().to_vec();
+    body.extend_from_slice(header);
+    body.extend_from_slice(b"bytes");
+    assert!(frame::read_frame(&mut Cursor::new(frame_of(&body))).is_err());
+}
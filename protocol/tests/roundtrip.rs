@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{TimeZone, Utc};
+use lia_protocol::{
+    is_valid_workspace_path, DiffFile, DiskStats, EgressPolicy, EntryKind, ExecStream, FileError,
+    FileErrorKind, GuestStats, McpServerConfig, NetworkConnection, NetworkLimits,
+    NetworkLogResponse, NetworkStats, PermissionDecision, PermissionMode, PermissionPolicy,
+    RepoDiff, Sequenced, SshKeyResponse, TaskConfig, TaskFile, TaskListResponse, TaskResponse,
+    TaskSource, TaskStatsResponse, TaskStatus, TerminalMessage, TrafficStats, TreeEntry,
+    VsockMessage, WorkspaceDisk, PROTOCOL_VERSION,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+/// One of every message, with optional fields filled in
+fn all_messages() -> Vec<VsockMessage> {
+    let mut mcp_servers = BTreeMap::new();
+    mcp_servers.insert(
+        "docs".to_string(),
+        McpServerConfig {
+            transport: Some("http".to_string()),
+            command: None,
+            args: vec![],
+            env: HashMap::new(),
+            url: Some("https://docs.example.com/mcp".to_string()),
+            headers: HashMap::from([("Authorization".to_string(), "Bearer x".to_string())]),
+        },
+    );
+
+    vec![
+        VsockMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: "0.1.0".to_string(),
+            features: vec!["replay".to_string(), "heartbeat".to_string()],
+            workspace: Some(WorkspaceDisk {
+                mounted: true,
+                total_bytes: 53_660_876_800,
+                available_bytes: 53_600_000_000,
+            }),
+        },
+        VsockMessage::Init {
+            api_key: "sk-ant-test".to_string(),
+            prompt: "Fix the build".to_string(),
+            files: Some(vec![TaskFile {
+                name: "notes/plan.md".to_string(),
+                content: "# Plan\n".to_string(),
+            }]),
+            mcp_servers: Some(mcp_servers),
+            permissions: Some(PermissionPolicy {
+                timeout_secs: 300,
+                default_decision: PermissionDecision::Deny,
+            }),
+        },
+        VsockMessage::Output {
+            data: "{\"type\":\"assistant\"}".to_string(),
+        },
+        VsockMessage::Input {
+            data: "continue".to_string(),
+        },
+        VsockMessage::Exit { code: 0 },
+        VsockMessage::AgentStarted { resumed: true },
+        VsockMessage::AgentExited {
+            code: 1,
+            session_id: Some("5b1c".to_string()),
+        },
+        VsockMessage::Error {
+            message: "Claude binary not found".to_string(),
+        },
+        VsockMessage::PermissionRequest {
+            request_id: "req-1".to_string(),
+            tool_name: "Bash".to_string(),
+            input: json!({ "command": "ls" }),
+        },
+        VsockMessage::PermissionResponse {
+            request_id: "req-1".to_string(),
+            decision: PermissionDecision::Allow,
+            message: Some("ok".to_string()),
+        },
+        VsockMessage::PermissionResolved {
+            request_id: "req-1".to_string(),
+            decision: PermissionDecision::Allow,
+            timed_out: false,
+        },
+        VsockMessage::Attach { last_seq: 42 },
+        VsockMessage::Ack { seq: 42 },
+        VsockMessage::Interrupt,
+        VsockMessage::TurnCancelled,
+        VsockMessage::Heartbeat {
+            stats: Some(GuestStats {
+                uptime_secs: 120,
+                load_avg: 0.5,
+                mem_total_mb: 2048,
+                mem_available_mb: 1500,
+            }),
+        },
+        VsockMessage::FileWrite {
+            request_id: "file-1".to_string(),
+            path: "data/input.csv".to_string(),
+            offset: 0,
+            data: b"a,b\n1,2\n".to_vec(),
+            done: true,
+        },
+        VsockMessage::FileRead {
+            request_id: "file-2".to_string(),
+            path: "out/report.pdf".to_string(),
+            offset: 0,
+            len: 1024,
+        },
+        VsockMessage::FileData {
+            request_id: "file-2".to_string(),
+            offset: 0,
+            data: vec![0, 159, 146, 150],
+            size: 4,
+        },
+        VsockMessage::ListTree {
+            request_id: "tree-1".to_string(),
+            path: "".to_string(),
+            depth: 2,
+        },
+        VsockMessage::Tree {
+            request_id: "tree-1".to_string(),
+            entries: vec![
+                TreeEntry {
+                    path: "repo".to_string(),
+                    kind: EntryKind::Dir,
+                    size: 4096,
+                    mode: 0o755,
+                    mtime: 1_700_000_000,
+                    git_status: None,
+                },
+                TreeEntry {
+                    path: "repo/main.rs".to_string(),
+                    kind: EntryKind::File,
+                    size: 120,
+                    mode: 0o644,
+                    mtime: 1_700_000_100,
+                    git_status: Some(" M".to_string()),
+                },
+            ],
+            truncated: false,
+        },
+        VsockMessage::ArchiveStart {
+            request_id: "archive-1".to_string(),
+            exclude: vec![".git".to_string(), "node_modules".to_string()],
+        },
+        VsockMessage::ArchiveNext {
+            request_id: "archive-1".to_string(),
+        },
+        VsockMessage::ArchiveData {
+            request_id: "archive-1".to_string(),
+            data: vec![0x1f, 0x8b, 0x08],
+            done: true,
+        },
+        VsockMessage::GetDiff {
+            request_id: "diff-1".to_string(),
+            stat_only: false,
+        },
+        VsockMessage::Diff {
+            request_id: "diff-1".to_string(),
+            repos: vec![RepoDiff {
+                path: "app".to_string(),
+                base_ref: Some("origin/HEAD".to_string()),
+                base: Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string()),
+                head: Some("9fceb02d0ae598e95dc970b74767f19372d61af8".to_string()),
+                files: vec![
+                    DiffFile {
+                        path: "src/main.rs".to_string(),
+                        old_path: None,
+                        status: "M".to_string(),
+                        additions: Some(3),
+                        deletions: Some(1),
+                    },
+                    DiffFile {
+                        path: "logo.png".to_string(),
+                        old_path: Some("img/logo.png".to_string()),
+                        status: "R".to_string(),
+                        additions: None,
+                        deletions: None,
+                    },
+                ],
+                untracked: vec!["notes.txt".to_string()],
+                patch: Some("diff --git a/src/main.rs b/src/main.rs\n".to_string()),
+                truncated: false,
+            }],
+        },
+        VsockMessage::ExecStart {
+            request_id: "exec-1".to_string(),
+            argv: vec!["npm".to_string(), "test".to_string()],
+            env: BTreeMap::from([("CI".to_string(), "1".to_string())]),
+            cwd: Some("app".to_string()),
+            timeout_secs: 300,
+        },
+        VsockMessage::ExecKill {
+            request_id: "exec-1".to_string(),
+        },
+        VsockMessage::ExecOutput {
+            request_id: "exec-1".to_string(),
+            stream: ExecStream::Stderr,
+            data: "1 failing\n".to_string(),
+        },
+        VsockMessage::ExecExit {
+            request_id: "exec-1".to_string(),
+            code: Some(1),
+            signal: None,
+            timed_out: false,
+            error: None,
+        },
+        VsockMessage::PtyOpen {
+            request_id: "pty-1".to_string(),
+            cols: 120,
+            rows: 40,
+        },
+        VsockMessage::PtyInput {
+            request_id: "pty-1".to_string(),
+            data: b"ls\r".to_vec(),
+        },
+        VsockMessage::PtyResize {
+            request_id: "pty-1".to_string(),
+            cols: 80,
+            rows: 24,
+        },
+        VsockMessage::PtyClose {
+            request_id: "pty-1".to_string(),
+        },
+        VsockMessage::PtyOutput {
+            request_id: "pty-1".to_string(),
+            data: b"\x1b[1;34msrc\x1b[0m\r\n".to_vec(),
+        },
+        VsockMessage::PtyExit {
+            request_id: "pty-1".to_string(),
+            code: None,
+            signal: Some(1),
+        },
+        VsockMessage::Personalize {
+            request_id: "personalize-1".to_string(),
+            ip_address: "172.16.0.105".to_string(),
+            gateway: "172.16.0.1".to_string(),
+            mac_address: "02:FC:00:00:00:69".to_string(),
+            ssh_public_key: Some("ssh-ed25519 AAAAC3Nz user@host".to_string()),
+            time_unix_ms: 1_760_000_000_000,
+            entropy: vec![0x5a; 64],
+        },
+        VsockMessage::Personalized {
+            request_id: "personalize-1".to_string(),
+            error: Some("Failed to run ip: No such file or directory".to_string()),
+        },
+        VsockMessage::FileResult {
+            request_id: "file-3".to_string(),
+            size: 0,
+            error: Some(FileError {
+                kind: FileErrorKind::NotFound,
+                message: "No such file".to_string(),
+            }),
+        },
+    ]
+}
+
+/// Wire name of each message. The match is exhaustive, so a new variant
+/// fails to compile here until it is added to `all_messages` as well.
+fn wire_name(msg: &VsockMessage) -> &'static str {
+    match msg {
+        VsockMessage::Hello { .. } => "hello",
+        VsockMessage::Init { .. } => "init",
+        VsockMessage::Output { .. } => "output",
+        VsockMessage::Input { .. } => "input",
+        VsockMessage::Exit { .. } => "exit",
+        VsockMessage::AgentStarted { .. } => "agent_started",
+        VsockMessage::AgentExited { .. } => "agent_exited",
+        VsockMessage::Error { .. } => "error",
+        VsockMessage::PermissionRequest { .. } => "permission_request",
+        VsockMessage::PermissionResponse { .. } => "permission_response",
+        VsockMessage::PermissionResolved { .. } => "permission_resolved",
+        VsockMessage::Attach { .. } => "attach",
+        VsockMessage::Ack { .. } => "ack",
+        VsockMessage::Interrupt => "interrupt",
+        VsockMessage::TurnCancelled => "turn_cancelled",
+        VsockMessage::Heartbeat { .. } => "heartbeat",
+        VsockMessage::FileWrite { .. } => "file_write",
+        VsockMessage::FileRead { .. } => "file_read",
+        VsockMessage::FileData { .. } => "file_data",
+        VsockMessage::FileResult { .. } => "file_result",
+        VsockMessage::ListTree { .. } => "list_tree",
+        VsockMessage::Tree { .. } => "tree",
+        VsockMessage::ArchiveStart { .. } => "archive_start",
+        VsockMessage::ArchiveNext { .. } => "archive_next",
+        VsockMessage::ArchiveData { .. } => "archive_data",
+        VsockMessage::GetDiff { .. } => "get_diff",
+        VsockMessage::Diff { .. } => "diff",
+        VsockMessage::ExecStart { .. } => "exec_start",
+        VsockMessage::ExecKill { .. } => "exec_kill",
+        VsockMessage::ExecOutput { .. } => "exec_output",
+        VsockMessage::ExecExit { .. } => "exec_exit",
+        VsockMessage::PtyOpen { .. } => "pty_open",
+        VsockMessage::PtyInput { .. } => "pty_input",
+        VsockMessage::PtyResize { .. } => "pty_resize",
+        VsockMessage::PtyClose { .. } => "pty_close",
+        VsockMessage::PtyOutput { .. } => "pty_output",
+        VsockMessage::PtyExit { .. } => "pty_exit",
+        VsockMessage::Personalize { .. } => "personalize",
+        VsockMessage::Personalized { .. } => "personalized",
+    }
+}
+
+#[test]
+fn test_vsock_messages_roundtrip() {
+    let messages = all_messages();
+    let mut names: Vec<&str> = messages.iter().map(wire_name).collect();
+    names.sort_unstable();
+    names.dedup();
+    assert_eq!(names.len(), messages.len(), "all_messages has duplicate variants");
+
+    for msg in messages {
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(value["type"], wire_name(&msg), "wrong tag for {:?}", msg);
+
+        let line = serde_json::to_string(&msg).unwrap();
+        let parsed: VsockMessage = serde_json::from_str(&line).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+    }
+}
+
+#[test]
+fn test_optional_fields_are_omitted() {
+    let init = VsockMessage::Init {
+        api_key: "key".to_string(),
+        prompt: "hi".to_string(),
+        files: None,
+        mcp_servers: None,
+        permissions: None,
+    };
+    let value = serde_json::to_value(&init).unwrap();
+    assert_eq!(
+        value,
+        json!({ "type": "init", "api_key": "key", "prompt": "hi", "files": null })
+    );
+
+    let heartbeat = serde_json::to_value(VsockMessage::Heartbeat { stats: None }).unwrap();
+    assert_eq!(heartbeat, json!({ "type": "heartbeat" }));
+
+    let not_started = serde_json::to_value(VsockMessage::ExecExit {
+        request_id: "exec-2".to_string(),
+        code: None,
+        signal: None,
+        timed_out: false,
+        error: Some("npm: No such file or directory".to_string()),
+    })
+    .unwrap();
+    assert_eq!(
+        not_started,
+        json!({
+            "type": "exec_exit",
+            "request_id": "exec-2",
+            "timed_out": false,
+            "error": "npm: No such file or directory"
+        })
+    );
+}
+
+#[test]
+fn test_missing_optional_fields_parse() {
+    let hello: VsockMessage =
+        serde_json::from_str(r#"{"type":"hello","protocol_version":1,"version":"0.1.0"}"#).unwrap();
+    match hello {
+        VsockMessage::Hello {
+            features,
+            workspace,
+            ..
+        } => {
+            assert!(features.is_empty());
+            assert!(workspace.is_none());
+        }
+        other => panic!("expected hello, got {:?}", other),
+    }
+
+    let exited: VsockMessage = serde_json::from_str(r#"{"type":"agent_exited","code":0}"#).unwrap();
+    match exited {
+        VsockMessage::AgentExited { session_id, .. } => assert!(session_id.is_none()),
+        other => panic!("expected agent_exited, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_unknown_message_type_is_rejected() {
+    let result = serde_json::from_str::<VsockMessage>(r#"{"type":"teleport"}"#);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_sequenced_wire_format() {
+    let msg = VsockMessage::Output {
+        data: "hello".to_string(),
+    };
+    let line = serde_json::to_string(&Sequenced {
+        seq: Some(7),
+        msg: &msg,
+    })
+    .unwrap();
+    assert_eq!(line, r#"{"seq":7,"type":"output","data":"hello"}"#);
+
+    let parsed: Sequenced<VsockMessage> = serde_json::from_str(&line).unwrap();
+    assert_eq!(parsed.seq, Some(7));
+    assert!(matches!(parsed.msg, VsockMessage::Output { ref data } if data == "hello"));
+
+    // Hellos and pre-attach errors are not sequenced
+    let parsed: Sequenced<VsockMessage> =
+        serde_json::from_str(r#"{"type":"turn_cancelled"}"#).unwrap();
+    assert_eq!(parsed.seq, None);
+    assert!(matches!(parsed.msg, VsockMessage::TurnCancelled));
+}
+
+#[test]
+fn test_file_data_is_base64() {
+    let msg = VsockMessage::FileData {
+        request_id: "r".to_string(),
+        offset: 0,
+        data: vec![0xff, 0x00, 0x10],
+        size: 3,
+    };
+    let value = serde_json::to_value(&msg).unwrap();
+    assert_eq!(value["data"], "/wAQ");
+
+    let bad = r#"{"type":"file_write","request_id":"r","path":"a","offset":0,"data":"%%","done":true}"#;
+    assert!(serde_json::from_str::<VsockMessage>(bad).is_err());
+
+    let result = serde_json::to_value(VsockMessage::FileResult {
+        request_id: "r".to_string(),
+        size: 3,
+        error: None,
+    })
+    .unwrap();
+    assert_eq!(result, json!({ "type": "file_result", "request_id": "r", "size": 3 }));
+}
+
+#[test]
+fn test_workspace_paths() {
+    for path in ["a.txt", "data/input.csv", "dir/./file", ".hidden/x"] {
+        assert!(is_valid_workspace_path(path), "{} should be valid", path);
+    }
+    for path in ["", "/etc/passwd", "../secret", "a/../../b", ".", "./a"] {
+        assert!(!is_valid_workspace_path(path), "{} should be invalid", path);
+    }
+}
+
+#[test]
+fn test_mcp_server_config_transport_field() {
+    let config: McpServerConfig =
+        serde_json::from_str(r#"{"type":"sse","url":"http://localhost:9000/sse"}"#).unwrap();
+    assert_eq!(config.transport.as_deref(), Some("sse"));
+    assert!(config.validate().is_ok());
+
+    let value = serde_json::to_value(&config).unwrap();
+    assert_eq!(value, json!({ "type": "sse", "url": "http://localhost:9000/sse" }));
+
+    let missing_command: McpServerConfig = serde_json::from_str("{}").unwrap();
+    assert!(missing_command.validate().is_err());
+}
+
+#[test]
+fn test_task_status_names() {
+    let statuses = [
+        (TaskStatus::Pending, "pending"),
+        (TaskStatus::Starting, "starting"),
+        (TaskStatus::Running, "running"),
+        (TaskStatus::AwaitingInput, "awaiting_input"),
+        (TaskStatus::Suspended, "suspended"),
+        (TaskStatus::Terminated, "terminated"),
+    ];
+    for (status, name) in statuses {
+        assert_eq!(serde_json::to_value(status).unwrap(), name);
+        assert_eq!(status.to_string(), name);
+        let parsed: TaskStatus = serde_json::from_value(json!(name)).unwrap();
+        assert_eq!(parsed, status);
+    }
+}
+
+#[test]
+fn test_task_config_defaults() {
+    let config: TaskConfig = serde_json::from_str("{}").unwrap();
+    assert_eq!(config.timeout_minutes, 30);
+    assert_eq!(config.max_memory_mb, 2048);
+    assert_eq!(config.vcpu_count, 2);
+    assert_eq!(config.storage_gb, 50);
+    assert_eq!(config.permission_mode, PermissionMode::Bypass);
+    assert!(config.permission_policy().is_none());
+    assert!(config.preview_ports.is_empty());
+    assert!(config.egress.is_none());
+    assert!(config.upload_kbps.is_none());
+    assert!(config.download_kbps.is_none());
+    assert!(config.connections_per_sec.is_none());
+
+    let config: TaskConfig =
+        serde_json::from_str(r#"{"permission_mode":"supervised","approval_default":"allow"}"#)
+            .unwrap();
+    let policy = config.permission_policy().unwrap();
+    assert_eq!(policy.timeout_secs, 300);
+    assert_eq!(policy.default_decision, PermissionDecision::Allow);
+}
+
+#[test]
+fn test_egress_policy_wire_format() {
+    let cases = [
+        (EgressPolicy::Deny, json!({"mode": "deny"})),
+        (EgressPolicy::Open, json!({"mode": "open"})),
+        (
+            EgressPolicy::Allowlist {
+                domains: vec!["github.com".to_string()],
+                cidrs: vec!["140.82.112.0/20".to_string()],
+            },
+            json!({"mode": "allowlist", "domains": ["github.com"], "cidrs": ["140.82.112.0/20"]}),
+        ),
+    ];
+    for (policy, wire) in cases {
+        assert_eq!(serde_json::to_value(&policy).unwrap(), wire);
+        assert_eq!(serde_json::from_value::<EgressPolicy>(wire).unwrap(), policy);
+    }
+
+    let config: TaskConfig =
+        serde_json::from_str(r#"{"egress":{"mode":"allowlist","domains":["pypi.org"]}}"#).unwrap();
+    assert_eq!(
+        config.egress,
+        Some(EgressPolicy::Allowlist {
+            domains: vec!["pypi.org".to_string()],
+            cidrs: vec![],
+        })
+    );
+}
+
+#[test]
+fn test_task_list_response_roundtrip() {
+    let task = TaskResponse {
+        id: Uuid::from_u128(1),
+        user_id: "user-1".to_string(),
+        guild_id: None,
+        status: TaskStatus::AwaitingInput,
+        source: TaskSource::Web,
+        repositories: vec!["owner/repo".to_string()],
+        vm_id: Some("vm-1".to_string()),
+        config: Some(TaskConfig::default()),
+        created_at: Utc.with_ymd_and_hms(2024, 1, 15, 10, 30, 0).unwrap(),
+        started_at: None,
+        completed_at: None,
+        exit_code: None,
+        error_message: None,
+        web_url: "http://localhost:5173/tasks/1".to_string(),
+        ssh_command: Some("ssh root@172.16.0.100".to_string()),
+        ip_address: Some("172.16.0.100".to_string()),
+        healthy: true,
+        sidecar_version: Some("0.1.0".to_string()),
+        workspace_bytes: Some(53_660_876_800),
+        preview_token: Some("0f1e2d3c4b5a69788796a5b4c3d2e1f0".to_string()),
+    };
+    let list = TaskListResponse {
+        tasks: vec![task],
+        total: 1,
+        page: 1,
+        per_page: 20,
+    };
+
+    let value = serde_json::to_value(&list).unwrap();
+    assert_eq!(value["tasks"][0]["status"], "awaiting_input");
+    assert_eq!(value["tasks"][0]["source"], "web");
+    assert_eq!(value["tasks"][0]["created_at"], "2024-01-15T10:30:00Z");
+
+    let parsed: TaskListResponse = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+}
+
+#[test]
+fn test_ssh_key_roundtrip() {
+    let key = SshKeyResponse {
+        id: Uuid::from_u128(2),
+        user_id: "user-1".to_string(),
+        key_type: "ssh-ed25519".to_string(),
+        public_key: "AAAAC3NzaC1lZDI1NTE5AAAAIJLaEozDKWlPAr2buJpLdJZEOCMFEtJZMT1sw2CLEPdD"
+            .to_string(),
+        comment: Some("user@laptop".to_string()),
+        fingerprint: "SHA256:OMaRChZIDHUrVGGKxKMN1ZnEjuw742dnp5MuddTB4Pw".to_string(),
+        created_at: Utc.with_ymd_and_hms(2024, 1, 15, 10, 30, 0).unwrap(),
+    };
+
+    let value = serde_json::to_value(&key).unwrap();
+    assert_eq!(value["key_type"], "ssh-ed25519");
+    assert_eq!(value["created_at"], "2024-01-15T10:30:00Z");
+
+    let parsed: SshKeyResponse = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+}
+
+#[test]
+fn test_terminal_message_wire_format() {
+    let cases = [
+        (
+            TerminalMessage::Resize { cols: 120, rows: 40 },
+            json!({"type": "resize", "cols": 120, "rows": 40}),
+        ),
+        (
+            TerminalMessage::Exit {
+                code: None,
+                signal: Some(9),
+            },
+            json!({"type": "exit", "code": null, "signal": 9}),
+        ),
+        (
+            TerminalMessage::Error {
+                message: "Connection to the VM was lost".to_string(),
+            },
+            json!({"type": "error", "message": "Connection to the VM was lost"}),
+        ),
+    ];
+    for (msg, wire) in cases {
+        assert_eq!(serde_json::to_value(&msg).unwrap(), wire);
+        assert_eq!(serde_json::from_value::<TerminalMessage>(wire).unwrap(), msg);
+    }
+}
+
+#[test]
+fn test_task_stats_roundtrip() {
+    let stats = TaskStatsResponse {
+        task_id: Uuid::from_u128(7),
+        network: NetworkStats {
+            limits: NetworkLimits {
+                upload_kbps: 10_000,
+                download_kbps: 0,
+                connections_per_sec: 20,
+            },
+            upload: TrafficStats {
+                bytes: 1_234_567,
+                packets: 2_000,
+                dropped: 12,
+                rate_kbps: 9_800.5,
+            },
+            download: TrafficStats::default(),
+        },
+        disk: DiskStats {
+            rootfs_bytes: 48_234_496,
+            data_bytes: 0,
+        },
+    };
+
+    let value = serde_json::to_value(&stats).unwrap();
+    assert_eq!(value["network"]["limits"]["upload_kbps"], 10_000);
+    assert_eq!(value["network"]["upload"]["dropped"], 12);
+    let parsed: TaskStatsResponse = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.task_id, stats.task_id);
+    assert_eq!(parsed.network, stats.network);
+    assert_eq!(parsed.disk, stats.disk);
+}
+
+#[test]
+fn test_network_log_roundtrip() {
+    let log = NetworkLogResponse {
+        task_id: Uuid::from_u128(8),
+        connections: vec![
+            NetworkConnection {
+                id: 2,
+                host: "example.com".to_string(),
+                port: 443,
+                method: "CONNECT".to_string(),
+                allowed: false,
+                bytes_up: 0,
+                bytes_down: 0,
+                started_at: Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 5).unwrap(),
+                duration_ms: 0,
+                error: Some("Not allowed by the egress policy".to_string()),
+            },
+            NetworkConnection {
+                id: 1,
+                host: "github.com".to_string(),
+                port: 443,
+                method: "CONNECT".to_string(),
+                allowed: true,
+                bytes_up: 2_048,
+                bytes_down: 1_500_000,
+                started_at: Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap(),
+                duration_ms: 850,
+                error: None,
+            },
+        ],
+        total: 2,
+        page: 1,
+        per_page: 50,
+    };
+
+    let value = serde_json::to_value(&log).unwrap();
+    assert_eq!(value["connections"][0]["allowed"], false);
+    assert_eq!(value["connections"][1]["bytes_down"], 1_500_000);
+    assert!(value["connections"][1]["error"].is_null());
+    let parsed: NetworkLogResponse = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.task_id, log.task_id);
+    assert_eq!(parsed.connections, log.connections);
+}
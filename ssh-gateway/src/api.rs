@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use lia_protocol::{SshKeyResponse, TaskListResponse, TaskResponse};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+pub struct ApiClient {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+/// One event of a command's output stream
+#[derive(Debug, Clone)]
+pub enum ExecEvent {
+    Stdout(String),
+    Stderr(String),
+    Exit {
+        code: Option<i32>,
+        signal: Option<i32>,
+        timed_out: bool,
+    },
+    Error(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExecEventData {
+    data: Option<String>,
+    code: Option<i32>,
+    signal: Option<i32>,
+    #[serde(default)]
+    timed_out: bool,
+    error: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The registered key with this fingerprint, if any
+    pub async fn find_ssh_key(&self, fingerprint: &str) -> Result<Option<SshKeyResponse>> {
+        let url = format!("{}/api/v1/ssh-keys", self.base_url);
+
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("fingerprint", fingerprint)])
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    pub async fn get_task(&self, id: Uuid) -> Result<Option<TaskResponse>> {
+        let url = format!("{}/api/v1/tasks/{}", self.base_url, id);
+
+        let response = self.client.get(&url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    pub async fn list_tasks(&self, user_id: &str) -> Result<TaskListResponse> {
+        let url = format!("{}/api/v1/tasks", self.base_url);
+
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("user_id", user_id), ("per_page", "100")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TaskListResponse>()
+            .await?;
+
+        Ok(response)
+    }
+
+    /// WebSocket URL of the task's interactive shell
+    pub fn terminal_url(&self, id: Uuid, cols: u16, rows: u16) -> String {
+        let base = if let Some(rest) = self.base_url.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else if let Some(rest) = self.base_url.strip_prefix("http://") {
+            format!("ws://{}", rest)
+        } else {
+            self.base_url.clone()
+        };
+        format!(
+            "{}/api/v1/tasks/{}/terminal?cols={}&rows={}",
+            base, id, cols, rows
+        )
+    }
+
+    /// Run `argv` in the task's VM, streaming its output
+    pub async fn exec(
+        &self,
+        id: Uuid,
+        argv: &[&str],
+        timeout_secs: u64,
+    ) -> Result<impl Stream<Item = Result<ExecEvent>>> {
+        let url = format!("{}/api/v1/tasks/{}/exec", self.base_url, id);
+        let body = serde_json::json!({ "argv": argv, "timeout_secs": timeout_secs });
+
+        let response = self.client.post(&url).json(&body).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            let message = body
+                .get("message")
+                .and_then(|m| m.as_str())
+                .unwrap_or("request failed");
+            return Err(anyhow!("{} ({})", message, status));
+        }
+
+        // Events can be split across chunks, so buffer up to each blank line
+        let mut buffer = String::new();
+        Ok(response
+            .bytes_stream()
+            .map(move |chunk| {
+                let chunk = chunk.map_err(|e| anyhow!("Stream error: {}", e))?;
+                buffer.push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n"));
+                let mut events = Vec::new();
+                while let Some(end) = buffer.find("\n\n") {
+                    let raw: String = buffer.drain(..end + 2).collect();
+                    events.extend(parse_sse_event(&raw));
+                }
+                Ok(events)
+            })
+            .flat_map(|result: Result<Vec<ExecEvent>>| {
+                let items: Vec<Result<ExecEvent>> = match result {
+                    Ok(events) => events.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                futures::stream::iter(items)
+            }))
+    }
+}
+
+fn parse_sse_event(raw: &str) -> Option<ExecEvent> {
+    let mut event_type = "";
+    let mut data = String::new();
+
+    for line in raw.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event_type = value.trim();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+
+    let parsed: ExecEventData = serde_json::from_str(&data).unwrap_or_default();
+    match event_type {
+        "stdout" => Some(ExecEvent::Stdout(parsed.data.unwrap_or_default())),
+        "stderr" => Some(ExecEvent::Stderr(parsed.data.unwrap_or_default())),
+        "exit" => Some(ExecEvent::Exit {
+            code: parsed.code,
+            signal: parsed.signal,
+            timed_out: parsed.timed_out,
+        }),
+        "error" => Some(ExecEvent::Error(
+            parsed.error.unwrap_or_else(|| "Command failed".to_string()),
+        )),
+        // Keep-alive comments and anything unknown
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_output_events() {
+        let event = parse_sse_event("event: stdout\ndata: {\"data\":\"hello\\n\"}\n\n");
+        assert!(matches!(event, Some(ExecEvent::Stdout(ref s)) if s == "hello\n"));
+
+        // The space after the colon is optional
+        let event = parse_sse_event("event:stderr\ndata:{\"data\":\"oops\"}\n\n");
+        assert!(matches!(event, Some(ExecEvent::Stderr(ref s)) if s == "oops"));
+    }
+
+    #[test]
+    fn parse_exit_events() {
+        let event = parse_sse_event("event: exit\ndata: {\"code\":3,\"signal\":null,\"timed_out\":false}\n\n");
+        assert!(matches!(
+            event,
+            Some(ExecEvent::Exit {
+                code: Some(3),
+                signal: None,
+                timed_out: false
+            })
+        ));
+
+        let event = parse_sse_event("event: exit\ndata: {\"signal\":9,\"timed_out\":true}\n\n");
+        assert!(matches!(
+            event,
+            Some(ExecEvent::Exit {
+                code: None,
+                signal: Some(9),
+                timed_out: true
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_error_events() {
+        let event = parse_sse_event("event: error\ndata: {\"error\":\"VM not running\"}\n\n");
+        assert!(matches!(event, Some(ExecEvent::Error(ref s)) if s == "VM not running"));
+
+        let event = parse_sse_event("event: error\ndata: not json\n\n");
+        assert!(matches!(event, Some(ExecEvent::Error(ref s)) if s == "Command failed"));
+    }
+
+    #[test]
+    fn parse_multiline_data() {
+        let event = parse_sse_event("event: stdout\ndata: {\"data\":\ndata: \"split\"}\n\n");
+        assert!(matches!(event, Some(ExecEvent::Stdout(ref s)) if s == "split"));
+    }
+
+    #[test]
+    fn parse_ignores_keepalives_and_unknown_events() {
+        assert!(parse_sse_event(": keep-alive\n\n").is_none());
+        assert!(parse_sse_event("event: progress\ndata: {}\n\n").is_none());
+        assert!(parse_sse_event("").is_none());
+    }
+}
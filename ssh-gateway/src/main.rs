@@ -0,0 +1,131 @@
+//! SSH gateway for task VMs. The host's sshd authenticates users against the
+//! keys they registered with the API (`authorized-keys`), and every login runs
+//! `session`, which routes it by task id to that task's VM over the API.
+//! See docs/ssh-gateway.md for the sshd setup.
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+mod api;
+mod session;
+mod terminal;
+use api::ApiClient;
+
+#[derive(Parser)]
+#[command(name = "lia-ssh-gateway", about = "SSH gateway for Lia task VMs", version)]
+struct Cli {
+    /// VM API URL
+    #[arg(long, env = "LIA_API_URL", default_value = "http://localhost:8811")]
+    api_url: String,
+
+    /// File that sessions are logged to, one JSON object per line
+    #[arg(
+        long,
+        env = "LIA_SSH_LOG_FILE",
+        default_value = "/var/log/lia/ssh-gateway.log"
+    )]
+    log_file: PathBuf,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// sshd AuthorizedKeysCommand: print the authorized_keys line for a key
+    AuthorizedKeys {
+        /// Fingerprint of the offered key (sshd's %f)
+        fingerprint: String,
+    },
+    /// Run a login; the task and command come from SSH_ORIGINAL_COMMAND
+    Session {
+        /// Fingerprint of the key the user logged in with
+        #[arg(long)]
+        key: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let client = ApiClient::new(&cli.api_url);
+
+    match cli.command {
+        Commands::AuthorizedKeys { fingerprint } => {
+            authorized_keys(&client, &cli.api_url, &cli.log_file, &fingerprint).await
+        }
+        Commands::Session { key } => {
+            let code = session::run(&client, &cli.log_file, &key).await?;
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Print the forced-command authorized_keys line for a registered key.
+/// Printing nothing makes sshd refuse the key.
+async fn authorized_keys(
+    client: &ApiClient,
+    api_url: &str,
+    log_file: &std::path::Path,
+    fingerprint: &str,
+) -> Result<()> {
+    let Some(key) = client.find_ssh_key(fingerprint).await? else {
+        return Ok(());
+    };
+
+    let exe = std::env::current_exe()?;
+    let args = [
+        exe.to_string_lossy().to_string(),
+        "--api-url".to_string(),
+        api_url.to_string(),
+        "--log-file".to_string(),
+        log_file.to_string_lossy().to_string(),
+        "session".to_string(),
+        "--key".to_string(),
+        key.fingerprint.clone(),
+    ];
+    let command = args
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Result<Vec<_>>>()?
+        .join(" ");
+
+    println!(
+        "restrict,pty,command=\"{}\" {} {}",
+        command, key.key_type, key.public_key
+    );
+    Ok(())
+}
+
+/// Single-quote an argument of the forced command. Quotes of either kind
+/// would break out of the authorized_keys option, so they are refused.
+fn quote(arg: &str) -> Result<String> {
+    if arg.contains(['\'', '"', '\\', '\n']) {
+        return Err(anyhow!("cannot use {:?} in the forced command", arg));
+    }
+    Ok(format!("'{}'", arg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_wraps_plain_arguments() {
+        assert_eq!(quote("session").unwrap(), "'session'");
+        assert_eq!(
+            quote("/usr/local/bin/lia ssh gateway").unwrap(),
+            "'/usr/local/bin/lia ssh gateway'"
+        );
+        assert_eq!(quote("SHA256:ab+c/d=").unwrap(), "'SHA256:ab+c/d='");
+        assert_eq!(quote("").unwrap(), "''");
+    }
+
+    #[test]
+    fn quote_refuses_breakouts() {
+        for arg in ["it's", "say \"hi\"", "back\\slash", "two\nlines"] {
+            assert!(quote(arg).is_err(), "{:?} was accepted", arg);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! One login through the gateway: work out the user from their key and the
+//! task from the ssh command line, check the task is theirs and running,
+//! then hand off to the terminal or a one-off command. Every session is
+//! logged before anything reaches the VM.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use futures::StreamExt;
+use lia_protocol::{TaskResponse, TaskStatus};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::api::{ApiClient, ExecEvent};
+use crate::terminal;
+
+/// The API's upper bound for a command's run time
+const EXEC_TIMEOUT_SECS: u64 = 3600;
+
+/// Run the session and return the exit status for ssh
+pub async fn run(client: &ApiClient, log_file: &Path, fingerprint: &str) -> Result<i32> {
+    let Some(key) = client.find_ssh_key(fingerprint).await? else {
+        eprintln!("This key is no longer registered.");
+        return Ok(1);
+    };
+    let user_id = key.user_id;
+
+    let original = std::env::var("SSH_ORIGINAL_COMMAND").unwrap_or_default();
+    let original = original.trim();
+    let (task_arg, command) = match original.split_once(char::is_whitespace) {
+        Some((task, command)) => (task, Some(command.trim())),
+        None => (original, None),
+    };
+    if task_arg.is_empty() {
+        print_usage(client, &user_id).await?;
+        return Ok(0);
+    }
+
+    let Some(task) = find_task(client, &user_id, task_arg).await? else {
+        eprintln!("No task {}.", task_arg);
+        return Ok(1);
+    };
+    if !matches!(task.status, TaskStatus::Running | TaskStatus::AwaitingInput) {
+        eprintln!("Task {} is {}, not running.", task.id, task.status);
+        return Ok(1);
+    }
+
+    // Fail closed: a session that cannot be logged does not start
+    let mut log = SessionLog::open(log_file)?;
+    log.write(serde_json::json!({
+        "event": "start",
+        "time": Utc::now(),
+        "pid": std::process::id(),
+        "user_id": user_id,
+        "key": fingerprint,
+        "task_id": task.id,
+        "client": std::env::var("SSH_CONNECTION")
+            .ok()
+            .map(|c| c.split_whitespace().take(2).collect::<Vec<_>>().join(" ")),
+        "command": command,
+    }))?;
+
+    let started = Instant::now();
+    let result = match command {
+        Some(command) => exec(client, task.id, command).await,
+        None => terminal::run(client, task.id).await,
+    };
+
+    let exit_code = *result.as_ref().unwrap_or(&1);
+    let logged = log.write(serde_json::json!({
+        "event": "end",
+        "time": Utc::now(),
+        "pid": std::process::id(),
+        "task_id": task.id,
+        "exit_code": exit_code,
+        "error": result.as_ref().err().map(|e| e.to_string()),
+        "duration_secs": started.elapsed().as_secs_f64(),
+    }));
+    if let Err(e) = logged {
+        eprintln!("lia-ssh-gateway: {:#}", e);
+    }
+
+    result
+}
+
+/// The user's task with this id; other users' tasks are reported as missing
+async fn find_task(
+    client: &ApiClient,
+    user_id: &str,
+    task_arg: &str,
+) -> Result<Option<TaskResponse>> {
+    let Ok(id) = task_arg.parse::<Uuid>() else {
+        return Ok(None);
+    };
+    Ok(client
+        .get_task(id)
+        .await?
+        .filter(|task| task.user_id == user_id))
+}
+
+async fn print_usage(client: &ApiClient, user_id: &str) -> Result<()> {
+    let tasks = client.list_tasks(user_id).await?;
+    let running: Vec<_> = tasks
+        .tasks
+        .iter()
+        .filter(|t| matches!(t.status, TaskStatus::Running | TaskStatus::AwaitingInput))
+        .collect();
+
+    eprintln!("Usage: ssh -t <gateway> <task-id> [command]");
+    if running.is_empty() {
+        eprintln!("\nYou have no running tasks.");
+    } else {
+        eprintln!("\nYour running tasks:");
+        for task in running {
+            eprintln!("  {}  {}", task.id, task.created_at.format("%Y-%m-%d %H:%M"));
+        }
+    }
+    Ok(())
+}
+
+/// Run `command` with bash in the VM, passing its output through
+async fn exec(client: &ApiClient, id: Uuid, command: &str) -> Result<i32> {
+    let stream = client
+        .exec(id, &["bash", "-lc", command], EXEC_TIMEOUT_SECS)
+        .await?;
+    tokio::pin!(stream);
+
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    while let Some(event) = stream.next().await {
+        match event? {
+            ExecEvent::Stdout(data) => {
+                stdout.write_all(data.as_bytes()).await?;
+                stdout.flush().await?;
+            }
+            ExecEvent::Stderr(data) => {
+                stderr.write_all(data.as_bytes()).await?;
+                stderr.flush().await?;
+            }
+            ExecEvent::Exit {
+                code,
+                signal,
+                timed_out,
+            } => {
+                if timed_out {
+                    eprintln!("Command timed out after {}s.", EXEC_TIMEOUT_SECS);
+                }
+                return Ok(exit_status(code, signal));
+            }
+            ExecEvent::Error(message) => return Err(anyhow!(message)),
+        }
+    }
+
+    Err(anyhow!("Connection to the VM was lost"))
+}
+
+/// Shell-style exit status of a process that exited or was killed
+pub fn exit_status(code: Option<i32>, signal: Option<i32>) -> i32 {
+    match (code, signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 255,
+    }
+}
+
+struct SessionLog {
+    file: std::fs::File,
+}
+
+impl SessionLog {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open session log {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Append one record; a single write keeps concurrent sessions' lines whole
+    fn write(&mut self, record: serde_json::Value) -> Result<()> {
+        let mut line = record.to_string();
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .context("cannot write session log")
+    }
+}
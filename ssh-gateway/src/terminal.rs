@@ -0,0 +1,125 @@
+//! Interactive shell: bridges the ssh session's terminal to the task's
+//! terminal WebSocket. Keystrokes go up as binary frames, output comes back
+//! the same way, and window changes become resize messages.
+
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use lia_protocol::TerminalMessage;
+use nix::sys::termios::{self, SetArg, Termios};
+use std::io::IsTerminal;
+use std::os::fd::AsFd;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::api::ApiClient;
+use crate::session::exit_status;
+
+/// Size used when stdout is not a terminal
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// Ctrl-D, sent once when a non-interactive stdin ends
+const EOT: u8 = 0x04;
+
+pub async fn run(client: &ApiClient, id: Uuid) -> Result<i32> {
+    let (cols, rows) = window_size().unwrap_or(DEFAULT_SIZE);
+    let url = client.terminal_url(id, cols, rows);
+    let (ws, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(|e| anyhow!("cannot open terminal: {}", e))?;
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    // The remote PTY does the line editing and echo
+    let _raw = RawMode::enable()?;
+
+    let mut winch = signal(SignalKind::window_change())?;
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut buf = vec![0u8; 8192];
+    let mut stdin_open = true;
+
+    loop {
+        tokio::select! {
+            read = stdin.read(&mut buf), if stdin_open => {
+                let n = read?;
+                if n == 0 {
+                    stdin_open = false;
+                    ws_tx.send(Message::Binary(vec![EOT])).await?;
+                } else {
+                    ws_tx.send(Message::Binary(buf[..n].to_vec())).await?;
+                }
+            }
+            _ = winch.recv() => {
+                if let Some((cols, rows)) = window_size() {
+                    let resize = TerminalMessage::Resize { cols, rows };
+                    ws_tx.send(Message::Text(serde_json::to_string(&resize)?)).await?;
+                }
+            }
+            msg = ws_rx.next() => {
+                match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        stdout.write_all(&data).await?;
+                        stdout.flush().await?;
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<TerminalMessage>(&text) {
+                            Ok(TerminalMessage::Exit { code, signal }) => {
+                                return Ok(exit_status(code, signal));
+                            }
+                            Ok(TerminalMessage::Error { message }) => {
+                                return Err(anyhow!(message));
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => {
+                        return Err(anyhow!("Connection to the VM was lost"));
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                }
+            }
+        }
+    }
+}
+
+/// Columns and rows of the terminal on stdout, if it is one
+fn window_size() -> Option<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes into the winsize we pass
+    let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (rc == 0 && size.ws_col > 0 && size.ws_row > 0).then_some((size.ws_col, size.ws_row))
+}
+
+/// Puts stdin in raw mode while alive, if it is a terminal
+struct RawMode {
+    saved: Option<Termios>,
+}
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return Ok(Self { saved: None });
+        }
+        let saved = termios::tcgetattr(stdin.as_fd())?;
+        let mut raw = saved.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw)?;
+        Ok(Self { saved: Some(saved) })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            let _ = termios::tcsetattr(std::io::stdin().as_fd(), SetArg::TCSANOW, saved);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Claude Code process supervision. Each `Agent` is one `claude` process with
+//! its relay threads. When the process exits the sidecar reports
+//! `AgentExited` and keeps the vsock session open; the next input spawns a new
+//! process that resumes the captured session with `--resume <session_id>`.
+
+use std::io::{BufRead, BufReader};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use lia_protocol::{PermissionDecision, VsockMessage};
+use tracing::info;
+
+use crate::link::HostLink;
+use crate::permissions::Approvals;
+use crate::turn::{Turn, TurnEvent};
+use crate::{failed_mcp_servers, init_session_id, send_error, write_stdin, ClaudeInputMessage};
+
+/// Settings shared by every Claude process of a task
+pub struct AgentOptions {
+    pub claude_path: &'static str,
+    pub api_key: String,
+    pub mcp_config: Option<&'static str>,
+    /// Set in supervised mode
+    pub approvals: Option<Arc<Approvals>>,
+    /// Last session id reported by Claude, used to resume after an exit
+    pub session_id: Arc<Mutex<Option<String>>>,
+    pub link: Arc<HostLink>,
+}
+
+pub struct Agent {
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+    turn: Arc<Turn>,
+    running: Arc<AtomicBool>,
+    supervisor: JoinHandle<()>,
+}
+
+impl Agent {
+    /// Spawn Claude, send `prompt` as the first user message and start the
+    /// relay threads. With `resume`, the process continues that session.
+    pub fn spawn(options: &AgentOptions, resume: Option<&str>, prompt: String) -> Result<Self> {
+        // Spawn Claude Code process with piped I/O
+        // Use stream-json for both input and output for structured bidirectional communication
+        // Run as 'claude' user to allow --dangerously-skip-permissions (which doesn't work as root)
+        let mut command = Command::new("sudo");
+        command
+            .arg("-u")
+            .arg("claude")
+            .arg("-E") // Preserve environment (for ANTHROPIC_API_KEY and the egress proxy)
+            .arg("--")
+            .arg(options.claude_path)
+            .arg("--print")
+            .arg("--input-format")
+            .arg("stream-json")
+            .arg("--output-format")
+            .arg("stream-json")
+            .arg("--verbose")
+            .arg("--include-partial-messages")
+            .env("ANTHROPIC_API_KEY", &options.api_key)
+            .env("HOME", "/home/claude")
+            .current_dir("/workspace")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if options.approvals.is_some() {
+            command.arg("--permission-prompt-tool").arg("stdio");
+        } else {
+            command.arg("--dangerously-skip-permissions");
+        }
+        if let Some(path) = options.mcp_config {
+            command.arg("--mcp-config").arg(path);
+        }
+        if let Some(session_id) = resume {
+            command.arg("--resume").arg(session_id);
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to spawn Claude Code: {}", e))?;
+
+        let stdin = Arc::new(Mutex::new(child.stdin.take()));
+        let child_stdout = child.stdout.take().expect("Failed to get stdout");
+        let child_stderr = child.stderr.take().expect("Failed to get stderr");
+
+        // Send the prompt to Claude via stdin as JSON
+        let turn = Arc::new(Turn::new());
+        let initial_json = serde_json::to_string(&ClaudeInputMessage::user(prompt))? + "\n";
+        if !write_stdin(&stdin, &initial_json) {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("Failed to send initial prompt to Claude");
+        }
+        turn.start();
+        info!("Sent initial prompt to Claude");
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        // Thread: stdout -> vsock (line-based for stream-json format)
+        let running_clone = running.clone();
+        let link = options.link.clone();
+        let approvals = options.approvals.clone();
+        let turn_clone = turn.clone();
+        let session_id = options.session_id.clone();
+        let mcp_configured = options.mcp_config.is_some();
+        let stdout_thread = std::thread::spawn(move || {
+            info!("stdout_thread started");
+            let reader = BufReader::new(child_stdout);
+            let mut line_count = 0;
+            let mut init_seen = false;
+            for line in reader.lines() {
+                if !running_clone.load(Ordering::Relaxed) {
+                    info!("stdout_thread: running flag is false, breaking");
+                    break;
+                }
+                match line {
+                    Ok(data) => {
+                        line_count += 1;
+                        // Tool approvals go to the host instead of the output stream
+                        if let Some(ref approvals) = approvals {
+                            if data.contains("\"control_request\"") {
+                                if let Some(msg) = approvals.register(&data) {
+                                    link.send(&msg);
+                                    continue;
+                                }
+                            }
+                        }
+                        let turn_event = turn_clone.observe(&data);
+                        match turn_event {
+                            TurnEvent::InterruptAck => continue,
+                            TurnEvent::InterruptFailed(ref error) => {
+                                send_error(
+                                    &link,
+                                    &format!("Failed to interrupt the current turn: {}", error),
+                                );
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        // Remember the session and report MCP servers that failed to connect
+                        let mut mcp_failures = None;
+                        if !init_seen && data.contains("\"init\"") {
+                            if let Some(id) = init_session_id(&data) {
+                                info!("Claude session id: {}", id);
+                                *session_id.lock().unwrap() = Some(id);
+                                init_seen = true;
+                                if mcp_configured {
+                                    mcp_failures = failed_mcp_servers(&data);
+                                }
+                            }
+                        }
+                        // Each line is a complete JSON object from Claude Code
+                        link.send(&VsockMessage::Output { data });
+                        for (name, status) in mcp_failures.unwrap_or_default() {
+                            send_error(
+                                &link,
+                                &format!("MCP server '{}' failed to connect ({})", name, status),
+                            );
+                        }
+                        if turn_event == TurnEvent::Cancelled {
+                            info!("Turn cancelled, waiting for next input");
+                            // Claude abandons tool calls that were waiting for approval
+                            if let Some(ref approvals) = approvals {
+                                approvals.clear();
+                            }
+                            link.send(&VsockMessage::TurnCancelled);
+                        }
+                    }
+                    Err(e) => {
+                        info!("stdout_thread: read error: {}, breaking", e);
+                        break;
+                    }
+                }
+            }
+            info!("stdout_thread finished after {} lines", line_count);
+        });
+
+        // Thread: stderr -> vsock
+        let running_clone = running.clone();
+        let link = options.link.clone();
+        let stderr_thread = std::thread::spawn(move || {
+            let mut reader = BufReader::new(child_stderr);
+            let mut buffer = [0u8; 4096];
+            while running_clone.load(Ordering::Relaxed) {
+                match std::io::Read::read(&mut reader, &mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let data = String::from_utf8_lossy(&buffer[..n]).to_string();
+                        link.send(&VsockMessage::Output { data });
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        // Thread: apply the default decision to approvals nobody answered in time
+        let timeout_thread = options.approvals.clone().map(|approvals| {
+            let running_clone = running.clone();
+            let stdin = stdin.clone();
+            let link = options.link.clone();
+            std::thread::spawn(move || {
+                while running_clone.load(Ordering::Relaxed) {
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    for (request_id, decision, line) in approvals.expire() {
+                        info!("Approval {} timed out, applying {:?}", request_id, decision);
+                        write_stdin(&stdin, &line);
+                        link.send(&VsockMessage::PermissionResolved {
+                            request_id,
+                            decision,
+                            timed_out: true,
+                        });
+                    }
+                }
+            })
+        });
+
+        // Thread: wait for Claude to exit, then report it to the host
+        let running_clone = running.clone();
+        let link = options.link.clone();
+        let approvals = options.approvals.clone();
+        let session_id = options.session_id.clone();
+        let supervisor = std::thread::spawn(move || {
+            let exit_code = match child.wait() {
+                Ok(status) => status.code().unwrap_or(-1),
+                Err(e) => {
+                    tracing::error!("Failed to wait for Claude Code: {}", e);
+                    -1
+                }
+            };
+            info!("Claude Code exited with code: {}", exit_code);
+
+            // Stop relay threads
+            running_clone.store(false, Ordering::Relaxed);
+            let _ = stdout_thread.join();
+            let _ = stderr_thread.join();
+            if let Some(thread) = timeout_thread {
+                let _ = thread.join();
+            }
+            // Approvals still waiting died with Claude; tell the host so it
+            // stops offering them
+            if let Some(approvals) = approvals {
+                for request_id in approvals.clear() {
+                    link.send(&VsockMessage::PermissionResolved {
+                        request_id,
+                        decision: PermissionDecision::Deny,
+                        timed_out: false,
+                    });
+                }
+            }
+
+            // If Claude exited with an error, send error message
+            if exit_code != 0 {
+                send_error(
+                    &link,
+                    &format!("Claude Code exited with code {}", exit_code),
+                );
+            }
+
+            let session_id = session_id.lock().unwrap().clone();
+            link.send(&VsockMessage::AgentExited {
+                code: exit_code,
+                session_id,
+            });
+        });
+
+        Ok(Self {
+            stdin,
+            turn,
+            running,
+            supervisor,
+        })
+    }
+
+    /// Whether the Claude process is still alive
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Send a follow-up user message. Returns false if Claude is gone.
+    pub fn send_input(&self, data: String) -> bool {
+        let claude_msg = ClaudeInputMessage::user(data);
+        let json = match serde_json::to_string(&claude_msg) {
+            Ok(j) => j + "\n",
+            Err(_) => return false,
+        };
+        if !write_stdin(&self.stdin, &json) {
+            return false;
+        }
+        self.turn.start();
+        true
+    }
+
+    /// Ask Claude to cancel the running turn. Returns false if no turn is running.
+    pub fn interrupt(&self) -> bool {
+        match self.turn.interrupt() {
+            Some(request) => write_stdin(&self.stdin, &request),
+            None => false,
+        }
+    }
+
+    /// Write a raw stream-json line (e.g. a permission control response)
+    pub fn write_line(&self, line: &str) -> bool {
+        write_stdin(&self.stdin, line)
+    }
+
+    /// Wait until the exit has been reported to the host
+    pub fn wait(self) {
+        let _ = self.supervisor.join();
+    }
+}
@@ -0,0 +1,204 @@
+//! Whole-workspace archives for the host. `tar` runs as the claude user and
+//! writes the archive to a pipe. Each archive has a thread of its own that
+//! reads one chunk per host request, so a slow download holds tar back
+//! instead of buffering the workspace in memory.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Child, ChildStdout, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use lia_protocol::{FileError, FileErrorKind, VsockMessage, FILE_CHUNK_LEN};
+use tracing::{info, warn};
+
+use crate::exec::claude_command;
+use crate::files::{self, WORKSPACE};
+use crate::link::HostLink;
+
+struct Archive {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+/// Chunk requests for each running archive's thread, tagged with a number
+/// so a finished thread never removes its replacement
+type Running = Arc<Mutex<HashMap<String, (u64, Sender<()>)>>>;
+
+/// Archives in progress on one host connection. Unfinished ones are killed
+/// when the connection goes away.
+#[derive(Default)]
+pub struct Archives {
+    running: Running,
+    started: u64,
+}
+
+impl Archives {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tar and send the first chunk from the archive's thread
+    pub fn start(&mut self, link: &Arc<HostLink>, request_id: String, exclude: &[String]) {
+        // Dropping a stale archive's sender stops its thread
+        self.running.lock().unwrap().remove(&request_id);
+        let archive = match spawn(exclude) {
+            Ok(archive) => archive,
+            Err(error) => {
+                link.send_live(&failure(request_id, error));
+                return;
+            }
+        };
+        info!("Archiving workspace (excluding {:?})", exclude);
+
+        self.started += 1;
+        let (tx, rx) = mpsc::channel();
+        tx.send(()).expect("receiver is alive");
+        self.running
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), (self.started, tx));
+        let id = self.started;
+        let running = self.running.clone();
+        let link = link.clone();
+        std::thread::spawn(move || serve(archive, request_id, id, rx, link, running));
+    }
+
+    /// Ask the archive's thread for the next chunk
+    pub fn next(&self, link: &HostLink, request_id: String) {
+        let sent = match self.running.lock().unwrap().get(&request_id) {
+            Some((_, tx)) => tx.send(()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            link.send_live(&failure(
+                request_id,
+                FileError {
+                    kind: FileErrorKind::Io,
+                    message: "no archive in progress".to_string(),
+                },
+            ));
+        }
+    }
+}
+
+impl Drop for Archives {
+    fn drop(&mut self) {
+        // The threads see their senders go and kill tar
+        self.running.lock().unwrap().clear();
+    }
+}
+
+/// Send one chunk per request until tar is done. Stops tar if the requests
+/// end first (the connection went away or the archive was restarted).
+fn serve(
+    mut archive: Archive,
+    request_id: String,
+    id: u64,
+    requests: Receiver<()>,
+    link: Arc<HostLink>,
+    running: Running,
+) {
+    while requests.recv().is_ok() {
+        let reply = match read_chunk(&mut archive) {
+            Ok((data, false)) => {
+                link.send_live(&VsockMessage::ArchiveData {
+                    request_id: request_id.clone(),
+                    data,
+                    done: false,
+                });
+                continue;
+            }
+            Ok((data, true)) => match finish(&mut archive.child) {
+                Ok(()) => VsockMessage::ArchiveData {
+                    request_id: request_id.clone(),
+                    data,
+                    done: true,
+                },
+                Err(error) => failure(request_id.clone(), error),
+            },
+            Err(error) => {
+                stop(&mut archive.child);
+                failure(request_id.clone(), error)
+            }
+        };
+
+        let mut running = running.lock().unwrap();
+        if running.get(&request_id).is_some_and(|(current, _)| *current == id) {
+            running.remove(&request_id);
+        }
+        drop(running);
+        link.send_live(&reply);
+        return;
+    }
+    stop(&mut archive.child);
+}
+
+/// Entries named like one of `exclude` are left out at any depth. Names are
+/// matched literally, so they must not contain a slash.
+fn spawn(exclude: &[String]) -> Result<Archive, FileError> {
+    if let Some(name) = exclude
+        .iter()
+        .find(|name| name.is_empty() || name.contains('/') || *name == "." || *name == "..")
+    {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidPath,
+            message: format!("{:?} is not a file name", name),
+        });
+    }
+
+    let mut command = claude_command("tar")?;
+    command
+        .args([
+            "--create",
+            "--file=-",
+            "--directory",
+            WORKSPACE,
+            "--no-wildcards",
+            "--hard-dereference",
+        ])
+        .args(exclude.iter().map(|name| format!("--exclude={}", name)))
+        .arg(".")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    let mut child = command.spawn().map_err(|e| files::io_error(e, "tar"))?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(Archive { child, stdout })
+}
+
+/// Read up to one chunk; true once tar closed its output
+fn read_chunk(archive: &mut Archive) -> Result<(Vec<u8>, bool), FileError> {
+    let mut data = Vec::with_capacity(FILE_CHUNK_LEN);
+    let read = (&mut archive.stdout)
+        .take(FILE_CHUNK_LEN as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| files::io_error(e, "tar"))?;
+    Ok((data, read < FILE_CHUNK_LEN))
+}
+
+/// Wait for tar. Exit status 1 means some files changed while being read,
+/// which is expected in a live workspace.
+fn finish(child: &mut Child) -> Result<(), FileError> {
+    let status = child.wait().map_err(|e| files::io_error(e, "tar"))?;
+    match status.code() {
+        Some(0 | 1) => Ok(()),
+        _ => Err(FileError {
+            kind: FileErrorKind::Io,
+            message: format!("tar failed: {}", status),
+        }),
+    }
+}
+
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn failure(request_id: String, error: FileError) -> VsockMessage {
+    warn!("Workspace archive failed: {}", error.message);
+    VsockMessage::FileResult {
+        request_id,
+        size: 0,
+        error: Some(error),
+    }
+}
@@ -0,0 +1,191 @@
+//! Changes in the workspace's git repositories, for reviewing agent work from
+//! the host. Each repository is compared against the commit where it left its
+//! upstream, so new commits and uncommitted edits show up alike.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use lia_protocol::{DiffFile, FileError, RepoDiff, VsockMessage};
+use tracing::warn;
+
+use crate::files::{self, WORKSPACE};
+use crate::git;
+
+/// Most patch text returned by one GetDiff, over all repositories
+const MAX_PATCH_LEN: usize = 4 * 1024 * 1024;
+
+/// Git's empty tree, the base of a repository without commits
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Answer a GetDiff
+pub fn diff(request_id: String, stat_only: bool) -> VsockMessage {
+    match repositories() {
+        Ok(roots) => {
+            let mut budget = if stat_only { 0 } else { MAX_PATCH_LEN };
+            let repos = roots
+                .iter()
+                .map(|(path, root)| repo_diff(path, root, stat_only, &mut budget))
+                .collect();
+            VsockMessage::Diff { request_id, repos }
+        }
+        Err(error) => {
+            warn!("Diff failed: {}", error.message);
+            VsockMessage::FileResult {
+                request_id,
+                size: 0,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+/// The workspace itself if it is a repository, then every top-level
+/// directory that is one, by path relative to the workspace. Symlinks are
+/// not followed; git then runs as claude in the paths found.
+fn repositories() -> Result<Vec<(String, PathBuf)>, FileError> {
+    let workspace = files::open_workspace()?;
+    let root = files::real_path(&workspace).map_err(|e| files::io_error(e, WORKSPACE))?;
+    let mut repos = Vec::new();
+    if files::has_entry(&workspace, ".git") {
+        repos.push((String::new(), root.clone()));
+    }
+
+    let children = files::read_entries(&workspace).map_err(|e| files::io_error(e, WORKSPACE))?;
+    for child in children {
+        if !child.is_dir() || child.name == ".git" {
+            continue;
+        }
+        let Ok(dir) = files::open_subdir(&workspace, &child.name) else {
+            continue;
+        };
+        if files::has_entry(&dir, ".git") {
+            repos.push((child.name.to_string_lossy().into_owned(), root.join(&child.name)));
+        }
+    }
+    Ok(repos)
+}
+
+fn repo_diff(path: &str, root: &Path, stat_only: bool, budget: &mut usize) -> RepoDiff {
+    let rev = |args: &[&str]| git::run(root, args).map(|out| out.trim_end().to_string());
+    let head = rev(&["rev-parse", "--verify", "--quiet", "HEAD"]);
+
+    // The branch point with the remote's default branch, or with the
+    // branch's upstream; without either only uncommitted changes show
+    let mut base_ref = None;
+    let mut base = head.clone();
+    if head.is_some() {
+        let upstream = rev(&["rev-parse", "--abbrev-ref", "HEAD@{upstream}"]);
+        for candidate in ["origin/HEAD".to_string()].into_iter().chain(upstream) {
+            if let Some(fork) = rev(&["merge-base", "HEAD", &candidate]) {
+                base_ref = Some(candidate);
+                base = Some(fork);
+                break;
+            }
+        }
+    }
+    let against = base.as_deref().unwrap_or(EMPTY_TREE);
+
+    // External diff drivers and textconv filters are commands from the
+    // repository's config
+    let diff_args = |extra: &[&'static str]| {
+        let mut args = vec!["diff", "--no-color", "--no-ext-diff", "--no-textconv", "-M"];
+        args.extend_from_slice(extra);
+        args.push(against);
+        args.push("--");
+        args
+    };
+    let files = match (
+        git::run(root, &diff_args(&["--name-status", "-z"])),
+        git::run(root, &diff_args(&["--numstat", "-z"])),
+    ) {
+        (Some(names), Some(numstat)) => changed_files(&names, &numstat),
+        _ => {
+            warn!("git diff failed in {}", root.display());
+            Vec::new()
+        }
+    };
+    let untracked = git::run(root, &["ls-files", "--others", "--exclude-standard", "-z"])
+        .map(|out| {
+            out.split('\0')
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut truncated = false;
+    let patch = if stat_only {
+        None
+    } else {
+        git::run(root, &diff_args(&[])).map(|mut patch| {
+            if patch.len() > *budget {
+                let mut end = *budget;
+                while !patch.is_char_boundary(end) {
+                    end -= 1;
+                }
+                patch.truncate(end);
+                truncated = true;
+            }
+            *budget -= patch.len();
+            patch
+        })
+    };
+
+    RepoDiff {
+        path: path.to_string(),
+        base_ref,
+        base,
+        head,
+        files,
+        untracked,
+        patch,
+        truncated,
+    }
+}
+
+/// Join `--name-status -z` (status, then one path or old and new path) with
+/// `--numstat -z` (counts and a path, or counts, empty, old and new path)
+fn changed_files(names: &str, numstat: &str) -> Vec<DiffFile> {
+    let mut counts = HashMap::new();
+    let mut records = numstat.split('\0');
+    while let Some(record) = records.next() {
+        let mut fields = record.splitn(3, '\t');
+        let (Some(added), Some(deleted), Some(path)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let path = if path.is_empty() {
+            records.next();
+            records.next().unwrap_or_default()
+        } else {
+            path
+        };
+        // Binary files count as "-"
+        counts.insert(path.to_string(), (added.parse().ok(), deleted.parse().ok()));
+    }
+
+    let mut files = Vec::new();
+    let mut records = names.split('\0');
+    while let Some(status) = records.next() {
+        let Some(letter) = status.get(..1) else {
+            continue;
+        };
+        let old_path = if matches!(letter, "R" | "C") {
+            records.next().map(String::from)
+        } else {
+            None
+        };
+        let Some(path) = records.next() else {
+            break;
+        };
+        let (additions, deletions) = counts.get(path).copied().unwrap_or((None, None));
+        files.push(DiffFile {
+            path: path.to_string(),
+            old_path,
+            status: letter.to_string(),
+            additions,
+            deletions,
+        });
+    }
+    files
+}
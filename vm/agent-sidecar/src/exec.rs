@@ -0,0 +1,288 @@
+//! Commands run for the host outside of Claude (`npm test`, `ps aux`), as the
+//! claude user. Output is sent back as it arrives. Each command gets its own
+//! process group, which is killed on timeout, on ExecKill and when the host
+//! connection goes away.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lia_protocol::{ExecStream, FileError, FileErrorKind, VsockMessage};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{Pid, User};
+use tracing::{info, warn};
+
+use crate::files::{self, WORKSPACE};
+use crate::link::HostLink;
+
+/// Largest piece of output in one ExecOutput
+const OUTPUT_CHUNK_LEN: usize = 64 * 1024;
+
+/// How long output may keep coming after the command exited, from background
+/// processes that inherited its stdout
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PATH: &str = "/home/claude/.local/bin:/usr/local/bin:/usr/bin:/bin";
+
+/// Egress proxy settings the sidecar gets from /etc/lia/proxy.env, kept in
+/// the commands' clean environment
+const PROXY_VARS: &[&str] = &[
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+];
+
+/// Process groups of the commands running on one host connection
+type Running = Arc<Mutex<HashMap<String, Pid>>>;
+
+/// Commands started on one host connection. Those still running are killed
+/// when the connection goes away.
+#[derive(Default)]
+pub struct Execs {
+    running: Running,
+}
+
+impl Execs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start `argv` and report its output and exit to the host from threads
+    /// of its own
+    pub fn start(
+        &self,
+        link: &Arc<HostLink>,
+        request_id: String,
+        argv: &[String],
+        env: &BTreeMap<String, String>,
+        cwd: Option<&str>,
+        timeout_secs: u64,
+    ) {
+        let child = match spawn(argv, env, cwd) {
+            Ok(child) => child,
+            Err(error) => {
+                warn!("Exec of {:?} failed: {}", argv, error.message);
+                link.send_live(&VsockMessage::ExecExit {
+                    request_id,
+                    code: None,
+                    signal: None,
+                    timed_out: false,
+                    error: Some(error.message),
+                });
+                return;
+            }
+        };
+        info!("Exec {:?} started (pid {})", argv, child.id());
+
+        let pgid = Pid::from_raw(child.id() as i32);
+        self.running
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), pgid);
+        let running = self.running.clone();
+        let link = link.clone();
+        let timeout = Duration::from_secs(timeout_secs);
+        std::thread::spawn(move || supervise(child, request_id, timeout, link, running));
+    }
+
+    /// Kill a running command; its ExecExit follows from the supervisor
+    pub fn kill(&self, request_id: &str) {
+        if let Some(pgid) = self.running.lock().unwrap().get(request_id) {
+            let _ = killpg(*pgid, Signal::SIGKILL);
+        }
+    }
+}
+
+impl Drop for Execs {
+    fn drop(&mut self) {
+        for (_, pgid) in self.running.lock().unwrap().drain() {
+            let _ = killpg(pgid, Signal::SIGKILL);
+        }
+    }
+}
+
+fn spawn(
+    argv: &[String],
+    env: &BTreeMap<String, String>,
+    cwd: Option<&str>,
+) -> Result<Child, FileError> {
+    let Some(program) = argv.first() else {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidPath,
+            message: "empty command".to_string(),
+        });
+    };
+    // An absolute cwd replaces the workspace
+    let cwd = Path::new(WORKSPACE).join(cwd.unwrap_or_default());
+    claude_command(program)?
+        .args(&argv[1..])
+        .envs(env)
+        .current_dir(&cwd)
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| files::io_error(e, program))
+}
+
+/// `program` set up to run as the claude user, in a clean login-like
+/// environment. Supplementary groups are dropped.
+pub fn claude_command(program: &str) -> Result<Command, FileError> {
+    let user = User::from_name("claude")
+        .ok()
+        .flatten()
+        .ok_or_else(|| FileError {
+            kind: FileErrorKind::Io,
+            message: "user claude does not exist".to_string(),
+        })?;
+    let mut command = Command::new(program);
+    command
+        .env_clear()
+        .env("PATH", PATH)
+        .env("HOME", &user.dir)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", "/bin/bash")
+        .env("LANG", "C.UTF-8")
+        .current_dir(WORKSPACE)
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw());
+    for var in PROXY_VARS {
+        if let Ok(value) = std::env::var(var) {
+            command.env(var, value);
+        }
+    }
+    Ok(command)
+}
+
+/// Relay the command's output, enforce its timeout and report its exit
+fn supervise(
+    mut child: Child,
+    request_id: String,
+    timeout: Duration,
+    link: Arc<HostLink>,
+    running: Running,
+) {
+    let (drained_tx, drained_rx) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    for (pipe, stream) in [
+        (Box::new(stdout) as Box<dyn Read + Send>, ExecStream::Stdout),
+        (Box::new(stderr), ExecStream::Stderr),
+    ] {
+        let link = link.clone();
+        let request_id = request_id.clone();
+        let drained_tx = drained_tx.clone();
+        std::thread::spawn(move || {
+            relay_output(pipe, stream, &request_id, &link);
+            let _ = drained_tx.send(());
+        });
+    }
+
+    let pgid = Pid::from_raw(child.id() as i32);
+    let deadline = Instant::now() + timeout;
+    let mut timed_out = false;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to wait for exec {}: {}", request_id, e);
+                break None;
+            }
+        }
+        if !timed_out && Instant::now() >= deadline {
+            info!("Exec {} timed out, killing it", request_id);
+            timed_out = true;
+            let _ = killpg(pgid, Signal::SIGKILL);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    running.lock().unwrap().remove(&request_id);
+
+    // Let the output catch up before reporting the exit
+    let drain_deadline = Instant::now() + DRAIN_TIMEOUT;
+    for _ in 0..2 {
+        let left = drain_deadline.saturating_duration_since(Instant::now());
+        if drained_rx.recv_timeout(left).is_err() {
+            break;
+        }
+    }
+
+    info!("Exec {} ended: {:?}", request_id, status);
+    link.send_live(&VsockMessage::ExecExit {
+        request_id,
+        code: status.and_then(|s| s.code()),
+        signal: status.and_then(|s| s.signal()),
+        timed_out,
+        error: None,
+    });
+}
+
+/// Send everything read from `pipe` as ExecOutput, never splitting a UTF-8
+/// character between two messages
+fn relay_output(
+    mut pipe: Box<dyn Read + Send>,
+    stream: ExecStream,
+    request_id: &str,
+    link: &HostLink,
+) {
+    let mut buffer = vec![0; OUTPUT_CHUNK_LEN];
+    let mut pending = 0;
+    loop {
+        let read = match pipe.read(&mut buffer[pending..]) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        let filled = pending + read;
+        let end = complete_len(&buffer[..filled]);
+        if end > 0 {
+            send_output(link, request_id, stream, &buffer[..end]);
+        }
+        buffer.copy_within(end..filled, 0);
+        pending = filled - end;
+    }
+    if pending > 0 {
+        send_output(link, request_id, stream, &buffer[..pending]);
+    }
+}
+
+/// Length of `data` without an incomplete UTF-8 character at its end
+fn complete_len(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        // Skip continuation bytes until the lead byte of the last character
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let width = match byte {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        return if width > back {
+            data.len() - back
+        } else {
+            data.len()
+        };
+    }
+    data.len()
+}
+
+fn send_output(link: &HostLink, request_id: &str, stream: ExecStream, data: &[u8]) {
+    link.send_live(&VsockMessage::ExecOutput {
+        request_id: request_id.to_string(),
+        stream,
+        data: String::from_utf8_lossy(data).into_owned(),
+    });
+}
@@ -0,0 +1,584 @@
+//! Workspace file transfers for the host. Paths are relative to /workspace
+//! and may not leave it, neither through `..` nor through symlinks. Uploads
+//! go to a temporary file next to the destination and are renamed into place
+//! once complete, so an aborted upload never leaves half a file behind.
+//!
+//! The agent owns the workspace and may swap a directory for a symlink at
+//! any time, so paths are not checked and then opened as root. Everything is
+//! opened relative to a descriptor of the workspace with `openat2` and
+//! `RESOLVE_BENEATH`, which checks and opens in one step, and files are
+//! created and renamed relative to a descriptor of their directory.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lia_protocol::{is_valid_workspace_path, FileError, FileErrorKind, VsockMessage, FILE_CHUNK_LEN};
+use nix::errno::Errno;
+use nix::dir::Dir;
+use nix::fcntl::{renameat, AtFlags, OFlag};
+use nix::sys::stat::{fstatat, mkdirat, FileStat, Mode};
+use nix::unistd::{fchownat, unlinkat, Gid, Uid, UnlinkatFlags};
+use tracing::{info, warn};
+
+pub const WORKSPACE: &str = "/workspace";
+
+/// Distinguishes temporary upload files
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+struct Upload {
+    file: File,
+    /// Directory of the destination, which the temporary file is renamed in
+    dir: File,
+    temp: OsString,
+    name: OsString,
+    written: u64,
+}
+
+impl Upload {
+    fn discard(&self) {
+        let _ = unlinkat(
+            Some(self.dir.as_raw_fd()),
+            self.temp.as_os_str(),
+            UnlinkatFlags::NoRemoveDir,
+        );
+    }
+}
+
+/// Uploads in progress on one host connection. Unfinished uploads are
+/// discarded when the connection goes away.
+#[derive(Default)]
+pub struct Transfers {
+    uploads: HashMap<String, Upload>,
+    /// Uploads that already failed; their remaining chunks are dropped
+    failed: HashSet<String>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply one upload chunk. Returns the reply for the host once the
+    /// upload is finished or has failed.
+    pub fn write(
+        &mut self,
+        request_id: String,
+        path: &str,
+        offset: u64,
+        data: &[u8],
+        done: bool,
+    ) -> Option<VsockMessage> {
+        if self.failed.contains(&request_id) {
+            if done {
+                self.failed.remove(&request_id);
+            }
+            return None;
+        }
+
+        match self.write_chunk(&request_id, path, offset, data, done) {
+            Ok(None) => None,
+            Ok(Some(size)) => {
+                info!("Uploaded {} ({} bytes)", path, size);
+                Some(VsockMessage::FileResult {
+                    request_id,
+                    size,
+                    error: None,
+                })
+            }
+            Err(error) => {
+                warn!("Upload of {} failed: {}", path, error.message);
+                if let Some(upload) = self.uploads.remove(&request_id) {
+                    upload.discard();
+                }
+                if !done {
+                    self.failed.insert(request_id.clone());
+                }
+                Some(VsockMessage::FileResult {
+                    request_id,
+                    size: 0,
+                    error: Some(error),
+                })
+            }
+        }
+    }
+
+    /// Returns the final size once the last chunk is written
+    fn write_chunk(
+        &mut self,
+        request_id: &str,
+        path: &str,
+        offset: u64,
+        data: &[u8],
+        done: bool,
+    ) -> Result<Option<u64>, FileError> {
+        if offset == 0 {
+            if let Some(stale) = self.uploads.remove(request_id) {
+                stale.discard();
+            }
+            let upload = start_upload(path)?;
+            self.uploads.insert(request_id.to_string(), upload);
+        }
+
+        let upload = self.uploads.get_mut(request_id).ok_or_else(|| FileError {
+            kind: FileErrorKind::Io,
+            message: format!("chunk at offset {} for an upload that never started", offset),
+        })?;
+        if upload.written != offset {
+            return Err(FileError {
+                kind: FileErrorKind::Io,
+                message: format!("expected a chunk at offset {}, got {}", upload.written, offset),
+            });
+        }
+        upload.file.write_all(data).map_err(|e| io_error(e, path))?;
+        upload.written += data.len() as u64;
+
+        if !done {
+            return Ok(None);
+        }
+        let upload = self.uploads.remove(request_id).expect("upload exists");
+        upload.file.sync_all().map_err(|e| io_error(e, path))?;
+        let dir = Some(upload.dir.as_raw_fd());
+        if let Err(e) = renameat(dir, upload.temp.as_os_str(), dir, upload.name.as_os_str()) {
+            upload.discard();
+            return Err(io_error(e.into(), path));
+        }
+        Ok(Some(upload.written))
+    }
+}
+
+impl Drop for Transfers {
+    fn drop(&mut self) {
+        for (_, upload) in self.uploads.drain() {
+            upload.discard();
+        }
+    }
+}
+
+/// Answer a FileRead with up to `len` bytes (at most one chunk) of `path`
+pub fn read(request_id: String, path: &str, offset: u64, len: u64) -> VsockMessage {
+    match read_chunk(path, offset, len) {
+        Ok((data, size)) => VsockMessage::FileData {
+            request_id,
+            offset,
+            data,
+            size,
+        },
+        Err(error) => {
+            warn!("Download of {} failed: {}", path, error.message);
+            VsockMessage::FileResult {
+                request_id,
+                size: 0,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+fn read_chunk(path: &str, offset: u64, len: u64) -> Result<(Vec<u8>, u64), FileError> {
+    let workspace = open_workspace()?;
+    // Not blocking on a FIFO; it is turned away below
+    let mut file = open_beneath(
+        &workspace,
+        relative(path)?,
+        libc::O_RDONLY | libc::O_NONBLOCK,
+        0,
+    )
+    .map_err(|e| beneath_error(e, path))?;
+    let metadata = file.metadata().map_err(|e| io_error(e, path))?;
+    if !metadata.is_file() {
+        return Err(FileError {
+            kind: FileErrorKind::NotFound,
+            message: format!("{} is not a regular file", path),
+        });
+    }
+
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| io_error(e, path))?;
+    let mut data = Vec::new();
+    file.take(len.min(FILE_CHUNK_LEN as u64))
+        .read_to_end(&mut data)
+        .map_err(|e| io_error(e, path))?;
+    Ok((data, metadata.len()))
+}
+
+/// Create the destination's directories and a temporary file beside it.
+/// New files and directories belong to the owner of the workspace.
+fn start_upload(path: &str) -> Result<Upload, FileError> {
+    let relative = relative(path)?;
+    let name = relative
+        .file_name()
+        .expect("workspace paths have a file name")
+        .to_os_string();
+    let workspace = open_workspace()?;
+    let owner = workspace.metadata().map_err(|e| io_error(e, WORKSPACE))?;
+    let owner = (Uid::from_raw(owner.uid()), Gid::from_raw(owner.gid()));
+
+    let parent = relative.parent().expect("workspace paths have a parent");
+    let dir = create_dirs(&workspace, parent, owner).map_err(|e| beneath_error(e, path))?;
+    let is_dir = fstatat(
+        Some(dir.as_raw_fd()),
+        name.as_os_str(),
+        AtFlags::AT_SYMLINK_NOFOLLOW,
+    )
+    .is_ok_and(|stat| stat.st_mode & libc::S_IFMT == libc::S_IFDIR);
+    if is_dir {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidPath,
+            message: format!("{} is a directory", path),
+        });
+    }
+
+    let temp = OsString::from(format!(
+        ".{}.upload-{}",
+        name.to_string_lossy(),
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = open_beneath(
+        &dir,
+        Path::new(&temp),
+        libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+        0o666,
+    )
+    .map_err(|e| io_error(e, path))?;
+    std::os::unix::fs::fchown(&file, Some(owner.0.as_raw()), Some(owner.1.as_raw()))
+        .map_err(|e| io_error(e, path))?;
+
+    Ok(Upload {
+        file,
+        dir,
+        temp,
+        name,
+        written: 0,
+    })
+}
+
+/// Open `dir` below the workspace, creating it and the directories above it
+/// that are missing for `owner`
+fn create_dirs(workspace: &File, dir: &Path, owner: (Uid, Gid)) -> io::Result<File> {
+    let mut current = workspace.try_clone()?;
+    let mut below = PathBuf::new();
+    for component in dir.components() {
+        below.push(component);
+        current = match open_dir(workspace, &below) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Created in the directory just opened, and owned before
+                // anything is put in it
+                let name = component.as_os_str();
+                match mkdirat(Some(current.as_raw_fd()), name, Mode::from_bits_truncate(0o777)) {
+                    Ok(()) => fchownat(
+                        Some(current.as_raw_fd()),
+                        name,
+                        Some(owner.0),
+                        Some(owner.1),
+                        AtFlags::AT_SYMLINK_NOFOLLOW,
+                    )?,
+                    Err(Errno::EEXIST) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                open_dir(workspace, &below)?
+            }
+            opened => opened?,
+        };
+    }
+    Ok(current)
+}
+
+pub fn open_workspace() -> Result<File, FileError> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(WORKSPACE)
+        .map_err(|e| io_error(e, WORKSPACE))
+}
+
+pub fn open_dir(workspace: &File, path: &Path) -> io::Result<File> {
+    open_beneath(workspace, path, libc::O_RDONLY | libc::O_DIRECTORY, 0)
+}
+
+/// Open the directory `name` in `dir`, refusing a symlink
+pub fn open_subdir(dir: &File, name: &OsStr) -> io::Result<File> {
+    open_nofollow(dir, Path::new(name), libc::O_RDONLY | libc::O_DIRECTORY, 0)
+}
+
+/// An entry of a directory, as `lstat` sees it
+pub struct DirEntry {
+    pub name: OsString,
+    pub stat: FileStat,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+    }
+}
+
+/// The entries of `dir` in name order. Entries removed while listing are
+/// left out.
+pub fn read_entries(dir: &File) -> io::Result<Vec<DirEntry>> {
+    let mut listing = Dir::openat(
+        Some(dir.as_raw_fd()),
+        ".",
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let mut entries = Vec::new();
+    for entry in listing.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if matches!(name.to_bytes(), b"." | b"..") {
+            continue;
+        }
+        if let Ok(stat) = fstatat(Some(dir.as_raw_fd()), name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            entries.push(DirEntry {
+                name: OsStr::from_bytes(name.to_bytes()).to_os_string(),
+                stat,
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Whether `dir` has an entry `name`, which may be a symlink
+pub fn has_entry(dir: &File, name: &str) -> bool {
+    fstatat(Some(dir.as_raw_fd()), name, AtFlags::AT_SYMLINK_NOFOLLOW).is_ok()
+}
+
+/// Where an open file currently is. Only for display and for commands run
+/// as the claude user; nothing is opened as root through it.
+pub fn real_path(file: &File) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Open `path` relative to `dir`, failing if it leads out of `dir`, through
+/// `..` or symlinks. Symlinks that stay inside are followed. Kernels before
+/// 5.6 have no `openat2`; there no symlinks are followed at all.
+fn open_beneath(dir: &File, path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: open_how is plain data; its non-exhaustive fields stay zero
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = mode as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+    // SAFETY: `c_path` and `how` outlive the call, `how` has the size passed
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            c_path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd >= 0 {
+        // SAFETY: openat2 returned a new descriptor that nothing else owns
+        return Ok(unsafe { File::from_raw_fd(fd as libc::c_int) });
+    }
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(error);
+    }
+    open_nofollow(dir, path, flags, mode)
+}
+
+/// `open_beneath` without `openat2`: one component at a time, refusing
+/// symlinks
+fn open_nofollow(dir: &File, path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+    let mut current = dir.try_clone()?;
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        let last = components.peek().is_none();
+        let c_name = CString::new(component.as_os_str().as_bytes())?;
+        let flags = if last {
+            flags
+        } else {
+            libc::O_RDONLY | libc::O_DIRECTORY
+        };
+        // SAFETY: `c_name` outlives the call
+        let fd = unsafe {
+            libc::openat(
+                current.as_raw_fd(),
+                c_name.as_ptr(),
+                flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                mode as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openat returned a new descriptor that nothing else owns
+        current = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(current)
+}
+
+/// Check a host path, which is relative to the workspace
+pub fn relative(path: &str) -> Result<&Path, FileError> {
+    if !is_valid_workspace_path(path) {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidPath,
+            message: format!("{:?} is not a relative path inside the workspace", path),
+        });
+    }
+    Ok(Path::new(path))
+}
+
+/// Like `io_error`, telling apart paths that `open_beneath` refused
+pub fn beneath_error(e: io::Error, path: &str) -> FileError {
+    if e.raw_os_error() == Some(libc::EXDEV) {
+        return FileError {
+            kind: FileErrorKind::InvalidPath,
+            message: format!("{} leads out of the workspace", path),
+        };
+    }
+    io_error(e, path)
+}
+
+pub fn io_error(e: io::Error, path: &str) -> FileError {
+    let kind = match e.kind() {
+        io::ErrorKind::NotFound => FileErrorKind::NotFound,
+        _ => FileErrorKind::Io,
+    };
+    FileError {
+        kind,
+        message: format!("{}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// A workspace and a directory beside it, with a file the workspace's
+    /// symlinks must not reach
+    struct Scratch {
+        root: PathBuf,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("lia-files-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(root.join("workspace/sub")).unwrap();
+            std::fs::create_dir_all(root.join("outside")).unwrap();
+            std::fs::write(root.join("outside/secret"), "secret").unwrap();
+            std::fs::write(root.join("workspace/sub/file"), "file").unwrap();
+            symlink(root.join("outside"), root.join("workspace/absolute")).unwrap();
+            symlink("../outside", root.join("workspace/relative")).unwrap();
+            symlink("sub/file", root.join("workspace/inside")).unwrap();
+            Self { root }
+        }
+
+        fn workspace(&self) -> PathBuf {
+            self.root.join("workspace")
+        }
+
+        fn open(&self) -> File {
+            File::open(self.workspace()).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn owner() -> (Uid, Gid) {
+        (nix::unistd::getuid(), nix::unistd::getgid())
+    }
+
+    #[test]
+    fn relative_rejects_paths_out_of_the_workspace() {
+        for path in ["", "/etc/passwd", "../etc/passwd", "a/../../b", "./a"] {
+            let error = relative(path).unwrap_err();
+            assert_eq!(error.kind, FileErrorKind::InvalidPath, "{:?}", path);
+        }
+        assert_eq!(relative("a/b.txt").unwrap(), Path::new("a/b.txt"));
+    }
+
+    #[test]
+    fn open_beneath_stays_in_the_workspace() {
+        let scratch = Scratch::new("open");
+        let workspace = scratch.open();
+        for path in ["absolute/secret", "relative/secret"] {
+            assert!(
+                open_beneath(&workspace, Path::new(path), libc::O_RDONLY, 0).is_err(),
+                "{:?}",
+                path
+            );
+        }
+        let mut file = open_beneath(&workspace, Path::new("sub/file"), libc::O_RDONLY, 0).unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "file");
+    }
+
+    #[test]
+    fn open_nofollow_refuses_symlinks() {
+        let scratch = Scratch::new("nofollow");
+        let workspace = scratch.open();
+        for path in ["absolute/secret", "relative/secret", "inside"] {
+            assert!(
+                open_nofollow(&workspace, Path::new(path), libc::O_RDONLY, 0).is_err(),
+                "{:?}",
+                path
+            );
+        }
+        assert!(open_nofollow(&workspace, Path::new("sub/file"), libc::O_RDONLY, 0).is_ok());
+    }
+
+    #[test]
+    fn read_entries_does_not_follow_symlinks() {
+        let scratch = Scratch::new("entries");
+        let workspace = scratch.open();
+        let entries = read_entries(&workspace).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.to_str().unwrap()).collect();
+        assert_eq!(names, ["absolute", "inside", "relative", "sub"]);
+        let dirs: Vec<_> = entries.iter().map(DirEntry::is_dir).collect();
+        assert_eq!(dirs, [false, false, false, true]);
+
+        for name in ["absolute", "relative"] {
+            assert!(open_subdir(&workspace, OsStr::new(name)).is_err(), "{:?}", name);
+        }
+        let sub = open_subdir(&workspace, OsStr::new("sub")).unwrap();
+        assert!(has_entry(&sub, "file"));
+        assert!(!has_entry(&sub, "missing"));
+        assert_eq!(
+            real_path(&sub).unwrap(),
+            std::fs::canonicalize(scratch.workspace().join("sub")).unwrap()
+        );
+    }
+
+    #[test]
+    fn create_dirs_does_not_follow_symlinks_out() {
+        let scratch = Scratch::new("create");
+        let workspace = scratch.open();
+        for path in ["absolute/new", "relative/new"] {
+            assert!(create_dirs(&workspace, Path::new(path), owner()).is_err(), "{:?}", path);
+        }
+        assert!(!scratch.root.join("outside/new").exists());
+
+        create_dirs(&workspace, Path::new("sub/a/b"), owner()).unwrap();
+        assert!(scratch.workspace().join("sub/a/b").is_dir());
+    }
+
+    #[test]
+    fn escapes_are_invalid_paths() {
+        let scratch = Scratch::new("errors");
+        let workspace = scratch.open();
+        let error = open_beneath(&workspace, Path::new("absolute/secret"), libc::O_RDONLY, 0)
+            .map_err(|e| beneath_error(e, "absolute/secret"))
+            .unwrap_err();
+        assert_eq!(error.kind, FileErrorKind::InvalidPath);
+    }
+}
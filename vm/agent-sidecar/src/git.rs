@@ -0,0 +1,22 @@
+//! Running git on the workspace
+
+use std::path::Path;
+
+use crate::exec;
+
+/// Run git in `dir` and return its stdout, or None if it fails. Repository
+/// config can name commands to run (`core.fsmonitor`, hooks), and the agent
+/// controls it, so git runs as the claude user with those turned off.
+pub fn run(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = exec::claude_command("git")
+        .ok()?
+        .args(["-c", "core.fsmonitor=", "-c", "core.hooksPath=/dev/null", "-C"])
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,283 @@
+//! Connection to the host. Every message to the host carries a sequence
+//! number and stays buffered until the host acknowledges it, so a new host
+//! connection (API restart, dropped relay) can take over the session with
+//! `Attach { last_seq }` and receive everything it missed.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+
+use lia_protocol::frame::Transport;
+use lia_protocol::VsockMessage;
+use tracing::{info, warn};
+
+/// Unacknowledged messages kept for replay; older ones are dropped
+const REPLAY_LIMIT: usize = 10_000;
+
+#[derive(Default)]
+struct LinkState {
+    conn: Option<std::fs::File>,
+    /// Encoding negotiated with the current connection
+    transport: Transport,
+    /// Incremented on every attach, identifies the current connection
+    generation: u64,
+    next_seq: u64,
+    unacked: VecDeque<(u64, VsockMessage)>,
+}
+
+#[derive(Default)]
+pub struct HostLink {
+    state: Mutex<LinkState>,
+}
+
+impl HostLink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a message for the host and write it to the current connection,
+    /// if any. Messages sent while no host is attached are replayed on attach.
+    pub fn send(&self, msg: &VsockMessage) {
+        let mut state = self.state.lock().unwrap();
+        state.next_seq += 1;
+        let seq = state.next_seq;
+
+        if state.unacked.len() == REPLAY_LIMIT {
+            state.unacked.pop_front();
+        }
+        state.unacked.push_back((seq, msg.clone()));
+
+        state.write(Some(seq), msg);
+    }
+
+    /// Write a message to the current connection without sequencing or
+    /// buffering it (heartbeats are only meaningful live)
+    pub fn send_live(&self, msg: &VsockMessage) {
+        self.state.lock().unwrap().write(None, msg);
+    }
+
+    /// Make `conn` the host connection, replaying everything after `last_seq`
+    /// in the connection's `transport`. A previous connection is shut down.
+    /// Returns the connection's generation.
+    pub fn attach(
+        &self,
+        mut conn: std::fs::File,
+        last_seq: u64,
+        transport: Transport,
+    ) -> std::io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.unacked.retain(|(seq, _)| *seq > last_seq);
+
+        if let Some((first, _)) = state.unacked.front() {
+            if *first > last_seq + 1 {
+                warn!(
+                    "Replay buffer starts at {}, host missed messages {}..{}",
+                    first,
+                    last_seq + 1,
+                    first - 1
+                );
+            }
+        }
+        info!(
+            "Host attached at seq {}, replaying {} message(s)",
+            last_seq,
+            state.unacked.len()
+        );
+
+        for (seq, msg) in &state.unacked {
+            conn.write_all(&transport.encode(Some(*seq), msg)?)?;
+        }
+        conn.flush()?;
+        state.transport = transport;
+
+        // The old connection's reader sees EOF and exits
+        if let Some(old) = state.conn.replace(conn) {
+            info!("New host connection takes over the session");
+            unsafe { libc::shutdown(old.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+        state.generation += 1;
+        Ok(state.generation)
+    }
+
+    /// Forget the connection if it is still the current one (it hit EOF)
+    pub fn detach(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation && state.conn.take().is_some() {
+            info!("Host connection closed");
+        }
+    }
+
+    /// The host has processed everything up to `seq`
+    pub fn ack(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        while state.unacked.front().is_some_and(|(s, _)| *s <= seq) {
+            state.unacked.pop_front();
+        }
+    }
+}
+
+impl LinkState {
+    /// Write to the current connection, dropping it if the write fails
+    fn write(&mut self, seq: Option<u64>, msg: &VsockMessage) {
+        let Some(conn) = self.conn.as_mut() else {
+            return;
+        };
+        let bytes = match self.transport.encode(seq, msg) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to encode message for host: {}", e);
+                return;
+            }
+        };
+        if conn.write_all(&bytes).and_then(|_| conn.flush()).is_err() {
+            warn!("Write to host failed, waiting for reconnect");
+            self.conn = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+
+    use lia_protocol::Sequenced;
+
+    use super::*;
+
+    /// A host connection for the link and the host's end of it
+    fn connection() -> (std::fs::File, BufReader<UnixStream>) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (OwnedFd::from(ours).into(), BufReader::new(theirs))
+    }
+
+    fn output(data: &str) -> VsockMessage {
+        VsockMessage::Output {
+            data: data.to_string(),
+        }
+    }
+
+    /// Read one JSON line from the host's end
+    fn receive(host: &mut BufReader<UnixStream>) -> (Option<u64>, String) {
+        let mut line = String::new();
+        host.read_line(&mut line).unwrap();
+        let sequenced: Sequenced<VsockMessage> = serde_json::from_str(&line).unwrap();
+        match sequenced.msg {
+            VsockMessage::Output { data } => (sequenced.seq, data),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn unacked_seqs(link: &HostLink) -> Vec<u64> {
+        let state = link.state.lock().unwrap();
+        state.unacked.iter().map(|(seq, _)| *seq).collect()
+    }
+
+    #[test]
+    fn attach_replays_after_last_seq() {
+        let link = HostLink::new();
+        link.send(&output("one"));
+        link.send(&output("two"));
+        link.send(&output("three"));
+        link.send_live(&output("not buffered"));
+
+        let (conn, mut host) = connection();
+        link.attach(conn, 1, Transport::Lines).unwrap();
+        assert_eq!(receive(&mut host), (Some(2), "two".to_string()));
+        assert_eq!(receive(&mut host), (Some(3), "three".to_string()));
+
+        // Live messages follow the replay
+        link.send(&output("four"));
+        link.send_live(&output("live"));
+        assert_eq!(receive(&mut host), (Some(4), "four".to_string()));
+        assert_eq!(receive(&mut host), (None, "live".to_string()));
+        assert_eq!(unacked_seqs(&link), [2, 3, 4]);
+    }
+
+    #[test]
+    fn ack_trims_replay_buffer() {
+        let link = HostLink::new();
+        for data in ["one", "two", "three"] {
+            link.send(&output(data));
+        }
+
+        link.ack(2);
+        assert_eq!(unacked_seqs(&link), [3]);
+        // Acks are cumulative and may repeat
+        link.ack(1);
+        assert_eq!(unacked_seqs(&link), [3]);
+        link.ack(3);
+        assert!(unacked_seqs(&link).is_empty());
+
+        // Nothing left to replay, new messages keep counting up
+        let (conn, mut host) = connection();
+        link.attach(conn, 3, Transport::Lines).unwrap();
+        link.send(&output("four"));
+        assert_eq!(receive(&mut host), (Some(4), "four".to_string()));
+    }
+
+    #[test]
+    fn replay_buffer_drops_oldest_beyond_limit() {
+        let link = HostLink::new();
+        for i in 0..REPLAY_LIMIT + 5 {
+            link.send(&output(&i.to_string()));
+        }
+
+        let seqs = unacked_seqs(&link);
+        assert_eq!(seqs.len(), REPLAY_LIMIT);
+        assert_eq!(seqs[0], 6);
+        assert_eq!(*seqs.last().unwrap(), (REPLAY_LIMIT + 5) as u64);
+
+        // A host that last saw seq 2 gets what is left, starting at 6
+        let (conn, host) = connection();
+        let reader = std::thread::spawn(move || {
+            let mut host = host;
+            let mut first = None;
+            let mut count = 0;
+            loop {
+                let mut line = String::new();
+                if host.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let sequenced: Sequenced<VsockMessage> = serde_json::from_str(&line).unwrap();
+                first.get_or_insert(sequenced.seq);
+                count += 1;
+            }
+            (first.flatten(), count)
+        });
+        let generation = link.attach(conn, 2, Transport::Lines).unwrap();
+        link.detach(generation);
+        drop(link);
+        assert_eq!(reader.join().unwrap(), (Some(6), REPLAY_LIMIT));
+    }
+
+    #[test]
+    fn new_connection_takes_over() {
+        let link = HostLink::new();
+        link.send(&output("one"));
+
+        let (old_conn, mut old_host) = connection();
+        let old_generation = link.attach(old_conn, 0, Transport::Lines).unwrap();
+        assert_eq!(receive(&mut old_host), (Some(1), "one".to_string()));
+
+        let (new_conn, mut new_host) = connection();
+        let new_generation = link.attach(new_conn, 0, Transport::Lines).unwrap();
+        assert!(new_generation > old_generation);
+        assert_eq!(receive(&mut new_host), (Some(1), "one".to_string()));
+
+        // The old connection is shut down
+        let mut rest = Vec::new();
+        old_host.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // The old reader's EOF must not detach the new connection
+        link.detach(old_generation);
+        link.send(&output("two"));
+        assert_eq!(receive(&mut new_host), (Some(2), "two".to_string()));
+
+        link.detach(new_generation);
+        assert!(link.state.lock().unwrap().conn.is_none());
+    }
+}
@@ -1,59 +1,125 @@
+use std::collections::BTreeMap;
 use std::io::{BufRead, BufReader, Write};
 use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::io::RawFd;
-use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::process::ChildStdin;
+use std::sync::{mpsc, Arc, Mutex};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use lia_protocol::frame::{self, Transport};
+use lia_protocol::{McpServerConfig, VsockMessage, FEATURES, PROTOCOL_VERSION};
 use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use tracing::info;
 
+mod agent;
+mod archive;
+mod diff;
+mod exec;
+mod files;
+mod git;
+mod link;
+mod permissions;
+mod personalize;
+mod pty;
+mod stats;
+mod tree;
+mod turn;
+
+use agent::{Agent, AgentOptions};
+use link::HostLink;
+use permissions::Approvals;
+
 // vsock constants
 const VSOCK_PORT: u32 = 5000;
 
-// Message types matching the host API
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum VsockMessage {
-    Init {
-        api_key: String,
-        prompt: String,
-        files: Option<Vec<TaskFile>>,
-    },
-    Output {
-        data: String,
-    },
-    Input {
-        data: String,
-    },
-    Exit {
-        code: i32,
-    },
-    /// Error message sent to host when something fails
-    Error {
-        message: String,
-    },
-    Heartbeat,
+/// How often the sidecar sends a heartbeat to the host
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// MCP config handed to Claude via --mcp-config (owned by the claude user)
+const MCP_CONFIG_PATH: &str = "/home/claude/.lia/mcp.json";
+
+/// Write a stream-json line to Claude's stdin. Returns false if the write failed.
+fn write_stdin(stdin: &Mutex<Option<ChildStdin>>, line: &str) -> bool {
+    let mut stdin = stdin.lock().unwrap();
+    let Some(stdin) = stdin.as_mut() else {
+        return false;
+    };
+    if stdin.write_all(line.as_bytes()).is_err() {
+        return false;
+    }
+    let _ = stdin.flush();
+    true
 }
 
 /// Send an error message to the host via vsock
-fn send_error(writer: &mut std::fs::File, message: &str) {
+fn send_error(link: &HostLink, message: &str) {
     tracing::error!("Sending error to host: {}", message);
-    let msg = VsockMessage::Error {
+    link.send(&VsockMessage::Error {
         message: message.to_string(),
-    };
-    if let Ok(json) = serde_json::to_string(&msg) {
-        let _ = writer.write_all((json + "\n").as_bytes());
-        let _ = writer.flush();
+    });
+}
+
+/// Write the MCP config file and hand it to the claude user.
+/// The file may carry credentials in env/headers, so it is private to claude.
+fn write_mcp_config(servers: &BTreeMap<String, McpServerConfig>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::path::Path::new(MCP_CONFIG_PATH);
+    let dir = path.parent().expect("MCP config path has a parent");
+    std::fs::create_dir_all(dir)?;
+
+    let config = serde_json::json!({ "mcpServers": servers });
+    std::fs::write(path, serde_json::to_vec_pretty(&config)?)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    let user = nix::unistd::User::from_name("claude")?
+        .ok_or_else(|| anyhow::anyhow!("claude user not found"))?;
+    for p in [dir, path] {
+        std::os::unix::fs::chown(p, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
     }
+
+    Ok(())
+}
+
+/// MCP server statuses that mean the server will not come up without
+/// intervention. Servers still starting are reported as `pending`.
+const MCP_FAILED_STATUSES: &[&str] = &["failed", "needs-auth"];
+
+/// Inspect Claude's `system/init` event and collect MCP servers that failed to connect
+fn failed_mcp_servers(line: &str) -> Option<Vec<(String, String)>> {
+    let event: serde_json::Value = serde_json::from_str(line).ok()?;
+    if event.get("type")?.as_str()? != "system" || event.get("subtype")?.as_str()? != "init" {
+        return None;
+    }
+
+    let failed = event
+        .get("mcp_servers")
+        .and_then(|s| s.as_array())
+        .map(|servers| {
+            servers
+                .iter()
+                .filter_map(|s| {
+                    let name = s.get("name")?.as_str()?;
+                    let status = s.get("status")?.as_str()?;
+                    MCP_FAILED_STATUSES
+                        .contains(&status)
+                        .then(|| (name.to_string(), status.to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(failed)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TaskFile {
-    pub name: String,
-    pub content: String,
+/// Extract the session id from Claude's `system/init` event
+fn init_session_id(line: &str) -> Option<String> {
+    let event: serde_json::Value = serde_json::from_str(line).ok()?;
+    if event.get("type")?.as_str()? != "system" || event.get("subtype")?.as_str()? != "init" {
+        return None;
+    }
+    Some(event.get("session_id")?.as_str()?.to_string())
 }
 
 /// Claude Code stream-json input format
@@ -120,38 +186,37 @@ fn main() -> Result<()> {
     };
     info!("Listening on vsock port {}", VSOCK_PORT);
 
-    // Accept connection from host
-    let vsock_fd = accept_vsock(listen_fd)?;
-    info!("Accepted connection from host via vsock");
-
-    // Read init message
-    let vsock_reader = unsafe { std::fs::File::from_raw_fd(vsock_fd) };
-    let mut vsock_writer = vsock_reader.try_clone()?;
-
-    let mut line = String::new();
-    let mut reader = BufReader::new(&vsock_reader);
-    if let Err(e) = reader.read_line(&mut line) {
-        send_error(&mut vsock_writer, &format!("Failed to read init message: {}", e));
-        anyhow::bail!("Failed to read init message: {}", e);
+    // Accept host connections in the background. Each connection attaches to
+    // the link and feeds its messages into the loop below.
+    let link = Arc::new(HostLink::new());
+    let (host_tx, host_rx) = mpsc::channel::<VsockMessage>();
+    {
+        let link = link.clone();
+        std::thread::spawn(move || accept_loop(listen_fd, link, host_tx));
     }
 
-    let init_msg: VsockMessage = match serde_json::from_str(&line) {
-        Ok(msg) => msg,
-        Err(e) => {
-            send_error(&mut vsock_writer, &format!("Failed to parse init message: {} (raw: {})", e, line.trim()));
-            anyhow::bail!("Failed to parse init message: {}", e);
-        }
-    };
+    // Heartbeats let the host tell a quiet agent from a wedged VM
+    {
+        let link = link.clone();
+        std::thread::spawn(move || loop {
+            link.send_live(&VsockMessage::Heartbeat {
+                stats: Some(stats::collect()),
+            });
+            std::thread::sleep(HEARTBEAT_INTERVAL);
+        });
+    }
 
-    let (api_key, prompt, files) = match init_msg {
-        VsockMessage::Init {
-            api_key,
-            prompt,
-            files,
-        } => (api_key, prompt, files),
-        _ => {
-            send_error(&mut vsock_writer, &format!("Expected Init message, got {:?}", init_msg));
-            anyhow::bail!("Expected Init message, got {:?}", init_msg);
+    // Wait for the init message
+    let (api_key, prompt, files, mcp_servers, permissions) = loop {
+        match host_rx.recv()? {
+            VsockMessage::Init {
+                api_key,
+                prompt,
+                files,
+                mcp_servers,
+                permissions,
+            } => break (api_key, prompt, files, mcp_servers, permissions),
+            msg => send_error(&link, &format!("Expected Init message, got {:?}", msg)),
         }
     };
 
@@ -163,196 +228,424 @@ fn main() -> Result<()> {
             let path = std::path::Path::new("/workspace").join(&file.name);
             if let Some(parent) = path.parent() {
                 if let Err(e) = std::fs::create_dir_all(parent) {
-                    send_error(&mut vsock_writer, &format!("Failed to create directory {}: {}", parent.display(), e));
+                    send_error(&link, &format!("Failed to create directory {}: {}", parent.display(), e));
                     anyhow::bail!("Failed to create directory: {}", e);
                 }
             }
             if let Err(e) = std::fs::write(&path, &file.content) {
-                send_error(&mut vsock_writer, &format!("Failed to write file {}: {}", path.display(), e));
+                send_error(&link, &format!("Failed to write file {}: {}", path.display(), e));
                 anyhow::bail!("Failed to write file: {}", e);
             }
             info!("Wrote file: {}", path.display());
         }
     }
 
+    // Write MCP server config if provided
+    let mcp_config = match mcp_servers {
+        Some(servers) if !servers.is_empty() => {
+            if let Err(e) = write_mcp_config(&servers) {
+                send_error(&link, &format!("Failed to write MCP config: {}", e));
+                anyhow::bail!("Failed to write MCP config: {}", e);
+            }
+            info!("Wrote MCP config with {} server(s)", servers.len());
+            Some(MCP_CONFIG_PATH)
+        }
+        _ => None,
+    };
+
     // Check if Claude binary exists
     let claude_path = "/home/claude/.local/bin/claude";
     if !std::path::Path::new(claude_path).exists() {
-        send_error(&mut vsock_writer, &format!("Claude binary not found at {}", claude_path));
+        send_error(&link, &format!("Claude binary not found at {}", claude_path));
         anyhow::bail!("Claude binary not found");
     }
 
-    // Spawn Claude Code process with piped I/O
-    // Use stream-json for both input and output for structured bidirectional communication
-    // Run as 'claude' user to allow --dangerously-skip-permissions (which doesn't work as root)
-    let mut child = match Command::new("sudo")
-        .arg("-u")
-        .arg("claude")
-        .arg("-E")  // Preserve environment (for ANTHROPIC_API_KEY)
-        .arg("--")
-        .arg(claude_path)
-        .arg("--print")
-        .arg("--input-format")
-        .arg("stream-json")
-        .arg("--output-format")
-        .arg("stream-json")
-        .arg("--verbose")
-        .arg("--include-partial-messages")
-        .arg("--dangerously-skip-permissions")
-        .env("ANTHROPIC_API_KEY", &api_key)
-        .env("HOME", "/home/claude")
-        .current_dir("/workspace")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
+    // Supervised mode routes tool approvals through stdin/stdout control messages
+    let approvals = permissions.map(|policy| {
+        info!(
+            "Supervised mode: approvals time out after {}s (default: {:?})",
+            policy.timeout_secs, policy.default_decision
+        );
+        Arc::new(Approvals::new(policy))
+    });
+
+    let options = AgentOptions {
+        claude_path,
+        api_key,
+        mcp_config,
+        approvals: approvals.clone(),
+        session_id: Arc::new(Mutex::new(None)),
+        link: link.clone(),
+    };
+
+    let mut agent = match Agent::spawn(&options, None, prompt) {
+        Ok(agent) => agent,
         Err(e) => {
-            send_error(&mut vsock_writer, &format!("Failed to spawn Claude Code: {}", e));
-            anyhow::bail!("Failed to spawn Claude Code: {}", e);
+            send_error(&link, &e.to_string());
+            return Err(e);
         }
     };
+    link.send(&VsockMessage::AgentStarted { resumed: false });
+
+    // Host messages -> Claude. Host connections come and go, the session
+    // outlives them. Claude is relaunched with the previous session whenever
+    // input arrives after it exited.
+    for msg in host_rx {
+        match msg {
+            VsockMessage::Input { data } => {
+                if agent.is_running() && agent.send_input(data.clone()) {
+                    continue;
+                }
 
-    let mut child_stdin = child.stdin.take().expect("Failed to get stdin");
-    let child_stdout = child.stdout.take().expect("Failed to get stdout");
-    let child_stderr = child.stderr.take().expect("Failed to get stderr");
-
-    // Send initial prompt to Claude via stdin as JSON
-    let initial_msg = ClaudeInputMessage::user(prompt);
-    let initial_json = serde_json::to_string(&initial_msg)? + "\n";
-    if let Err(e) = child_stdin.write_all(initial_json.as_bytes()) {
-        send_error(&mut vsock_writer, &format!("Failed to send initial prompt to Claude: {}", e));
-        anyhow::bail!("Failed to send initial prompt: {}", e);
-    }
-    if let Err(e) = child_stdin.flush() {
-        send_error(&mut vsock_writer, &format!("Failed to flush stdin: {}", e));
-        anyhow::bail!("Failed to flush stdin: {}", e);
-    }
-    info!("Sent initial prompt to Claude");
-
-    let running = Arc::new(AtomicBool::new(true));
-
-    // Thread: stdout -> vsock (line-based for stream-json format)
-    let running_clone = running.clone();
-    let mut vsock_writer_stdout = vsock_writer.try_clone()?;
-    let stdout_thread = std::thread::spawn(move || {
-        info!("stdout_thread started");
-        let reader = BufReader::new(child_stdout);
-        let mut line_count = 0;
-        for line in reader.lines() {
-            if !running_clone.load(Ordering::Relaxed) {
-                info!("stdout_thread: running flag is false, breaking");
-                break;
-            }
-            match line {
-                Ok(data) => {
-                    line_count += 1;
-                    // Each line is a complete JSON object from Claude Code
-                    let msg = VsockMessage::Output { data };
-                    let json = serde_json::to_string(&msg).unwrap() + "\n";
-                    if vsock_writer_stdout.write_all(json.as_bytes()).is_err() {
-                        info!("stdout_thread: write failed, breaking");
-                        break;
+                // Claude is gone: wait for its exit to be reported, then resume the session
+                agent.wait();
+                let resume = options.session_id.lock().unwrap().clone();
+                info!("Relaunching Claude Code (resume: {:?})", resume);
+                agent = match Agent::spawn(&options, resume.as_deref(), data) {
+                    Ok(agent) => agent,
+                    Err(e) => {
+                        send_error(&link, &e.to_string());
+                        return Err(e);
                     }
-                    let _ = vsock_writer_stdout.flush();
+                };
+                link.send(&VsockMessage::AgentStarted {
+                    resumed: resume.is_some(),
+                });
+            }
+            VsockMessage::Interrupt => {
+                if agent.is_running() && agent.interrupt() {
+                    info!("Interrupting current turn");
+                } else {
+                    info!("Ignoring interrupt: no turn in progress");
                 }
-                Err(e) => {
-                    info!("stdout_thread: read error: {}, breaking", e);
-                    break;
+            }
+            VsockMessage::PermissionResponse {
+                request_id,
+                decision,
+                message,
+            } => {
+                let Some(ref approvals) = approvals else {
+                    continue;
+                };
+                let Some(response) = approvals.resolve(&request_id, decision, message.as_deref())
+                else {
+                    info!("Ignoring response for unknown approval {}", request_id);
+                    continue;
+                };
+                if !agent.write_line(&response) {
+                    continue;
                 }
+                link.send(&VsockMessage::PermissionResolved {
+                    request_id,
+                    decision,
+                    timed_out: false,
+                });
+            }
+            VsockMessage::Init { .. } => {
+                send_error(&link, "Session already initialized, reconnect with Attach");
+            }
+            VsockMessage::Heartbeat { .. } => {
+                tracing::debug!("Heartbeat received from host");
+            }
+            // The link already replayed for this connection
+            VsockMessage::Attach { .. } => {}
+            // Handled per connection by serve_host
+            VsockMessage::Hello { .. }
+            | VsockMessage::Ack { .. }
+            | VsockMessage::FileWrite { .. }
+            | VsockMessage::FileRead { .. }
+            | VsockMessage::ListTree { .. }
+            | VsockMessage::ArchiveStart { .. }
+            | VsockMessage::ArchiveNext { .. }
+            | VsockMessage::GetDiff { .. }
+            | VsockMessage::ExecStart { .. }
+            | VsockMessage::ExecKill { .. }
+            | VsockMessage::PtyOpen { .. }
+            | VsockMessage::PtyInput { .. }
+            | VsockMessage::PtyResize { .. }
+            | VsockMessage::PtyClose { .. }
+            | VsockMessage::Personalize { .. } => {}
+            // Sidecar -> host messages
+            VsockMessage::Output { .. }
+            | VsockMessage::Exit { .. }
+            | VsockMessage::AgentStarted { .. }
+            | VsockMessage::AgentExited { .. }
+            | VsockMessage::Error { .. }
+            | VsockMessage::PermissionRequest { .. }
+            | VsockMessage::PermissionResolved { .. }
+            | VsockMessage::TurnCancelled
+            | VsockMessage::FileData { .. }
+            | VsockMessage::FileResult { .. }
+            | VsockMessage::Tree { .. }
+            | VsockMessage::ArchiveData { .. }
+            | VsockMessage::Diff { .. }
+            | VsockMessage::ExecOutput { .. }
+            | VsockMessage::ExecExit { .. }
+            | VsockMessage::PtyOutput { .. }
+            | VsockMessage::PtyExit { .. }
+            | VsockMessage::Personalized { .. } => {
+                tracing::warn!("Ignoring unexpected message from host: {:?}", msg);
             }
         }
-        info!("stdout_thread finished after {} lines", line_count);
-    });
+    }
 
-    // Thread: stderr -> vsock
-    let running_clone = running.clone();
-    let mut vsock_writer_stderr = vsock_writer.try_clone()?;
-    let stderr_thread = std::thread::spawn(move || {
-        let mut reader = BufReader::new(child_stderr);
-        let mut buffer = [0u8; 4096];
-        while running_clone.load(Ordering::Relaxed) {
-            match std::io::Read::read(&mut reader, &mut buffer) {
-                Ok(0) => break,
-                Ok(n) => {
-                    let data = String::from_utf8_lossy(&buffer[..n]).to_string();
-                    let msg = VsockMessage::Output { data };
-                    let json = serde_json::to_string(&msg).unwrap() + "\n";
-                    if vsock_writer_stderr.write_all(json.as_bytes()).is_err() {
-                        break;
-                    }
-                    let _ = vsock_writer_stderr.flush();
-                }
-                Err(_) => break,
+    info!("Agent sidecar shutting down");
+    Ok(())
+}
+
+/// Accept host connections forever; a new connection takes over from the previous one
+fn accept_loop(listen_fd: RawFd, link: Arc<HostLink>, host_tx: mpsc::Sender<VsockMessage>) {
+    loop {
+        let fd = match accept_vsock(listen_fd) {
+            Ok(fd) => fd,
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
+            }
+        };
+        info!("Accepted connection from host via vsock");
+
+        let link = link.clone();
+        let host_tx = host_tx.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = serve_host(fd, &link, &host_tx) {
+                tracing::warn!("Host connection failed: {}", e);
             }
+        });
+    }
+}
+
+/// Answer on a connection that is not attached to the link yet
+fn reply(conn: &std::fs::File, msg: &VsockMessage) -> Result<()> {
+    let json = serde_json::to_string(msg)? + "\n";
+    let mut conn = conn;
+    conn.write_all(json.as_bytes())?;
+    conn.flush()?;
+    Ok(())
+}
+
+/// Features announced in our hello, including the transports this build supports
+fn hello_features() -> Vec<String> {
+    FEATURES
+        .iter()
+        .chain(frame::transport_features().iter())
+        .map(|f| f.to_string())
+        .collect()
+}
+
+/// Answer the host's hello with ours, or with an Error if the host speaks a
+/// different protocol version. Returns the transport for the rest of the
+/// connection.
+fn handshake(conn: &std::fs::File, line: &str) -> Result<Transport> {
+    let (protocol_version, version, features) = match serde_json::from_str(line) {
+        Ok(VsockMessage::Hello {
+            protocol_version,
+            version,
+            features,
+            ..
+        }) => (protocol_version, version, features),
+        Ok(_) | Err(_) => {
+            let message = format!(
+                "Expected a protocol {} hello, got: {}",
+                PROTOCOL_VERSION,
+                line.trim()
+            );
+            let error = VsockMessage::Error {
+                message: message.clone(),
+            };
+            let _ = reply(conn, &error);
+            anyhow::bail!(message);
         }
-    });
+    };
+
+    if protocol_version != PROTOCOL_VERSION {
+        let message = format!(
+            "Host {} speaks vsock protocol {}, sidecar {} speaks {}",
+            version,
+            protocol_version,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION
+        );
+        let error = VsockMessage::Error {
+            message: message.clone(),
+        };
+        let _ = reply(conn, &error);
+        anyhow::bail!(message);
+    }
+
+    let ours = hello_features();
+    let transport = Transport::negotiate(&ours, &features);
+    info!(
+        "Host {} connected (features: {}, transport: {:?})",
+        version,
+        features.join(", "),
+        transport
+    );
+    let hello = VsockMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: ours,
+        workspace: stats::workspace_disk(),
+    };
+    reply(conn, &hello)?;
+    Ok(transport)
+}
 
-    // Thread: vsock input -> stdin (convert to Claude's stream-json format)
-    let running_clone = running.clone();
-    let input_thread = std::thread::spawn(move || {
-        let mut reader = BufReader::new(vsock_reader);
-        let mut line = String::new();
-        while running_clone.load(Ordering::Relaxed) {
-            line.clear();
-            match reader.read_line(&mut line) {
-                Ok(0) => break,
-                Ok(_) => {
-                    if let Ok(msg) = serde_json::from_str::<VsockMessage>(&line) {
-                        match msg {
-                            VsockMessage::Input { data } => {
-                                // Wrap user input in Claude's expected JSON format
-                                let claude_msg = ClaudeInputMessage::user(data);
-                                let json = match serde_json::to_string(&claude_msg) {
-                                    Ok(j) => j + "\n",
-                                    Err(_) => continue,
-                                };
-                                if child_stdin.write_all(json.as_bytes()).is_err() {
-                                    break;
-                                }
-                                let _ = child_stdin.flush();
-                            }
-                            VsockMessage::Heartbeat => {
-                                // Respond to heartbeat
-                            }
-                            _ => {}
-                        }
+/// Next message from the host, or None once the connection is closed
+fn next_message(reader: &mut BufReader<std::fs::File>, transport: Transport) -> Option<VsockMessage> {
+    match transport {
+        Transport::Lines => {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(_) => {}
+                }
+                match serde_json::from_str(&line) {
+                    Ok(msg) => return Some(msg),
+                    Err(e) => {
+                        tracing::warn!("Failed to parse host message: {} (raw: {})", e, line.trim())
                     }
                 }
-                Err(_) => break,
             }
         }
-    });
-
-    // Wait for Claude Code to exit
-    let status = child.wait()?;
-    let exit_code = status.code().unwrap_or(-1);
-    info!("Claude Code exited with code: {}", exit_code);
+        // A bad frame leaves the stream out of sync, so drop the connection
+        Transport::Framed { .. } => match frame::read_frame(reader) {
+            Ok(frame) => frame.map(|sequenced| sequenced.msg),
+            Err(e) => {
+                tracing::warn!("Failed to read frame from host: {}", e);
+                None
+            }
+        },
+    }
+}
 
-    // Stop relay threads
-    running.store(false, Ordering::Relaxed);
+/// Attach one host connection to the link and forward its messages. After the
+/// hello exchange (in JSON lines) the connection switches to the negotiated
+/// transport; the first message is `Init` (new session) or `Attach`
+/// (reconnect to a running one), or `Personalize`, which is answered without
+/// attaching the connection. File transfers, archives, commands and shells
+/// are handled right here and do not outlive the connection.
+fn serve_host(fd: RawFd, link: &Arc<HostLink>, host_tx: &mpsc::Sender<VsockMessage>) -> Result<()> {
+    let conn = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut reader = BufReader::new(conn.try_clone()?);
 
-    // If Claude exited with an error, send error message
-    if exit_code != 0 {
-        send_error(&mut vsock_writer, &format!("Claude Code exited with code {}", exit_code));
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(());
     }
+    let transport = handshake(&conn, &line)?;
 
-    // Send exit message
-    let exit_msg = VsockMessage::Exit { code: exit_code };
-    let json = serde_json::to_string(&exit_msg)? + "\n";
-    let _ = vsock_writer.write_all(json.as_bytes());
-    let _ = vsock_writer.flush();
+    let Some(first) = next_message(&mut reader, transport) else {
+        return Ok(());
+    };
 
-    // Give the client time to read buffered data before closing the connection
-    std::thread::sleep(std::time::Duration::from_millis(500));
+    if let VsockMessage::Personalize {
+        request_id,
+        ip_address,
+        gateway,
+        mac_address,
+        ssh_public_key,
+        time_unix_ms,
+        entropy,
+    } = first
+    {
+        let identity = personalize::Identity {
+            ip_address: &ip_address,
+            gateway: &gateway,
+            mac_address: &mac_address,
+            ssh_public_key: ssh_public_key.as_deref(),
+            time_unix_ms,
+            entropy: &entropy,
+        };
+        let result = personalize::apply(request_id, &identity);
+        let mut conn = &conn;
+        conn.write_all(&transport.encode(None, &result)?)?;
+        conn.flush()?;
+        return Ok(());
+    }
 
-    // Wait for threads to finish
-    let _ = stdout_thread.join();
-    let _ = stderr_thread.join();
-    let _ = input_thread.join();
+    let last_seq = match first {
+        VsockMessage::Attach { last_seq } => last_seq,
+        _ => 0,
+    };
+    let generation = link.attach(conn, last_seq, transport)?;
+    // Init starts the session; Attach before Init is reported as an error
+    host_tx.send(first)?;
+
+    let mut transfers = files::Transfers::new();
+    let mut archives = archive::Archives::new();
+    let execs = exec::Execs::new();
+    let ptys = pty::Ptys::new();
+    while let Some(msg) = next_message(&mut reader, transport) {
+        match msg {
+            VsockMessage::Ack { seq } => link.ack(seq),
+            // Transfer replies are not replayed; the host retries on a new connection
+            VsockMessage::FileWrite {
+                request_id,
+                path,
+                offset,
+                data,
+                done,
+            } => {
+                if let Some(reply) = transfers.write(request_id, &path, offset, &data, done) {
+                    link.send_live(&reply);
+                }
+            }
+            VsockMessage::FileRead {
+                request_id,
+                path,
+                offset,
+                len,
+            } => link.send_live(&files::read(request_id, &path, offset, len)),
+            // Listings and diffs can take a while (git), so they get their own thread
+            VsockMessage::ListTree {
+                request_id,
+                path,
+                depth,
+            } => {
+                let link = link.clone();
+                std::thread::spawn(move || link.send_live(&tree::list(request_id, &path, depth)));
+            }
+            VsockMessage::GetDiff {
+                request_id,
+                stat_only,
+            } => {
+                let link = link.clone();
+                std::thread::spawn(move || link.send_live(&diff::diff(request_id, stat_only)));
+            }
+            VsockMessage::ArchiveStart {
+                request_id,
+                exclude,
+            } => archives.start(link, request_id, &exclude),
+            VsockMessage::ArchiveNext { request_id } => archives.next(link, request_id),
+            VsockMessage::ExecStart {
+                request_id,
+                argv,
+                env,
+                cwd,
+                timeout_secs,
+            } => execs.start(link, request_id, &argv, &env, cwd.as_deref(), timeout_secs),
+            VsockMessage::ExecKill { request_id } => execs.kill(&request_id),
+            VsockMessage::PtyOpen {
+                request_id,
+                cols,
+                rows,
+            } => ptys.open(link, request_id, cols, rows),
+            VsockMessage::PtyInput { request_id, data } => ptys.input(&request_id, data),
+            VsockMessage::PtyResize {
+                request_id,
+                cols,
+                rows,
+            } => ptys.resize(&request_id, cols, rows),
+            VsockMessage::PtyClose { request_id } => ptys.close(&request_id),
+            msg => host_tx.send(msg)?,
+        }
+    }
 
-    info!("Agent sidecar shutting down");
+    link.detach(generation);
     Ok(())
 }
 
@@ -408,7 +701,7 @@ fn listen_vsock(port: u32) -> Result<RawFd> {
     // Listen for connections
     let raw_fd = fd.as_raw_fd();
     info!("Calling listen() on fd={}", raw_fd);
-    let ret = unsafe { libc::listen(raw_fd, 1) };
+    let ret = unsafe { libc::listen(raw_fd, 4) };
     if ret < 0 {
         let err = std::io::Error::last_os_error();
         tracing::error!("listen() failed: {} (errno={})", err, err.raw_os_error().unwrap_or(-1));
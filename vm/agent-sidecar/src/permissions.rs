@@ -0,0 +1,251 @@
+//! Supervised mode: Claude runs with `--permission-prompt-tool stdio`, so every
+//! tool call arrives on stdout as a `can_use_tool` control request. The sidecar
+//! forwards it to the host and answers Claude once the user decides, or applies
+//! the task's default decision when the timeout expires.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lia_protocol::{PermissionDecision, PermissionPolicy, VsockMessage};
+
+struct PendingApproval {
+    input: serde_json::Value,
+    deadline: Instant,
+}
+
+pub struct Approvals {
+    policy: PermissionPolicy,
+    pending: Mutex<HashMap<String, PendingApproval>>,
+}
+
+impl Approvals {
+    pub fn new(policy: PermissionPolicy) -> Self {
+        Self {
+            policy,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// If `line` is a `can_use_tool` control request, remember it and return
+    /// the message to forward to the host
+    pub fn register(&self, line: &str) -> Option<VsockMessage> {
+        let event: serde_json::Value = serde_json::from_str(line).ok()?;
+        if event.get("type")?.as_str()? != "control_request" {
+            return None;
+        }
+        let request = event.get("request")?;
+        if request.get("subtype")?.as_str()? != "can_use_tool" {
+            return None;
+        }
+
+        let request_id = event.get("request_id")?.as_str()?.to_string();
+        let tool_name = request.get("tool_name")?.as_str()?.to_string();
+        let input = request.get("input").cloned().unwrap_or_default();
+
+        self.pending.lock().unwrap().insert(
+            request_id.clone(),
+            PendingApproval {
+                input: input.clone(),
+                deadline: Instant::now() + Duration::from_secs(self.policy.timeout_secs as u64),
+            },
+        );
+
+        Some(VsockMessage::PermissionRequest {
+            request_id,
+            tool_name,
+            input,
+        })
+    }
+
+    /// Apply the user's decision. Returns the control response line for
+    /// Claude's stdin, or None if the request is unknown or already resolved.
+    pub fn resolve(
+        &self,
+        request_id: &str,
+        decision: PermissionDecision,
+        message: Option<&str>,
+    ) -> Option<String> {
+        let pending = self.pending.lock().unwrap().remove(request_id)?;
+        Some(control_response(request_id, decision, &pending.input, message))
+    }
+
+    /// Forget all pending approvals without answering them (the turn they
+    /// belonged to was interrupted, or Claude exited). Returns the dropped
+    /// request ids.
+    pub fn clear(&self) -> Vec<String> {
+        self.pending.lock().unwrap().drain().map(|(id, _)| id).collect()
+    }
+
+    /// Resolve every approval past its deadline with the default decision.
+    /// Returns (request_id, decision, control response line) for each.
+    pub fn expire(&self) -> Vec<(String, PermissionDecision, String)> {
+        let now = Instant::now();
+        let decision = self.policy.default_decision;
+        let mut pending = self.pending.lock().unwrap();
+
+        let expired: Vec<String> = pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                let approval = pending.remove(&id)?;
+                let line = control_response(
+                    &id,
+                    decision,
+                    &approval.input,
+                    Some("No response from the user before the approval timeout"),
+                );
+                Some((id, decision, line))
+            })
+            .collect()
+    }
+}
+
+/// Build the stream-json `control_response` answering a `can_use_tool` request
+fn control_response(
+    request_id: &str,
+    decision: PermissionDecision,
+    input: &serde_json::Value,
+    message: Option<&str>,
+) -> String {
+    let result = match decision {
+        PermissionDecision::Allow => serde_json::json!({
+            "behavior": "allow",
+            "updatedInput": input,
+        }),
+        PermissionDecision::Deny => serde_json::json!({
+            "behavior": "deny",
+            "message": message.unwrap_or("The user denied this tool call"),
+        }),
+    };
+
+    let response = serde_json::json!({
+        "type": "control_response",
+        "response": {
+            "subtype": "success",
+            "request_id": request_id,
+            "response": result,
+        },
+    });
+
+    response.to_string() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approvals(timeout_secs: u32) -> Approvals {
+        Approvals::new(PermissionPolicy {
+            timeout_secs,
+            default_decision: PermissionDecision::Deny,
+        })
+    }
+
+    fn can_use_tool(request_id: &str) -> String {
+        serde_json::json!({
+            "type": "control_request",
+            "request_id": request_id,
+            "request": {
+                "subtype": "can_use_tool",
+                "tool_name": "Bash",
+                "input": {"command": "ls"},
+            },
+        })
+        .to_string()
+    }
+
+    fn parse(line: &str) -> serde_json::Value {
+        assert!(line.ends_with('\n'));
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn register_ignores_other_lines() {
+        let approvals = approvals(60);
+        assert!(approvals.register("not json").is_none());
+        assert!(approvals.register(r#"{"type":"assistant"}"#).is_none());
+        assert!(approvals
+            .register(r#"{"type":"control_request","request_id":"r","request":{"subtype":"interrupt"}}"#)
+            .is_none());
+        assert!(approvals.clear().is_empty());
+    }
+
+    #[test]
+    fn resolve_answers_once() {
+        let approvals = approvals(60);
+        match approvals.register(&can_use_tool("r1")) {
+            Some(VsockMessage::PermissionRequest { request_id, tool_name, input }) => {
+                assert_eq!(request_id, "r1");
+                assert_eq!(tool_name, "Bash");
+                assert_eq!(input["command"], "ls");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let line = approvals.resolve("r1", PermissionDecision::Allow, None).unwrap();
+        let response = parse(&line);
+        assert_eq!(response["type"], "control_response");
+        assert_eq!(response["response"]["request_id"], "r1");
+        assert_eq!(response["response"]["response"]["behavior"], "allow");
+        assert_eq!(response["response"]["response"]["updatedInput"]["command"], "ls");
+
+        assert!(approvals.resolve("r1", PermissionDecision::Allow, None).is_none());
+        assert!(approvals.resolve("unknown", PermissionDecision::Deny, None).is_none());
+    }
+
+    #[test]
+    fn resolve_deny_carries_message() {
+        let approvals = approvals(60);
+        approvals.register(&can_use_tool("r1"));
+        approvals.register(&can_use_tool("r2"));
+
+        let line = approvals
+            .resolve("r1", PermissionDecision::Deny, Some("not that"))
+            .unwrap();
+        assert_eq!(parse(&line)["response"]["response"]["message"], "not that");
+
+        let line = approvals.resolve("r2", PermissionDecision::Deny, None).unwrap();
+        let response = parse(&line);
+        assert_eq!(response["response"]["response"]["behavior"], "deny");
+        assert_eq!(response["response"]["response"]["message"], "The user denied this tool call");
+    }
+
+    #[test]
+    fn expire_applies_default_decision() {
+        let patient = approvals(60);
+        patient.register(&can_use_tool("r1"));
+        assert!(patient.expire().is_empty());
+
+        let approvals = approvals(0);
+        approvals.register(&can_use_tool("r1"));
+        let expired = approvals.expire();
+        assert_eq!(expired.len(), 1);
+        let (request_id, decision, line) = &expired[0];
+        assert_eq!(request_id, "r1");
+        assert_eq!(*decision, PermissionDecision::Deny);
+        assert_eq!(parse(line)["response"]["response"]["behavior"], "deny");
+
+        // An expired approval can no longer be answered
+        assert!(approvals.expire().is_empty());
+        assert!(approvals.resolve("r1", PermissionDecision::Allow, None).is_none());
+    }
+
+    #[test]
+    fn clear_returns_dropped_ids() {
+        let approvals = approvals(60);
+        approvals.register(&can_use_tool("r1"));
+        approvals.register(&can_use_tool("r2"));
+
+        let mut dropped = approvals.clear();
+        dropped.sort();
+        assert_eq!(dropped, ["r1", "r2"]);
+        assert!(approvals.clear().is_empty());
+        assert!(approvals.resolve("r1", PermissionDecision::Allow, None).is_none());
+    }
+}
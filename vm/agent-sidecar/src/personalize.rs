@@ -0,0 +1,130 @@
+//! Personalization of a VM restored from a golden snapshot. Every VM restored
+//! from the same snapshot wakes up with the same network identity, clock and
+//! random state, which the host replaces right after the restore.
+
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use lia_protocol::VsockMessage;
+use tracing::{info, warn};
+
+/// Interface the VM's virtio-net device shows up as
+const INTERFACE: &str = "eth0";
+
+const AUTHORIZED_KEYS: &str = "/root/.ssh/authorized_keys";
+
+/// RNDADDENTROPY and RNDRESEEDCRNG from <linux/random.h>
+const RNDADDENTROPY: libc::c_ulong = 0x4008_5203;
+const RNDRESEEDCRNG: libc::c_ulong = 0x5207;
+
+pub struct Identity<'a> {
+    pub ip_address: &'a str,
+    pub gateway: &'a str,
+    pub mac_address: &'a str,
+    pub ssh_public_key: Option<&'a str>,
+    pub time_unix_ms: i64,
+    pub entropy: &'a [u8],
+}
+
+/// Apply `identity` and answer with Personalized. The entropy goes in first,
+/// so nothing after it runs on the snapshot's random state.
+pub fn apply(request_id: String, identity: &Identity) -> VsockMessage {
+    let result = add_entropy(identity.entropy)
+        .and_then(|()| set_clock(identity.time_unix_ms))
+        .and_then(|()| configure_network(identity))
+        .and_then(|()| install_ssh_key(identity.ssh_public_key));
+    let error = match result {
+        Ok(()) => {
+            info!(
+                "Personalized as {} ({})",
+                identity.ip_address, identity.mac_address
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Personalization failed: {:#}", e);
+            Some(format!("{:#}", e))
+        }
+    };
+    VsockMessage::Personalized { request_id, error }
+}
+
+/// Credit `entropy` to the kernel's pool and reseed the CRNG from it
+fn add_entropy(entropy: &[u8]) -> Result<()> {
+    // struct rand_pool_info { int entropy_count; int buf_size; __u32 buf[]; }
+    let mut info = Vec::with_capacity(8 + entropy.len());
+    info.extend_from_slice(&((entropy.len() * 8) as i32).to_ne_bytes());
+    info.extend_from_slice(&(entropy.len() as i32).to_ne_bytes());
+    info.extend_from_slice(entropy);
+
+    let random = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/random")
+        .context("Failed to open /dev/random")?;
+    // SAFETY: `info` is a complete rand_pool_info of `entropy.len()` bytes
+    let rc = unsafe { libc::ioctl(random.as_raw_fd(), RNDADDENTROPY, info.as_ptr()) };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to add entropy");
+    }
+    // SAFETY: RNDRESEEDCRNG takes no argument
+    let rc = unsafe { libc::ioctl(random.as_raw_fd(), RNDRESEEDCRNG) };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to reseed the CRNG");
+    }
+    Ok(())
+}
+
+fn set_clock(time_unix_ms: i64) -> Result<()> {
+    let now = libc::timespec {
+        tv_sec: time_unix_ms.div_euclid(1000),
+        tv_nsec: time_unix_ms.rem_euclid(1000) * 1_000_000,
+    };
+    // SAFETY: `now` is a valid timespec
+    let rc = unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &now) };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set the clock");
+    }
+    Ok(())
+}
+
+/// Swap the snapshot's MAC and address for ours, as lia-network-init would
+/// have set them at boot
+fn configure_network(identity: &Identity) -> Result<()> {
+    let address = format!("{}/24", identity.ip_address);
+    let steps: [&[&str]; 6] = [
+        &["link", "set", INTERFACE, "down"],
+        &["link", "set", INTERFACE, "address", identity.mac_address],
+        &["addr", "flush", "dev", INTERFACE],
+        &["addr", "add", &address, "dev", INTERFACE],
+        &["link", "set", INTERFACE, "up"],
+        &["route", "replace", "default", "via", identity.gateway],
+    ];
+    for args in steps {
+        let output = Command::new("ip")
+            .args(args)
+            .output()
+            .context("Failed to run ip")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "ip {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn install_ssh_key(key: Option<&str>) -> Result<()> {
+    let Some(key) = key else {
+        return Ok(());
+    };
+    std::fs::create_dir_all("/root/.ssh").context("Failed to create /root/.ssh")?;
+    std::fs::write(AUTHORIZED_KEYS, format!("{}\n", key.trim()))
+        .context("Failed to write authorized_keys")?;
+    std::fs::set_permissions(AUTHORIZED_KEYS, std::fs::Permissions::from_mode(0o600))
+        .context("Failed to restrict authorized_keys")?;
+    Ok(())
+}
@@ -0,0 +1,216 @@
+//! Interactive shells for the host's terminal WebSocket. Each PtyOpen gets a
+//! login shell as the claude user on a PTY of its own; any number can run
+//! side by side. Shells still open when the host connection goes away get a
+//! hangup, like on a closed terminal.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::Child;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use lia_protocol::{FileError, VsockMessage};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use tracing::{info, warn};
+
+use crate::exec;
+use crate::files;
+use crate::link::HostLink;
+
+/// Largest piece of output in one PtyOutput
+const OUTPUT_CHUNK_LEN: usize = 64 * 1024;
+
+/// How long output may keep coming after the shell exited
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct Pty {
+    /// Master side, for resizing
+    master: File,
+    /// Keystrokes for the writer thread
+    input: mpsc::Sender<Vec<u8>>,
+    /// The shell leads its own session and process group
+    pid: Pid,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Pty>>>;
+
+/// Shells opened on one host connection
+#[derive(Default)]
+pub struct Ptys {
+    sessions: Sessions,
+}
+
+impl Ptys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a shell and relay its output to the host until it ends
+    pub fn open(&self, link: &Arc<HostLink>, request_id: String, cols: u16, rows: u16) {
+        let (child, master) = match spawn(cols, rows) {
+            Ok(spawned) => spawned,
+            Err(error) => {
+                warn!("Failed to open a shell: {}", error.message);
+                link.send_live(&VsockMessage::FileResult {
+                    request_id,
+                    size: 0,
+                    error: Some(error),
+                });
+                return;
+            }
+        };
+        let pid = Pid::from_raw(child.id() as i32);
+        info!("Shell {} opened (pid {})", request_id, pid);
+
+        let clone = |master: &File| master.try_clone().map_err(|e| files::io_error(e, "pty"));
+        let (reader, writer) = match (clone(&master), clone(&master)) {
+            (Ok(reader), Ok(writer)) => (reader, writer),
+            (Err(error), _) | (_, Err(error)) => {
+                let _ = killpg(pid, Signal::SIGKILL);
+                link.send_live(&VsockMessage::FileResult {
+                    request_id,
+                    size: 0,
+                    error: Some(error),
+                });
+                return;
+            }
+        };
+
+        let (input, keystrokes) = mpsc::channel();
+        std::thread::spawn(move || write_input(writer, keystrokes));
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), Pty { master, input, pid });
+
+        let sessions = self.sessions.clone();
+        let link = link.clone();
+        std::thread::spawn(move || relay(child, reader, request_id, link, sessions));
+    }
+
+    pub fn input(&self, request_id: &str, data: Vec<u8>) {
+        if let Some(pty) = self.sessions.lock().unwrap().get(request_id) {
+            let _ = pty.input.send(data);
+        }
+    }
+
+    pub fn resize(&self, request_id: &str, cols: u16, rows: u16) {
+        if let Some(pty) = self.sessions.lock().unwrap().get(request_id) {
+            let size = winsize(cols, rows);
+            // The shell gets SIGWINCH from the kernel
+            unsafe { libc::ioctl(pty.master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        }
+    }
+
+    /// Hang up a shell; its PtyExit follows once it is gone
+    pub fn close(&self, request_id: &str) {
+        if let Some(pty) = self.sessions.lock().unwrap().get(request_id) {
+            let _ = killpg(pty.pid, Signal::SIGHUP);
+        }
+    }
+}
+
+impl Drop for Ptys {
+    fn drop(&mut self) {
+        for (_, pty) in self.sessions.lock().unwrap().drain() {
+            let _ = killpg(pty.pid, Signal::SIGHUP);
+        }
+    }
+}
+
+fn winsize(cols: u16, rows: u16) -> Winsize {
+    Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Open a PTY and start `bash --login` on its slave side as the session
+/// leader, with the PTY as controlling terminal
+fn spawn(cols: u16, rows: u16) -> Result<(Child, File), FileError> {
+    let pty = openpty(&winsize(cols, rows), None).map_err(|e| files::io_error(e.into(), "pty"))?;
+    for fd in [&pty.master, &pty.slave] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| files::io_error(e.into(), "pty"))?;
+    }
+    let slave = |fd: &OwnedFd| fd.try_clone().map_err(|e| files::io_error(e, "pty"));
+
+    let mut command = exec::claude_command("/bin/bash")?;
+    command
+        .arg("--login")
+        .env("TERM", "xterm-256color")
+        .stdin(slave(&pty.slave)?)
+        .stdout(slave(&pty.slave)?)
+        .stderr(slave(&pty.slave)?);
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .map_err(|e| files::io_error(e, "/bin/bash"))?;
+    // Only the shell keeps the slave side open, so reads on the master
+    // fail once it and its children are gone
+    drop(pty.slave);
+    Ok((child, File::from(pty.master)))
+}
+
+fn write_input(mut master: File, keystrokes: mpsc::Receiver<Vec<u8>>) {
+    for data in keystrokes {
+        if master.write_all(&data).is_err() {
+            break;
+        }
+    }
+}
+
+/// Send the shell's output to the host, then its exit status once it ended
+fn relay(
+    mut child: Child,
+    mut master: File,
+    request_id: String,
+    link: Arc<HostLink>,
+    sessions: Sessions,
+) {
+    let (drained_tx, drained_rx) = mpsc::channel();
+    let reader_link = link.clone();
+    let reader_id = request_id.clone();
+    std::thread::spawn(move || {
+        let mut buffer = vec![0; OUTPUT_CHUNK_LEN];
+        loop {
+            match master.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => reader_link.send_live(&VsockMessage::PtyOutput {
+                    request_id: reader_id.clone(),
+                    data: buffer[..read].to_vec(),
+                }),
+            }
+        }
+        let _ = drained_tx.send(());
+    });
+
+    let status = child.wait().ok();
+    // Reads end once nothing holds the terminal open anymore; background
+    // jobs may keep it open after the shell is gone
+    let _ = drained_rx.recv_timeout(DRAIN_TIMEOUT);
+
+    // Dropping the session ends the writer thread
+    sessions.lock().unwrap().remove(&request_id);
+    info!("Shell {} ended: {:?}", request_id, status);
+    link.send_live(&VsockMessage::PtyExit {
+        request_id,
+        code: status.and_then(|s| s.code()),
+        signal: status.and_then(|s| s.signal()),
+    });
+}
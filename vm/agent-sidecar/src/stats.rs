@@ -0,0 +1,48 @@
+//! Guest statistics reported in heartbeats, read from /proc, and the
+//! workspace disk reported in the hello
+
+use std::os::unix::fs::MetadataExt;
+
+use lia_protocol::{GuestStats, WorkspaceDisk};
+
+use crate::files::WORKSPACE;
+
+/// Collect current stats. Values that can't be read are left at zero.
+pub fn collect() -> GuestStats {
+    let mut stats = GuestStats::default();
+
+    if let Ok(uptime) = std::fs::read_to_string("/proc/uptime") {
+        stats.uptime_secs = first_field(&uptime).unwrap_or_default() as u64;
+    }
+    if let Ok(loadavg) = std::fs::read_to_string("/proc/loadavg") {
+        stats.load_avg = first_field(&loadavg).unwrap_or_default();
+    }
+    if let Ok(meminfo) = std::fs::read_to_string("/proc/meminfo") {
+        for line in meminfo.lines() {
+            if let Some(value) = line.strip_prefix("MemTotal:") {
+                stats.mem_total_mb = first_field(value).unwrap_or_default() as u64 / 1024;
+            } else if let Some(value) = line.strip_prefix("MemAvailable:") {
+                stats.mem_available_mb = first_field(value).unwrap_or_default() as u64 / 1024;
+            }
+        }
+    }
+
+    stats
+}
+
+/// The filesystem holding /workspace, or None if it can't be read
+pub fn workspace_disk() -> Option<WorkspaceDisk> {
+    let fs = nix::sys::statvfs::statvfs(WORKSPACE).ok()?;
+    let workspace = std::fs::metadata(WORKSPACE).ok()?;
+    let root = std::fs::metadata("/").ok()?;
+    let block = fs.fragment_size() as u64;
+    Some(WorkspaceDisk {
+        mounted: workspace.dev() != root.dev(),
+        total_bytes: fs.blocks() * block,
+        available_bytes: fs.blocks_available() * block,
+    })
+}
+
+fn first_field(s: &str) -> Option<f64> {
+    s.split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,193 @@
+//! Workspace listings for the host: metadata of every entry down to a given
+//! depth, with git status, leaving out what `.gitignore` excludes. Both come
+//! from a single `git status` per repository.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use lia_protocol::{EntryKind, FileError, FileErrorKind, TreeEntry, VsockMessage};
+use tracing::warn;
+
+use crate::files::{self, WORKSPACE};
+use crate::git;
+
+/// Most entries returned by one listing
+const MAX_ENTRIES: usize = 5_000;
+
+/// Answer a ListTree
+pub fn list(request_id: String, path: &str, depth: u32) -> VsockMessage {
+    match list_dir(path, depth) {
+        Ok(listing) => VsockMessage::Tree {
+            request_id,
+            entries: listing.entries,
+            truncated: listing.truncated,
+        },
+        Err(error) => {
+            warn!("Listing of {:?} failed: {}", path, error.message);
+            VsockMessage::FileResult {
+                request_id,
+                size: 0,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+fn list_dir(path: &str, depth: u32) -> Result<Listing, FileError> {
+    // Opened beneath the workspace, and walked from there without following
+    // symlinks, so the agent cannot redirect the listing elsewhere
+    let workspace = files::open_workspace()?;
+    let root = if path.is_empty() {
+        workspace.try_clone().map_err(|e| files::io_error(e, WORKSPACE))?
+    } else {
+        files::open_dir(&workspace, files::relative(path)?).map_err(|e| {
+            if e.raw_os_error() == Some(libc::ENOTDIR) {
+                FileError {
+                    kind: FileErrorKind::InvalidPath,
+                    message: format!("{} is not a directory", path),
+                }
+            } else {
+                files::beneath_error(e, path)
+            }
+        })?
+    };
+
+    // Real paths are only shown and handed to git, which runs as claude
+    let workspace_path = files::real_path(&workspace).map_err(|e| files::io_error(e, WORKSPACE))?;
+    let root_path = files::real_path(&root).map_err(|e| files::io_error(e, path))?;
+    let repo = git::run(&root_path, &["rev-parse", "--show-toplevel"])
+        .map(|top| PathBuf::from(top.trim_end()))
+        .filter(|top| top.starts_with(&workspace_path))
+        .and_then(|top| RepoStatus::load(&top));
+
+    let mut listing = Listing {
+        workspace: workspace_path,
+        entries: Vec::new(),
+        truncated: false,
+    };
+    listing.walk(&root, &root_path, depth, repo.as_ref());
+    Ok(listing)
+}
+
+struct Listing {
+    workspace: PathBuf,
+    entries: Vec<TreeEntry>,
+    truncated: bool,
+}
+
+impl Listing {
+    /// Add the entries of `dir` (at `path`) in name order, descending
+    /// `depth - 1` more levels
+    fn walk(&mut self, dir: &File, path: &Path, depth: u32, repo: Option<&RepoStatus>) {
+        if depth == 0 {
+            return;
+        }
+        // Unreadable directories are listed without their contents
+        let Ok(children) = files::read_entries(dir) else {
+            return;
+        };
+
+        for child in children {
+            if child.name == ".git" {
+                continue;
+            }
+            let path = path.join(&child.name);
+            let git_status = match repo.and_then(|repo| repo.code(&path, child.is_dir())) {
+                Some("!!") => continue,
+                code => code.map(String::from),
+            };
+
+            if self.entries.len() == MAX_ENTRIES {
+                self.truncated = true;
+                return;
+            }
+            let kind = match child.stat.st_mode & libc::S_IFMT {
+                libc::S_IFDIR => EntryKind::Dir,
+                libc::S_IFLNK => EntryKind::Symlink,
+                libc::S_IFREG => EntryKind::File,
+                _ => EntryKind::Other,
+            };
+            self.entries.push(TreeEntry {
+                path: path
+                    .strip_prefix(&self.workspace)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .into_owned(),
+                kind,
+                size: child.stat.st_size as u64,
+                mode: child.stat.st_mode & 0o7777,
+                mtime: child.stat.st_mtime,
+                git_status,
+            });
+
+            if kind == EntryKind::Dir && depth > 1 {
+                // Swapped for a symlink since it was listed: not descended into
+                let Ok(subdir) = files::open_subdir(dir, &child.name) else {
+                    continue;
+                };
+                // A nested repository has its own status and ignore rules
+                let nested = files::has_entry(&subdir, ".git")
+                    .then(|| RepoStatus::load(&path))
+                    .flatten();
+                self.walk(&subdir, &path, depth - 1, nested.as_ref().or(repo));
+            }
+            if self.truncated {
+                return;
+            }
+        }
+    }
+}
+
+/// `git status` of one repository, keyed by path relative to its top level.
+/// Untracked and ignored directories appear once, with a trailing slash.
+struct RepoStatus {
+    root: PathBuf,
+    codes: HashMap<String, String>,
+}
+
+impl RepoStatus {
+    /// None if git is missing or fails
+    fn load(root: &Path) -> Option<Self> {
+        let output = git::run(root, &["status", "--porcelain=v1", "-z", "--ignored=matching"])?;
+        let mut codes = HashMap::new();
+        let mut records = output.split('\0');
+        while let Some(record) = records.next() {
+            if record.len() < 4 {
+                continue;
+            }
+            let (code, path) = (&record[..2], &record[3..]);
+            // Renames and copies are followed by the original path
+            if code.starts_with('R') || code.starts_with('C') {
+                records.next();
+            }
+            codes.insert(path.to_string(), code.to_string());
+        }
+        Some(Self {
+            root: root.to_path_buf(),
+            codes,
+        })
+    }
+
+    /// Status code of `path`, or of the untracked or ignored directory it is in
+    fn code(&self, path: &Path, is_dir: bool) -> Option<&str> {
+        let relative = path.strip_prefix(&self.root).ok()?.to_str()?;
+        let own = if is_dir {
+            format!("{}/", relative)
+        } else {
+            relative.to_string()
+        };
+        if let Some(code) = self.codes.get(&own) {
+            return Some(code);
+        }
+
+        let mut end = relative.len();
+        while let Some(slash) = relative[..end].rfind('/') {
+            if let Some(code) = self.codes.get(&relative[..=slash]) {
+                return Some(code);
+            }
+            end = slash;
+        }
+        None
+    }
+}
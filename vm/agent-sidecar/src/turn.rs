@@ -0,0 +1,204 @@
+//! Turn tracking for interrupts. A turn starts when a user message is written
+//! to Claude's stdin and ends with the `result` event. An interrupt is sent as
+//! a stream-json `control_request` with subtype `interrupt`; Claude abandons
+//! the turn, emits its `result`, and keeps the session open for the next input.
+//! Inputs written while a turn runs queue up in Claude, each a turn of its own.
+
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct TurnState {
+    /// Turns written to stdin whose `result` has not come yet
+    pending: u32,
+    /// request_id of the interrupt sent for the current turn, if any
+    interrupt: Option<String>,
+    next_id: u64,
+}
+
+/// What the stdout thread should do with a line from Claude
+#[derive(Debug, PartialEq, Eq)]
+pub enum TurnEvent {
+    /// Relay the line as normal output
+    Output,
+    /// Relay the line; the turn ended because of an interrupt
+    Cancelled,
+    /// Claude acknowledged the interrupt; the line is internal and not relayed
+    InterruptAck,
+    /// Claude rejected the interrupt (the turn keeps running)
+    InterruptFailed(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Turn {
+    state: Mutex<TurnState>,
+}
+
+impl Turn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a new turn (a user message was written to stdin)
+    pub fn start(&self) {
+        self.state.lock().unwrap().pending += 1;
+    }
+
+    /// Build the interrupt control request for the running turn. Returns None
+    /// when no turn is running or an interrupt is already in flight.
+    pub fn interrupt(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending == 0 || state.interrupt.is_some() {
+            return None;
+        }
+
+        state.next_id += 1;
+        let request_id = format!("lia-interrupt-{}", state.next_id);
+        state.interrupt = Some(request_id.clone());
+
+        let request = serde_json::json!({
+            "type": "control_request",
+            "request_id": request_id,
+            "request": { "subtype": "interrupt" },
+        });
+        Some(request.to_string() + "\n")
+    }
+
+    /// Classify a stdout line and update the turn state
+    pub fn observe(&self, line: &str) -> TurnEvent {
+        // Cheap pre-check, most lines are stream events
+        if !line.contains("\"result\"") && !line.contains("\"control_response\"") {
+            return TurnEvent::Output;
+        }
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+            return TurnEvent::Output;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match event.get("type").and_then(|t| t.as_str()) {
+            Some("result") => {
+                state.pending = state.pending.saturating_sub(1);
+                match state.interrupt.take() {
+                    Some(_) => TurnEvent::Cancelled,
+                    None => TurnEvent::Output,
+                }
+            }
+            Some("control_response") => {
+                let request_id = event
+                    .pointer("/response/request_id")
+                    .and_then(|id| id.as_str());
+                if request_id.is_none() || request_id != state.interrupt.as_deref() {
+                    return TurnEvent::Output;
+                }
+                if event.pointer("/response/subtype").and_then(|s| s.as_str()) == Some("error") {
+                    state.interrupt = None;
+                    let error = event
+                        .pointer("/response/error")
+                        .and_then(|e| e.as_str())
+                        .unwrap_or("unknown error");
+                    return TurnEvent::InterruptFailed(error.to_string());
+                }
+                TurnEvent::InterruptAck
+            }
+            _ => TurnEvent::Output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESULT: &str = r#"{"type":"result","subtype":"success"}"#;
+
+    fn ack(request_id: &str) -> String {
+        serde_json::json!({
+            "type": "control_response",
+            "response": { "subtype": "success", "request_id": request_id },
+        })
+        .to_string()
+    }
+
+    fn request_id(request: &str) -> String {
+        let request: serde_json::Value = serde_json::from_str(request).unwrap();
+        request["request_id"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn no_interrupt_without_a_turn() {
+        let turn = Turn::new();
+        assert_eq!(turn.interrupt(), None);
+
+        turn.start();
+        assert_eq!(turn.observe(RESULT), TurnEvent::Output);
+        assert_eq!(turn.interrupt(), None);
+    }
+
+    #[test]
+    fn queued_input_keeps_a_turn_running() {
+        let turn = Turn::new();
+        turn.start();
+        turn.start();
+
+        // The first turn ends while the second is still to run
+        assert_eq!(turn.observe(RESULT), TurnEvent::Output);
+        let request = turn.interrupt().expect("the second turn is running");
+        assert_eq!(
+            turn.observe(&ack(&request_id(&request))),
+            TurnEvent::InterruptAck
+        );
+        assert_eq!(turn.observe(RESULT), TurnEvent::Cancelled);
+        assert_eq!(turn.interrupt(), None);
+    }
+
+    #[test]
+    fn one_interrupt_at_a_time() {
+        let turn = Turn::new();
+        turn.start();
+        let first = turn.interrupt().unwrap();
+        assert_eq!(turn.interrupt(), None);
+
+        assert_eq!(
+            turn.observe(&ack(&request_id(&first))),
+            TurnEvent::InterruptAck
+        );
+        assert_eq!(turn.observe(RESULT), TurnEvent::Cancelled);
+    }
+
+    #[test]
+    fn rejected_interrupt_can_be_retried() {
+        let turn = Turn::new();
+        turn.start();
+        let request = turn.interrupt().unwrap();
+        let rejection = serde_json::json!({
+            "type": "control_response",
+            "response": {
+                "subtype": "error",
+                "request_id": request_id(&request),
+                "error": "nothing to interrupt",
+            },
+        })
+        .to_string();
+        assert_eq!(
+            turn.observe(&rejection),
+            TurnEvent::InterruptFailed("nothing to interrupt".to_string())
+        );
+
+        // The turn keeps running and ends normally unless interrupted again
+        let retry = turn.interrupt().expect("the turn is still running");
+        assert_ne!(request_id(&retry), request_id(&request));
+        assert_eq!(
+            turn.observe(&ack(&request_id(&retry))),
+            TurnEvent::InterruptAck
+        );
+        assert_eq!(turn.observe(RESULT), TurnEvent::Cancelled);
+    }
+
+    #[test]
+    fn other_control_responses_are_output() {
+        let turn = Turn::new();
+        turn.start();
+        turn.interrupt().unwrap();
+        assert_eq!(turn.observe(&ack("someone-else")), TurnEvent::Output);
+        assert_eq!(turn.observe(r#"{"type":"assistant"}"#), TurnEvent::Output);
+    }
+}
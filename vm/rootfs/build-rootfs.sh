@@ -233,6 +233,7 @@ cat > ${MOUNT_DIR}/usr/local/bin/lia-network-init << 'EOF'
 #!/bin/bash
 # Configure networking from kernel command line parameters
 # Format: lia.ip=172.16.0.X lia.gateway=172.16.0.1 lia.ssh_key="ssh-rsa ..."
+#         lia.proxy=http://172.16.0.1:3128 (optional egress proxy)
 
 CMDLINE=$(cat /proc/cmdline)
 
@@ -240,6 +241,7 @@ CMDLINE=$(cat /proc/cmdline)
 IP=$(echo "$CMDLINE" | tr ' ' '\n' | grep '^lia.ip=' | cut -d= -f2)
 GATEWAY=$(echo "$CMDLINE" | tr ' ' '\n' | grep '^lia.gateway=' | cut -d= -f2)
 SSH_KEY=$(echo "$CMDLINE" | tr ' ' '\n' | grep '^lia.ssh_key=' | cut -d= -f2- | sed 's/+/ /g')
+PROXY=$(echo "$CMDLINE" | tr ' ' '\n' | grep '^lia.proxy=' | cut -d= -f2-)
 
 if [ -n "$IP" ]; then
     echo "Configuring IP: $IP"
@@ -254,9 +256,34 @@ if [ -n "$SSH_KEY" ]; then
     echo "$SSH_KEY" > /root/.ssh/authorized_keys
     chmod 600 /root/.ssh/authorized_keys
 fi
+
+# Proxy settings for the sidecar (EnvironmentFile) and login shells
+mkdir -p /etc/lia
+if [ -n "$PROXY" ]; then
+    echo "Configuring egress proxy: $PROXY"
+    cat > /etc/lia/proxy.env << PROXY_EOF
+HTTP_PROXY=$PROXY
+HTTPS_PROXY=$PROXY
+NO_PROXY=localhost,127.0.0.1,::1
+http_proxy=$PROXY
+https_proxy=$PROXY
+no_proxy=localhost,127.0.0.1,::1
+PROXY_EOF
+else
+    : > /etc/lia/proxy.env
+fi
 EOF
 chmod +x ${MOUNT_DIR}/usr/local/bin/lia-network-init
 
+cat > ${MOUNT_DIR}/etc/profile.d/lia-proxy.sh << 'EOF'
+# Egress proxy of the VM, if the host set one (see lia-network-init)
+if [ -r /etc/lia/proxy.env ]; then
+    set -a
+    . /etc/lia/proxy.env
+    set +a
+fi
+EOF
+
 # Create systemd service for network init
 cat > ${MOUNT_DIR}/etc/systemd/system/lia-network-init.service << 'EOF'
 [Unit]
@@ -274,6 +301,53 @@ WantedBy=multi-user.target
 EOF
 chroot ${MOUNT_DIR} /bin/bash -c "systemctl enable lia-network-init.service"
 
+# Create workspace volume script (run at boot)
+cat > ${MOUNT_DIR}/usr/local/bin/lia-workspace-init << 'EOF'
+#!/bin/bash
+# Mount the task's data volume at /workspace
+# Format: lia.workspace=/dev/vdb
+# Without the parameter, or the device, /workspace stays on the root filesystem.
+
+DEVICE=$(tr ' ' '\n' < /proc/cmdline | grep '^lia.workspace=' | cut -d= -f2)
+[ -n "$DEVICE" ] || exit 0
+mountpoint -q /workspace && exit 0
+
+for _ in $(seq 1 50); do
+    [ -b "$DEVICE" ] && break
+    sleep 0.1
+done
+if [ ! -b "$DEVICE" ]; then
+    echo "Workspace volume $DEVICE not found, keeping /workspace on the root filesystem"
+    exit 0
+fi
+
+echo "Mounting workspace volume $DEVICE"
+if ! mount -o discard,noatime "$DEVICE" /workspace; then
+    echo "Failed to mount $DEVICE, keeping /workspace on the root filesystem"
+    exit 0
+fi
+rmdir /workspace/lost+found 2>/dev/null || true
+chown claude:claude /workspace
+chmod 755 /workspace
+EOF
+chmod +x ${MOUNT_DIR}/usr/local/bin/lia-workspace-init
+
+cat > ${MOUNT_DIR}/etc/systemd/system/lia-workspace-init.service << 'EOF'
+[Unit]
+Description=Lia Workspace Volume
+Before=ssh.service
+After=local-fs.target systemd-udevd.service
+
+[Service]
+Type=oneshot
+ExecStart=/usr/local/bin/lia-workspace-init
+RemainAfterExit=yes
+
+[Install]
+WantedBy=multi-user.target
+EOF
+chroot ${MOUNT_DIR} /bin/bash -c "systemctl enable lia-workspace-init.service"
+
 # Install kernel modules for vsock (required for QEMU vhost-vsock-pci)
 echo "Installing kernel modules for vsock..."
 KERNEL_VERSION=$(uname -r)
@@ -333,7 +407,7 @@ chroot ${MOUNT_DIR} /bin/bash -c "systemctl enable vsock-modules.service"
 cat > ${MOUNT_DIR}/etc/systemd/system/agent-sidecar.service << 'EOF'
 [Unit]
 Description=Lia Agent Sidecar
-After=network.target lia-network-init.service vsock-modules.service
+After=network.target lia-network-init.service lia-workspace-init.service vsock-modules.service
 Requires=vsock-modules.service
 
 [Service]
@@ -344,6 +418,7 @@ RestartSec=5
 StandardOutput=journal
 StandardError=journal
 Environment="PATH=/home/claude/.local/bin:/usr/local/bin:/usr/bin:/bin"
+EnvironmentFile=-/etc/lia/proxy.env
 WorkingDirectory=/workspace
 
 [Install]
@@ -111,6 +111,7 @@ install_dependencies() {
         debootstrap \
         e2fsprogs \
         iptables \
+        nftables \
         iproute2 \
         bridge-utils \
         openssh-client \
@@ -268,6 +269,16 @@ setup_networking() {
     # Load TUN module for TAP devices
     modprobe tun 2>/dev/null || true
 
+    # Connection tracking on the bridge, used by the VMs' egress rules.
+    # Without it replies are never seen as established and get dropped.
+    if ! modprobe nf_conntrack_bridge; then
+        log_error "Kernel module nf_conntrack_bridge is required for egress rules"
+        exit 1
+    fi
+
+    # Network block devices, used to read qcow2 rootfs overlays of stopped VMs
+    modprobe nbd max_part=0 2>/dev/null || true
+
     # Check if bridge already exists
     if ip link show ${BRIDGE_NAME} &>/dev/null; then
         log_info "Bridge ${BRIDGE_NAME} already exists"
@@ -355,6 +366,23 @@ fi
 TAPSCRIPT
     chmod +x /usr/local/bin/lia-delete-tap
 
+    # Script to rename the TAP device of a running VM
+    cat > /usr/local/bin/lia-rename-tap << 'TAPSCRIPT'
+#!/bin/bash
+set -euo pipefail
+
+FROM=$1
+TO=$2
+
+# A device can only be renamed while it is down
+ip link set ${FROM} down
+ip link set ${FROM} name ${TO}
+ip link set ${TO} up
+
+echo "Renamed TAP device ${FROM} to ${TO}"
+TAPSCRIPT
+    chmod +x /usr/local/bin/lia-rename-tap
+
     log_success "Helper scripts created"
 }
 
@@ -376,6 +404,8 @@ RemainAfterExit=yes
 ExecStart=/bin/bash -c '\\
     modprobe tun; \\
     modprobe vhost_vsock; \\
+    modprobe nf_conntrack_bridge || exit 1; \\
+    modprobe nbd max_part=0 || true; \\
     chmod 666 /dev/vhost-vsock 2>/dev/null || true; \\
     ip link show ${BRIDGE_NAME} || ip link add name ${BRIDGE_NAME} type bridge; \\
     ip addr show ${BRIDGE_NAME} | grep -q ${BRIDGE_IP} || ip addr add ${BRIDGE_IP}/24 dev ${BRIDGE_NAME}; \\
@@ -55,6 +55,19 @@ chmod 666 /dev/vhost-vsock 2>/dev/null || true
 # Load TUN module for TAP devices
 modprobe tun 2>/dev/null || true
 
+# nftables and bridge connection tracking, used by the VMs' egress rules
+if ! command -v nft &> /dev/null; then
+    apt-get install -y nftables
+fi
+# Without it the egress rules never see replies as established and drop them
+if ! modprobe nf_conntrack_bridge; then
+    echo "Error: kernel module nf_conntrack_bridge is required for egress rules"
+    exit 1
+fi
+
+# Network block devices, used to read qcow2 rootfs overlays of stopped VMs
+modprobe nbd max_part=0 2>/dev/null || true
+
 # ============================================
 # Network Bridge Setup
 # ============================================
@@ -138,6 +151,23 @@ fi
 TAPSCRIPT
 chmod +x /usr/local/bin/lia-delete-tap
 
+# Script to rename the TAP device of a running VM
+cat > /usr/local/bin/lia-rename-tap << 'TAPSCRIPT'
+#!/bin/bash
+set -euo pipefail
+
+FROM=$1
+TO=$2
+
+# A device can only be renamed while it is down
+ip link set ${FROM} down
+ip link set ${FROM} name ${TO}
+ip link set ${TO} up
+
+echo "Renamed TAP device ${FROM} to ${TO}"
+TAPSCRIPT
+chmod +x /usr/local/bin/lia-rename-tap
+
 # ============================================
 # Systemd service for bridge persistence
 # ============================================
@@ -152,6 +182,8 @@ RemainAfterExit=yes
 ExecStart=/bin/bash -c '\
     modprobe tun; \
     modprobe vhost_vsock; \
+    modprobe nf_conntrack_bridge || exit 1; \
+    modprobe nbd max_part=0 || true; \
     chmod 666 /dev/vhost-vsock 2>/dev/null || true; \
     ip link show ${BRIDGE_NAME} || ip link add name ${BRIDGE_NAME} type bridge; \
     ip addr show ${BRIDGE_NAME} | grep -q ${BRIDGE_IP} || ip addr add ${BRIDGE_IP}/24 dev ${BRIDGE_NAME}; \